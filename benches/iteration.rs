@@ -0,0 +1,124 @@
+//! Simple timing benchmarks for tree traversals.
+//!
+//! Run with `cargo bench --bench iteration`. Each benchmark prints the best
+//! time out of several runs.
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use rowan::{
+    arena,
+    cursor::{SyntaxElement, SyntaxNode},
+    test_utils::{nested_tree, wide_tree},
+    WalkEvent,
+};
+
+/// Counts allocations, so that benchmarks can report them.
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn allocations_during(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn bench(name: &str, mut f: impl FnMut() -> usize) {
+    let mut best = Duration::from_secs(u64::MAX);
+    let mut res = 0;
+    for _ in 0..5 {
+        let start = Instant::now();
+        res = f();
+        best = best.min(start.elapsed());
+    }
+    println!("{:<40} {:>10.2?} ({})", name, best, res);
+}
+
+fn main() {
+    let root = SyntaxNode::new_root(nested_tree(1_000_000));
+    bench("next_token over 1M tokens", || {
+        let mut n = 0;
+        let mut token = root.first_token();
+        while let Some(it) = token {
+            n += 1;
+            token = it.next_token();
+        }
+        n
+    });
+    bench("prev_token over 1M tokens", || {
+        let mut n = 0;
+        let mut token = root.last_token();
+        while let Some(it) = token {
+            n += 1;
+            token = it.prev_token();
+        }
+        n
+    });
+    bench("preorder over 1M tokens", || root.preorder().count());
+    bench("preorder_with_tokens over 1M tokens", || {
+        root.preorder_with_tokens()
+            .filter(|event| matches!(event, WalkEvent::Enter(SyntaxElement::Token(_))))
+            .count()
+    });
+
+    let wide = SyntaxNode::new_root(wide_tree(100_000));
+    bench("next_sibling over 100k children", || {
+        std::iter::successors(wide.first_child(), |it| it.next_sibling()).count()
+    });
+    bench("next_sibling_or_token over 100k children", || {
+        std::iter::successors(wide.first_child_or_token(), |it| it.next_sibling_or_token()).count()
+    });
+    bench("children_with_tokens().nth(99_999)", || {
+        wide.children_with_tokens().nth(99_999).is_some() as usize
+    });
+    bench("children().nth(49_999)", || wide.children().nth(49_999).is_some() as usize);
+
+    // The result is the number of allocations; the `Vec` itself accounts for
+    // about 20 of them.
+    let green = nested_tree(1_000_000);
+    let n_nodes = green.descendant_node_count() as usize + 1;
+    bench("collect descendants (allocations)", || {
+        allocations_during(|| {
+            let root = SyntaxNode::new_root(green.clone());
+            let nodes = root
+                .preorder()
+                .filter_map(|event| match event {
+                    WalkEvent::Enter(node) => Some(node),
+                    WalkEvent::Leave(_) => None,
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(nodes.len(), n_nodes);
+        })
+    });
+    bench("collect arena descendants (allocations)", || {
+        allocations_during(|| {
+            let arena = arena::Arena::new();
+            let root = arena::SyntaxNode::new_root(green.clone(), &arena);
+            let nodes = root
+                .preorder()
+                .filter_map(|event| match event {
+                    WalkEvent::Enter(node) => Some(node),
+                    WalkEvent::Leave(_) => None,
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(nodes.len(), n_nodes);
+        })
+    });
+}
@@ -0,0 +1,97 @@
+//! Timing benchmarks for lookups, edits and construction, on trees of
+//! different shapes.
+//!
+//! Run with `cargo bench --bench operations`. Each benchmark prints the best
+//! time out of several runs.
+use std::{
+    hint::black_box,
+    iter,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use rowan::{
+    cursor::{SyntaxElement, SyntaxNode},
+    test_utils::{deep_tree, mixed_tree, nested_tree, wide_tree, Rng, WORD},
+    GreenNode, TokenAtOffset, WalkEvent,
+};
+
+fn bench(name: &str, mut f: impl FnMut() -> usize) {
+    let mut best = Duration::from_secs(u64::MAX);
+    let mut res = 0;
+    for _ in 0..5 {
+        let start = Instant::now();
+        res = f();
+        best = best.min(start.elapsed());
+    }
+    println!("{:<50} {:>10.2?} ({})", name, best, res);
+}
+
+const N_QUERIES: usize = 1_000;
+
+fn main() {
+    let shapes: Vec<(&str, GreenNode)> = vec![
+        ("wide-flat", wide_tree(100_000)),
+        ("deep-narrow", deep_tree(2_000)),
+        ("nested", nested_tree(100_000)),
+        ("mixed", mixed_tree(&mut Rng::new(92), 100_000)),
+    ];
+
+    for (shape, green) in shapes.iter() {
+        let root = SyntaxNode::new_root(green.clone());
+        let len = root.text_range().end();
+
+        bench(&format!("{}: preorder_with_tokens", shape), || {
+            root.preorder_with_tokens()
+                .filter(|event| matches!(event, WalkEvent::Enter(SyntaxElement::Token(_))))
+                .count()
+        });
+        bench(&format!("{}: next_token scan", shape), || {
+            iter::successors(root.first_token(), |it| it.next_token()).count()
+        });
+
+        let mut rng = Rng::new(1);
+        bench(&format!("{}: token_at_offset x{}", shape, N_QUERIES), || {
+            (0..N_QUERIES)
+                .filter(|_| !matches!(root.token_at_offset(rng.offset(len)), TokenAtOffset::None))
+                .count()
+        });
+        let mut rng = Rng::new(2);
+        bench(&format!("{}: covering_node x{}", shape, N_QUERIES), || {
+            (0..N_QUERIES).filter(|_| root.covering_node(rng.range(len)).kind() == WORD).count()
+        });
+
+        // Replaces a random ancestor of a random token with itself, so the
+        // depth of the edit varies.
+        let mut rng = Rng::new(3);
+        bench(&format!("{}: replace_with x{}", shape, N_QUERIES / 10), || {
+            let mut total_len = 0;
+            for _ in 0..N_QUERIES / 10 {
+                let token = match root.token_at_offset(rng.offset(len)).right_biased() {
+                    Some(it) => it,
+                    None => continue,
+                };
+                let ancestors = token.parent().ancestors().collect::<Vec<_>>();
+                let node = &ancestors[rng.below(ancestors.len() as u64) as usize];
+                total_len += node.replace_with(node.green().clone()).text_len().to_usize();
+            }
+            total_len
+        });
+    }
+
+    bench("builder: mixed tree with 100k tokens", || {
+        mixed_tree(&mut Rng::new(92), 100_000).text_len().to_usize()
+    });
+    bench("builder: nested tree with 100k tokens", || nested_tree(100_000).text_len().to_usize());
+
+    // Green trees are `Send + Sync`, so their reference counts are atomic.
+    // Compare with non-atomic counting, on a single thread.
+    let green = nested_tree(10);
+    bench("refcount: GreenNode clone + drop x1M", || {
+        (0..1_000_000).map(|_| black_box(green.clone()).children().len()).sum()
+    });
+    let rc = Rc::new(green.clone());
+    bench("refcount: Rc<GreenNode> clone + drop x1M", || {
+        (0..1_000_000).map(|_| black_box(rc.clone()).children().len()).sum()
+    });
+}
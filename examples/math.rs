@@ -42,14 +42,14 @@ impl<I: Iterator<Item = (SyntaxKind, SmolStr)>> Parser<I> {
     }
     fn bump(&mut self) {
         if let Some((token, string)) = self.iter.next() {
-            self.builder.token(token.into(), string);
+            self.builder.token(token, string);
         }
     }
     fn parse_val(&mut self) {
         match self.peek() {
             Some(NUMBER) => self.bump(),
             _ => {
-                self.builder.start_node(ERROR.into());
+                self.builder.start_node(ERROR);
                 self.bump();
                 self.builder.finish_node();
             }
@@ -59,7 +59,7 @@ impl<I: Iterator<Item = (SyntaxKind, SmolStr)>> Parser<I> {
         let checkpoint = self.builder.checkpoint();
         next(self);
         while self.peek().map(|t| tokens.contains(&t)).unwrap_or(false) {
-            self.builder.start_node_at(checkpoint, OPERATION.into());
+            self.builder.start_node_at(checkpoint, OPERATION);
             self.bump();
             next(self);
             self.builder.finish_node();
@@ -72,7 +72,7 @@ impl<I: Iterator<Item = (SyntaxKind, SmolStr)>> Parser<I> {
         self.handle_operation(&[ADD, SUB], Self::parse_mul)
     }
     fn parse(mut self) -> TreeArc<SyntaxNode> {
-        self.builder.start_node(ROOT.into());
+        self.builder.start_node(ROOT);
         self.parse_add();
         self.builder.finish_node();
 
@@ -81,7 +81,7 @@ impl<I: Iterator<Item = (SyntaxKind, SmolStr)>> Parser<I> {
 }
 
 fn print(indent: usize, element: SyntaxElement) {
-    let kind: SyntaxKind = element.kind().into();
+    let kind: SyntaxKind = element.kind();
     print!("{:indent$}", "", indent = indent);
     match element {
         SyntaxElement::Node(node) => {
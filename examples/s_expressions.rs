@@ -276,7 +276,7 @@ impl Atom {
         self.text().parse().ok()
     }
     fn as_op(&self) -> Option<Op> {
-        let op = match self.text().as_str() {
+        let op = match self.text() {
             "+" => Op::Add,
             "-" => Op::Sub,
             "*" => Op::Mul,
@@ -285,7 +285,7 @@ impl Atom {
         };
         Some(op)
     }
-    fn text(&self) -> &SmolStr {
+    fn text(&self) -> &str {
         match &self.0.green().children()[0] {
             rowan::GreenElement::Token(token) => token.text(),
             _ => unreachable!(),
@@ -334,6 +334,7 @@ nan
 (+ (* 15 2) 62)
 ";
     let root = parse(sexps);
+    assert!(root.errors.is_empty());
     let res = root.syntax().sexps().map(|it| it.eval()).collect::<Vec<_>>();
     eprintln!("{:?}", res);
     assert_eq!(res, vec![Some(92), Some(92), None, None, Some(92),])
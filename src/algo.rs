@@ -1,10 +1,34 @@
-use std::iter;
+use std::{cmp, collections::BTreeMap, fmt, hash::Hasher, iter, slice};
 use std::ops::Range;
 
-use crate::{SyntaxNode, SyntaxElement, TextUnit, TextRange, SyntaxToken, SyntaxIndex};
+use rustc_hash::{FxHashMap, FxHashSet, FxHasher};
+
+use crate::{
+    cursor, GreenElement, GreenNode, MemoryStats, SmolStr, SyntaxElement, SyntaxIndex, SyntaxKind,
+    SyntaxNode, SyntaxToken, TextRange, TextUnit,
+};
 
 /// `WalkEvent` describes tree walking process.
-#[derive(Debug, Copy, Clone)]
+///
+/// ```
+/// # use rowan::{cursor::SyntaxNode, GreenNodeBuilder, SyntaxKind, WalkEvent};
+/// let mut builder = GreenNodeBuilder::new();
+/// builder.start_node(SyntaxKind(1));
+/// builder.token(SyntaxKind(0), "x".into());
+/// builder.finish_node();
+/// let root = SyntaxNode::new_root(builder.finish());
+///
+/// let kinds = root.preorder_with_tokens().map(|event| event.map(|it| it.kind().0));
+/// let kinds = kinds.collect::<Vec<_>>();
+/// assert_eq!(
+///     kinds,
+///     [WalkEvent::Enter(1), WalkEvent::Enter(0), WalkEvent::Leave(0), WalkEvent::Leave(1)]
+/// );
+/// let entered = root.preorder_with_tokens().filter_map(WalkEvent::enter);
+/// let texts = entered.filter_map(|it| it.into_token()).map(|it| it.text().to_string());
+/// assert_eq!(texts.collect::<Vec<_>>(), ["x"]);
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum WalkEvent<T> {
     /// Fired before traversing the node.
     Enter(T),
@@ -12,8 +36,63 @@ pub enum WalkEvent<T> {
     Leave(T),
 }
 
+impl<T> WalkEvent<T> {
+    /// Applies `f` to the element, keeping the direction.
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> WalkEvent<U> {
+        match self {
+            WalkEvent::Enter(it) => WalkEvent::Enter(f(it)),
+            WalkEvent::Leave(it) => WalkEvent::Leave(f(it)),
+        }
+    }
+
+    pub fn as_ref(&self) -> WalkEvent<&T> {
+        match self {
+            WalkEvent::Enter(it) => WalkEvent::Enter(it),
+            WalkEvent::Leave(it) => WalkEvent::Leave(it),
+        }
+    }
+
+    /// The element, whatever the direction.
+    pub fn into_inner(self) -> T {
+        match self {
+            WalkEvent::Enter(it) | WalkEvent::Leave(it) => it,
+        }
+    }
+
+    pub fn is_enter(&self) -> bool {
+        matches!(self, WalkEvent::Enter(_))
+    }
+
+    pub fn is_leave(&self) -> bool {
+        matches!(self, WalkEvent::Leave(_))
+    }
+
+    /// The element of an `Enter` event, for `filter_map`.
+    pub fn enter(self) -> Option<T> {
+        match self {
+            WalkEvent::Enter(it) => Some(it),
+            WalkEvent::Leave(_) => None,
+        }
+    }
+
+    /// The element of a `Leave` event, for `filter_map`.
+    pub fn leave(self) -> Option<T> {
+        match self {
+            WalkEvent::Enter(_) => None,
+            WalkEvent::Leave(it) => Some(it),
+        }
+    }
+}
+
+/// The direction of a walk along the siblings of an element.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Next,
+    Prev,
+}
+
 /// There might be zero, one or two leaves at a given offset.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum TokenAtOffset<T> {
     /// No leaves at offset -- possible for the empty file.
     None,
@@ -24,6 +103,14 @@ pub enum TokenAtOffset<T> {
 }
 
 impl<T> TokenAtOffset<T> {
+    /// Applies `f` to each of the leaves.
+    pub fn map<U>(self, mut f: impl FnMut(T) -> U) -> TokenAtOffset<U> {
+        match self {
+            TokenAtOffset::None => TokenAtOffset::None,
+            TokenAtOffset::Single(it) => TokenAtOffset::Single(f(it)),
+            TokenAtOffset::Between(left, right) => TokenAtOffset::Between(f(left), f(right)),
+        }
+    }
     /// Convert to option, preferring the right leaf in case of a tie.
     pub fn right_biased(self) -> Option<T> {
         match self {
@@ -40,6 +127,21 @@ impl<T> TokenAtOffset<T> {
             TokenAtOffset::Between(left, _) => Some(left),
         }
     }
+    /// Convert to option, preferring a leaf for which `pred` is true, and
+    /// the left one if neither or both are: `pick(|it| it.kind() == IDENT)`.
+    pub fn pick(self, pred: impl Fn(&T) -> bool) -> Option<T> {
+        match self {
+            TokenAtOffset::None => None,
+            TokenAtOffset::Single(node) => Some(node),
+            TokenAtOffset::Between(left, right) => {
+                if !pred(&left) && pred(&right) {
+                    Some(right)
+                } else {
+                    Some(left)
+                }
+            }
+        }
+    }
 }
 
 impl<T> Iterator for TokenAtOffset<T> {
@@ -63,7 +165,7 @@ impl<T> Iterator for TokenAtOffset<T> {
         match self {
             TokenAtOffset::None => (0, Some(0)),
             TokenAtOffset::Single(_) => (1, Some(1)),
-            TokenAtOffset::Between(_,_) => (2, Some(2)),
+            TokenAtOffset::Between(_, _) => (2, Some(2)),
         }
     }
 }
@@ -97,9 +199,8 @@ impl<'a> Iterator for SyntaxElementChildren<'a> {
 
     #[inline]
     fn next(&mut self) -> Option<SyntaxElement<'a>> {
-        self.current.take().map(|current| {
+        self.current.take().inspect(|current| {
             self.current = current.next_sibling_or_token();
-            current
         })
     }
 }
@@ -232,7 +333,7 @@ impl SyntaxNode {
     /// contains the range. If the range is empty and is contained in two leaf
     /// nodes, either one can be returned. Precondition: range must be contained
     /// withing the current node
-    pub fn covering_node(&self, range: TextRange) -> SyntaxElement {
+    pub fn covering_node(&self, range: TextRange) -> SyntaxElement<'_> {
         let mut res: SyntaxElement = self.into();
         loop {
             assert!(
@@ -262,6 +363,12 @@ impl SyntaxNode {
             + self.green().memory_size_of_subtree()
             + self.memory_size_of_red_children()
     }
+
+    /// Memory used by the green tree of this node, see
+    /// `GreenNode::memory_usage`.
+    pub fn memory_usage(&self) -> MemoryStats {
+        self.green().memory_usage()
+    }
 }
 
 impl<'a> SyntaxElement<'a> {
@@ -273,3 +380,1259 @@ impl<'a> SyntaxElement<'a> {
         }
     }
 }
+
+/// The subtrees which differ between two trees, see `diff`.
+#[derive(Debug, Clone, Default)]
+pub struct TreeDiff {
+    replacements: Vec<(cursor::SyntaxElement, cursor::SyntaxElement)>,
+}
+
+impl TreeDiff {
+    /// Pairs of an element of the old tree and the element of the new tree
+    /// which replaces it, in the order of the old tree. The old elements
+    /// don't overlap.
+    pub fn replacements(&self) -> &[(cursor::SyntaxElement, cursor::SyntaxElement)] {
+        &self.replacements
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.replacements.is_empty()
+    }
+
+    /// The replacements as edits of the old text, one per replacement, which
+    /// can be applied back to front.
+    pub fn to_text_edits(&self) -> Vec<TextEdit> {
+        self.replacements
+            .iter()
+            .map(|(old, new)| {
+                let mut insert = String::new();
+                push_text(&new.to_green(), &mut insert);
+                TextEdit { delete: old.text_range(), insert: insert.into() }
+            })
+            .collect()
+    }
+}
+
+/// Computes the elements of `old` to replace with elements of `new` to turn
+/// the first tree into the second.
+///
+/// The trees are compared top-down: nodes with the same green node are
+/// skipped, and nodes of the same kind with the same number of children are
+/// compared child by child. Other pairs of elements are replaced as a whole,
+/// so that the replaced subtrees are as small as this allows. Unlike
+/// `text_diff`, the result refers to the elements of both trees, for
+/// instance to invalidate what was computed for the old ones.
+pub fn diff(old: &cursor::SyntaxNode, new: &cursor::SyntaxNode) -> TreeDiff {
+    let mut replacements = Vec::new();
+    let mut stack: Vec<(cursor::SyntaxElement, cursor::SyntaxElement)> =
+        vec![(old.clone().into(), new.clone().into())];
+    while let Some(pair) = stack.pop() {
+        match &pair {
+            (cursor::SyntaxElement::Node(old), cursor::SyntaxElement::Node(new)) => {
+                if old.green().ptr_eq(new.green()) {
+                    continue;
+                }
+                if old.kind() == new.kind()
+                    && old.green().children().len() == new.green().children().len()
+                {
+                    // Pushed in reverse, to be compared in order.
+                    let first = stack.len();
+                    stack.extend(old.children_with_tokens().zip(new.children_with_tokens()));
+                    stack[first..].reverse();
+                    continue;
+                }
+            }
+            (cursor::SyntaxElement::Token(old), cursor::SyntaxElement::Token(new))
+                if old.green() == new.green() =>
+            {
+                continue
+            }
+            _ => (),
+        }
+        replacements.push(pair);
+    }
+    TreeDiff { replacements }
+}
+
+/// How much of a green tree is shared with another one, see `sharing_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SharingStats {
+    /// Number of nodes of the new tree in subtrees shared with the old one.
+    pub reused_nodes: usize,
+    /// Number of the other nodes of the new tree.
+    pub fresh_nodes: usize,
+    /// Bytes of text of the shared subtrees.
+    pub reused_text_len: usize,
+    /// Bytes of text of the other tokens of the new tree. Only nodes are
+    /// looked up, so a shared token in a fresh node counts as fresh.
+    pub fresh_text_len: usize,
+}
+
+impl fmt::Display for SharingStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let percent = |part: usize, fresh: usize| match part + fresh {
+            0 => 0.0,
+            total => part as f64 * 100.0 / total as f64,
+        };
+        write!(
+            f,
+            "{} of {} nodes reused ({:.1}%), covering {} of {} bytes of text ({:.1}%)",
+            self.reused_nodes,
+            self.reused_nodes + self.fresh_nodes,
+            percent(self.reused_nodes, self.fresh_nodes),
+            self.reused_text_len,
+            self.reused_text_len + self.fresh_text_len,
+            percent(self.reused_text_len, self.fresh_text_len),
+        )
+    }
+}
+
+/// Counts the nodes of `new` which are shared with `old`, that is which are
+/// the same allocations. Equal nodes built separately are not shared.
+///
+/// Each node of `new` is counted as many times as it appears in the tree.
+/// The walk of `new` stops at the shared nodes, as their whole subtrees are
+/// shared too.
+pub fn sharing_stats(old: &GreenNode, new: &GreenNode) -> SharingStats {
+    let mut old_nodes = FxHashSet::default();
+    let mut stack = vec![old];
+    while let Some(node) = stack.pop() {
+        if old_nodes.insert(node.as_ptr()) {
+            stack.extend(node.children().iter().filter_map(|it| match it {
+                GreenElement::Node(it) => Some(it),
+                GreenElement::Token(_) => None,
+            }));
+        }
+    }
+    let mut stats = SharingStats::default();
+    let mut stack = vec![new];
+    while let Some(node) = stack.pop() {
+        if old_nodes.contains(&node.as_ptr()) {
+            stats.reused_nodes += node.descendant_node_count() as usize + 1;
+            stats.reused_text_len += node.text_len().to_usize();
+            continue;
+        }
+        stats.fresh_nodes += 1;
+        for child in node.children() {
+            match child {
+                GreenElement::Node(it) => stack.push(it),
+                GreenElement::Token(it) => stats.fresh_text_len += it.text_len().to_usize(),
+            }
+        }
+    }
+    stats
+}
+
+/// Groups of structurally equal subtrees of `root`, ignoring the elements
+/// of the kinds for which `ignore` is true (and the subtrees of such nodes),
+/// for instance to find duplicated code regardless of whitespace.
+///
+/// Only the subtrees with at least `min_tokens` tokens (not counting the
+/// ignored ones) are looked at. The subtrees are grouped by hash, with a
+/// full comparison within each group. Larger duplicates come first: the
+/// nodes inside of a reported duplicate, which are themselves duplicated
+/// by the other copies, are not reported. The groups are sorted by their
+/// first node, and the nodes of each group are in preorder.
+pub fn find_duplicates(
+    root: &cursor::SyntaxNode,
+    min_tokens: usize,
+    ignore: impl Fn(SyntaxKind) -> bool,
+) -> Vec<Vec<cursor::SyntaxNode>> {
+    struct Candidate {
+        /// Preorder index among all of the nodes.
+        index: usize,
+        n_tokens: usize,
+        node: cursor::SyntaxNode,
+    }
+
+    // Hashes are computed bottom-up: the hasher of each node on the stack,
+    // its number of tokens and its index.
+    let mut stack: Vec<(FxHasher, usize, usize)> = Vec::new();
+    let mut by_hash = FxHashMap::<u64, Vec<Candidate>>::default();
+    let mut n_nodes = 0;
+    let mut ignored_depth = 0;
+    for event in root.preorder_with_tokens() {
+        match event {
+            WalkEvent::Enter(cursor::SyntaxElement::Node(node)) => {
+                n_nodes += 1;
+                if ignored_depth > 0 || ignore(node.kind()) {
+                    ignored_depth += 1;
+                    continue;
+                }
+                let mut h = FxHasher::default();
+                h.write_u16(node.kind().0);
+                stack.push((h, 0, n_nodes - 1));
+            }
+            WalkEvent::Enter(cursor::SyntaxElement::Token(token)) => {
+                if ignored_depth > 0 || ignore(token.kind()) {
+                    continue;
+                }
+                let (h, n_tokens, _) = stack.last_mut().unwrap();
+                h.write_u8(0);
+                h.write_u16(token.kind().0);
+                h.write_usize(token.text().len());
+                h.write(token.text().as_bytes());
+                *n_tokens += 1;
+            }
+            WalkEvent::Leave(cursor::SyntaxElement::Node(node)) => {
+                if ignored_depth > 0 {
+                    ignored_depth -= 1;
+                    continue;
+                }
+                let (h, n_tokens, index) = stack.pop().unwrap();
+                let hash = h.finish();
+                if let Some((parent, parent_tokens, _)) = stack.last_mut() {
+                    parent.write_u8(1);
+                    parent.write_u64(hash);
+                    *parent_tokens += n_tokens;
+                }
+                if n_tokens >= min_tokens {
+                    by_hash.entry(hash).or_default().push(Candidate { index, n_tokens, node });
+                }
+            }
+            WalkEvent::Leave(cursor::SyntaxElement::Token(_)) => (),
+        }
+    }
+
+    let mut groups = Vec::new();
+    for candidates in by_hash.into_values().filter(|it| it.len() > 1) {
+        // Split the candidates with the same hash into equal ones.
+        let mut classes: Vec<Vec<Candidate>> = Vec::new();
+        for candidate in candidates {
+            let green = candidate.node.green();
+            match classes.iter_mut().find(|it| filtered_eq(it[0].node.green(), green, &ignore)) {
+                Some(class) => class.push(candidate),
+                None => classes.push(vec![candidate]),
+            }
+        }
+        groups.extend(classes.into_iter().filter(|it| it.len() > 1));
+    }
+    for group in groups.iter_mut() {
+        group.sort_by_key(|it| it.index);
+    }
+    groups.sort_by_key(|group| (cmp::Reverse(group[0].n_tokens), group[0].index));
+
+    // The reported nodes, as ranges of preorder indices of their subtrees.
+    // They don't overlap, so the last one starting before a node is the
+    // only one which may contain it.
+    let mut reported = BTreeMap::new();
+    let mut res = Vec::new();
+    for group in groups {
+        let group = group
+            .into_iter()
+            .filter(|it| {
+                let enclosing = reported.range(..=it.index).next_back();
+                enclosing.is_none_or(|(_, &end)| it.index >= end)
+            })
+            .collect::<Vec<_>>();
+        if group.len() < 2 {
+            continue;
+        }
+        for it in group.iter() {
+            reported.insert(it.index, it.index + it.node.subtree_node_count() as usize);
+        }
+        res.push(group);
+    }
+    res.sort_by_key(|group| group[0].index);
+    res.into_iter().map(|group| group.into_iter().map(|it| it.node).collect()).collect()
+}
+
+/// Whether the subtrees are equal, except for the ignored elements.
+fn filtered_eq(left: &GreenNode, right: &GreenNode, ignore: &dyn Fn(SyntaxKind) -> bool) -> bool {
+    #[derive(PartialEq)]
+    enum Event<'a> {
+        Enter(SyntaxKind),
+        Token(SyntaxKind, &'a str),
+        Leave,
+    }
+    fn events<'a>(
+        node: &'a GreenNode,
+        ignore: &'a dyn Fn(SyntaxKind) -> bool,
+    ) -> impl Iterator<Item = Event<'a>> + 'a {
+        let mut stack = vec![node.children().iter()];
+        iter::once(Event::Enter(node.kind())).chain(iter::from_fn(move || loop {
+            let children = stack.last_mut()?;
+            match children.next() {
+                None => {
+                    stack.pop();
+                    return Some(Event::Leave);
+                }
+                Some(child) if ignore(child.kind()) => continue,
+                Some(GreenElement::Node(it)) => {
+                    stack.push(it.children().iter());
+                    return Some(Event::Enter(it.kind()));
+                }
+                Some(GreenElement::Token(it)) => return Some(Event::Token(it.kind(), it.text())),
+            }
+        }))
+    }
+    events(left, ignore).eq(events(right, ignore))
+}
+
+/// The node of the tree of `new_root` which corresponds to `old_node` of the
+/// tree of `old_root`, after an edit which turned the first tree into the
+/// second.
+///
+/// The path of child indices from `old_root` to `old_node` is followed in
+/// the new tree. At each level, the children before and after the edit are
+/// those which are equal at the start and at the end of both lists, so an
+/// index after the edit is shifted by the number of inserted or removed
+/// children. If the path enters the edited children, or the kinds on the
+/// path differ, the result is the node of the kind of `old_node` in the
+/// edited children of the new tree whose range is the closest to the old
+/// range, relative to the start of the edited children. `None` if there is
+/// no such node, for instance if `old_node` was removed, or if `old_node` is
+/// not in the tree of `old_root`.
+pub fn corresponding_node(
+    old_root: &cursor::SyntaxNode,
+    old_node: &cursor::SyntaxNode,
+    new_root: &cursor::SyntaxNode,
+) -> Option<cursor::SyntaxNode> {
+    let path = old_root.try_path_to(&old_node.clone().into())?;
+    if old_root.kind() != new_root.kind() {
+        return closest_node(
+            old_node,
+            old_root.text_range().start(),
+            vec![new_root.clone().into()],
+        );
+    }
+    let (mut old, mut new) = (old_root.clone(), new_root.clone());
+    for index in path {
+        let index = index as usize;
+        let (old_children, new_children) = (old.green().children(), new.green().children());
+        let prefix = old_children
+            .iter()
+            .zip(new_children.iter())
+            .take_while(|(old, new)| same_element(old, new))
+            .count();
+        let suffix = old_children[prefix..]
+            .iter()
+            .rev()
+            .zip(new_children[prefix..].iter().rev())
+            .take_while(|(old, new)| same_element(old, new))
+            .count();
+        let new_index = if index < prefix || old_children.len() == new_children.len() {
+            Some(index)
+        } else if index >= old_children.len() - suffix {
+            Some(index + new_children.len() - old_children.len())
+        } else {
+            None
+        };
+        let old_child = old.child_or_token_at(index).and_then(cursor::SyntaxElement::into_node);
+        let new_child = new_index
+            .and_then(|it| new.child_or_token_at(it))
+            .and_then(cursor::SyntaxElement::into_node);
+        match (old_child, new_child) {
+            (Some(old_child), Some(new_child)) if old_child.kind() == new_child.kind() => {
+                old = old_child;
+                new = new_child;
+            }
+            _ => {
+                let old_start = old.text_range().start() + old.green().child_offset(prefix);
+                let edited = (prefix..new_children.len() - suffix)
+                    .filter_map(|it| new.child_or_token_at(it))
+                    .collect();
+                return closest_node(old_node, old_start, edited);
+            }
+        }
+    }
+    Some(new)
+}
+
+/// The node of the kind of `old_node` in the subtrees of `edited`, whose
+/// range relative to the start of the first of them is the closest to that
+/// of `old_node` relative to `old_start`.
+fn closest_node(
+    old_node: &cursor::SyntaxNode,
+    old_start: TextUnit,
+    edited: Vec<cursor::SyntaxElement>,
+) -> Option<cursor::SyntaxNode> {
+    let new_start = edited.first()?.text_range().start();
+    let old_range = old_node.text_range();
+    let distance = |node: &cursor::SyntaxNode| {
+        let range = node.text_range();
+        let delta = |old: TextUnit, new: TextUnit| {
+            let (old, new) = ((old - old_start).to_usize(), (new - new_start).to_usize());
+            cmp::max(old, new) - cmp::min(old, new)
+        };
+        delta(old_range.start(), range.start()) + delta(old_range.end(), range.end())
+    };
+    edited
+        .into_iter()
+        .filter_map(cursor::SyntaxElement::into_node)
+        .flat_map(|it| it.preorder())
+        .filter_map(|event| match event {
+            WalkEvent::Enter(it) if it.kind() == old_node.kind() => Some(it),
+            _ => None,
+        })
+        .min_by_key(distance)
+}
+
+/// The token which closes `token`, if it opens one of `pairs` of (opener,
+/// closer) kinds, or which opens `token`, if it closes one. A kind which is
+/// both an opener and a closer is looked up as an opener.
+///
+/// Only the tokens among the siblings of `token` are looked at, as the
+/// delimiters of a node are children of that node, and nested delimiters of
+/// the same pair, if there are tokens of it between the two, are skipped.
+/// `None` if the parent lacks the mate, as it happens in nodes produced by
+/// error recovery.
+pub fn matching_delimiter(
+    token: &cursor::SyntaxToken,
+    pairs: &[(SyntaxKind, SyntaxKind)],
+) -> Option<cursor::SyntaxToken> {
+    let (same, mate, direction) = pairs.iter().find_map(|&(opener, closer)| {
+        if token.kind() == opener {
+            Some((opener, closer, Direction::Next))
+        } else if token.kind() == closer {
+            Some((closer, opener, Direction::Prev))
+        } else {
+            None
+        }
+    })?;
+    let step = match direction {
+        Direction::Next => cursor::SyntaxElement::next_sibling_or_token,
+        Direction::Prev => cursor::SyntaxElement::prev_sibling_or_token,
+    };
+    // The number of unclosed delimiters, including `token`.
+    let mut depth = 1;
+    let siblings = iter::successors(step(&token.clone().into()), step);
+    for sibling in siblings {
+        let sibling = match sibling {
+            cursor::SyntaxElement::Token(it) => it,
+            cursor::SyntaxElement::Node(_) => continue,
+        };
+        if sibling.kind() == mate {
+            depth -= 1;
+            if depth == 0 {
+                return Some(sibling);
+            }
+        } else if sibling.kind() == same {
+            depth += 1;
+        }
+    }
+    None
+}
+
+/// The deepest node which contains both `a` and `b`, including `a` (or `b`)
+/// itself if it contains the other. `None` if they are in different trees.
+pub fn least_common_ancestor(
+    a: &cursor::SyntaxNode,
+    b: &cursor::SyntaxNode,
+) -> Option<cursor::SyntaxNode> {
+    let (mut a, mut b) = (a.clone(), b.clone());
+    let (depth_a, depth_b) = (a.ancestors().count(), b.ancestors().count());
+    // Bring the deeper node up to the depth of the other, then walk both
+    // up in lockstep until they meet, or run out of parents.
+    for _ in depth_b..depth_a {
+        a = a.parent()?;
+    }
+    for _ in depth_a..depth_b {
+        b = b.parent()?;
+    }
+    while a != b {
+        a = a.parent()?;
+        b = b.parent()?;
+    }
+    Some(a)
+}
+
+/// `least_common_ancestor` of elements, where a token stands for its parent.
+pub fn least_common_ancestor_of_elements(
+    a: &cursor::SyntaxElement,
+    b: &cursor::SyntaxElement,
+) -> Option<cursor::SyntaxNode> {
+    let node = |element: &cursor::SyntaxElement| match element {
+        cursor::SyntaxElement::Node(it) => it.clone(),
+        cursor::SyntaxElement::Token(it) => it.parent(),
+    };
+    least_common_ancestor(&node(a), &node(b))
+}
+
+/// The closest sibling of `element` in `direction`, skipping the elements
+/// of trivia kinds. `None` if there are only trivia up to the first (or last)
+/// child of the parent.
+pub fn non_trivia_sibling(
+    element: cursor::SyntaxElement,
+    direction: Direction,
+    is_trivia: impl Fn(SyntaxKind) -> bool,
+) -> Option<cursor::SyntaxElement> {
+    let step = match direction {
+        Direction::Next => cursor::SyntaxElement::next_sibling_or_token,
+        Direction::Prev => cursor::SyntaxElement::prev_sibling_or_token,
+    };
+    let mut element = step(&element)?;
+    while is_trivia(element.kind()) {
+        element = step(&element)?;
+    }
+    Some(element)
+}
+
+/// A single replacement of a range of text.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TextEdit {
+    /// The range of the old text to remove.
+    pub delete: TextRange,
+    /// The text to insert in place of `delete`.
+    pub insert: SmolStr,
+}
+
+/// Computes the text edits which turn the text of `old` into the text of
+/// `new`.
+///
+/// The edits are sorted, don't overlap and use offsets of the old text, so
+/// they can be applied back to front. Subtrees shared between the two trees
+/// are skipped in O(1), so, for a tree produced by editing the old one, the
+/// cost is proportional to the size of the changed region. Where the
+/// structure of the trees diverges, the whole text of the diverging node is
+/// replaced.
+pub fn text_diff(old: &cursor::SyntaxNode, new: &cursor::SyntaxNode) -> Vec<TextEdit> {
+    let mut edits = Vec::new();
+    diff_nodes(old.green(), new.green(), old.text_range().start(), &mut edits);
+    edits
+}
+
+fn diff_nodes(old: &GreenNode, new: &GreenNode, offset: TextUnit, edits: &mut Vec<TextEdit>) {
+    let (old, new) = (GreenElement::Node(old.clone()), GreenElement::Node(new.clone()));
+    // Pairs of elements at the same offset left to compare, the next one on
+    // top, so that the edits come out sorted. An explicit stack, as the
+    // trees can be deeper than the call stack.
+    let mut stack = vec![(&old, &new, offset)];
+    while let Some((old, new, offset)) = stack.pop() {
+        let (old_node, new_node) = match (old, new) {
+            (GreenElement::Node(old_node), GreenElement::Node(new_node))
+                if old_node.kind() == new_node.kind() =>
+            {
+                (old_node, new_node)
+            }
+            _ if same_element(old, new) => continue,
+            _ => {
+                replace(slice::from_ref(old), slice::from_ref(new), offset, edits);
+                continue;
+            }
+        };
+        if same_node(old_node, new_node) {
+            continue;
+        }
+        let (old_children, new_children) = (old_node.children(), new_node.children());
+        let prefix = old_children
+            .iter()
+            .zip(new_children.iter())
+            .take_while(|(old, new)| same_element(old, new))
+            .count();
+        let suffix = old_children[prefix..]
+            .iter()
+            .rev()
+            .zip(new_children[prefix..].iter().rev())
+            .take_while(|(old, new)| same_element(old, new))
+            .count();
+        let old_middle = &old_children[prefix..old_children.len() - suffix];
+        let new_middle = &new_children[prefix..new_children.len() - suffix];
+        let offset = offset + text_len(&old_children[..prefix]);
+
+        if old_middle.len() != new_middle.len() {
+            replace(old_middle, new_middle, offset, edits);
+            continue;
+        }
+        let mut child_end = offset + text_len(old_middle);
+        for (old_child, new_child) in old_middle.iter().zip(new_middle.iter()).rev() {
+            let child_offset = child_end - old_child.text_len();
+            stack.push((old_child, new_child, child_offset));
+            child_end = child_offset;
+        }
+    }
+}
+
+fn replace(
+    old: &[GreenElement],
+    new: &[GreenElement],
+    offset: TextUnit,
+    edits: &mut Vec<TextEdit>,
+) {
+    let mut insert = String::new();
+    for element in new {
+        push_text(element, &mut insert);
+    }
+    let delete = TextRange::offset_len(offset, text_len(old));
+    if delete.is_empty() && insert.is_empty() {
+        return;
+    }
+    // Merge with the previous edit, if they touch.
+    if let Some(last) = edits.last_mut() {
+        if last.delete.end() == delete.start() {
+            last.delete = last.delete.extend_to(&delete);
+            last.insert = format!("{}{}", last.insert, insert).into();
+            return;
+        }
+    }
+    edits.push(TextEdit { delete, insert: insert.into() })
+}
+
+fn same_element(old: &GreenElement, new: &GreenElement) -> bool {
+    match (old, new) {
+        (GreenElement::Node(old), GreenElement::Node(new)) => same_node(old, new),
+        (GreenElement::Token(old), GreenElement::Token(new)) => old == new,
+        _ => false,
+    }
+}
+
+fn same_node(old: &GreenNode, new: &GreenNode) -> bool {
+    // The hash check makes the (linear) structural comparison rare for
+    // different nodes, and `structurally_eq` is O(1) for shared ones.
+    old.content_hash() == new.content_hash() && old.structurally_eq(new)
+}
+
+fn text_len(elements: &[GreenElement]) -> TextUnit {
+    elements.iter().map(|it| it.text_len()).sum()
+}
+
+fn push_text(element: &GreenElement, buf: &mut String) {
+    let mut stack = vec![element];
+    while let Some(element) = stack.pop() {
+        match element {
+            GreenElement::Node(it) => stack.extend(it.children().iter().rev()),
+            GreenElement::Token(it) => buf.push_str(it.text()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_utils::{self, text_of, Rng, LIST, WORD, WS},
+        GreenNodeBuilder, GreenToken,
+    };
+
+    #[test]
+    fn walk_event_helpers() {
+        let enter = WalkEvent::Enter(1);
+        let leave = WalkEvent::Leave(2);
+        assert_eq!(enter.map(|it| it * 10), WalkEvent::Enter(10));
+        assert_eq!(leave.map(|it| it * 10), WalkEvent::Leave(20));
+        assert_eq!(enter.as_ref(), WalkEvent::Enter(&1));
+        assert_eq!(leave.as_ref(), WalkEvent::Leave(&2));
+        assert_eq!((enter.into_inner(), leave.into_inner()), (1, 2));
+        assert!(enter.is_enter() && !enter.is_leave());
+        assert!(leave.is_leave() && !leave.is_enter());
+        assert_eq!((enter.enter(), enter.leave()), (Some(1), None));
+        assert_eq!((leave.enter(), leave.leave()), (None, Some(2)));
+
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(LIST);
+        builder.token(WORD, "a".into());
+        builder.start_node(LIST);
+        builder.token(WS, " ".into());
+        builder.finish_node();
+        builder.finish_node();
+        let root = cursor::SyntaxNode::new_root(builder.finish());
+        let kinds = root.preorder_with_tokens().map(|event| event.map(|it| it.kind()));
+        assert_eq!(
+            kinds.collect::<Vec<_>>(),
+            [
+                WalkEvent::Enter(LIST),
+                WalkEvent::Enter(WORD),
+                WalkEvent::Leave(WORD),
+                WalkEvent::Enter(LIST),
+                WalkEvent::Enter(WS),
+                WalkEvent::Leave(WS),
+                WalkEvent::Leave(LIST),
+                WalkEvent::Leave(LIST),
+            ]
+        );
+        let events = root.preorder_with_tokens().collect::<Vec<_>>();
+        let entered = events.iter().filter(|it| it.is_enter()).count();
+        assert_eq!(entered, events.len() / 2);
+        let left = events.into_iter().filter_map(WalkEvent::leave).map(|it| it.text_range());
+        assert_eq!(
+            left.map(|it| (it.start().to_usize(), it.end().to_usize())).collect::<Vec<_>>(),
+            [(0, 1), (1, 2), (1, 2), (0, 2)]
+        );
+    }
+
+    #[test]
+    fn token_at_offset_combinators() {
+        let none = || TokenAtOffset::<u32>::None;
+        let single = || TokenAtOffset::Single(1);
+        let between = || TokenAtOffset::Between(2, 3);
+
+        assert_eq!(none().left_biased(), None);
+        assert_eq!(single().left_biased(), Some(1));
+        assert_eq!(between().left_biased(), Some(2));
+        assert_eq!(none().right_biased(), None);
+        assert_eq!(single().right_biased(), Some(1));
+        assert_eq!(between().right_biased(), Some(3));
+
+        assert_eq!(none().map(|it| it * 10), TokenAtOffset::None);
+        assert_eq!(single().map(|it| it * 10), TokenAtOffset::Single(10));
+        assert_eq!(between().map(|it| it * 10), TokenAtOffset::Between(20, 30));
+
+        let odd = |it: &u32| it % 2 == 1;
+        assert_eq!(none().pick(odd), None);
+        assert_eq!(single().pick(|_| false), Some(1));
+        assert_eq!(between().pick(odd), Some(3));
+        assert_eq!(between().pick(|_| true), Some(2));
+        assert_eq!(between().pick(|_| false), Some(2));
+
+        assert_eq!(none().collect::<Vec<_>>(), []);
+        assert_eq!(single().collect::<Vec<_>>(), [1]);
+        assert_eq!(between().collect::<Vec<_>>(), [2, 3]);
+        let mut tokens = between();
+        assert_eq!(tokens.len(), 2);
+        tokens.next();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens, TokenAtOffset::Single(3));
+    }
+
+    fn apply(text: &str, edits: &[TextEdit]) -> String {
+        let mut res = text.to_string();
+        for window in edits.windows(2) {
+            assert!(window[0].delete.end() <= window[1].delete.start());
+        }
+        for edit in edits.iter().rev() {
+            let range = edit.delete.start().to_usize()..edit.delete.end().to_usize();
+            res.replace_range(range, &edit.insert);
+        }
+        res
+    }
+
+    fn random_tree(rng: &mut Rng) -> cursor::SyntaxNode {
+        fn go(rng: &mut Rng, builder: &mut GreenNodeBuilder, depth: usize) {
+            builder.start_node(LIST);
+            for _ in 0..rng.below(4) + 1 {
+                if depth > 0 && rng.below(3) == 0 {
+                    go(rng, builder, depth - 1);
+                } else if rng.below(2) == 0 {
+                    builder.token(WS, " ".into());
+                } else {
+                    builder.token(WORD, ["a", "bb", "ccc"][rng.below(3) as usize].into());
+                }
+            }
+            builder.finish_node();
+        }
+        let mut builder = GreenNodeBuilder::new();
+        go(rng, &mut builder, 4);
+        cursor::SyntaxNode::new_root(builder.finish())
+    }
+
+    fn random_edit(rng: &mut Rng, root: &cursor::SyntaxNode) -> GreenNode {
+        let elements = root
+            .preorder_with_tokens()
+            .filter_map(|event| match event {
+                WalkEvent::Enter(it) => Some(it),
+                WalkEvent::Leave(_) => None,
+            })
+            .collect::<Vec<_>>();
+        let word: GreenElement = GreenToken::new(WORD, "zz".into()).into();
+        match &elements[rng.below(elements.len() as u64) as usize] {
+            cursor::SyntaxElement::Token(it) => match rng.below(2) {
+                0 => it.with_text("z".repeat(rng.below(3) as usize)),
+                _ => it.with_kind(LIST),
+            },
+            cursor::SyntaxElement::Node(it) => {
+                let n_children = it.green().children().len();
+                match rng.below(4) {
+                    0 if it.parent().is_some() => it.detach(),
+                    1 => it.insert_children(rng.below(n_children as u64 + 1) as usize, vec![word]),
+                    2 => it.retag(WS),
+                    _ => {
+                        let start = rng.below(n_children as u64 + 1) as usize;
+                        let end = start + rng.below((n_children - start) as u64 + 1) as usize;
+                        it.splice_children(start..end, vec![word.clone(), word])
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn text_diff_of_shared_trees() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(LIST);
+        builder.token(WORD, "foo".into());
+        builder.token(WS, " ".into());
+        builder.start_node(LIST);
+        builder.token(WORD, "bar".into());
+        builder.finish_node();
+        builder.finish_node();
+        let old = cursor::SyntaxNode::new_root(builder.finish());
+
+        assert_eq!(text_diff(&old, &old), Vec::new());
+        let bar = old.last_child().unwrap().first_token().unwrap();
+        let new = cursor::SyntaxNode::new_root(bar.with_text("quux"));
+        assert_eq!(
+            text_diff(&old, &new),
+            vec![TextEdit {
+                delete: TextRange::from_to(4.into(), 7.into()),
+                insert: "quux".into()
+            }]
+        );
+        let new = cursor::SyntaxNode::new_root(old.retag(WS));
+        assert_eq!(
+            text_diff(&old, &new),
+            vec![TextEdit {
+                delete: TextRange::from_to(0.into(), 7.into()),
+                insert: "foo bar".into()
+            }]
+        );
+    }
+
+    #[test]
+    fn text_diff_of_deep_trees() {
+        let old = cursor::SyntaxNode::new_root(test_utils::deep_tree(100_000));
+        let token = old.first_token().unwrap();
+        let new = cursor::SyntaxNode::new_root(token.with_text("quux"));
+        assert_eq!(
+            text_diff(&old, &new),
+            vec![TextEdit {
+                delete: TextRange::from_to(0.into(), 1.into()),
+                insert: "quux".into()
+            }]
+        );
+        assert_eq!(
+            text_diff(&new, &old),
+            vec![TextEdit { delete: TextRange::from_to(0.into(), 4.into()), insert: "x".into() }]
+        );
+    }
+
+    #[test]
+    fn text_diff_round_trips() {
+        let mut rng = Rng::new(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..500 {
+            let old = random_tree(&mut rng);
+            let mut new = old.clone();
+            for _ in 0..rng.below(3) + 1 {
+                new = cursor::SyntaxNode::new_root(random_edit(&mut rng, &new));
+            }
+            let edits = text_diff(&old, &new);
+            assert_eq!(apply(&text_of(&old), &edits), text_of(&new));
+        }
+    }
+
+    #[test]
+    fn least_common_ancestors() {
+        // LIST
+        //   LIST
+        //     WORD "a"
+        //     LIST
+        //       WORD "b"
+        //     LIST
+        //       WORD "c"
+        //   WORD "d"
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(LIST);
+        builder.start_node(LIST);
+        builder.token(WORD, "a".into());
+        for text in ["b", "c"].iter() {
+            builder.start_node(LIST);
+            builder.token(WORD, (*text).into());
+            builder.finish_node();
+        }
+        builder.finish_node();
+        builder.token(WORD, "d".into());
+        builder.finish_node();
+        let root = cursor::SyntaxNode::new_root(builder.finish());
+        let outer = root.first_child().unwrap();
+        let b = outer.first_child().unwrap();
+        let c = outer.last_child().unwrap();
+
+        assert_eq!(least_common_ancestor(&b, &b), Some(b.clone()));
+        assert_eq!(least_common_ancestor(&b, &c), Some(outer.clone()));
+        assert_eq!(least_common_ancestor(&c, &b), Some(outer.clone()));
+        assert_eq!(least_common_ancestor(&outer, &c), Some(outer.clone()));
+        assert_eq!(least_common_ancestor(&c, &root), Some(root.clone()));
+
+        let other = random_tree(&mut Rng::new(92));
+        assert_eq!(least_common_ancestor(&b, &other), None);
+        assert_eq!(least_common_ancestor(&other, &root), None);
+
+        let a = outer.first_token().unwrap();
+        let d = root.last_token().unwrap();
+        let lca = |x: cursor::SyntaxElement, y: cursor::SyntaxElement| {
+            least_common_ancestor_of_elements(&x, &y)
+        };
+        assert_eq!(lca(a.clone().into(), a.clone().into()), Some(outer.clone()));
+        assert_eq!(lca(a.clone().into(), c.clone().into()), Some(outer.clone()));
+        assert_eq!(lca(b.first_token().unwrap().into(), d.into()), Some(root.clone()));
+        assert_eq!(lca(a.into(), other.into()), None);
+    }
+
+    #[test]
+    fn non_trivia_siblings() {
+        // LIST
+        //   WORD "a"
+        //   WS " "
+        //   WS "\n"
+        //   LIST
+        //     WS " "
+        //     WORD "b"
+        //     WS " "
+        //   WS " "
+        //   LIST
+        //     WS " "
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(LIST);
+        builder.token(WORD, "a".into());
+        builder.token(WS, " ".into());
+        builder.token(WS, "\n".into());
+        builder.start_node(LIST);
+        builder.token(WS, " ".into());
+        builder.token(WORD, "b".into());
+        builder.token(WS, " ".into());
+        builder.finish_node();
+        builder.token(WS, " ".into());
+        builder.start_node(LIST);
+        builder.token(WS, " ".into());
+        builder.finish_node();
+        builder.finish_node();
+        let root = cursor::SyntaxNode::new_root(builder.finish());
+        let is_ws = |kind| kind == WS;
+        let sibling = |element: &cursor::SyntaxElement, direction| {
+            non_trivia_sibling(element.clone(), direction, is_ws)
+        };
+        let children = root.children_with_tokens().collect::<Vec<_>>();
+        let (a, list, last) = (&children[0], &children[3], &children[5]);
+
+        assert_eq!(sibling(a, Direction::Next).as_ref(), Some(list));
+        assert_eq!(sibling(list, Direction::Prev).as_ref(), Some(a));
+        assert_eq!(sibling(list, Direction::Next).as_ref(), Some(last));
+        assert_eq!(sibling(&children[1], Direction::Next).as_ref(), Some(list));
+        assert_eq!(sibling(a, Direction::Prev), None);
+        assert_eq!(sibling(last, Direction::Next), None);
+
+        // The walk stays among the siblings.
+        let b: cursor::SyntaxElement =
+            root.first_child().unwrap().first_token().unwrap().next_token().unwrap().into();
+        assert_eq!(b.kind(), WORD);
+        assert_eq!(sibling(&b, Direction::Next), None);
+        assert_eq!(sibling(&b, Direction::Prev), None);
+        let only_trivia = root.last_child().unwrap().first_child_or_token().unwrap();
+        assert_eq!(sibling(&only_trivia, Direction::Next), None);
+        assert_eq!(sibling(&only_trivia, Direction::Prev), None);
+        assert_eq!(non_trivia_sibling(b, Direction::Next, |_| false).unwrap().kind(), WS);
+    }
+
+    #[test]
+    fn diff_of_shared_trees() {
+        let root = random_tree(&mut Rng::new(368));
+        assert!(diff(&root, &root).is_empty());
+        // Equal trees without shared nodes.
+        let copy = cursor::SyntaxNode::new_root(GreenNode::new(
+            root.kind(),
+            root.green().children().to_vec().into_boxed_slice(),
+        ));
+        assert!(!copy.green().ptr_eq(root.green()));
+        assert!(diff(&root, &copy).is_empty());
+        assert!(diff(&root, &copy).to_text_edits().is_empty());
+    }
+
+    #[test]
+    fn diff_of_a_deep_token() {
+        // LIST
+        //   WORD "foo"
+        //   LIST
+        //     WS " "
+        //     LIST
+        //       WORD "bar"
+        //   WORD "baz"
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(LIST);
+        builder.token(WORD, "foo".into());
+        builder.start_node(LIST);
+        builder.token(WS, " ".into());
+        builder.start_node(LIST);
+        builder.token(WORD, "bar".into());
+        builder.finish_node();
+        builder.finish_node();
+        builder.token(WORD, "baz".into());
+        builder.finish_node();
+        let old = cursor::SyntaxNode::new_root(builder.finish());
+        let bar = old.first_child().unwrap().last_child().unwrap().first_token().unwrap();
+        let new = cursor::SyntaxNode::new_root(bar.with_text("quux"));
+
+        let tree_diff = diff(&old, &new);
+        let new_bar = new.first_child().unwrap().last_child().unwrap().first_token().unwrap();
+        assert_eq!(tree_diff.replacements(), [(bar.into(), new_bar.into())]);
+        assert_eq!(
+            tree_diff.to_text_edits(),
+            vec![TextEdit {
+                delete: TextRange::from_to(4.into(), 7.into()),
+                insert: "quux".into()
+            }]
+        );
+
+        let retagged = cursor::SyntaxNode::new_root(old.retag(WS));
+        let tree_diff = diff(&old, &retagged);
+        assert_eq!(tree_diff.replacements(), [(old.clone().into(), retagged.clone().into())]);
+        assert_eq!(apply(&text_of(&old), &tree_diff.to_text_edits()), "foo barbaz");
+    }
+
+    #[test]
+    fn diff_round_trips() {
+        let mut rng = Rng::new(0x2545_f491_4f6c_dd1d);
+        for _ in 0..500 {
+            let old = random_tree(&mut rng);
+            let mut new = old.clone();
+            for _ in 0..rng.below(3) + 1 {
+                new = cursor::SyntaxNode::new_root(random_edit(&mut rng, &new));
+            }
+            let tree_diff = diff(&old, &new);
+            assert_eq!(apply(&text_of(&old), &tree_diff.to_text_edits()), text_of(&new));
+            let replacements = tree_diff.replacements();
+            for pair in replacements.windows(2) {
+                assert!(pair[0].0.text_range().end() <= pair[1].0.text_range().start());
+            }
+            if old.kind() == new.kind() {
+                let green = old.replace_descendants(
+                    replacements.iter().map(|(old, new)| (old.clone(), new.to_green())),
+                );
+                assert!(green.structurally_eq(new.green()));
+            }
+        }
+    }
+
+    #[test]
+    fn corresponding_nodes() {
+        let tree = |sexpr: &str| {
+            let kind = |name: &str| match name {
+                "WS" => Some(WS),
+                "WORD" => Some(WORD),
+                "LIST" => Some(LIST),
+                _ => None,
+            };
+            cursor::SyntaxNode::new_root(GreenNode::from_sexpr(sexpr, &kind).unwrap())
+        };
+        let old = tree(
+            r#"(LIST
+                (LIST (WORD "a"))
+                (WS " ")
+                (LIST (WORD "b") (LIST (WORD "c")))
+                (WS " ")
+                (LIST (WORD "d")))"#,
+        );
+        let b = old.children().nth(1).unwrap();
+        let c = b.last_child().unwrap();
+        let find = |node: &cursor::SyntaxNode, new: &GreenNode| {
+            let new = cursor::SyntaxNode::new_root(new.clone());
+            corresponding_node(&old, node, &new).map(|it| (text_of(&it), it.text_range()))
+        };
+        let range = |start: u32, end: u32| TextRange::from_to(start.into(), end.into());
+
+        // Before the node.
+        let z = tree(r#"(LIST (LIST (WORD "zz")) (WS " "))"#);
+        let before = old.insert_children(0, z.green().children().to_vec());
+        assert_eq!(find(&b, &before), Some(("bc".to_string(), range(5, 7))));
+        assert_eq!(find(&c, &before), Some(("c".to_string(), range(6, 7))));
+
+        // After the node.
+        let d = old.last_child().unwrap().first_token().unwrap();
+        let after = d.with_text("dd");
+        assert_eq!(find(&c, &after), Some(("c".to_string(), range(3, 4))));
+        let after = old.remove_children(3..5);
+        assert_eq!(find(&c, &after), Some(("c".to_string(), range(3, 4))));
+
+        // Inside of the node.
+        let inside = c.first_token().unwrap().with_text("ccc");
+        assert_eq!(find(&b, &inside), Some(("bccc".to_string(), range(2, 6))));
+        assert_eq!(find(&c, &inside), Some(("ccc".to_string(), range(3, 6))));
+        let x = tree(r#"(LIST (WORD "x"))"#);
+        let inside = b.insert_children(1, x.green().children().to_vec());
+        assert_eq!(find(&b, &inside), Some(("bxc".to_string(), range(2, 5))));
+        assert_eq!(find(&c, &inside), Some(("c".to_string(), range(4, 5))));
+
+        // The node itself is replaced by another one of the same kind.
+        let e = tree(r#"(LIST (LIST (WORD "e")))"#);
+        let replaced = old.splice_children(2..3, e.green().children().to_vec());
+        assert_eq!(find(&b, &replaced), Some(("e".to_string(), range(2, 3))));
+
+        // The node is removed.
+        let removed = old.remove_children(2..4);
+        assert_eq!(find(&b, &removed), None);
+        assert_eq!(find(&c, &removed), None);
+        let removed = b.remove_children(1..2);
+        assert_eq!(find(&c, &removed), None);
+        assert_eq!(find(&b, &removed), Some(("b".to_string(), range(2, 3))));
+
+        // Not a node of the old tree.
+        assert_eq!(corresponding_node(&b, &old, &old), None);
+        assert_eq!(corresponding_node(&old, &z, &old), None);
+    }
+
+    #[test]
+    fn matching_delimiters() {
+        const L_PAREN: SyntaxKind = SyntaxKind(3);
+        const R_PAREN: SyntaxKind = SyntaxKind(4);
+        const L_BRACK: SyntaxKind = SyntaxKind(5);
+        const R_BRACK: SyntaxKind = SyntaxKind(6);
+        const PAIRS: &[(SyntaxKind, SyntaxKind)] = &[(L_PAREN, R_PAREN), (L_BRACK, R_BRACK)];
+        let tree = |sexpr: &str| {
+            let kind = |name: &str| match name {
+                "WORD" => Some(WORD),
+                "LIST" => Some(LIST),
+                "L_PAREN" => Some(L_PAREN),
+                "R_PAREN" => Some(R_PAREN),
+                "L_BRACK" => Some(L_BRACK),
+                "R_BRACK" => Some(R_BRACK),
+                _ => None,
+            };
+            cursor::SyntaxNode::new_root(GreenNode::from_sexpr(sexpr, &kind).unwrap())
+        };
+        // The offsets of the tokens of `root` and of their mates.
+        let mates = |root: &cursor::SyntaxNode| {
+            root.preorder_with_tokens()
+                .filter_map(|event| match event {
+                    WalkEvent::Enter(cursor::SyntaxElement::Token(it)) => Some(it),
+                    _ => None,
+                })
+                .filter_map(|it| {
+                    let mate = matching_delimiter(&it, PAIRS)?;
+                    Some((it.text_range().start().to_usize(), mate.text_range().start().to_usize()))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // ( a ( b ) ( ) )
+        let nested = tree(
+            r#"(LIST (L_PAREN "(") (WORD "a") (L_PAREN "(") (WORD "b") (R_PAREN ")") (L_PAREN "(") (R_PAREN ")") (R_PAREN ")"))"#,
+        );
+        assert_eq!(mates(&nested), [(0, 7), (2, 4), (4, 2), (5, 6), (6, 5), (7, 0)]);
+
+        // [ ( ] ) with a nested node in between, which has delimiters of its own.
+        let interleaved = tree(
+            r#"(LIST
+                (L_BRACK "[")
+                (L_PAREN "(")
+                (LIST (L_BRACK "[") (WORD "x") (R_PAREN ")"))
+                (R_BRACK "]")
+                (R_PAREN ")"))"#,
+        );
+        assert_eq!(mates(&interleaved), [(0, 5), (1, 6), (5, 0), (6, 1)]);
+
+        // ( ( a ) with the last paren missing.
+        let unbalanced = tree(r#"(LIST (L_PAREN "(") (L_PAREN "(") (WORD "a") (R_PAREN ")"))"#);
+        assert_eq!(mates(&unbalanced), [(1, 3), (3, 1)]);
+        let word = unbalanced.last_token().unwrap().prev_token().unwrap();
+        assert_eq!(matching_delimiter(&word, PAIRS), None);
+    }
+
+    #[test]
+    fn sharing() {
+        let old = cursor::SyntaxNode::new_root(test_utils::mixed_tree(&mut Rng::new(373), 2_000));
+        let n_nodes = old.subtree_node_count() as usize;
+        let text_len = old.text_range().len().to_usize();
+        let same = sharing_stats(old.green(), old.green());
+        assert_eq!(
+            same,
+            SharingStats {
+                reused_nodes: n_nodes,
+                fresh_nodes: 0,
+                reused_text_len: text_len,
+                fresh_text_len: 0
+            }
+        );
+
+        // Replace a node in the middle.
+        let node = old
+            .preorder()
+            .filter_map(|event| match event {
+                WalkEvent::Enter(it) => Some(it),
+                WalkEvent::Leave(_) => None,
+            })
+            .nth(n_nodes / 2)
+            .unwrap();
+        let new = node.replace_with(GreenNode::new(LIST, Vec::new().into_boxed_slice()));
+        let stats = sharing_stats(old.green(), &new);
+        assert_eq!(
+            stats.reused_nodes + stats.fresh_nodes,
+            new.descendant_node_count() as usize + 1
+        );
+        assert_eq!(stats.reused_text_len + stats.fresh_text_len, new.text_len().to_usize());
+        assert!(stats.fresh_nodes <= 17, "{}", stats);
+        assert!(stats.reused_text_len * 4 > new.text_len().to_usize() * 3, "{}", stats);
+
+        // The same tree, built again.
+        let rebuilt = test_utils::mixed_tree(&mut Rng::new(373), 2_000);
+        assert_eq!(&rebuilt, old.green());
+        let stats = sharing_stats(old.green(), &rebuilt);
+        assert_eq!(stats.reused_nodes, 0);
+        assert_eq!(stats.reused_text_len, 0);
+        assert_eq!(stats.fresh_nodes, n_nodes);
+
+        let stats =
+            SharingStats { reused_nodes: 3, fresh_nodes: 1, reused_text_len: 2, fresh_text_len: 1 };
+        assert_eq!(
+            stats.to_string(),
+            "3 of 4 nodes reused (75.0%), covering 2 of 3 bytes of text (66.7%)"
+        );
+        assert_eq!(
+            SharingStats::default().to_string(),
+            "0 of 0 nodes reused (0.0%), covering 0 of 0 bytes of text (0.0%)"
+        );
+    }
+
+    #[test]
+    fn duplicates() {
+        const FN: SyntaxKind = SyntaxKind(3);
+        const BODY: SyntaxKind = SyntaxKind(4);
+        const STMT: SyntaxKind = SyntaxKind(5);
+        let kind = |name: &str| match name {
+            "WS" => Some(WS),
+            "WORD" => Some(WORD),
+            "LIST" => Some(LIST),
+            "FN" => Some(FN),
+            "BODY" => Some(BODY),
+            "STMT" => Some(STMT),
+            _ => None,
+        };
+        let root = cursor::SyntaxNode::new_root(
+            GreenNode::from_sexpr(
+                r#"(LIST
+                    (FN (WORD "f")
+                        (BODY (WORD "let") (WS " ") (WORD "x")
+                            (STMT (WORD "a") (WORD "b"))
+                            (STMT (WORD "a") (WORD "b"))))
+                    (WS "\n")
+                    (FN (WORD "g")
+                        (BODY (WS "  ") (WORD "let") (WS "\n") (WORD "x")
+                            (STMT (WORD "a") (WS " ") (WORD "b"))
+                            (STMT (WORD "a") (WORD "b"))))
+                    (STMT (WORD "a") (WORD "b"))
+                    (STMT (WS " ") (WORD "a") (WORD "b")))"#,
+                &kind,
+            )
+            .unwrap(),
+        );
+        let describe = |groups: Vec<Vec<cursor::SyntaxNode>>| {
+            groups
+                .into_iter()
+                .map(|group| {
+                    group
+                        .into_iter()
+                        .map(|it| format!("{:?}@{:?}", it.kind(), it.text_range()))
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // The bodies, and the statements which are not in the bodies.
+        assert_eq!(
+            describe(find_duplicates(&root, 2, |kind| kind == WS)),
+            [
+                vec!["SyntaxKind(4)@[1; 10)", "SyntaxKind(4)@[12; 24)"],
+                vec!["SyntaxKind(5)@[24; 26)", "SyntaxKind(5)@[26; 29)"],
+            ]
+        );
+        assert_eq!(find_duplicates(&root, 7, |kind| kind == WS), Vec::<Vec<_>>::new());
+
+        // With whitespace, the bodies differ, but their statements don't.
+        assert_eq!(
+            describe(find_duplicates(&root, 2, |_| false)),
+            [vec![
+                "SyntaxKind(5)@[6; 8)",
+                "SyntaxKind(5)@[8; 10)",
+                "SyntaxKind(5)@[22; 24)",
+                "SyntaxKind(5)@[24; 26)",
+            ]]
+        );
+    }
+}
@@ -1,10 +1,1107 @@
+//! Typed wrappers around the `cursor` trees.
+//!
+//! A `Language` maps raw `SyntaxKind`s to a kind type of its own, usually an
+//! enum, and `SyntaxNode<L>`, `SyntaxToken<L>` and `SyntaxElement<L>` are the
+//! `cursor` types with the kinds of `L`. Each method delegates to the method
+//! of the same name of the untyped type, and `From` converts between the two.
+//!
+//! ```
+//! use rowan::{
+//!     api::{Language, SyntaxNode},
+//!     GreenNodeBuilder, SyntaxKind,
+//! };
+//!
+//! #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+//! enum Kind {
+//!     Word,
+//!     Whitespace,
+//!     List,
+//! }
+//!
+//! #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+//! enum Lang {}
+//!
+//! impl Language for Lang {
+//!     type Kind = Kind;
+//!     fn kind_from_raw(raw: SyntaxKind) -> Kind {
+//!         [Kind::Word, Kind::Whitespace, Kind::List][raw.0 as usize]
+//!     }
+//!     fn kind_to_raw(kind: Kind) -> SyntaxKind {
+//!         SyntaxKind(kind as u16)
+//!     }
+//! }
+//!
+//! let mut builder = GreenNodeBuilder::new();
+//! builder.start_node(Lang::kind_to_raw(Kind::List));
+//! builder.token(Lang::kind_to_raw(Kind::Word), "hello".into());
+//! builder.token(Lang::kind_to_raw(Kind::Whitespace), " ".into());
+//! builder.start_node(Lang::kind_to_raw(Kind::List));
+//! builder.token(Lang::kind_to_raw(Kind::Word), "world".into());
+//! builder.finish_node();
+//! builder.finish_node();
+//!
+//! let root = SyntaxNode::<Lang>::new_root(builder.finish());
+//! assert_eq!(root.kind(), Kind::List);
+//! let kinds = root.children_with_tokens().map(|it| it.kind()).collect::<Vec<_>>();
+//! assert_eq!(kinds, [Kind::Word, Kind::Whitespace, Kind::List]);
+//!
+//! let inner = root.last_child().unwrap();
+//! let word = inner.first_token().unwrap();
+//! assert_eq!(word.text(), "world");
+//! assert_eq!(word.prev_token().unwrap().kind(), Kind::Whitespace);
+//! assert_eq!(format!("{:?}", inner), "List@[6; 11)");
+//!
+//! // The untyped node is still there.
+//! let raw: rowan::cursor::SyntaxNode = inner.into();
+//! assert_eq!(raw.kind(), SyntaxKind(2));
+//! ```
+use std::{convert::TryFrom, fmt, hash::Hash, iter, marker::PhantomData, ops::Range};
+
 use crate::{
-    SyntaxKind,
-    cursor::{SyntaxNode, SyntaxToken},
+    cursor::{
+        self, InvalidReplacement, KindMismatch, OffsetOutOfRange, RangeOutOfBounds,
+        SyntaxNodeHandle, SyntaxText, TriviaEdit,
+    },
+    GreenElement, GreenNode, GreenToken, MemoryStats, SmolStr, SyntaxKind, TextRange, TextUnit,
+    TokenAtOffset, WalkEvent,
 };
 
-impl SyntaxNode {
-    pub fn ancestors(&self) -> impl Iterator<Item = SyntaxNode> {
-        std::iter::successors(Some(self.clone()), SyntaxNode::parent)
+impl cursor::SyntaxNode {
+    pub fn ancestors(&self) -> impl Iterator<Item = cursor::SyntaxNode> {
+        std::iter::successors(Some(self.clone()), cursor::SyntaxNode::parent)
+    }
+}
+
+/// The kinds of a language. `kind_to_raw` and `kind_from_raw` must be
+/// inverse of each other; `kind_from_raw` may panic on raw kinds which the
+/// language doesn't use.
+pub trait Language: Sized + Copy + fmt::Debug + Eq + Hash {
+    type Kind: fmt::Debug;
+
+    fn kind_from_raw(raw: SyntaxKind) -> Self::Kind;
+    fn kind_to_raw(kind: Self::Kind) -> SyntaxKind;
+}
+
+/// A typed node of the AST, which wraps a `SyntaxNode` of one of the kinds
+/// accepted by `can_cast`.
+///
+/// ```
+/// # use rowan::{api::{AstNode, Language, SyntaxNode}, GreenNodeBuilder, SyntaxKind};
+/// # #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// # enum Lang {}
+/// # impl Language for Lang {
+/// #     type Kind = SyntaxKind;
+/// #     fn kind_from_raw(raw: SyntaxKind) -> SyntaxKind { raw }
+/// #     fn kind_to_raw(kind: SyntaxKind) -> SyntaxKind { kind }
+/// # }
+/// const FN_DEF: SyntaxKind = SyntaxKind(1);
+///
+/// struct FnDef(SyntaxNode<Lang>);
+///
+/// impl AstNode for FnDef {
+///     type Language = Lang;
+///     fn can_cast(kind: SyntaxKind) -> bool {
+///         kind == FN_DEF
+///     }
+///     fn cast(node: SyntaxNode<Lang>) -> Option<FnDef> {
+///         if FnDef::can_cast(node.kind()) {
+///             Some(FnDef(node))
+///         } else {
+///             None
+///         }
+///     }
+///     fn syntax(&self) -> &SyntaxNode<Lang> {
+///         &self.0
+///     }
+/// }
+///
+/// let mut builder = GreenNodeBuilder::new();
+/// builder.start_node(FN_DEF);
+/// builder.finish_node();
+/// let node = SyntaxNode::<Lang>::new_root(builder.finish());
+/// assert!(node.cast_to::<FnDef>().is_some());
+/// ```
+pub trait AstNode {
+    type Language: Language;
+
+    fn can_cast(kind: <Self::Language as Language>::Kind) -> bool;
+    /// Wraps `node` if it is of a kind accepted by `can_cast`.
+    fn cast(node: SyntaxNode<Self::Language>) -> Option<Self>
+    where
+        Self: Sized;
+    fn syntax(&self) -> &SyntaxNode<Self::Language>;
+}
+
+/// A typed token of the AST, which wraps a `SyntaxToken` of one of the kinds
+/// accepted by `can_cast`, the same way as `AstNode` does for nodes.
+pub trait AstToken {
+    type Language: Language;
+
+    fn can_cast(kind: <Self::Language as Language>::Kind) -> bool;
+    /// Wraps `token` if it is of a kind accepted by `can_cast`.
+    fn cast(token: SyntaxToken<Self::Language>) -> Option<Self>
+    where
+        Self: Sized;
+    fn syntax(&self) -> &SyntaxToken<Self::Language>;
+
+    fn text(&self) -> &str {
+        self.syntax().text()
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct SyntaxNode<L: Language> {
+    raw: cursor::SyntaxNode,
+    _p: PhantomData<L>,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct SyntaxToken<L: Language> {
+    raw: cursor::SyntaxToken,
+    _p: PhantomData<L>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SyntaxElement<L: Language> {
+    Node(SyntaxNode<L>),
+    Token(SyntaxToken<L>),
+}
+
+impl<L: Language> fmt::Debug for SyntaxNode<L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}@{:?}", self.kind(), self.text_range())
+    }
+}
+
+impl<L: Language> fmt::Display for SyntaxElement<L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.text(), f)
+    }
+}
+
+impl<L: Language> fmt::Debug for SyntaxToken<L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}@{:?} {:?}", self.kind(), self.text_range(), self.text())
+    }
+}
+
+impl<L: Language> From<cursor::SyntaxNode> for SyntaxNode<L> {
+    fn from(raw: cursor::SyntaxNode) -> SyntaxNode<L> {
+        SyntaxNode { raw, _p: PhantomData }
+    }
+}
+
+impl<L: Language> From<SyntaxNode<L>> for cursor::SyntaxNode {
+    fn from(node: SyntaxNode<L>) -> cursor::SyntaxNode {
+        node.raw
+    }
+}
+
+impl<L: Language> From<cursor::SyntaxToken> for SyntaxToken<L> {
+    fn from(raw: cursor::SyntaxToken) -> SyntaxToken<L> {
+        SyntaxToken { raw, _p: PhantomData }
+    }
+}
+
+impl<L: Language> From<SyntaxToken<L>> for cursor::SyntaxToken {
+    fn from(token: SyntaxToken<L>) -> cursor::SyntaxToken {
+        token.raw
+    }
+}
+
+impl<L: Language> From<cursor::SyntaxElement> for SyntaxElement<L> {
+    fn from(raw: cursor::SyntaxElement) -> SyntaxElement<L> {
+        match raw {
+            cursor::SyntaxElement::Node(it) => SyntaxElement::Node(it.into()),
+            cursor::SyntaxElement::Token(it) => SyntaxElement::Token(it.into()),
+        }
+    }
+}
+
+impl<L: Language> From<SyntaxElement<L>> for cursor::SyntaxElement {
+    fn from(element: SyntaxElement<L>) -> cursor::SyntaxElement {
+        match element {
+            SyntaxElement::Node(it) => cursor::SyntaxElement::Node(it.raw),
+            SyntaxElement::Token(it) => cursor::SyntaxElement::Token(it.raw),
+        }
+    }
+}
+
+impl<L: Language> From<SyntaxNode<L>> for SyntaxElement<L> {
+    fn from(node: SyntaxNode<L>) -> SyntaxElement<L> {
+        SyntaxElement::Node(node)
+    }
+}
+
+impl<L: Language> From<SyntaxToken<L>> for SyntaxElement<L> {
+    fn from(token: SyntaxToken<L>) -> SyntaxElement<L> {
+        SyntaxElement::Token(token)
+    }
+}
+
+impl<L: Language> TryFrom<SyntaxElement<L>> for SyntaxNode<L> {
+    type Error = SyntaxToken<L>;
+    fn try_from(element: SyntaxElement<L>) -> Result<SyntaxNode<L>, SyntaxToken<L>> {
+        match element {
+            SyntaxElement::Node(it) => Ok(it),
+            SyntaxElement::Token(it) => Err(it),
+        }
+    }
+}
+
+impl<L: Language> TryFrom<SyntaxElement<L>> for SyntaxToken<L> {
+    type Error = SyntaxNode<L>;
+    fn try_from(element: SyntaxElement<L>) -> Result<SyntaxToken<L>, SyntaxNode<L>> {
+        match element {
+            SyntaxElement::Node(it) => Err(it),
+            SyntaxElement::Token(it) => Ok(it),
+        }
+    }
+}
+
+impl<L: Language> SyntaxNode<L> {
+    pub fn new_root(green: GreenNode) -> SyntaxNode<L> {
+        cursor::SyntaxNode::new_root(green).into()
+    }
+
+    /// See `cursor::SyntaxNode::new_root_at`.
+    pub fn new_root_at(green: GreenNode, base: TextUnit) -> SyntaxNode<L> {
+        cursor::SyntaxNode::new_root_at(green, base).into()
+    }
+
+    pub fn replace_with(&self, replacement: GreenNode) -> GreenNode {
+        self.raw.replace_with(replacement)
+    }
+
+    pub fn try_replace_with(&self, replacement: GreenNode) -> Result<GreenNode, KindMismatch> {
+        self.raw.try_replace_with(replacement)
+    }
+
+    pub fn retag(&self, kind: L::Kind) -> GreenNode {
+        self.raw.retag(L::kind_to_raw(kind))
+    }
+
+    pub fn replace_descendants(
+        &self,
+        replacements: impl IntoIterator<Item = (SyntaxElement<L>, GreenElement)>,
+    ) -> GreenNode {
+        self.raw.replace_descendants(
+            replacements.into_iter().map(|(key, replacement)| (key.into(), replacement)),
+        )
+    }
+
+    pub fn try_replace_descendants(
+        &self,
+        replacements: impl IntoIterator<Item = (SyntaxElement<L>, GreenElement)>,
+    ) -> Result<GreenNode, InvalidReplacement> {
+        self.raw.try_replace_descendants(
+            replacements.into_iter().map(|(key, replacement)| (key.into(), replacement)),
+        )
+    }
+
+    pub fn map_trivia(
+        &self,
+        is_trivia: impl Fn(L::Kind) -> bool,
+        mut f: impl FnMut(&SyntaxToken<L>) -> Option<SmolStr>,
+    ) -> GreenNode {
+        self.raw
+            .map_trivia(|kind| is_trivia(L::kind_from_raw(kind)), |token| f(&token.clone().into()))
+    }
+
+    pub fn edit_trivia(
+        &self,
+        is_trivia: impl Fn(L::Kind) -> bool,
+        mut f: impl FnMut(&SyntaxToken<L>) -> TriviaEdit,
+    ) -> GreenNode {
+        self.raw
+            .edit_trivia(|kind| is_trivia(L::kind_from_raw(kind)), |token| f(&token.clone().into()))
+    }
+
+    pub fn insert_children(
+        &self,
+        at_index: usize,
+        new: impl IntoIterator<Item = GreenElement>,
+    ) -> GreenNode {
+        self.raw.insert_children(at_index, new)
+    }
+
+    pub fn remove_children(&self, range: Range<usize>) -> GreenNode {
+        self.raw.remove_children(range)
+    }
+
+    pub fn splice_children(
+        &self,
+        range: Range<usize>,
+        replace_with: Vec<GreenElement>,
+    ) -> GreenNode {
+        self.raw.splice_children(range, replace_with)
+    }
+
+    pub fn detach(&self) -> GreenNode {
+        self.raw.detach()
+    }
+
+    pub fn detach_with_adjacent_trivia(&self, is_trivia: impl Fn(L::Kind) -> bool) -> GreenNode {
+        self.raw.detach_with_adjacent_trivia(|kind| is_trivia(L::kind_from_raw(kind)))
+    }
+
+    /// See `cursor::SyntaxNode::clone_subtree`.
+    pub fn clone_subtree(&self) -> SyntaxNode<L> {
+        self.raw.clone_subtree().into()
+    }
+
+    /// See `cursor::SyntaxNode::index_path`.
+    pub fn index_path(&self) -> Vec<u32> {
+        self.raw.index_path()
+    }
+
+    /// See `cursor::SyntaxElement::position_cmp`.
+    pub fn position_cmp(&self, other: &SyntaxNode<L>) -> Option<std::cmp::Ordering> {
+        self.raw.position_cmp(&other.raw)
+    }
+
+    /// See `cursor::SyntaxNode::resolve_index_path`.
+    pub fn resolve_index_path(root: &SyntaxNode<L>, path: &[u32]) -> Option<SyntaxElement<L>> {
+        cursor::SyntaxNode::resolve_index_path(&root.raw, path).map(SyntaxElement::from)
+    }
+
+    /// A handle to the untyped node, see `cursor::SyntaxNode::handle`.
+    pub fn handle(&self) -> SyntaxNodeHandle {
+        self.raw.handle()
+    }
+
+    pub fn text_range(&self) -> TextRange {
+        self.raw.text_range()
+    }
+
+    pub fn text(&self) -> SyntaxText {
+        self.raw.text()
+    }
+
+    pub fn kind(&self) -> L::Kind {
+        L::kind_from_raw(self.raw.kind())
+    }
+
+    pub fn subtree_node_count(&self) -> u32 {
+        self.raw.subtree_node_count()
+    }
+
+    pub fn subtree_token_count(&self) -> u32 {
+        self.raw.subtree_token_count()
+    }
+
+    pub fn green(&self) -> &GreenNode {
+        self.raw.green()
+    }
+
+    pub fn to_green(&self) -> GreenNode {
+        self.raw.to_green()
+    }
+
+    /// See `cursor::SyntaxNode::green_root`.
+    pub fn green_root(&self) -> GreenNode {
+        self.raw.green_root()
+    }
+
+    pub fn memory_usage(&self) -> MemoryStats {
+        self.raw.memory_usage()
+    }
+
+    pub fn parent(&self) -> Option<SyntaxNode<L>> {
+        self.raw.parent().map(SyntaxNode::from)
+    }
+
+    pub fn ancestors(&self) -> impl Iterator<Item = SyntaxNode<L>> {
+        self.raw.ancestors().map(SyntaxNode::from)
+    }
+
+    /// See `cursor::SyntaxNode::nth_ancestor`.
+    pub fn nth_ancestor(&self, n: usize) -> Option<SyntaxNode<L>> {
+        self.raw.nth_ancestor(n).map(SyntaxNode::from)
+    }
+
+    pub fn depth(&self) -> usize {
+        self.raw.depth()
+    }
+
+    /// See `cursor::SyntaxNode::ancestor_at_depth`.
+    pub fn ancestor_at_depth(&self, depth: usize) -> Option<SyntaxNode<L>> {
+        self.raw.ancestor_at_depth(depth).map(SyntaxNode::from)
+    }
+
+    pub fn children(&self) -> SyntaxNodeChildren<L> {
+        SyntaxNodeChildren { raw: self.raw.children(), _p: PhantomData }
+    }
+
+    pub fn children_with_tokens(&self) -> SyntaxElementChildren<L> {
+        SyntaxElementChildren { raw: self.raw.children_with_tokens(), _p: PhantomData }
+    }
+
+    pub fn next_sibling(&self) -> Option<SyntaxNode<L>> {
+        self.raw.next_sibling().map(SyntaxNode::from)
+    }
+
+    pub fn next_sibling_or_token(&self) -> Option<SyntaxElement<L>> {
+        self.raw.next_sibling_or_token().map(SyntaxElement::from)
+    }
+
+    pub fn prev_sibling(&self) -> Option<SyntaxNode<L>> {
+        self.raw.prev_sibling().map(SyntaxNode::from)
+    }
+
+    pub fn prev_sibling_or_token(&self) -> Option<SyntaxElement<L>> {
+        self.raw.prev_sibling_or_token().map(SyntaxElement::from)
+    }
+
+    pub fn first_child(&self) -> Option<SyntaxNode<L>> {
+        self.raw.first_child().map(SyntaxNode::from)
+    }
+
+    pub fn first_child_or_token(&self) -> Option<SyntaxElement<L>> {
+        self.raw.first_child_or_token().map(SyntaxElement::from)
+    }
+
+    pub fn last_child(&self) -> Option<SyntaxNode<L>> {
+        self.raw.last_child().map(SyntaxNode::from)
+    }
+
+    pub fn last_child_or_token(&self) -> Option<SyntaxElement<L>> {
+        self.raw.last_child_or_token().map(SyntaxElement::from)
+    }
+
+    pub fn first_token(&self) -> Option<SyntaxToken<L>> {
+        self.raw.first_token().map(SyntaxToken::from)
+    }
+
+    pub fn last_token(&self) -> Option<SyntaxToken<L>> {
+        self.raw.last_token().map(SyntaxToken::from)
+    }
+
+    pub fn preorder(&self) -> Preorder<L> {
+        Preorder { raw: self.raw.preorder(), _p: PhantomData }
+    }
+
+    pub fn preorder_with_tokens(&self) -> impl Iterator<Item = WalkEvent<SyntaxElement<L>>> {
+        self.raw.preorder_with_tokens().map(|event| event.map(SyntaxElement::from))
+    }
+
+    /// See `cursor::SyntaxNode::descendants_filtered`.
+    pub fn descendants_filtered(
+        &self,
+        mut enter: impl FnMut(&SyntaxNode<L>) -> bool,
+    ) -> impl Iterator<Item = SyntaxNode<L>> {
+        self.raw.descendants_filtered(move |it| enter(&it.clone().into())).map(SyntaxNode::from)
+    }
+
+    pub fn descendants_with_tokens_filtered(
+        &self,
+        mut enter: impl FnMut(&SyntaxNode<L>) -> bool,
+    ) -> impl Iterator<Item = SyntaxElement<L>> {
+        self.raw
+            .descendants_with_tokens_filtered(move |it| enter(&it.clone().into()))
+            .map(SyntaxElement::from)
+    }
+
+    pub fn parallel_map_subtrees<T, F>(&self, depth: usize, f: F) -> Vec<T>
+    where
+        T: Send,
+        F: Fn(SyntaxNode<L>) -> T + Sync,
+    {
+        self.raw.parallel_map_subtrees(depth, |node| f(node.into()))
+    }
+
+    pub fn token_at_offset(&self, offset: TextUnit) -> TokenAtOffset<SyntaxToken<L>> {
+        self.raw.token_at_offset(offset).map(SyntaxToken::from)
+    }
+
+    pub fn try_token_at_offset(
+        &self,
+        offset: TextUnit,
+    ) -> Result<TokenAtOffset<SyntaxToken<L>>, OffsetOutOfRange> {
+        Ok(self.raw.try_token_at_offset(offset)?.map(SyntaxToken::from))
+    }
+
+    pub fn line_of_offset(&self, offset: TextUnit) -> u32 {
+        self.raw.line_of_offset(offset)
+    }
+
+    pub fn covering_node(&self, range: TextRange) -> SyntaxElement<L> {
+        self.raw.covering_node(range).into()
+    }
+
+    pub fn try_covering_node(
+        &self,
+        range: TextRange,
+    ) -> Result<SyntaxElement<L>, RangeOutOfBounds> {
+        self.raw.try_covering_node(range).map(SyntaxElement::from)
+    }
+
+    /// `T::cast` of this node.
+    pub fn cast_to<T: AstNode<Language = L>>(&self) -> Option<T> {
+        T::cast(self.clone())
+    }
+
+    /// The closest of the ancestors of this node, starting with the node
+    /// itself, which is a `T`.
+    pub fn ancestor_of_type<T: AstNode<Language = L>>(&self) -> Option<T> {
+        self.ancestors().find_map(T::cast)
+    }
+
+    /// The first child of this node which is a `T`, same as
+    /// `first_child_of_type`.
+    pub fn child_of_type<T: AstNode<Language = L>>(&self) -> Option<T> {
+        self.first_child_of_type()
+    }
+
+    /// The children of this node which are `T`s, in order.
+    pub fn children_of_type<T: AstNode<Language = L>>(&self) -> AstChildren<T> {
+        AstChildren { inner: self.children(), _p: PhantomData }
+    }
+
+    /// The first child of this node which is a `T`.
+    pub fn first_child_of_type<T: AstNode<Language = L>>(&self) -> Option<T> {
+        self.children_of_type().next()
+    }
+
+    /// The tokens of the subtree of this node which are `T`s, in order.
+    pub fn tokens_of_type<T: AstToken<Language = L>>(&self) -> impl Iterator<Item = T> {
+        self.preorder_with_tokens().filter_map(|event| match event {
+            WalkEvent::Enter(SyntaxElement::Token(it)) => T::cast(it),
+            _ => None,
+        })
+    }
+}
+
+impl<L: Language> SyntaxToken<L> {
+    pub fn replace_with(&self, replacement: GreenToken) -> GreenNode {
+        self.raw.replace_with(replacement)
+    }
+
+    pub fn try_replace_with(&self, replacement: GreenToken) -> Result<GreenNode, KindMismatch> {
+        self.raw.try_replace_with(replacement)
+    }
+
+    pub fn with_text(&self, text: impl Into<SmolStr>) -> GreenNode {
+        self.raw.with_text(text)
+    }
+
+    pub fn with_kind(&self, kind: L::Kind) -> GreenNode {
+        self.raw.with_kind(L::kind_to_raw(kind))
+    }
+
+    pub fn text_range(&self) -> TextRange {
+        self.raw.text_range()
+    }
+
+    pub fn kind(&self) -> L::Kind {
+        L::kind_from_raw(self.raw.kind())
+    }
+
+    pub fn text(&self) -> &str {
+        self.raw.text()
+    }
+
+    pub fn green(&self) -> &GreenToken {
+        self.raw.green()
+    }
+
+    pub fn to_green(&self) -> GreenToken {
+        self.raw.to_green()
+    }
+
+    pub fn parent(&self) -> SyntaxNode<L> {
+        self.raw.parent().into()
+    }
+
+    pub fn index_path(&self) -> Vec<u32> {
+        self.raw.index_path()
+    }
+
+    pub fn position_cmp(&self, other: &SyntaxToken<L>) -> Option<std::cmp::Ordering> {
+        self.raw.position_cmp(&other.raw)
+    }
+
+    pub fn next_sibling_or_token(&self) -> Option<SyntaxElement<L>> {
+        self.raw.next_sibling_or_token().map(SyntaxElement::from)
+    }
+
+    pub fn prev_sibling_or_token(&self) -> Option<SyntaxElement<L>> {
+        self.raw.prev_sibling_or_token().map(SyntaxElement::from)
+    }
+
+    pub fn next_token(&self) -> Option<SyntaxToken<L>> {
+        self.raw.next_token().map(SyntaxToken::from)
+    }
+
+    pub fn prev_token(&self) -> Option<SyntaxToken<L>> {
+        self.raw.prev_token().map(SyntaxToken::from)
+    }
+}
+
+impl<L: Language> SyntaxElement<L> {
+    pub fn text_range(&self) -> TextRange {
+        match self {
+            SyntaxElement::Node(it) => it.text_range(),
+            SyntaxElement::Token(it) => it.text_range(),
+        }
+    }
+
+    /// See `cursor::SyntaxElement::text`.
+    pub fn text(&self) -> SyntaxText {
+        cursor::SyntaxElement::from(self.clone()).text()
+    }
+
+    pub fn text_len(&self) -> TextUnit {
+        self.text_range().len()
+    }
+
+    pub fn to_green(&self) -> GreenElement {
+        match self {
+            SyntaxElement::Node(it) => it.to_green().into(),
+            SyntaxElement::Token(it) => it.to_green().into(),
+        }
+    }
+
+    /// See `cursor::SyntaxElement::position_cmp`.
+    pub fn position_cmp(&self, other: &SyntaxElement<L>) -> Option<std::cmp::Ordering> {
+        cursor::SyntaxElement::from(self.clone()).position_cmp(&other.clone().into())
+    }
+
+    pub fn kind(&self) -> L::Kind {
+        match self {
+            SyntaxElement::Node(it) => it.kind(),
+            SyntaxElement::Token(it) => it.kind(),
+        }
+    }
+
+    pub fn is_node(&self) -> bool {
+        matches!(self, SyntaxElement::Node(_))
+    }
+
+    pub fn is_token(&self) -> bool {
+        matches!(self, SyntaxElement::Token(_))
+    }
+
+    pub fn as_node(&self) -> Option<&SyntaxNode<L>> {
+        match self {
+            SyntaxElement::Node(it) => Some(it),
+            SyntaxElement::Token(_) => None,
+        }
+    }
+
+    pub fn as_token(&self) -> Option<&SyntaxToken<L>> {
+        match self {
+            SyntaxElement::Node(_) => None,
+            SyntaxElement::Token(it) => Some(it),
+        }
+    }
+
+    pub fn into_node(self) -> Option<SyntaxNode<L>> {
+        SyntaxNode::try_from(self).ok()
+    }
+
+    pub fn into_token(self) -> Option<SyntaxToken<L>> {
+        SyntaxToken::try_from(self).ok()
+    }
+
+    pub fn parent(&self) -> Option<SyntaxNode<L>> {
+        match self {
+            SyntaxElement::Node(it) => it.parent(),
+            SyntaxElement::Token(it) => Some(it.parent()),
+        }
+    }
+
+    pub fn first_token(&self) -> Option<SyntaxToken<L>> {
+        match self {
+            SyntaxElement::Node(it) => it.first_token(),
+            SyntaxElement::Token(it) => Some(it.clone()),
+        }
+    }
+
+    pub fn last_token(&self) -> Option<SyntaxToken<L>> {
+        match self {
+            SyntaxElement::Node(it) => it.last_token(),
+            SyntaxElement::Token(it) => Some(it.clone()),
+        }
+    }
+
+    pub fn next_sibling_or_token(&self) -> Option<SyntaxElement<L>> {
+        match self {
+            SyntaxElement::Node(it) => it.next_sibling_or_token(),
+            SyntaxElement::Token(it) => it.next_sibling_or_token(),
+        }
+    }
+
+    pub fn prev_sibling_or_token(&self) -> Option<SyntaxElement<L>> {
+        match self {
+            SyntaxElement::Node(it) => it.prev_sibling_or_token(),
+            SyntaxElement::Token(it) => it.prev_sibling_or_token(),
+        }
+    }
+
+    /// `T::cast` of this element, if it is a token.
+    pub fn cast_token<T: AstToken<Language = L>>(&self) -> Option<T> {
+        match self {
+            SyntaxElement::Node(_) => None,
+            SyntaxElement::Token(it) => T::cast(it.clone()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SyntaxNodeChildren<L: Language> {
+    raw: cursor::SyntaxNodeChildren,
+    _p: PhantomData<L>,
+}
+
+impl<L: Language> Iterator for SyntaxNodeChildren<L> {
+    type Item = SyntaxNode<L>;
+    fn next(&mut self) -> Option<SyntaxNode<L>> {
+        self.raw.next().map(SyntaxNode::from)
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.raw.size_hint()
+    }
+}
+
+impl<L: Language> iter::FusedIterator for SyntaxNodeChildren<L> {}
+
+/// See `cursor::SyntaxNode`'s impl.
+impl<L: Language> IntoIterator for &SyntaxNode<L> {
+    type Item = SyntaxNode<L>;
+    type IntoIter = SyntaxNodeChildren<L>;
+    fn into_iter(self) -> SyntaxNodeChildren<L> {
+        self.children()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SyntaxElementChildren<L: Language> {
+    raw: cursor::SyntaxElementChildren,
+    _p: PhantomData<L>,
+}
+
+impl<L: Language> Iterator for SyntaxElementChildren<L> {
+    type Item = SyntaxElement<L>;
+    fn next(&mut self) -> Option<SyntaxElement<L>> {
+        self.raw.next().map(SyntaxElement::from)
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.raw.size_hint()
+    }
+}
+
+impl<L: Language> ExactSizeIterator for SyntaxElementChildren<L> {}
+
+impl<L: Language> iter::FusedIterator for SyntaxElementChildren<L> {}
+
+/// The children of a node which cast to `T`, see
+/// `SyntaxNode::children_of_type`.
+pub struct AstChildren<T: AstNode> {
+    inner: SyntaxNodeChildren<T::Language>,
+    _p: PhantomData<T>,
+}
+
+impl<T: AstNode> Clone for AstChildren<T> {
+    fn clone(&self) -> AstChildren<T> {
+        AstChildren { inner: self.inner.clone(), _p: PhantomData }
+    }
+}
+
+impl<T: AstNode> fmt::Debug for AstChildren<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AstChildren").field("inner", &self.inner).finish()
+    }
+}
+
+impl<T: AstNode> Iterator for AstChildren<T> {
+    type Item = T;
+    fn next(&mut self) -> Option<T> {
+        self.inner.find_map(T::cast)
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.inner.size_hint().1)
+    }
+}
+
+impl<T: AstNode> iter::FusedIterator for AstChildren<T> {}
+
+#[derive(Clone, Debug)]
+pub struct Preorder<L: Language> {
+    raw: cursor::Preorder,
+    _p: PhantomData<L>,
+}
+
+impl<L: Language> Iterator for Preorder<L> {
+    type Item = WalkEvent<SyntaxNode<L>>;
+    fn next(&mut self) -> Option<WalkEvent<SyntaxNode<L>>> {
+        self.raw.next().map(|event| event.map(SyntaxNode::from))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils, GreenNodeBuilder};
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    enum Kind {
+        Word,
+        Ws,
+        List,
+        Root,
+        Comment,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    enum Lang {}
+
+    impl Language for Lang {
+        type Kind = Kind;
+        fn kind_from_raw(raw: SyntaxKind) -> Kind {
+            [Kind::Word, Kind::Ws, Kind::List, Kind::Root, Kind::Comment][raw.0 as usize]
+        }
+        fn kind_to_raw(kind: Kind) -> SyntaxKind {
+            SyntaxKind(kind as u16)
+        }
+    }
+
+    fn sample() -> SyntaxNode<Lang> {
+        SyntaxNode::new_root(test_utils::sample())
+    }
+
+    #[test]
+    fn typed_navigation() {
+        let root = sample();
+        let events = root
+            .preorder_with_tokens()
+            .filter_map(|event| match event {
+                WalkEvent::Enter(it) => Some(format!("{:?}", it)),
+                WalkEvent::Leave(_) => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            [
+                "Node(Root@[0; 11))",
+                "Node(List@[0; 7))",
+                "Token(Word@[0; 3) \"foo\")",
+                "Token(Ws@[3; 4) \" \")",
+                "Node(List@[4; 7))",
+                "Token(Word@[4; 7) \"bar\")",
+                "Token(Ws@[7; 8) \" \")",
+                "Token(Word@[8; 11) \"baz\")",
+            ]
+        );
+
+        let inner = root.children().next().unwrap();
+        assert_eq!(inner.parent(), Some(root.clone()));
+        assert_eq!(
+            inner.ancestors().map(|it| it.kind()).collect::<Vec<_>>(),
+            [Kind::List, Kind::Root]
+        );
+        assert_eq!(root.preorder().count(), 6);
+        let covering = root.covering_node(TextRange::from_to(5.into(), 6.into()));
+        assert!(covering.is_token() && covering.as_node().is_none());
+        assert_eq!(covering.as_token().unwrap().text(), "bar");
+        assert_eq!(covering.into_token().unwrap().text(), "bar");
+        assert_eq!(root.text().to_string(), "foo bar baz");
+        let elements = root.children_with_tokens().map(|it| it.to_string()).collect::<Vec<_>>();
+        assert_eq!(elements, ["foo bar", " ", "baz"]);
+        let covering = root.covering_node(TextRange::from_to(3.into(), 7.into()));
+        assert_eq!(SyntaxNode::try_from(covering), Ok(inner.clone()));
+        let tokens = root.token_at_offset(3.into()).map(|it| it.kind()).collect::<Vec<_>>();
+        assert_eq!(tokens, [Kind::Word, Kind::Ws]);
+
+        // Conversions keep the identity of the node.
+        let raw = cursor::SyntaxNode::from(inner.clone());
+        assert_eq!(SyntaxNode::<Lang>::from(raw), inner);
+    }
+
+    #[test]
+    fn typed_edits() {
+        let root = sample();
+        let green = root.edit_trivia(
+            |kind| kind == Kind::Ws,
+            |token| {
+                assert_eq!(token.kind(), Kind::Ws);
+                TriviaEdit::Delete
+            },
+        );
+        let edited = SyntaxNode::<Lang>::new_root(green);
+        assert_eq!(edited.children_with_tokens().len(), 2);
+
+        let word = root.first_token().unwrap();
+        let green = word.next_token().unwrap().with_kind(Kind::Word);
+        let edited = SyntaxNode::<Lang>::new_root(green);
+        assert_eq!(edited.first_token().unwrap().next_token().unwrap().kind(), Kind::Word);
+
+        let inner = root.last_child().unwrap();
+        let edited = SyntaxNode::<Lang>::new_root(inner.retag(Kind::Ws));
+        assert_eq!(edited.last_child().unwrap().kind(), Kind::Ws);
+    }
+
+    struct Root(SyntaxNode<Lang>);
+    struct List(SyntaxNode<Lang>);
+
+    impl AstNode for Root {
+        type Language = Lang;
+        fn can_cast(kind: Kind) -> bool {
+            kind == Kind::Root
+        }
+        fn cast(node: SyntaxNode<Lang>) -> Option<Root> {
+            if Root::can_cast(node.kind()) {
+                Some(Root(node))
+            } else {
+                None
+            }
+        }
+        fn syntax(&self) -> &SyntaxNode<Lang> {
+            &self.0
+        }
+    }
+
+    impl AstNode for List {
+        type Language = Lang;
+        fn can_cast(kind: Kind) -> bool {
+            kind == Kind::List
+        }
+        fn cast(node: SyntaxNode<Lang>) -> Option<List> {
+            if List::can_cast(node.kind()) {
+                Some(List(node))
+            } else {
+                None
+            }
+        }
+        fn syntax(&self) -> &SyntaxNode<Lang> {
+            &self.0
+        }
+    }
+
+    #[test]
+    fn ast_nodes() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(Lang::kind_to_raw(Kind::Root));
+        builder.token(Lang::kind_to_raw(Kind::Ws), " ".into());
+        builder.start_node(Lang::kind_to_raw(Kind::List));
+        builder.start_node(Lang::kind_to_raw(Kind::List));
+        builder.token(Lang::kind_to_raw(Kind::Word), "a".into());
+        builder.finish_node();
+        builder.finish_node();
+        builder.finish_node();
+        let root = SyntaxNode::<Lang>::new_root(builder.finish());
+        let outer = root.first_child().unwrap();
+        let inner = outer.first_child().unwrap();
+
+        assert_eq!(root.cast_to::<Root>().unwrap().syntax(), &root);
+        assert!(root.cast_to::<List>().is_none());
+        assert!(inner.cast_to::<Root>().is_none());
+
+        assert_eq!(inner.ancestor_of_type::<List>().unwrap().syntax(), &inner);
+        assert_eq!(inner.ancestor_of_type::<Root>().unwrap().syntax(), &root);
+        assert!(root.ancestor_of_type::<List>().is_none());
+
+        assert_eq!(root.child_of_type::<List>().unwrap().syntax(), &outer);
+        assert_eq!(outer.child_of_type::<List>().unwrap().syntax(), &inner);
+        assert!(root.child_of_type::<Root>().is_none());
+        // Tokens are not children.
+        assert!(inner.child_of_type::<List>().is_none());
+    }
+
+    #[test]
+    fn children_of_type() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(Lang::kind_to_raw(Kind::Root));
+        for text in ["a", "b", "c"].iter() {
+            builder.start_node(Lang::kind_to_raw(Kind::List));
+            builder.token(Lang::kind_to_raw(Kind::Word), (*text).into());
+            builder.finish_node();
+            builder.token(Lang::kind_to_raw(Kind::Ws), " ".into());
+            builder.start_node(Lang::kind_to_raw(Kind::Root));
+            builder.finish_node();
+        }
+        builder.finish_node();
+        let root = SyntaxNode::<Lang>::new_root(builder.finish());
+
+        let lists = root.children_of_type::<List>();
+        let texts = lists
+            .clone()
+            .map(|it| it.syntax().first_token().unwrap().text().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(texts, ["a", "b", "c"]);
+        assert_eq!(lists.count(), 3);
+        assert_eq!(root.children_of_type::<Root>().count(), 3);
+        assert_eq!(
+            root.first_child_of_type::<List>().unwrap().syntax(),
+            &root.first_child().unwrap()
+        );
+
+        let empty = root.first_child().unwrap();
+        assert_eq!(empty.children_of_type::<List>().count(), 0);
+        assert!(empty.first_child_of_type::<List>().is_none());
+    }
+
+    struct Comment(SyntaxToken<Lang>);
+
+    impl AstToken for Comment {
+        type Language = Lang;
+        fn can_cast(kind: Kind) -> bool {
+            kind == Kind::Comment
+        }
+        fn cast(token: SyntaxToken<Lang>) -> Option<Comment> {
+            if Comment::can_cast(token.kind()) {
+                Some(Comment(token))
+            } else {
+                None
+            }
+        }
+        fn syntax(&self) -> &SyntaxToken<Lang> {
+            &self.0
+        }
+    }
+
+    #[test]
+    fn ast_tokens() {
+        // Root@[0; 13)
+        //   Comment "#a"
+        //   List@[2; 11)
+        //     Word "x"
+        //     Comment "#b"
+        //     List@[5; 11)
+        //       Ws " "
+        //       Comment "#c"
+        //       Word "yyy"
+        //   Word "zz"
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(Lang::kind_to_raw(Kind::Root));
+        builder.token(Lang::kind_to_raw(Kind::Comment), "#a".into());
+        builder.start_node(Lang::kind_to_raw(Kind::List));
+        builder.token(Lang::kind_to_raw(Kind::Word), "x".into());
+        builder.token(Lang::kind_to_raw(Kind::Comment), "#b".into());
+        builder.start_node(Lang::kind_to_raw(Kind::List));
+        builder.token(Lang::kind_to_raw(Kind::Ws), " ".into());
+        builder.token(Lang::kind_to_raw(Kind::Comment), "#c".into());
+        builder.token(Lang::kind_to_raw(Kind::Word), "yyy".into());
+        builder.finish_node();
+        builder.finish_node();
+        builder.token(Lang::kind_to_raw(Kind::Word), "zz".into());
+        builder.finish_node();
+        let root = SyntaxNode::<Lang>::new_root(builder.finish());
+
+        let texts = |node: &SyntaxNode<Lang>| {
+            node.tokens_of_type::<Comment>().map(|it| it.text().to_string()).collect::<Vec<_>>()
+        };
+        assert_eq!(texts(&root), ["#a", "#b", "#c"]);
+        let outer = root.first_child().unwrap();
+        assert_eq!(texts(&outer), ["#b", "#c"]);
+        assert_eq!(texts(&outer.first_child().unwrap()), ["#c"]);
+
+        let elements = root.children_with_tokens().collect::<Vec<_>>();
+        let comment = elements[0].cast_token::<Comment>().unwrap();
+        assert_eq!(comment.syntax().text_range(), TextRange::from_to(0.into(), 2.into()));
+        // Nodes and other tokens are not comments.
+        assert!(elements[1].cast_token::<Comment>().is_none());
+        assert!(elements[2].cast_token::<Comment>().is_none());
+        assert!(Comment::cast(root.last_token().unwrap()).is_none());
     }
 }
@@ -0,0 +1,924 @@
+//! Red nodes, allocated in an arena.
+//!
+//! This mirrors the navigation API of `cursor`, but instead of being a
+//! separate reference counted allocation, each `SyntaxNode` lives in an
+//! `Arena`, and is a pair of references. Nodes are never freed individually:
+//! all of them are deallocated at once, when the arena is dropped. This is a
+//! good fit for batch traversals, which keep a lot of nodes alive (for
+//! example, collect all the descendants into a `Vec`), but an arena which is
+//! used for a long time only grows. For editing, use `cursor`.
+use std::{fmt, iter, slice};
+
+use crate::{
+    GreenElement, GreenNode, GreenToken, SyntaxKind, TextRange, TextUnit, TokenAtOffset, WalkEvent,
+    cursor::{OffsetOutOfRange, RangeOutOfBounds},
+    green::child_index,
+};
+
+/// Memory for `SyntaxNode`s. Several trees can share a single arena.
+pub struct Arena<'a> {
+    nodes: colosseum::unsync::Arena<NodeData<'a>>,
+}
+
+impl<'a> Arena<'a> {
+    pub fn new() -> Arena<'a> {
+        Arena { nodes: colosseum::unsync::Arena::new() }
+    }
+
+    /// Creates an arena, which fits `n` nodes without allocating.
+    pub fn with_capacity(n: usize) -> Arena<'a> {
+        Arena { nodes: colosseum::unsync::Arena::with_capacity(n) }
+    }
+}
+
+impl Default for Arena<'_> {
+    fn default() -> Self {
+        Arena::new()
+    }
+}
+
+impl fmt::Debug for Arena<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Arena").finish()
+    }
+}
+
+enum NodeData<'a> {
+    Root(GreenNode),
+    Child {
+        green: &'a GreenNode,
+        parent: &'a NodeData<'a>,
+        /// The data of the root, which identifies the tree: it lives as long
+        /// as the arena, so it is never reused for another tree.
+        root: &'a NodeData<'a>,
+        index: u32,
+        offset: TextUnit,
+    },
+}
+
+#[derive(Clone, Copy)]
+pub struct SyntaxNode<'a> {
+    data: &'a NodeData<'a>,
+    arena: &'a Arena<'a>,
+}
+
+/// Nodes are equal if they are at the same position of the same tree, like
+/// `cursor::SyntaxNode`s. Tree here means a single `new_root`: two trees
+/// created from the same green node are different trees.
+///
+/// The root, the green node and the offset tell apart almost all different
+/// nodes in O(1), but the green node might be shared by several positions
+/// (for example, by the children of adjacent empty nodes which the builder
+/// deduplicated), so equal ones also compare their child indices up to a
+/// common ancestor.
+impl PartialEq for SyntaxNode<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        if !std::ptr::eq(self.root_data(), other.root_data())
+            || !std::ptr::eq(self.green(), other.green())
+            || self.text_range().start() != other.text_range().start()
+        {
+            return false;
+        }
+        // Both nodes have the same depth if they are equal.
+        let (mut a, mut b) = (self.data, other.data);
+        loop {
+            if std::ptr::eq(a, b) {
+                return true;
+            }
+            match (a, b) {
+                (
+                    NodeData::Child { parent: a_parent, index: a_index, .. },
+                    NodeData::Child { parent: b_parent, index: b_index, .. },
+                ) if a_index == b_index => {
+                    a = a_parent;
+                    b = b_parent;
+                }
+                _ => return false,
+            }
+        }
+    }
+}
+
+impl Eq for SyntaxNode<'_> {}
+
+// Consistent with `PartialEq`: equal nodes have the same root, green node
+// and offset.
+impl std::hash::Hash for SyntaxNode<'_> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        std::ptr::hash(self.root_data(), state);
+        std::ptr::hash(self.green(), state);
+        self.text_range().start().hash(state);
+    }
+}
+
+impl fmt::Debug for SyntaxNode<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}@{:?}", self.kind(), self.text_range())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SyntaxToken<'a> {
+    parent: SyntaxNode<'a>,
+    index: u32,
+    offset: TextUnit,
+}
+
+impl fmt::Debug for SyntaxToken<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}@{:?} {:?}", self.kind(), self.text_range(), self.text())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SyntaxElement<'a> {
+    Node(SyntaxNode<'a>),
+    Token(SyntaxToken<'a>),
+}
+
+impl<'a> From<SyntaxNode<'a>> for SyntaxElement<'a> {
+    fn from(node: SyntaxNode<'a>) -> SyntaxElement<'a> {
+        SyntaxElement::Node(node)
+    }
+}
+
+impl<'a> From<SyntaxToken<'a>> for SyntaxElement<'a> {
+    fn from(token: SyntaxToken<'a>) -> SyntaxElement<'a> {
+        SyntaxElement::Token(token)
+    }
+}
+
+impl<'a> SyntaxNode<'a> {
+    pub fn new_root(green: GreenNode, arena: &'a Arena<'a>) -> SyntaxNode<'a> {
+        let data = arena.nodes.alloc(NodeData::Root(green));
+        SyntaxNode { data, arena }
+    }
+
+    fn new_child(&self, green: &'a GreenNode, index: u32, offset: TextUnit) -> SyntaxNode<'a> {
+        let (parent, root) = (self.data, self.root_data());
+        let data = self.arena.nodes.alloc(NodeData::Child { green, parent, root, index, offset });
+        SyntaxNode { data, arena: self.arena }
+    }
+
+    fn root_data(&self) -> &'a NodeData<'a> {
+        match self.data {
+            NodeData::Root(_) => self.data,
+            NodeData::Child { root, .. } => root,
+        }
+    }
+
+    fn as_child(&self) -> Option<(SyntaxNode<'a>, u32, TextUnit)> {
+        match self.data {
+            NodeData::Root(_) => None,
+            NodeData::Child { parent, index, offset, .. } => {
+                Some((SyntaxNode { data: parent, arena: self.arena }, *index, *offset))
+            }
+        }
+    }
+
+    pub fn text_range(&self) -> TextRange {
+        let offset = match self.data {
+            NodeData::Root(_) => 0.into(),
+            NodeData::Child { offset, .. } => *offset,
+        };
+        TextRange::offset_len(offset, self.green().text_len())
+    }
+
+    pub fn kind(&self) -> SyntaxKind {
+        self.green().kind()
+    }
+
+    pub fn green(&self) -> &'a GreenNode {
+        match self.data {
+            NodeData::Root(green) => green,
+            NodeData::Child { green, .. } => green,
+        }
+    }
+
+    pub fn parent(&self) -> Option<SyntaxNode<'a>> {
+        self.as_child().map(|(parent, _, _)| parent)
+    }
+
+    pub fn ancestors(&self) -> impl Iterator<Item = SyntaxNode<'a>> {
+        iter::successors(Some(*self), SyntaxNode::parent)
+    }
+
+    pub fn children(&self) -> SyntaxNodeChildren<'a> {
+        SyntaxNodeChildren(Iter::new(*self))
+    }
+
+    pub fn children_with_tokens(&self) -> SyntaxElementChildren<'a> {
+        SyntaxElementChildren(Iter::new(*self))
+    }
+
+    pub fn next_sibling(&self) -> Option<SyntaxNode<'a>> {
+        let (parent, index, _) = self.as_child()?;
+        parent.nodes(parent.children_from(index as usize + 1)).next()
+    }
+
+    pub fn next_sibling_or_token(&self) -> Option<SyntaxElement<'a>> {
+        let (parent, index, _) = self.as_child()?;
+        let (element, index, offset) = parent.children_from(index as usize + 1).next()?;
+        Some(SyntaxElement::new(element, parent, index, offset))
+    }
+
+    pub fn prev_sibling(&self) -> Option<SyntaxNode<'a>> {
+        let (parent, index, _) = self.as_child()?;
+        parent.nodes(parent.children_to(index as usize)).next()
+    }
+
+    pub fn prev_sibling_or_token(&self) -> Option<SyntaxElement<'a>> {
+        let (parent, index, _) = self.as_child()?;
+        let (element, index, offset) = parent.children_to(index as usize).next()?;
+        Some(SyntaxElement::new(element, parent, index, offset))
+    }
+
+    /// Get first child, excluding tokens.
+    pub fn first_child(&self) -> Option<SyntaxNode<'a>> {
+        self.children().next()
+    }
+
+    /// Get the first, including tokens.
+    pub fn first_child_or_token(&self) -> Option<SyntaxElement<'a>> {
+        self.children_with_tokens().next()
+    }
+
+    /// Get last child, excluding tokens.
+    pub fn last_child(&self) -> Option<SyntaxNode<'a>> {
+        self.nodes(self.children_to(self.green().children().len())).next()
+    }
+
+    /// Get last child, including tokens.
+    pub fn last_child_or_token(&self) -> Option<SyntaxElement<'a>> {
+        let (element, index, offset) = self.children_to(self.green().children().len()).next()?;
+        Some(SyntaxElement::new(element, *self, index, offset))
+    }
+
+    // The offsets of the children are derived from the green node, which
+    // checks them, rather than from the offsets of their siblings.
+
+    /// Children of this node, starting with the one at `start_index`, with
+    /// their indices and (absolute) offsets.
+    fn children_from(
+        &self,
+        start_index: usize,
+    ) -> impl Iterator<Item = (&'a GreenElement, u32, TextUnit)> {
+        let base = self.text_range().start();
+        self.green().children_with_offsets_from(start_index).map(move |child| {
+            (child.element(), child_index(child.index()), base + child.rel_offset())
+        })
+    }
+
+    /// Children of this node before `end_index` in reverse order, with their
+    /// indices and (absolute) offsets.
+    fn children_to(
+        &self,
+        end_index: usize,
+    ) -> impl Iterator<Item = (&'a GreenElement, u32, TextUnit)> {
+        let base = self.text_range().start();
+        self.green().children_with_offsets_to(end_index).rev().map(move |child| {
+            (child.element(), child_index(child.index()), base + child.rel_offset())
+        })
+    }
+
+    /// The nodes among `children` of this node.
+    fn nodes(
+        self,
+        children: impl Iterator<Item = (&'a GreenElement, u32, TextUnit)>,
+    ) -> impl Iterator<Item = SyntaxNode<'a>> {
+        children.filter_map(move |(element, index, offset)| match element {
+            GreenElement::Node(node) => Some(self.new_child(node, index, offset)),
+            GreenElement::Token(_) => None,
+        })
+    }
+
+    /// Return the leftmost token in the subtree of this node
+    pub fn first_token(&self) -> Option<SyntaxToken<'a>> {
+        first_token_from(self.first_child_or_token()?, Some(*self))
+    }
+
+    /// Return the rightmost token in the subtree of this node
+    pub fn last_token(&self) -> Option<SyntaxToken<'a>> {
+        last_token_from(self.last_child_or_token()?, Some(*self))
+    }
+
+    /// Traverse the subtree rooted at the current node (including the current
+    /// node) in preorder, excluding tokens.
+    pub fn preorder(&self) -> impl Iterator<Item = WalkEvent<SyntaxNode<'a>>> {
+        let this = *self;
+        iter::successors(Some(WalkEvent::Enter(this)), move |pos| {
+            let next = match *pos {
+                WalkEvent::Enter(node) => match node.first_child() {
+                    Some(child) => WalkEvent::Enter(child),
+                    None => WalkEvent::Leave(node),
+                },
+                WalkEvent::Leave(node) => {
+                    if node == this {
+                        return None;
+                    }
+                    match node.next_sibling() {
+                        Some(sibling) => WalkEvent::Enter(sibling),
+                        None => WalkEvent::Leave(node.parent().unwrap()),
+                    }
+                }
+            };
+            Some(next)
+        })
+    }
+
+    /// Traverse the subtree rooted at the current node (including the current
+    /// node) in preorder, including tokens.
+    pub fn preorder_with_tokens(&self) -> impl Iterator<Item = WalkEvent<SyntaxElement<'a>>> {
+        let start: SyntaxElement = (*self).into();
+        iter::successors(Some(WalkEvent::Enter(start)), move |pos| {
+            let next = match *pos {
+                WalkEvent::Enter(el) => match el {
+                    SyntaxElement::Node(node) => match node.first_child_or_token() {
+                        Some(child) => WalkEvent::Enter(child),
+                        None => WalkEvent::Leave(node.into()),
+                    },
+                    SyntaxElement::Token(token) => WalkEvent::Leave(token.into()),
+                },
+                WalkEvent::Leave(el) => {
+                    if el == start {
+                        return None;
+                    }
+                    match el.next_sibling_or_token() {
+                        Some(sibling) => WalkEvent::Enter(sibling),
+                        None => WalkEvent::Leave(el.parent().unwrap().into()),
+                    }
+                }
+            };
+            Some(next)
+        })
+    }
+
+    /// Find a token in the subtree corresponding to this node, which covers the offset.
+    /// Precondition: offset must be withing node's range.
+    pub fn token_at_offset(&self, offset: TextUnit) -> TokenAtOffset<SyntaxToken<'a>> {
+        let range = self.text_range();
+        if !(range.start() <= offset && offset <= range.end()) {
+            panic!("token_at_offset: {}", OffsetOutOfRange { kind: self.kind(), range, offset });
+        }
+        if range.is_empty() {
+            return TokenAtOffset::None;
+        }
+
+        // The non-empty children, whose ranges contain the offset.
+        let children_at_offset = |node: &SyntaxNode<'a>| {
+            node.children_with_tokens().filter(move |child| {
+                let child_range = child.text_range();
+                !child_range.is_empty()
+                    && (child_range.start() <= offset && offset <= child_range.end())
+            })
+        };
+        // Descend while a single child contains the offset. Below the node
+        // where two children touch it, each of them has a single path.
+        let mut node = *self;
+        loop {
+            let mut children = children_at_offset(&node);
+            let left = children.next().unwrap();
+            let right = children.next();
+            assert!(children.next().is_none());
+            match (left, right) {
+                (SyntaxElement::Node(it), None) => node = it,
+                (SyntaxElement::Token(it), None) => return TokenAtOffset::Single(it),
+                (left, Some(right)) => {
+                    let token = |element: SyntaxElement<'a>| {
+                        element.descend(|node| children_at_offset(node).next()).unwrap()
+                    };
+                    return TokenAtOffset::Between(token(left), token(right));
+                }
+            }
+        }
+    }
+
+    /// Return the deepest node or token in the current subtree that fully
+    /// contains the range. If the range is empty and is contained in two leaf
+    /// nodes, either one can be returned. Precondition: range must be contained
+    /// withing the current node
+    pub fn covering_node(&self, range: TextRange) -> SyntaxElement<'a> {
+        let node_range = self.text_range();
+        if !range.is_subrange(&node_range) {
+            panic!("covering_node: {}", RangeOutOfBounds { kind: self.kind(), node_range, range });
+        }
+        let mut res: SyntaxElement = (*self).into();
+        loop {
+            res = match res {
+                SyntaxElement::Token(_) => return res,
+                SyntaxElement::Node(node) => {
+                    match node
+                        .children_with_tokens()
+                        .find(|child| range.is_subrange(&child.text_range()))
+                    {
+                        Some(child) => child,
+                        None => return res,
+                    }
+                }
+            };
+        }
+    }
+}
+
+impl<'a> SyntaxToken<'a> {
+    pub fn text_range(&self) -> TextRange {
+        TextRange::offset_len(self.offset, self.green().text_len())
+    }
+
+    pub fn kind(&self) -> SyntaxKind {
+        self.green().kind()
+    }
+
+    pub fn text(&self) -> &'a str {
+        self.green().text()
+    }
+
+    pub fn green(&self) -> &'a GreenToken {
+        match &self.parent.green().children()[self.index as usize] {
+            GreenElement::Token(it) => it,
+            GreenElement::Node(_) => unreachable!(),
+        }
+    }
+
+    pub fn parent(&self) -> SyntaxNode<'a> {
+        self.parent
+    }
+
+    pub fn next_sibling_or_token(&self) -> Option<SyntaxElement<'a>> {
+        let (element, index, offset) = self.parent.children_from(self.index as usize + 1).next()?;
+        Some(SyntaxElement::new(element, self.parent, index, offset))
+    }
+
+    pub fn prev_sibling_or_token(&self) -> Option<SyntaxElement<'a>> {
+        let (element, index, offset) = self.parent.children_to(self.index as usize).next()?;
+        Some(SyntaxElement::new(element, self.parent, index, offset))
+    }
+
+    /// Next token in the file (i.e, not necessary a sibling)
+    pub fn next_token(&self) -> Option<SyntaxToken<'a>> {
+        let next = match self.next_sibling_or_token() {
+            Some(it) => it,
+            None => next_element_after(self.parent, None)?,
+        };
+        first_token_from(next, None)
+    }
+
+    /// Previous token in the file (i.e, not necessary a sibling)
+    pub fn prev_token(&self) -> Option<SyntaxToken<'a>> {
+        let prev = match self.prev_sibling_or_token() {
+            Some(it) => it,
+            None => prev_element_before(self.parent, None)?,
+        };
+        last_token_from(prev, None)
+    }
+}
+
+/// The first token in the subtree of `element` or after it, skipping empty
+/// nodes. Stops at the end of `within`, if given.
+fn first_token_from<'a>(
+    element: SyntaxElement<'a>,
+    within: Option<SyntaxNode<'a>>,
+) -> Option<SyntaxToken<'a>> {
+    let mut element = element;
+    loop {
+        element = match element.descend(SyntaxNode::first_child_or_token) {
+            Ok(token) => return Some(token),
+            Err(empty) => next_element_after(empty, within)?,
+        }
+    }
+}
+
+fn last_token_from<'a>(
+    element: SyntaxElement<'a>,
+    within: Option<SyntaxNode<'a>>,
+) -> Option<SyntaxToken<'a>> {
+    let mut element = element;
+    loop {
+        element = match element.descend(SyntaxNode::last_child_or_token) {
+            Ok(token) => return Some(token),
+            Err(empty) => prev_element_before(empty, within)?,
+        }
+    }
+}
+
+/// The next sibling of `node` or of its closest ancestor which has one (but
+/// is still inside of `within`).
+fn next_element_after<'a>(
+    node: SyntaxNode<'a>,
+    within: Option<SyntaxNode<'a>>,
+) -> Option<SyntaxElement<'a>> {
+    let mut node = node;
+    loop {
+        if within == Some(node) {
+            return None;
+        }
+        if let Some(sibling) = node.next_sibling_or_token() {
+            return Some(sibling);
+        }
+        node = node.parent()?;
+    }
+}
+
+fn prev_element_before<'a>(
+    node: SyntaxNode<'a>,
+    within: Option<SyntaxNode<'a>>,
+) -> Option<SyntaxElement<'a>> {
+    let mut node = node;
+    loop {
+        if within == Some(node) {
+            return None;
+        }
+        if let Some(sibling) = node.prev_sibling_or_token() {
+            return Some(sibling);
+        }
+        node = node.parent()?;
+    }
+}
+
+impl<'a> SyntaxElement<'a> {
+    fn new(
+        element: &'a GreenElement,
+        parent: SyntaxNode<'a>,
+        index: u32,
+        offset: TextUnit,
+    ) -> SyntaxElement<'a> {
+        match element {
+            GreenElement::Node(node) => parent.new_child(node, index, offset).into(),
+            GreenElement::Token(_) => SyntaxToken { parent, index, offset }.into(),
+        }
+    }
+
+    pub fn text_range(&self) -> TextRange {
+        match self {
+            SyntaxElement::Node(it) => it.text_range(),
+            SyntaxElement::Token(it) => it.text_range(),
+        }
+    }
+
+    pub fn kind(&self) -> SyntaxKind {
+        match self {
+            SyntaxElement::Node(it) => it.kind(),
+            SyntaxElement::Token(it) => it.kind(),
+        }
+    }
+
+    pub fn parent(&self) -> Option<SyntaxNode<'a>> {
+        match self {
+            SyntaxElement::Node(it) => it.parent(),
+            SyntaxElement::Token(it) => Some(it.parent()),
+        }
+    }
+
+    pub fn first_token(&self) -> Option<SyntaxToken<'a>> {
+        match self {
+            SyntaxElement::Node(it) => it.first_token(),
+            SyntaxElement::Token(it) => Some(*it),
+        }
+    }
+
+    pub fn last_token(&self) -> Option<SyntaxToken<'a>> {
+        match self {
+            SyntaxElement::Node(it) => it.last_token(),
+            SyntaxElement::Token(it) => Some(*it),
+        }
+    }
+
+    pub fn next_sibling_or_token(&self) -> Option<SyntaxElement<'a>> {
+        match self {
+            SyntaxElement::Node(it) => it.next_sibling_or_token(),
+            SyntaxElement::Token(it) => it.next_sibling_or_token(),
+        }
+    }
+
+    pub fn prev_sibling_or_token(&self) -> Option<SyntaxElement<'a>> {
+        match self {
+            SyntaxElement::Node(it) => it.prev_sibling_or_token(),
+            SyntaxElement::Token(it) => it.prev_sibling_or_token(),
+        }
+    }
+
+    /// Descends to a token via `child`. If an empty node is found on the
+    /// way, returns it instead.
+    fn descend(
+        self,
+        child: impl Fn(&SyntaxNode<'a>) -> Option<SyntaxElement<'a>>,
+    ) -> Result<SyntaxToken<'a>, SyntaxNode<'a>> {
+        let mut element = self;
+        loop {
+            element = match element {
+                SyntaxElement::Node(it) => child(&it).ok_or(it)?,
+                SyntaxElement::Token(it) => return Ok(it),
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Iter<'a> {
+    parent: SyntaxNode<'a>,
+    green: slice::Iter<'a, GreenElement>,
+    offset: TextUnit,
+    index: u32,
+}
+
+impl<'a> Iter<'a> {
+    fn new(parent: SyntaxNode<'a>) -> Iter<'a> {
+        let offset = parent.text_range().start();
+        Iter { parent, green: parent.green().children().iter(), offset, index: 0 }
+    }
+
+    fn next(&mut self) -> Option<(&'a GreenElement, u32, TextUnit)> {
+        self.green.next().map(|element| {
+            let offset = self.offset;
+            let index = self.index;
+            self.offset += element.text_len();
+            self.index += 1;
+            (element, index, offset)
+        })
+    }
+
+    /// The number of children left.
+    fn len(&self) -> usize {
+        self.green.len()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SyntaxNodeChildren<'a>(Iter<'a>);
+
+impl<'a> Iterator for SyntaxNodeChildren<'a> {
+    type Item = SyntaxNode<'a>;
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((element, index, offset)) = self.0.next() {
+            if let GreenElement::Node(node) = element {
+                return Some(self.0.parent.new_child(node, index, offset));
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // Tokens are skipped.
+        (0, Some(self.0.len()))
+    }
+}
+
+impl<'a> iter::FusedIterator for SyntaxNodeChildren<'a> {}
+
+#[derive(Clone, Debug)]
+pub struct SyntaxElementChildren<'a>(Iter<'a>);
+
+impl<'a> Iterator for SyntaxElementChildren<'a> {
+    type Item = SyntaxElement<'a>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let parent = self.0.parent;
+        self.0.next().map(|(green, index, offset)| SyntaxElement::new(green, parent, index, offset))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.0.len(), Some(self.0.len()))
+    }
+}
+
+impl<'a> ExactSizeIterator for SyntaxElementChildren<'a> {}
+
+impl<'a> iter::FusedIterator for SyntaxElementChildren<'a> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cursor,
+        test_utils::{self, sample, LIST, ROOT, WORD, WS},
+        GreenNodeBuilder,
+    };
+
+    #[test]
+    fn navigation() {
+        let arena = Arena::new();
+        let root = SyntaxNode::new_root(sample(), &arena);
+        assert_eq!(root.kind(), ROOT);
+        assert_eq!(root.text_range(), TextRange::from_to(0.into(), 11.into()));
+        assert_eq!(root.children().count(), 1);
+        assert_eq!(root.children_with_tokens().count(), 3);
+
+        let list = root.first_child().unwrap();
+        assert_eq!(list.text_range(), TextRange::from_to(0.into(), 7.into()));
+        assert_eq!(list.parent(), Some(root));
+        assert!(list.next_sibling().is_none());
+        assert_eq!(list.next_sibling_or_token().unwrap().kind(), WS);
+        assert_eq!(root.last_child(), Some(list));
+
+        let inner = list.last_child().unwrap();
+        assert_eq!(inner.text_range(), TextRange::from_to(4.into(), 7.into()));
+        assert_eq!(inner.prev_sibling_or_token().unwrap().kind(), WS);
+        assert_eq!(inner.ancestors().count(), 3);
+
+        let baz = root.last_token().unwrap();
+        assert_eq!(baz.text(), "baz");
+        assert_eq!(baz.text_range(), TextRange::from_to(8.into(), 11.into()));
+        let bar = baz.prev_token().unwrap().prev_token().unwrap();
+        assert_eq!(bar.text(), "bar");
+        assert_eq!(bar.parent(), inner);
+        assert_eq!(bar.next_token().unwrap().kind(), WS);
+        assert_eq!(root.first_token().unwrap().text(), "foo");
+    }
+
+    #[test]
+    // The hash of a node doesn't depend on the arena, the mutable part.
+    #[allow(clippy::mutable_key_type)]
+    fn equality_of_shared_greens() {
+        use std::collections::HashSet;
+
+        let green = sample();
+        let arena = Arena::new();
+        let (a, b) =
+            (SyntaxNode::new_root(green.clone(), &arena), SyntaxNode::new_root(green, &arena));
+        assert_ne!(a, b);
+        let (a_list, b_list) = (a.first_child().unwrap(), b.first_child().unwrap());
+        assert_ne!(a_list, b_list);
+        assert_eq!(a_list, a.first_child().unwrap());
+        assert_ne!(a.first_token(), b.first_token());
+        assert_eq!(a.first_token(), a_list.first_token());
+
+        let set =
+            vec![a_list, a.last_token().unwrap().parent()].into_iter().collect::<HashSet<_>>();
+        assert_eq!(set.len(), 2);
+        assert!(set.contains(&a.first_child().unwrap()));
+        assert!(!set.contains(&b_list));
+        assert!(!set.contains(&b));
+        let elements =
+            a.preorder_with_tokens().filter_map(WalkEvent::enter).collect::<HashSet<_>>();
+        assert_eq!(elements.len(), 8);
+        assert!(b
+            .preorder_with_tokens()
+            .filter_map(WalkEvent::enter)
+            .all(|it| !elements.contains(&it)));
+
+        // ROOT[LIST[LIST[]], LIST[LIST[]]]: the outer lists share a green node,
+        // and the inner lists are at the same offset.
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(ROOT);
+        for _ in 0..2 {
+            builder.start_node(LIST);
+            builder.start_node(LIST);
+            builder.finish_node();
+            builder.finish_node();
+        }
+        builder.finish_node();
+        let root = SyntaxNode::new_root(builder.finish(), &arena);
+        let (first, second) = (root.first_child().unwrap(), root.last_child().unwrap());
+        assert!(first.green().ptr_eq(second.green()));
+        assert_ne!(first.first_child(), second.first_child());
+    }
+
+    #[test]
+    fn backward_siblings() {
+        let arena = Arena::new();
+        let green = test_utils::mixed_tree(&mut test_utils::Rng::new(381), 500);
+        let root = SyntaxNode::new_root(green, &arena);
+        for node in root.preorder().filter_map(WalkEvent::enter) {
+            let forward = node.children_with_tokens().collect::<Vec<_>>();
+            let mut backward =
+                iter::successors(node.last_child_or_token(), |it| it.prev_sibling_or_token())
+                    .collect::<Vec<_>>();
+            backward.reverse();
+            assert_eq!(backward, forward);
+            let ranges = |elements: &[SyntaxElement]| {
+                elements.iter().map(|it| it.text_range()).collect::<Vec<_>>()
+            };
+            assert_eq!(ranges(&backward), ranges(&forward));
+            let mut nodes =
+                iter::successors(node.last_child(), |it| it.prev_sibling()).collect::<Vec<_>>();
+            nodes.reverse();
+            assert_eq!(nodes, node.children().collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn preorder() {
+        let arena = Arena::new();
+        let root = SyntaxNode::new_root(sample(), &arena);
+        let events = root
+            .preorder()
+            .map(|event| match event {
+                WalkEvent::Enter(node) => format!("> {:?}", node.kind()),
+                WalkEvent::Leave(node) => format!("< {:?}", node.kind()),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                "> SyntaxKind(3)",
+                "> SyntaxKind(2)",
+                "> SyntaxKind(2)",
+                "< SyntaxKind(2)",
+                "< SyntaxKind(2)",
+                "< SyntaxKind(3)",
+            ]
+        );
+        let text: String = root
+            .preorder_with_tokens()
+            .filter_map(|event| match event {
+                WalkEvent::Enter(SyntaxElement::Token(token)) => Some(token.text()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(text, "foo bar baz");
+        assert_eq!(root.preorder_with_tokens().count(), 2 * 8);
+    }
+
+    #[test]
+    fn offset_queries() {
+        let arena = Arena::new();
+        let root = SyntaxNode::new_root(sample(), &arena);
+        match root.token_at_offset(3.into()) {
+            TokenAtOffset::Between(left, right) => {
+                assert_eq!(left.text(), "foo");
+                assert_eq!(right.text(), " ");
+            }
+            _ => panic!(),
+        }
+        match root.token_at_offset(1.into()) {
+            TokenAtOffset::Single(token) => assert_eq!(token.text(), "foo"),
+            _ => panic!(),
+        }
+        let covering = root.covering_node(TextRange::from_to(4.into(), 6.into()));
+        assert_eq!(covering.text_range(), TextRange::from_to(4.into(), 7.into()));
+        assert_eq!(covering.kind(), WORD);
+        let covering = root.covering_node(TextRange::from_to(2.into(), 6.into()));
+        assert_eq!(covering.kind(), LIST);
+        assert_eq!(covering.text_range(), TextRange::from_to(0.into(), 7.into()));
+    }
+
+    #[test]
+    fn offset_queries_in_deep_trees() {
+        let arena = Arena::new();
+        let root = SyntaxNode::new_root(test_utils::deep_tree(100_000), &arena);
+        match root.token_at_offset(1.into()) {
+            TokenAtOffset::Single(token) => assert_eq!(token.text(), "x"),
+            _ => panic!(),
+        }
+        let covering = root.covering_node(TextRange::from_to(0.into(), 1.into()));
+        assert_eq!(covering.kind(), WORD);
+    }
+
+    #[test]
+    fn same_as_cursor() {
+        fn describe(range: TextRange, kind: SyntaxKind, enter: bool) -> String {
+            format!("{} {:?}@{:?}", if enter { ">" } else { "<" }, kind, range)
+        }
+
+        let arena = Arena::new();
+        for seed in 0..200 {
+            let green = test_utils::gen_tree(seed, &test_utils::TreeConfig::default());
+            let root = SyntaxNode::new_root(green.clone(), &arena);
+            let cursor_root = cursor::SyntaxNode::new_root(green);
+
+            let expected = cursor_root
+                .preorder_with_tokens()
+                .map(|event| match event {
+                    WalkEvent::Enter(it) => describe(it.text_range(), it.kind(), true),
+                    WalkEvent::Leave(it) => describe(it.text_range(), it.kind(), false),
+                })
+                .collect::<Vec<_>>();
+            let actual = root
+                .preorder_with_tokens()
+                .map(|event| match event {
+                    WalkEvent::Enter(it) => describe(it.text_range(), it.kind(), true),
+                    WalkEvent::Leave(it) => describe(it.text_range(), it.kind(), false),
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(actual, expected);
+
+            let tokens = root
+                .preorder_with_tokens()
+                .filter_map(|event| match event {
+                    WalkEvent::Enter(SyntaxElement::Token(it)) => Some(it),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            let forward =
+                iter::successors(root.first_token(), |it| it.next_token()).collect::<Vec<_>>();
+            assert_eq!(forward, tokens);
+            let mut backward =
+                iter::successors(root.last_token(), |it| it.prev_token()).collect::<Vec<_>>();
+            backward.reverse();
+            assert_eq!(backward, tokens);
+
+            for node in root.preorder().filter_map(|event| match event {
+                WalkEvent::Enter(it) => Some(it),
+                WalkEvent::Leave(_) => None,
+            }) {
+                let children = node.children().collect::<Vec<_>>();
+                let forward = iter::successors(node.first_child(), |it| it.next_sibling())
+                    .collect::<Vec<_>>();
+                let mut backward =
+                    iter::successors(node.last_child(), |it| it.prev_sibling()).collect::<Vec<_>>();
+                backward.reverse();
+                assert_eq!(forward, children);
+                assert_eq!(backward, children);
+            }
+        }
+    }
+}
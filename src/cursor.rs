@@ -1,14 +1,29 @@
 use std::{
-    slice, ptr, iter, mem,
+    slice, ptr, iter, mem, fmt,
     rc::Rc,
     marker::PhantomData,
     cell::{Cell, RefCell},
     hash::{Hash, Hasher},
+    ops::Range,
+    convert::TryFrom,
+    sync::atomic::{AtomicU64, Ordering},
 };
 
+use rustc_hash::FxHasher;
+
 use crate::{
     GreenNode, GreenElement, TextUnit, TextRange, GreenToken, SyntaxKind, SmolStr, WalkEvent,
-    TokenAtOffset,
+    TokenAtOffset, MemoryStats, SyntaxKindSet, ValidationError, MAX_TEXT_LEN,
+    diagnostics::{ElementDisplay, KindDisplay},
+    green::child_index,
+};
+
+pub use crate::{
+    node_map::SyntaxNodeMap,
+    syntax_editor::{EditConflict, SyntaxEditor},
+    syntax_text::SyntaxText,
+    trivia::{TriviaConfig, WithTrivia},
+    visitor::{Rewrite, RewriteVisitor, VisitControl, Visitor},
 };
 
 #[derive(Debug, Clone)]
@@ -20,15 +35,45 @@ impl Drop for SyntaxNode {
     }
 }
 
+/// Nodes are equal if they are at the same position of the same tree, even
+/// if they are different red nodes. Tree here means a single `new_root`:
+/// two trees created from the same green node are different trees.
+///
+/// The green node and the offset tell apart almost all different nodes in
+/// O(1), but the green node might be shared by several positions (for
+/// example, by adjacent empty nodes which the builder deduplicated), so
+/// equal ones also compare their child indices up to a common ancestor.
 impl PartialEq for SyntaxNode {
     fn eq(&self, other: &SyntaxNode) -> bool {
-        ptr::eq(self.green(), other.green())
-            && self.text_range().start() == other.text_range().start()
+        if !ptr::eq(self.green(), other.green())
+            || self.text_range().start() != other.text_range().start()
+        {
+            return false;
+        }
+        // Both nodes have the same depth if they are equal.
+        let (mut a, mut b) = (self, other);
+        loop {
+            if Rc::ptr_eq(&a.0, &b.0) {
+                return true;
+            }
+            match (a.0.kind.as_child(), b.0.kind.as_child()) {
+                (Some((a_parent, a_index, _)), Some((b_parent, b_index, _)))
+                    if a_index == b_index =>
+                {
+                    a = a_parent;
+                    b = b_parent;
+                }
+                _ => return false,
+            }
+        }
     }
 }
 
 impl Eq for SyntaxNode {}
 
+// Consistent with `PartialEq`: equal nodes have the same green node and
+// offset. Nodes which differ only in their root hash the same, which keeps
+// hashing O(1).
 impl Hash for SyntaxNode {
     fn hash<H: Hasher>(&self, state: &mut H) {
         ptr::hash(self.green(), state);
@@ -36,6 +81,8 @@ impl Hash for SyntaxNode {
     }
 }
 
+/// Tokens are equal if they are the same child of equal parents, that is if
+/// they are at the same position of the same tree, see `same_token`.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct SyntaxToken {
     parent: SyntaxNode,
@@ -61,11 +108,363 @@ impl From<SyntaxToken> for SyntaxElement {
     }
 }
 
+/// Fails with the token, if the element is one.
+impl TryFrom<SyntaxElement> for SyntaxNode {
+    type Error = SyntaxToken;
+    fn try_from(element: SyntaxElement) -> Result<SyntaxNode, SyntaxToken> {
+        match element {
+            SyntaxElement::Node(it) => Ok(it),
+            SyntaxElement::Token(it) => Err(it),
+        }
+    }
+}
+
+/// Fails with the node, if the element is one.
+impl TryFrom<SyntaxElement> for SyntaxToken {
+    type Error = SyntaxNode;
+    fn try_from(element: SyntaxElement) -> Result<SyntaxToken, SyntaxNode> {
+        match element {
+            SyntaxElement::Node(it) => Err(it),
+            SyntaxElement::Token(it) => Ok(it),
+        }
+    }
+}
+
+/// What `SyntaxNode::edit_trivia` should do with a token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TriviaEdit {
+    /// Leave the token as is.
+    Keep,
+    /// Replace the text of the token.
+    Replace(SmolStr),
+    /// Remove the token from the tree.
+    Delete,
+}
+
+/// The error returned by `try_replace_with` when the replacement is of a
+/// different kind than the element being replaced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KindMismatch {
+    /// Kind of the element being replaced.
+    pub expected: SyntaxKind,
+    /// Kind of the replacement.
+    pub actual: SyntaxKind,
+}
+
+impl KindMismatch {
+    pub(crate) fn check(expected: SyntaxKind, actual: SyntaxKind) -> Result<(), KindMismatch> {
+        if expected == actual {
+            Ok(())
+        } else {
+            Err(KindMismatch { expected, actual })
+        }
+    }
+}
+
+impl fmt::Display for KindMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (expected, actual) = (KindDisplay(self.expected), KindDisplay(self.actual));
+        write!(f, "expected a replacement of kind {}, got {}", expected, actual)
+    }
+}
+
+impl std::error::Error for KindMismatch {}
+
+/// The error returned by `try_token_at_offset` for an offset outside of the
+/// node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OffsetOutOfRange {
+    /// Kind of the node.
+    pub kind: SyntaxKind,
+    /// Range of the node.
+    pub range: TextRange,
+    pub offset: TextUnit,
+}
+
+impl fmt::Display for OffsetOutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "offset {} is outside of {}", self.offset, ElementDisplay(self.kind, self.range))
+    }
+}
+
+impl std::error::Error for OffsetOutOfRange {}
+
+/// The error returned by `try_covering_node` for a range which is not within
+/// the node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RangeOutOfBounds {
+    /// Kind of the node.
+    pub kind: SyntaxKind,
+    /// Range of the node.
+    pub node_range: TextRange,
+    pub range: TextRange,
+}
+
+impl fmt::Display for RangeOutOfBounds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let node = ElementDisplay(self.kind, self.node_range);
+        write!(f, "range {:?} is not within {}", self.range, node)
+    }
+}
+
+impl std::error::Error for RangeOutOfBounds {}
+
+/// The error returned by `try_replace_descendants` for replacements which
+/// can't be applied together.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidReplacement {
+    /// The key is not a descendant of the node, nor the node itself.
+    NotADescendant(SyntaxElement),
+    /// The key is replaced more than once.
+    ReplacedTwice(SyntaxElement),
+    /// The key is inside of the subtree of another key.
+    InsideOfReplaced { element: SyntaxElement, replaced: SyntaxElement },
+    /// The node itself is a key, and is replaced by a node of another kind.
+    KindMismatch(KindMismatch),
+    /// The node itself is a key, and is replaced by a token of this kind.
+    NodeToToken(SyntaxKind),
+}
+
+impl fmt::Display for InvalidReplacement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let at = |element: &SyntaxElement| ElementDisplay(element.kind(), element.text_range());
+        match self {
+            InvalidReplacement::NotADescendant(element) => {
+                write!(f, "{} is not a descendant", at(element))
+            }
+            InvalidReplacement::ReplacedTwice(element) => {
+                write!(f, "overlapping replacements, {} is replaced more than once", at(element))
+            }
+            InvalidReplacement::InsideOfReplaced { element, replaced } => write!(
+                f,
+                "overlapping replacements, {} is inside of replaced {}",
+                at(element),
+                at(replaced)
+            ),
+            InvalidReplacement::KindMismatch(err) => err.fmt(f),
+            InvalidReplacement::NodeToToken(kind) => {
+                write!(f, "can't replace the node itself with a {} token", KindDisplay(*kind))
+            }
+        }
+    }
+}
+
+impl std::error::Error for InvalidReplacement {}
+
+/// A `Send + Sync` reference to a `SyntaxNode`, see `SyntaxNode::handle`.
+///
+/// Red nodes are tied to the thread they were created on, so to hand a node
+/// over to another thread, send its handle, and turn it back into a node
+/// there.
+#[derive(Debug, Clone)]
+pub struct SyntaxNodeHandle {
+    root: GreenNode,
+    /// Start of the root, see `SyntaxNode::new_root_at`.
+    base: TextUnit,
+    /// Child index and kind of each node on the way from the root.
+    path: Vec<(u32, SyntaxKind)>,
+    range: TextRange,
+}
+
+impl SyntaxNodeHandle {
+    /// Resolves the handle in its own tree, which is re-created from the
+    /// root green node.
+    pub fn into_node(self) -> SyntaxNode {
+        let root = SyntaxNode::new_root_at(self.root.clone(), self.base);
+        self.to_node(&root).unwrap()
+    }
+
+    /// Finds the node in the tree of `root`. Returns `None` if the tree does
+    /// not have a node of the same kind, at the same path and range.
+    pub fn to_node(&self, root: &SyntaxNode) -> Option<SyntaxNode> {
+        if root.kind() != self.root.kind() {
+            return None;
+        }
+        let mut node = root.clone();
+        for &(index, kind) in self.path.iter() {
+            let child = match node.green().children().get(index as usize) {
+                Some(GreenElement::Node(it)) if it.kind() == kind => it,
+                _ => return None,
+            };
+            let offset = node.text_range().start() + node.green().child_offset(index as usize);
+            node = SyntaxNode::new_child(child, node.clone(), index, offset);
+        }
+        if node.text_range() != self.range {
+            return None;
+        }
+        Some(node)
+    }
+}
+
+/// A locator of a node by its kind and range, see `SyntaxNodePtr::to_node`.
+///
+/// Unlike a `SyntaxNode`, a pointer doesn't keep the tree alive, and it can
+/// be resolved in a newer version of the tree, as long as the node is still
+/// there with the same kind and range. Pointers are ordered like the nodes
+/// in preorder: by the start of the range, longer ranges first, then by kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SyntaxNodePtr {
+    kind: SyntaxKind,
+    range: TextRange,
+}
+
+impl SyntaxNodePtr {
+    pub fn new(node: &SyntaxNode) -> SyntaxNodePtr {
+        SyntaxNodePtr { kind: node.kind(), range: node.text_range() }
+    }
+
+    pub fn kind(&self) -> SyntaxKind {
+        self.kind
+    }
+
+    pub fn range(&self) -> TextRange {
+        self.range
+    }
+
+    /// Finds the node of this kind and range in the tree of `root`. If
+    /// several nested nodes have both, as with a node whose only child is
+    /// of the same kind, the outermost one is returned.
+    ///
+    /// Returns `None` if there is no such node, in particular if the range
+    /// is not within `root`.
+    pub fn to_node(&self, root: &SyntaxNode) -> Option<SyntaxNode> {
+        if !self.range.is_subrange(&root.text_range()) {
+            return None;
+        }
+        // Walk down through the nodes which cover the range, in preorder.
+        // Only empty ranges can be covered by several siblings.
+        let mut stack = vec![root.clone()];
+        while let Some(node) = stack.pop() {
+            if node.text_range() == self.range && node.kind() == self.kind {
+                return Some(node);
+            }
+            let start = node.text_range().start();
+            let green = node.green();
+            let (index, _) = green.first_child_ending_after(self.range.start() - start, true);
+            let mut covering = Vec::new();
+            for child in green.children_with_offsets_from(index) {
+                let offset = start + child.rel_offset();
+                if offset > self.range.start() {
+                    break;
+                }
+                match child.element() {
+                    GreenElement::Node(it) if offset + it.text_len() >= self.range.end() => {
+                        let index = child_index(child.index());
+                        covering.push(SyntaxNode::new_child(it, node.clone(), index, offset));
+                    }
+                    _ => (),
+                }
+            }
+            stack.extend(covering.into_iter().rev());
+        }
+        None
+    }
+}
+
+impl PartialOrd for SyntaxNodePtr {
+    fn partial_cmp(&self, other: &SyntaxNodePtr) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SyntaxNodePtr {
+    fn cmp(&self, other: &SyntaxNodePtr) -> std::cmp::Ordering {
+        let key =
+            |it: &SyntaxNodePtr| (it.range.start(), std::cmp::Reverse(it.range.end()), it.kind.0);
+        key(self).cmp(&key(other))
+    }
+}
+
+/// Formats as `kind@start..end`, like `17@120..145`, which `FromStr` parses
+/// back.
+impl fmt::Display for SyntaxNodePtr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}..{}", self.kind.0, self.range.start(), self.range.end())
+    }
+}
+
+impl std::str::FromStr for SyntaxNodePtr {
+    type Err = ParseSyntaxNodePtrError;
+    fn from_str(text: &str) -> Result<SyntaxNodePtr, ParseSyntaxNodePtrError> {
+        let error = |message| ParseSyntaxNodePtrError { message };
+        let (kind, range) = text.split_once('@').ok_or(error("expected `kind@start..end`"))?;
+        let (start, end) = range.split_once("..").ok_or(error("expected `kind@start..end`"))?;
+        let number = |it: &str| {
+            // `u32::from_str` accepts a leading `+`.
+            if !it.is_empty() && it.bytes().all(|b| b.is_ascii_digit()) {
+                it.parse::<u32>().map_err(|_| error("number is too large"))
+            } else {
+                Err(error("expected a number"))
+            }
+        };
+        let kind = u16::try_from(number(kind)?).map_err(|_| error("number is too large"))?;
+        let (start, end) = (number(start)?, number(end)?);
+        if start > end {
+            return Err(error("the start of the range is after the end"));
+        }
+        Ok(SyntaxNodePtr {
+            kind: SyntaxKind(kind),
+            range: TextRange::from_to(start.into(), end.into()),
+        })
+    }
+}
+
+/// The error returned when parsing a `SyntaxNodePtr` fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseSyntaxNodePtrError {
+    message: &'static str,
+}
+
+impl fmt::Display for ParseSyntaxNodePtrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid syntax node pointer: {}", self.message)
+    }
+}
+
+impl std::error::Error for ParseSyntaxNodePtrError {}
+
+/// A pointer is a `(kind, start, end)` tuple.
+#[cfg(feature = "serde1")]
+impl serde::Serialize for SyntaxNodePtr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (start, end) = (self.range.start().to_usize(), self.range.end().to_usize());
+        (self.kind.0, start as u32, end as u32).serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde1")]
+impl<'de> serde::Deserialize<'de> for SyntaxNodePtr {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<SyntaxNodePtr, D::Error> {
+        let (kind, start, end) = <(u16, u32, u32)>::deserialize(deserializer)?;
+        if start > end {
+            return Err(serde::de::Error::custom("the start of the range is after the end"));
+        }
+        Ok(SyntaxNodePtr {
+            kind: SyntaxKind(kind),
+            range: TextRange::from_to(start.into(), end.into()),
+        })
+    }
+}
+
 #[derive(Debug)]
 enum Kind {
-    Root(GreenNode),
-    Child { parent: SyntaxNode, index: u32, offset: TextUnit },
-    Free { next_free: Option<Rc<NodeData>> },
+    /// The root owns the green tree, and starts at `base`. `id` is unique
+    /// to each tree, see `tree_id`.
+    Root {
+        green: GreenNode,
+        base: TextUnit,
+        id: u64,
+    },
+    Child {
+        parent: SyntaxNode,
+        index: u32,
+        offset: TextUnit,
+    },
+    Free {
+        next_free: Option<Rc<NodeData>>,
+    },
 }
 
 impl Kind {
@@ -86,27 +485,170 @@ struct NodeData {
 struct FreeList {
     first_free: Option<Rc<NodeData>>,
     len: usize,
+    capacity: usize,
+    #[cfg(feature = "freelist-stats")]
+    stats: FreeListStats,
+}
+
+const DEFAULT_FREE_LIST_CAPACITY: usize = 128;
+
+/// Sets the maximal number of dropped `SyntaxNode`s kept around by the
+/// current thread for reuse. The default is 128.
+///
+/// Each thread has its own free list, so this only affects the calling
+/// thread. A capacity of zero disables reuse, so that each `SyntaxNode` is
+/// a separate allocation. The list is filled lazily, as nodes are dropped;
+/// shrinking it frees the excess nodes immediately.
+///
+/// With the `no-free-list` feature there is no free list at all, and this
+/// does nothing.
+pub fn set_free_list_capacity(capacity: usize) {
+    FreeList::with(|it| {
+        it.capacity = capacity;
+        it.shrink_to(capacity);
+    })
+}
+
+/// Frees all the dropped `SyntaxNode`s kept around by the current thread for
+/// reuse, for instance in a long-lived thread which is done with syntax trees.
+/// The capacity stays the same, so the list fills up again as more nodes are
+/// dropped.
+///
+/// The list only holds nodes which are no longer referenced, so this doesn't
+/// affect the nodes still alive, and is always safe. Threads which never drop
+/// a node don't need this, as their list is empty.
+pub fn clear_free_list() {
+    shrink_free_list_to(0)
 }
 
-const FREE_LIST_LEN: usize = 128;
+/// Frees the dropped `SyntaxNode`s kept around by the current thread for
+/// reuse, except for `len` of them, see `clear_free_list`.
+pub fn shrink_free_list_to(len: usize) {
+    FreeList::with(|it| it.shrink_to(len))
+}
+
+/// The capacity of the free list of the current thread, see
+/// `set_free_list_capacity`.
+/// Always zero with the `no-free-list` feature.
+pub fn free_list_capacity() -> usize {
+    FreeList::with(|it| it.capacity)
+}
+
+/// Counters of the free list of the current thread, available with the
+/// `freelist-stats` feature. They stay zero with `no-free-list`.
+#[cfg(feature = "freelist-stats")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FreeListStats {
+    /// New nodes which reused a node from the list.
+    pub hits: u64,
+    /// New nodes which were allocated, because the list was empty.
+    pub misses: u64,
+    /// Dropped nodes which were put into the list.
+    pub pushes: u64,
+    /// Dropped nodes which were deallocated, because the list was full.
+    pub rejected_pushes: u64,
+    /// The current number of nodes in the list.
+    pub len: usize,
+}
+
+/// Returns the counters of the free list of the current thread.
+#[cfg(feature = "freelist-stats")]
+pub fn free_list_stats() -> FreeListStats {
+    FreeList::with(|it| FreeListStats { len: it.len, ..it.stats })
+}
+
+/// Sets all the counters of the free list of the current thread, except for
+/// the length, to zero.
+#[cfg(feature = "freelist-stats")]
+pub fn reset_free_list_stats() {
+    FreeList::with(|it| it.stats = FreeListStats::default())
+}
+
+#[cfg(feature = "node-count")]
+thread_local! {
+    static LIVE_NODES: Cell<usize> = const { Cell::new(0) };
+}
+
+/// The number of `SyntaxNode`s alive on the current thread, available with
+/// the `node-count` feature. Clones of a node count once, and so do the
+/// nodes kept alive as the ancestors of other nodes or tokens, while the
+/// dropped nodes kept in the free list for reuse don't count.
+#[cfg(feature = "node-count")]
+pub fn live_node_count() -> usize {
+    LIVE_NODES.with(|it| it.get())
+}
+
+/// A guard which panics when dropped if more `SyntaxNode`s are alive on the
+/// current thread than when it was created, available with the `node-count`
+/// feature. Useful in tests, to catch caches which keep nodes, and with
+/// them whole trees, alive:
+///
+/// ```ignore
+/// let _guard = AssertNoNodesLeaked::new();
+/// // Code which must not keep any nodes.
+/// ```
+#[cfg(feature = "node-count")]
+#[derive(Debug)]
+#[must_use = "the check happens when the guard is dropped"]
+pub struct AssertNoNodesLeaked {
+    count: usize,
+}
+
+#[cfg(feature = "node-count")]
+impl AssertNoNodesLeaked {
+    pub fn new() -> AssertNoNodesLeaked {
+        AssertNoNodesLeaked { count: live_node_count() }
+    }
+}
+
+#[cfg(feature = "node-count")]
+impl Default for AssertNoNodesLeaked {
+    fn default() -> AssertNoNodesLeaked {
+        AssertNoNodesLeaked::new()
+    }
+}
+
+#[cfg(feature = "node-count")]
+impl Drop for AssertNoNodesLeaked {
+    fn drop(&mut self) {
+        let count = live_node_count();
+        // Don't turn a panic, which might have skipped dropping the nodes,
+        // into an abort.
+        if count > self.count && !std::thread::panicking() {
+            panic!(
+                "{} SyntaxNodes leaked: {} alive, {} when the guard was created",
+                count - self.count,
+                count,
+                self.count
+            )
+        }
+    }
+}
 
 impl FreeList {
     fn new() -> FreeList {
-        let mut res = FreeList { first_free: None, len: 0 };
-        for _ in 0..FREE_LIST_LEN {
-            res.try_push(&mut Rc::new(NodeData {
-                kind: Kind::Free { next_free: None },
-                green: ptr::NonNull::dangling(),
-            }))
+        FreeList {
+            first_free: None,
+            len: 0,
+            capacity: DEFAULT_FREE_LIST_CAPACITY,
+            #[cfg(feature = "freelist-stats")]
+            stats: FreeListStats::default(),
         }
-        res
     }
 
+    #[cfg(not(feature = "no-free-list"))]
     fn with<T, F: FnOnce(&mut FreeList) -> T>(f: F) -> T {
         thread_local! {
             static INSTANCE: RefCell<FreeList> = RefCell::new(FreeList::new());
         }
-        INSTANCE.with(|it| f(&mut *it.borrow_mut()))
+        INSTANCE.with(|it| f(&mut it.borrow_mut()))
+    }
+
+    /// Without the thread-local, every call sees an empty list which can't
+    /// hold anything, so each new node is allocated.
+    #[cfg(feature = "no-free-list")]
+    fn with<T, F: FnOnce(&mut FreeList) -> T>(f: F) -> T {
+        f(&mut FreeList { capacity: 0, ..FreeList::new() })
     }
 
     fn pop(&mut self) -> Option<Rc<NodeData>> {
@@ -122,10 +664,38 @@ impl FreeList {
         Some(node)
     }
 
+    fn shrink_to(&mut self, len: usize) {
+        while self.len > len {
+            self.pop();
+        }
+    }
+
+    /// Like `pop`, but counts the hit or miss.
+    fn reuse(&mut self) -> Option<Rc<NodeData>> {
+        let res = self.pop();
+        #[cfg(feature = "freelist-stats")]
+        {
+            if res.is_some() {
+                self.stats.hits += 1;
+            } else {
+                self.stats.misses += 1;
+            }
+        }
+        res
+    }
+
     fn try_push(&mut self, node: &mut Rc<NodeData>) {
-        if self.len >= FREE_LIST_LEN {
+        if self.len >= self.capacity {
+            #[cfg(feature = "freelist-stats")]
+            {
+                self.stats.rejected_pushes += 1;
+            }
             return;
         }
+        #[cfg(feature = "freelist-stats")]
+        {
+            self.stats.pushes += 1;
+        }
         Rc::get_mut(node).unwrap().kind = Kind::Free { next_free: self.first_free.take() };
         self.first_free = Some(Rc::clone(node));
         self.len += 1;
@@ -134,7 +704,7 @@ impl FreeList {
 
 impl NodeData {
     fn new(kind: Kind, green: ptr::NonNull<GreenNode>) -> Rc<NodeData> {
-        let mut node = FreeList::with(|it| it.pop()).unwrap_or_else(|| {
+        let mut node = FreeList::with(|it| it.reuse()).unwrap_or_else(|| {
             Rc::new(NodeData {
                 kind: Kind::Free { next_free: None },
                 green: ptr::NonNull::dangling(),
@@ -146,13 +716,41 @@ impl NodeData {
             node.kind = kind;
             node.green = green;
         }
+        #[cfg(feature = "node-count")]
+        LIVE_NODES.with(|it| it.set(it.get() + 1));
         node
     }
+    /// Frees the node if this is the last reference to it, and then each of
+    /// its ancestors which this was the last reference to.
+    ///
+    /// A node holds a reference to its parent, so freeing it drops the
+    /// parent. The chain is unwound in a loop rather than by nested drops,
+    /// so that dropping the bottom of a deep tree doesn't overflow the stack.
     fn delete(this: &mut Rc<NodeData>) {
-        if let Some(this_mut) = Rc::get_mut(this) {
-            // NB: this might drop SyntaxNodes
-            this_mut.kind = Kind::Free { next_free: None };
-            FreeList::with(|it| it.try_push(this))
+        let mut parent = NodeData::free(this);
+        while let Some(mut node) = parent {
+            parent = NodeData::free(&mut node.0);
+            // `node` is now free, so dropping it doesn't recurse.
+        }
+    }
+
+    /// Frees the node if this is the last reference to it. Returns its
+    /// parent, which the caller becomes responsible for.
+    fn free(this: &mut Rc<NodeData>) -> Option<SyntaxNode> {
+        let this_mut = Rc::get_mut(this)?;
+        if let Kind::Free { .. } = this_mut.kind {
+            return None;
+        }
+        let kind = mem::replace(&mut this_mut.kind, Kind::Free { next_free: None });
+        #[cfg(feature = "node-count")]
+        LIVE_NODES.with(|it| it.set(it.get() - 1));
+        #[cfg(not(feature = "no-free-list"))]
+        FreeList::with(|it| it.try_push(this));
+        // The old kind is dropped only after the free list is released, as
+        // the root owns the green tree.
+        match kind {
+            Kind::Child { parent, .. } => Some(parent),
+            Kind::Root { .. } | Kind::Free { .. } => None,
         }
     }
 }
@@ -162,11 +760,39 @@ impl SyntaxNode {
         SyntaxNode(data)
     }
 
+    /// Creates a tree on top of `green`. The tree owns the green tree, but
+    /// doesn't copy it: keeping a clone of `green`, which is O(1), to cache
+    /// it is as cheap as getting it back with `green_root`.
     pub fn new_root(green: GreenNode) -> SyntaxNode {
-        let data = NodeData::new(Kind::Root(green), ptr::NonNull::dangling());
+        SyntaxNode::new_root_at(green, 0.into())
+    }
+
+    /// Creates a root which starts at `base` instead of 0, so that the
+    /// ranges of the tree are those of a larger text it is a part of, like a
+    /// function reparsed on its own.
+    ///
+    /// Panics if the tree ends past `MAX_TEXT_LEN`.
+    pub fn new_root_at(green: GreenNode, base: TextUnit) -> SyntaxNode {
+        #[cfg(all(debug_assertions, feature = "validate-trees"))]
+        {
+            if let Err(err) = green.validate() {
+                panic!("SyntaxNode::new_root: {}", err)
+            }
+        }
+        if base.to_usize() + green.text_len().to_usize() > MAX_TEXT_LEN {
+            panic!(
+                "SyntaxNode::new_root_at: a {} tree of length {} at {} ends past MAX_TEXT_LEN",
+                KindDisplay(green.kind()),
+                green.text_len(),
+                base
+            )
+        }
+        static NEXT_TREE_ID: AtomicU64 = AtomicU64::new(0);
+        let id = NEXT_TREE_ID.fetch_add(1, Ordering::Relaxed);
+        let data = NodeData::new(Kind::Root { green, base, id }, ptr::NonNull::dangling());
         let mut ret = SyntaxNode::new(data);
         let green: ptr::NonNull<GreenNode> = match &ret.0.kind {
-            Kind::Root(green) => green.into(),
+            Kind::Root { green, .. } => green.into(),
             _ => unreachable!(),
         };
         Rc::get_mut(&mut ret.0).unwrap().green = green;
@@ -188,184 +814,731 @@ impl SyntaxNode {
     /// Returns a green tree, equal to the green tree this node
     /// belongs two, except with this node substitute. The complexity
     /// of operation is proportional to the depth of the tree
+    ///
+    /// Panics if the kind of `replacement` differs from the kind of this
+    /// node, see `try_replace_with` for a non-panicking version.
     pub fn replace_with(&self, replacement: GreenNode) -> GreenNode {
-        assert_eq!(self.kind(), replacement.kind());
-        match self.0.kind.as_child() {
-            None => replacement,
-            Some((parent, me, _offset)) => {
-                let mut replacement = Some(replacement);
-                let children: Box<[_]> = parent
-                    .green()
-                    .children()
-                    .iter()
-                    .enumerate()
-                    .map(|(i, child)| {
-                        if i as u32 == me {
-                            replacement.take().unwrap().into()
-                        } else {
-                            child.clone()
-                        }
-                    })
-                    .collect();
-                assert!(replacement.is_none());
-                let new_parent = GreenNode::new(parent.kind(), children);
-                parent.replace_with(new_parent)
-            }
-        }
-    }
-
-    pub fn text_range(&self) -> TextRange {
-        let offset = match self.0.kind.as_child() {
-            Some((_, _, it)) => it,
-            _ => 0.into(),
-        };
-        TextRange::offset_len(offset, self.green().text_len())
+        self.try_replace_with(replacement).unwrap_or_else(|err| {
+            panic!("replace_with: {}, for {}", err, ElementDisplay(self.kind(), self.text_range()))
+        })
     }
 
-    pub fn kind(&self) -> SyntaxKind {
-        self.green().kind()
+    /// Like `replace_with`, but returns an error instead of panicking if the
+    /// kind of `replacement` differs from the kind of this node.
+    pub fn try_replace_with(&self, replacement: GreenNode) -> Result<GreenNode, KindMismatch> {
+        KindMismatch::check(self.kind(), replacement.kind())?;
+        Ok(self.replace_green(replacement))
     }
 
-    pub fn green(&self) -> &GreenNode {
-        unsafe { self.0.green.as_ref() }
+    /// Returns a green tree, equal to the green tree this node belongs to,
+    /// except with the kind of this node changed to `kind`. The children of
+    /// this node are shared with the original tree.
+    pub fn retag(&self, kind: SyntaxKind) -> GreenNode {
+        self.replace_green(self.green().with_kind(kind))
     }
 
-    pub fn parent(&self) -> Option<SyntaxNode> {
-        match &self.0.kind {
-            Kind::Root(_) => None,
-            Kind::Child { parent, .. } => Some(parent.clone()),
-            Kind::Free { .. } => unreachable!(),
+    pub(crate) fn replace_green(&self, replacement: GreenNode) -> GreenNode {
+        let mut node = self;
+        let mut replacement = replacement;
+        // Rebuild the ancestors bottom-up, so that deep trees don't blow
+        // the stack.
+        while let Some((parent, me, _offset)) = node.0.kind.as_child() {
+            replacement = parent.green().replace_child(me as usize, replacement.into());
+            node = parent;
         }
+        replacement
     }
 
-    pub fn children(&self) -> SyntaxNodeChildren {
-        SyntaxNodeChildren::new(self.clone())
+    /// Returns a green tree, equal to the green tree this node belongs to,
+    /// except with each of the `replacements` keys substituted by the
+    /// corresponding green element.
+    ///
+    /// All edits are applied in a single bottom-up pass, and subtrees without
+    /// edits are shared with the original tree. The keys must be distinct
+    /// descendants of this node (or this node itself), and no key may lie
+    /// inside the subtree of another key, as the result would depend on the
+    /// order of application. Replacing this node itself requires a node of
+    /// the same kind, like `replace_with`.
+    ///
+    /// Panics if a key is not a descendant of this node, if two keys overlap,
+    /// or if this node is replaced by a token or a node of another kind, see
+    /// `try_replace_descendants`.
+    pub fn replace_descendants(
+        &self,
+        replacements: impl IntoIterator<Item = (SyntaxElement, GreenElement)>,
+    ) -> GreenNode {
+        self.try_replace_descendants(replacements)
+            .unwrap_or_else(|err| panic!("replace_descendants: {}", err))
     }
 
-    pub fn children_with_tokens(&self) -> SyntaxElementChildren {
-        SyntaxElementChildren::new(self.clone())
+    /// Like `replace_descendants`, but returns an error instead of panicking
+    /// if the replacements can't be applied together. All of them are
+    /// checked before anything is rebuilt.
+    pub fn try_replace_descendants(
+        &self,
+        replacements: impl IntoIterator<Item = (SyntaxElement, GreenElement)>,
+    ) -> Result<GreenNode, InvalidReplacement> {
+        let mut edits = Vec::new();
+        for (key, replacement) in replacements {
+            let path = match self.try_path_to(&key) {
+                Some(it) => it,
+                None => return Err(InvalidReplacement::NotADescendant(key)),
+            };
+            edits.push((path, replacement, key));
+        }
+        if edits.is_empty() {
+            return Ok(self.green_root());
+        }
+        edits.sort_by(|(left, _, _), (right, _, _)| left.cmp(right));
+        for pair in edits.windows(2) {
+            let ((outer, _, replaced), (inner, _, element)) = (&pair[0], &pair[1]);
+            if outer == inner {
+                return Err(InvalidReplacement::ReplacedTwice(element.clone()));
+            }
+            if inner.starts_with(outer) {
+                let (element, replaced) = (element.clone(), replaced.clone());
+                return Err(InvalidReplacement::InsideOfReplaced { element, replaced });
+            }
+        }
+        if edits[0].0.is_empty() {
+            return match edits.pop().unwrap().1 {
+                GreenElement::Node(it) => {
+                    KindMismatch::check(self.kind(), it.kind())
+                        .map_err(InvalidReplacement::KindMismatch)?;
+                    Ok(self.replace_green(it))
+                }
+                GreenElement::Token(it) => Err(InvalidReplacement::NodeToToken(it.kind())),
+            };
+        }
+        let edits = edits
+            .into_iter()
+            .map(|(path, replacement, _)| (path, GreenEdit::Replace(Some(replacement))))
+            .collect::<Vec<_>>();
+        Ok(self.replace_green(rebuild(self.green(), &edits)))
     }
 
-    pub fn next_sibling(&self) -> Option<SyntaxNode> {
-        let (parent, index, _) = self.0.kind.as_child()?;
-
-        let (node, (index, offset)) = filter_nodes(
-            parent.green().children_from((index + 1) as usize, self.text_range().end()),
-        )
-        .next()?;
-
-        Some(SyntaxNode::new_child(node, parent.clone(), index as u32, offset))
+    /// Returns a green tree, equal to the green tree this node belongs to,
+    /// except with the trivia tokens of this subtree rewritten by `f`.
+    ///
+    /// `f` is called for each token, for whose kind `is_trivia` returns
+    /// `true`, in order. Returning `Some(text)` replaces the text of the
+    /// token (the kind stays the same), returning `None` keeps the token as
+    /// is. Untouched subtrees are shared with the original tree, and if
+    /// nothing changes, the current root green node is returned as is. To
+    /// delete tokens, use `edit_trivia`.
+    pub fn map_trivia(
+        &self,
+        is_trivia: impl Fn(SyntaxKind) -> bool,
+        mut f: impl FnMut(&SyntaxToken) -> Option<SmolStr>,
+    ) -> GreenNode {
+        self.edit_trivia(is_trivia, |token| match f(token) {
+            Some(text) => TriviaEdit::Replace(text),
+            None => TriviaEdit::Keep,
+        })
     }
 
-    pub fn next_sibling_or_token(&self) -> Option<SyntaxElement> {
-        let (parent, index, _) = self.0.kind.as_child()?;
-
-        let (element, (index, offset)) =
-            parent.green().children_from((index + 1) as usize, self.text_range().end()).next()?;
-
-        Some(SyntaxElement::new(element, parent.clone(), index as u32, offset))
+    /// Like `map_trivia`, but `f` can also delete trivia tokens.
+    pub fn edit_trivia(
+        &self,
+        is_trivia: impl Fn(SyntaxKind) -> bool,
+        mut f: impl FnMut(&SyntaxToken) -> TriviaEdit,
+    ) -> GreenNode {
+        let mut edits = Vec::new();
+        for event in self.preorder_with_tokens() {
+            let token = match event {
+                WalkEvent::Enter(SyntaxElement::Token(it)) if is_trivia(it.kind()) => it,
+                _ => continue,
+            };
+            let edit = match f(&token) {
+                TriviaEdit::Keep => continue,
+                TriviaEdit::Replace(text) if text == token.text() => continue,
+                TriviaEdit::Replace(text) => {
+                    GreenEdit::Replace(Some(GreenToken::new(token.kind(), text).into()))
+                }
+                TriviaEdit::Delete => GreenEdit::Replace(None),
+            };
+            let path = self
+                .try_path_to(&token.into())
+                .expect("edit_trivia: the preorder only visits the descendants of the node");
+            edits.push((path, edit));
+        }
+        if edits.is_empty() {
+            return self.green_root();
+        }
+        // Preorder visits tokens sorted by their paths.
+        self.replace_with(rebuild(self.green(), &edits))
     }
 
-    pub fn prev_sibling(&self) -> Option<SyntaxNode> {
-        let (parent, index, _) = self.0.kind.as_child()?;
-
-        let (node, (index, offset)) =
-            filter_nodes(parent.green().children_to(index as usize, self.text_range().start()))
-                .next()?;
-
-        Some(SyntaxNode::new_child(node, parent.clone(), index as u32, offset))
+    /// Child indices leading from this node to `element`, or `None` if it is
+    /// not a descendant of this node, nor the node itself.
+    pub(crate) fn try_path_to(&self, element: &SyntaxElement) -> Option<Vec<u32>> {
+        let mut res = Vec::new();
+        let mut node = match element {
+            SyntaxElement::Node(it) => it.clone(),
+            SyntaxElement::Token(it) => {
+                res.push(it.index);
+                it.parent()
+            }
+        };
+        while node != *self {
+            match node.0.kind.as_child() {
+                Some((parent, index, _offset)) => {
+                    res.push(index);
+                    node = parent.clone();
+                }
+                None => return None,
+            }
+        }
+        res.reverse();
+        Some(res)
     }
 
-    pub fn prev_sibling_or_token(&self) -> Option<SyntaxElement> {
-        let (parent, index, _) = self.0.kind.as_child()?;
-
-        let (element, (index, offset)) =
-            parent.green().children_to(index as usize, self.text_range().start()).next()?;
+    /// The child index of each node on the way from the root to this node,
+    /// which identifies it in its tree, or in a later version of the tree in
+    /// which it didn't move, see `resolve_index_path`. Empty for the root.
+    ///
+    /// Unlike a `SyntaxNodePtr`, a path can be resolved without searching,
+    /// but it doesn't record the kind or the range of the node.
+    pub fn index_path(&self) -> Vec<u32> {
+        let mut res = Vec::new();
+        let mut node = self;
+        while let Some((parent, index, _offset)) = node.0.kind.as_child() {
+            res.push(index);
+            node = parent;
+        }
+        res.reverse();
+        res
+    }
 
-        Some(SyntaxElement::new(element, parent.clone(), index as u32, offset))
+    /// Compares the positions of two nodes of the same tree in the document,
+    /// see `SyntaxElement::position_cmp`. `None` if they are from different
+    /// trees.
+    pub fn position_cmp(&self, other: &SyntaxNode) -> Option<std::cmp::Ordering> {
+        SyntaxElement::Node(self.clone()).position_cmp(&SyntaxElement::Node(other.clone()))
     }
 
-    /// Get first child, excluding tokens.
-    #[inline]
-    pub fn first_child(&self) -> Option<SyntaxNode> {
-        let (node, (index, offset)) =
-            filter_nodes(self.green().children_from(0, self.text_range().start())).next()?;
+    /// Finds the element at `path` in the tree of `root`, taking the child
+    /// at each index in turn, tokens included: the last index of the path
+    /// can lead to a token. `None` if an index is out of range, or if the
+    /// path goes on below a token.
+    pub fn resolve_index_path(root: &SyntaxNode, path: &[u32]) -> Option<SyntaxElement> {
+        let mut res = SyntaxElement::Node(root.clone());
+        for &index in path {
+            res = res.as_node()?.child_or_token_at(index as usize)?;
+        }
+        Some(res)
+    }
 
-        Some(SyntaxNode::new_child(node, self.clone(), index as u32, offset))
+    /// The child at `index`, counting tokens.
+    pub(crate) fn child_or_token_at(&self, index: usize) -> Option<SyntaxElement> {
+        let element = self.green().children().get(index)?;
+        let offset = self.text_range().start() + self.green().child_offset(index);
+        Some(SyntaxElement::new(element, self.clone(), child_index(index), offset))
     }
 
-    /// Get the first, including tokens.
+    /// Returns a green tree, equal to the green tree this node belongs to,
+    /// except with `new` elements inserted among the children of this node
+    /// at `at_index`. Like `replace_with`, this is proportional to the depth
+    /// of the tree.
+    ///
+    /// Inserting nothing returns the current root green node as is.
+    ///
+    /// Panics if `at_index` is greater than the number of children.
+    pub fn insert_children(
+        &self,
+        at_index: usize,
+        new: impl IntoIterator<Item = GreenElement>,
+    ) -> GreenNode {
+        let n_children = self.green().children().len();
+        assert!(
+            at_index <= n_children,
+            "insert_children: index {} is out of bounds, the node has {} children",
+            at_index,
+            n_children
+        );
+        let mut new = new.into_iter().peekable();
+        if new.peek().is_none() {
+            return self.green_root();
+        }
+        self.replace_with(self.green().splice_children(at_index..at_index, new))
+    }
+
+    /// Returns a green tree, equal to the green tree this node belongs to,
+    /// except with the children of this node in `range` removed.
+    ///
+    /// Removing an empty range returns the current root green node as is.
+    ///
+    /// Panics if `range` is out of bounds.
+    pub fn remove_children(&self, range: Range<usize>) -> GreenNode {
+        let n_children = self.green().children().len();
+        assert!(
+            range.start <= range.end && range.end <= n_children,
+            "remove_children: range {:?} is out of bounds, {} node has {} children",
+            range,
+            KindDisplay(self.kind()),
+            n_children
+        );
+        if range.is_empty() {
+            return self.green_root();
+        }
+        self.replace_with(self.green().splice_children(range, None))
+    }
+
+    /// Returns a green tree, equal to the green tree this node belongs to,
+    /// except with the children of this node in `range` replaced by
+    /// `replace_with`. Unlike a pair of `remove_children` and
+    /// `insert_children` calls, this goes up to the root only once.
+    ///
+    /// An empty `replace_with` just removes `range`, an empty `range` just
+    /// inserts `replace_with` at `range.start`. If both are empty, the
+    /// current root green node is returned as is.
+    ///
+    /// Panics if `range` is out of bounds.
+    pub fn splice_children(
+        &self,
+        range: Range<usize>,
+        replace_with: Vec<GreenElement>,
+    ) -> GreenNode {
+        let n_children = self.green().children().len();
+        assert!(
+            range.start <= range.end && range.end <= n_children,
+            "splice_children: range {:?} is out of bounds, {} node has {} children",
+            range,
+            KindDisplay(self.kind()),
+            n_children
+        );
+        if range.is_empty() && replace_with.is_empty() {
+            return self.green_root();
+        }
+        self.replace_with(self.green().splice_children(range, replace_with))
+    }
+
+    /// Returns a green tree, equal to the green tree this node belongs to,
+    /// except with this node removed from its parent.
+    ///
+    /// Detaching the root yields an empty node of the root's kind, as if all
+    /// of the root's children were removed.
+    pub fn detach(&self) -> GreenNode {
+        self.detach_with_adjacent_trivia(|_| false)
+    }
+
+    /// Like `detach`, but if this node is immediately followed by a token for
+    /// whose kind `is_trivia` returns `true`, that token is removed as well.
+    /// This way, removing an item doesn't leave its trailing whitespace
+    /// behind.
+    pub fn detach_with_adjacent_trivia(&self, is_trivia: impl Fn(SyntaxKind) -> bool) -> GreenNode {
+        match self.0.kind.as_child() {
+            None => GreenNode::new(self.kind(), Vec::new().into_boxed_slice()),
+            Some((parent, index, _offset)) => {
+                let index = index as usize;
+                let end = match self.next_sibling_or_token() {
+                    Some(SyntaxElement::Token(token)) if is_trivia(token.kind()) => index + 2,
+                    _ => index + 1,
+                };
+                parent.remove_children(index..end)
+            }
+        }
+    }
+
+    /// A handle to this node, which can be sent to another thread, and
+    /// turned back into a node there.
+    pub fn handle(&self) -> SyntaxNodeHandle {
+        let mut path = Vec::new();
+        let mut node = self.clone();
+        while let Some((parent, index, _offset)) = node.0.kind.as_child() {
+            path.push((index, node.kind()));
+            let parent = parent.clone();
+            node = parent;
+        }
+        path.reverse();
+        SyntaxNodeHandle {
+            root: node.green().clone(),
+            base: node.text_range().start(),
+            path,
+            range: self.text_range(),
+        }
+    }
+
+    /// Creates a new tree out of the subtree of this node, which doesn't
+    /// keep the rest of the old tree alive. The new root starts at 0: to
+    /// keep the offsets of the old tree, use
+    /// `SyntaxNode::new_root_at(node.green().clone(), node.text_range().start())`.
+    ///
+    /// The new nodes are not equal to the old ones, as they are in another
+    /// tree, even though they share the green nodes.
+    pub fn clone_subtree(&self) -> SyntaxNode {
+        SyntaxNode::new_root(self.green().clone())
+    }
+
+    /// A shared handle to the green tree of the root of this node's tree,
+    /// for caching it. No red nodes are created, and the green tree isn't
+    /// copied, see `GreenNode`.
+    pub fn green_root(&self) -> GreenNode {
+        let mut node = self;
+        while let Some((parent, _, _)) = node.0.kind.as_child() {
+            node = parent;
+        }
+        node.green().clone()
+    }
+
+    /// A number identifying the tree of this node, unlike the address of
+    /// the root, which is reused once the tree is dropped. Each `new_root`
+    /// gets a new one.
+    pub(crate) fn tree_id(&self) -> u64 {
+        let mut node = self;
+        while let Some((parent, _, _)) = node.0.kind.as_child() {
+            node = parent;
+        }
+        match &node.0.kind {
+            Kind::Root { id, .. } => *id,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn text_range(&self) -> TextRange {
+        let offset = match &self.0.kind {
+            Kind::Child { offset, .. } => *offset,
+            Kind::Root { base, .. } => *base,
+            Kind::Free { .. } => unreachable!(),
+        };
+        TextRange::offset_len(offset, self.green().text_len())
+    }
+
+    pub fn kind(&self) -> SyntaxKind {
+        self.green().kind()
+    }
+
+    /// Number of nodes in this subtree, including this node. O(1).
+    pub fn subtree_node_count(&self) -> u32 {
+        self.green().descendant_node_count().saturating_add(1)
+    }
+
+    /// Number of tokens in this subtree. O(1).
+    pub fn subtree_token_count(&self) -> u32 {
+        self.green().descendant_token_count()
+    }
+
+    pub fn green(&self) -> &GreenNode {
+        unsafe { self.0.green.as_ref() }
+    }
+
+    /// An owned handle to the green node, which outlives this node: an
+    /// atomic increment of the refcount, the node is not copied.
+    pub fn to_green(&self) -> GreenNode {
+        self.green().clone()
+    }
+
+    /// Whether both nodes have the same green node, not only an equal one.
+    /// This is O(1), and is the cheapest way to tell that a subtree didn't
+    /// change, for instance when a reparse reused it. Unlike `==`, it
+    /// doesn't compare the positions of the nodes.
+    pub fn same_green(&self, other: &SyntaxNode) -> bool {
+        self.green().ptr_eq(other.green())
+    }
+
+    /// An id of the green node of this node, equal for the nodes for which
+    /// `same_green` is true, for instance to be used as a cache key. It's
+    /// only valid while the green node is alive: once it is dropped, another
+    /// green node may get the same id.
+    pub fn green_ptr_id(&self) -> usize {
+        self.green().as_ptr() as usize
+    }
+
+    /// Checks that the offset of this node, and of each of its ancestors, is
+    /// the sum of the lengths of the preceding siblings, and that the green
+    /// subtree of this node is valid, see `GreenNode::validate`. The paths of
+    /// the errors start at the root of the tree.
+    pub fn validate_invariants(&self) -> Result<(), ValidationError> {
+        let mut chain = Vec::new();
+        let mut node = self;
+        while let Some((parent, index, offset)) = node.0.kind.as_child() {
+            chain.push((node, parent, index, offset));
+            node = parent;
+        }
+        let mut path = Vec::new();
+        for (node, parent, index, offset) in chain.into_iter().rev() {
+            path.push((index, node.kind()));
+            let preceding = &parent.green().children()[..index as usize];
+            let expected = parent.text_range().start()
+                + preceding.iter().map(|it| it.text_len()).sum::<TextUnit>();
+            if offset != expected {
+                return Err(ValidationError::new(path, "wrong node offset", expected, offset));
+            }
+        }
+        self.green().validate().map_err(|err| err.prepend_path(&path))
+    }
+
+    /// A hash of the kinds and token texts of this subtree, in preorder,
+    /// without the elements of the kinds for which `skip` is true, and the
+    /// subtrees of such nodes. Trees which differ only by skipped elements,
+    /// like whitespace and comments, hash the same.
+    ///
+    /// This is FxHash of `hasher_seed` followed by the elements, so, unlike
+    /// with a `DefaultHasher`, the hash doesn't change between runs and can
+    /// be stored. It depends on the pointer width, and may change between
+    /// versions of rowan.
+    pub fn content_hash_filtered(
+        &self,
+        hasher_seed: u64,
+        skip: impl Fn(SyntaxKind) -> bool,
+    ) -> u64 {
+        // Markers separate the levels, so that the shape of the tree is
+        // part of the hash.
+        const NODE: u8 = 0;
+        const TOKEN: u8 = 1;
+        const LEAVE: u8 = 2;
+        let mut h = FxHasher::default();
+        h.write_u64(hasher_seed);
+        if skip(self.kind()) {
+            return h.finish();
+        }
+        h.write_u8(NODE);
+        h.write_u16(self.kind().0);
+        let mut stack = vec![self.green().children().iter()];
+        while let Some(children) = stack.last_mut() {
+            match children.next() {
+                None => {
+                    stack.pop();
+                    h.write_u8(LEAVE);
+                }
+                Some(child) if skip(child.kind()) => (),
+                Some(GreenElement::Node(it)) => {
+                    h.write_u8(NODE);
+                    h.write_u16(it.kind().0);
+                    stack.push(it.children().iter());
+                }
+                Some(GreenElement::Token(it)) => {
+                    h.write_u8(TOKEN);
+                    h.write_u16(it.kind().0);
+                    h.write_usize(it.text().len());
+                    h.write(it.text().as_bytes());
+                }
+            }
+        }
+        h.finish()
+    }
+
+    /// Memory used by the green tree of this node, see
+    /// `GreenNode::memory_usage`.
+    pub fn memory_usage(&self) -> MemoryStats {
+        self.green().memory_usage()
+    }
+
+    pub fn parent(&self) -> Option<SyntaxNode> {
+        match &self.0.kind {
+            Kind::Root { .. } => None,
+            Kind::Child { parent, .. } => Some(parent.clone()),
+            Kind::Free { .. } => unreachable!(),
+        }
+    }
+
+    fn parent_ref(&self) -> Option<&SyntaxNode> {
+        self.0.kind.as_child().map(|(parent, _, _)| parent)
+    }
+
+    /// The `n`th node up from this one, counting like `ancestors().nth(n)`:
+    /// 0 is this node, 1 its parent. Only the result is cloned.
+    pub fn nth_ancestor(&self, n: usize) -> Option<SyntaxNode> {
+        let mut node = self;
+        for _ in 0..n {
+            node = node.parent_ref()?;
+        }
+        Some(node.clone())
+    }
+
+    /// The number of ancestors of this node: 0 for the root.
+    pub fn depth(&self) -> usize {
+        iter::successors(self.parent_ref(), |it| it.parent_ref()).count()
+    }
+
+    /// The ancestor of this node (or the node itself) at `depth`, like
+    /// `nth_ancestor(self.depth() - depth)`, but walking up only once: 0 is
+    /// the root. `None` if this node is less deep than `depth`.
+    pub fn ancestor_at_depth(&self, depth: usize) -> Option<SyntaxNode> {
+        // `lead` is `depth` levels above `node`, so when it gets to the root,
+        // `node` is at `depth`.
+        let mut lead = self;
+        for _ in 0..depth {
+            lead = lead.parent_ref()?;
+        }
+        let mut node = self;
+        while let Some(parent) = lead.parent_ref() {
+            lead = parent;
+            node = node.parent_ref().unwrap();
+        }
+        Some(node.clone())
+    }
+
+    pub fn children(&self) -> SyntaxNodeChildren {
+        SyntaxNodeChildren::new(self.clone())
+    }
+
+    pub fn children_with_tokens(&self) -> SyntaxElementChildren {
+        SyntaxElementChildren::new(self.clone())
+    }
+
+    /// The children of this node, excluding tokens, with a kind from `set`.
+    pub fn children_matching(&self, set: SyntaxKindSet) -> impl Iterator<Item = SyntaxNode> {
+        self.children().filter(move |it| set.contains(it.kind()))
+    }
+
+    /// The closest of the ancestors of this node, starting with the node
+    /// itself, with a kind from `set`.
+    pub fn ancestor_matching(&self, set: SyntaxKindSet) -> Option<SyntaxNode> {
+        self.ancestors().find(|it| set.contains(it.kind()))
+    }
+
+    /// Whether `other` is in the subtree of this node, other than the node
+    /// itself. Nodes of different trees are never related.
+    pub fn is_ancestor_of(&self, other: &SyntaxNode) -> bool {
+        other.parent().is_some_and(|it| self.contains_inclusive(&it))
+    }
+
+    /// Whether `other` is this node or in its subtree. Nodes of different
+    /// trees are never related, even if the trees share the green nodes.
+    pub fn contains_inclusive(&self, other: &SyntaxNode) -> bool {
+        let range = self.text_range();
+        // The ranges of the ancestors only grow, so the walk stops at the
+        // first one out of this node.
+        other.ancestors().take_while(|it| it.text_range().is_subrange(&range)).any(|it| it == *self)
+    }
+
+    /// Whether `element` is this node or in its subtree.
+    pub fn contains_element(&self, element: &SyntaxElement) -> bool {
+        match element {
+            SyntaxElement::Node(it) => self.contains_inclusive(it),
+            SyntaxElement::Token(it) => self.contains_inclusive(&it.parent()),
+        }
+    }
+
+    // Siblings are found by walking the green children of the parent, with
+    // the offsets derived from the parent, like those of the children.
+
+    pub fn next_sibling(&self) -> Option<SyntaxNode> {
+        let (parent, index, _) = self.0.kind.as_child()?;
+        let (node, (index, offset)) =
+            filter_nodes(parent.children_from(index as usize + 1)).next()?;
+        Some(SyntaxNode::new_child(node, parent.clone(), child_index(index), offset))
+    }
+
+    pub fn next_sibling_or_token(&self) -> Option<SyntaxElement> {
+        let (parent, index, _) = self.0.kind.as_child()?;
+        let (element, (index, offset)) = parent.children_from(index as usize + 1).next()?;
+        Some(SyntaxElement::new(element, parent.clone(), child_index(index), offset))
+    }
+
+    pub fn prev_sibling(&self) -> Option<SyntaxNode> {
+        let (parent, index, _) = self.0.kind.as_child()?;
+        let (node, (index, offset)) = filter_nodes(parent.children_to(index as usize)).next()?;
+        Some(SyntaxNode::new_child(node, parent.clone(), child_index(index), offset))
+    }
+
+    pub fn prev_sibling_or_token(&self) -> Option<SyntaxElement> {
+        let (parent, index, _) = self.0.kind.as_child()?;
+        let (element, (index, offset)) = parent.children_to(index as usize).next()?;
+        Some(SyntaxElement::new(element, parent.clone(), child_index(index), offset))
+    }
+
+    /// Get first child, excluding tokens.
+    #[inline]
+    pub fn first_child(&self) -> Option<SyntaxNode> {
+        let (node, (index, offset)) = filter_nodes(self.children_from(0)).next()?;
+
+        Some(SyntaxNode::new_child(node, self.clone(), child_index(index), offset))
+    }
+
+    /// Get the first, including tokens.
     pub fn first_child_or_token(&self) -> Option<SyntaxElement> {
-        let (element, (index, offset)) =
-            self.green().children_from(0, self.text_range().start()).next()?;
-        Some(SyntaxElement::new(element, self.clone(), index as u32, offset))
+        let (element, (index, offset)) = self.children_from(0).next()?;
+        Some(SyntaxElement::new(element, self.clone(), child_index(index), offset))
     }
 
     /// Get last child, excluding tokens.
     #[inline]
     pub fn last_child(&self) -> Option<SyntaxNode> {
-        let (node, (index, offset)) = filter_nodes(
-            self.green().children_to(self.green().children().len(), self.text_range().end()),
-        )
-        .next()?;
+        let (node, (index, offset)) =
+            filter_nodes(self.children_to(self.green().children().len())).next()?;
 
-        Some(SyntaxNode::new_child(node, self.clone(), index as u32, offset))
+        Some(SyntaxNode::new_child(node, self.clone(), child_index(index), offset))
     }
 
     /// Get last child, including tokens.
     pub fn last_child_or_token(&self) -> Option<SyntaxElement> {
-        let (element, (index, offset)) = self
-            .green()
-            .children_to(self.green().children().len(), self.text_range().end())
-            .next()?;
-        Some(SyntaxElement::new(element, self.clone(), index as u32, offset))
+        let (element, (index, offset)) = self.children_to(self.green().children().len()).next()?;
+        Some(SyntaxElement::new(element, self.clone(), child_index(index), offset))
     }
 
     /// Return the leftmost token in the subtree of this node
     #[inline]
     pub fn first_token(&self) -> Option<SyntaxToken> {
-        self.first_child_or_token()?.first_token()
+        first_token_from(self.first_child_or_token()?, Some(self))
     }
 
     /// Return the rightmost token in the subtree of this node
     #[inline]
     pub fn last_token(&self) -> Option<SyntaxToken> {
-        self.last_child_or_token()?.last_token()
+        last_token_from(self.last_child_or_token()?, Some(self))
     }
 
     /// Traverse the subtree rooted at the current node (including the current
     /// node) in preorder, excluding tokens.
     #[inline]
-    pub fn preorder(&self) -> impl Iterator<Item = WalkEvent<SyntaxNode>> {
-        let this = self.clone();
-        iter::successors(Some(WalkEvent::Enter(self.clone())), move |pos| {
-            let next = match pos {
-                WalkEvent::Enter(node) => match node.first_child() {
-                    Some(child) => WalkEvent::Enter(child),
-                    None => WalkEvent::Leave(node.clone()),
-                },
-                WalkEvent::Leave(node) => {
-                    if node == &this {
-                        return None;
-                    }
-                    match node.next_sibling() {
-                        Some(sibling) => WalkEvent::Enter(sibling),
-                        None => WalkEvent::Leave(node.parent().unwrap()),
-                    }
-                }
-            };
-            Some(next)
-        })
+    pub fn preorder(&self) -> Preorder {
+        Preorder::new(self.clone())
     }
 
     /// Traverse the subtree rooted at the current node (including the current
     /// node) in preorder, including tokens.
     #[inline]
-    pub fn preorder_with_tokens<'a>(&'a self) -> impl Iterator<Item = WalkEvent<SyntaxElement>> {
+    pub fn preorder_with_tokens(&self) -> impl Iterator<Item = WalkEvent<SyntaxElement>> {
+        self.preorder_with_tokens_filtered(|_| true)
+    }
+
+    /// This node and its descendants in preorder, without the descendants of
+    /// the nodes for which `enter` returns `false`. These nodes are yielded
+    /// nonetheless, and `enter` is called once for each yielded node,
+    /// including this one, before anything below it.
+    ///
+    /// Nodes which can't contain what a search looks for can be skipped this
+    /// way: `descendants_filtered(|it| it.kind() != STRING)` doesn't go into
+    /// strings.
+    pub fn descendants_filtered(
+        &self,
+        mut enter: impl FnMut(&SyntaxNode) -> bool,
+    ) -> impl Iterator<Item = SyntaxNode> {
+        let mut preorder = self.preorder();
+        iter::from_fn(move || loop {
+            if let WalkEvent::Enter(node) = preorder.next()? {
+                if !enter(&node) {
+                    preorder.skip_subtree();
+                }
+                return Some(node);
+            }
+        })
+    }
+
+    /// Like `descendants_filtered`, including the tokens, except those below
+    /// the nodes for which `enter` returns `false`.
+    pub fn descendants_with_tokens_filtered(
+        &self,
+        enter: impl FnMut(&SyntaxNode) -> bool,
+    ) -> impl Iterator<Item = SyntaxElement> {
+        self.preorder_with_tokens_filtered(enter).filter_map(WalkEvent::enter)
+    }
+
+    /// A preorder walk which leaves the nodes for which `enter` returns
+    /// `false` right after entering them.
+    fn preorder_with_tokens_filtered(
+        &self,
+        mut enter: impl FnMut(&SyntaxNode) -> bool,
+    ) -> impl Iterator<Item = WalkEvent<SyntaxElement>> {
         let start: SyntaxElement = self.clone().into();
         iter::successors(Some(WalkEvent::Enter(start.clone())), move |pos| {
             let next = match pos {
                 WalkEvent::Enter(el) => match el {
+                    SyntaxElement::Node(node) if !enter(node) => {
+                        WalkEvent::Leave(node.clone().into())
+                    }
                     SyntaxElement::Node(node) => match node.first_child_or_token() {
                         Some(child) => WalkEvent::Enter(child),
                         None => WalkEvent::Leave(node.clone().into()),
@@ -386,12 +1559,120 @@ impl SyntaxNode {
         })
     }
 
+    /// Calls `f` on each node `depth` levels below this one, in parallel, and
+    /// returns the results in order. Each thread gets a share of the nodes.
+    ///
+    /// Red nodes can't cross threads, so each node is detached: `f` gets it
+    /// as the root of its own red tree, with offsets relative to its start.
+    /// Tokens and nodes above `depth` are not visited, neither are nodes in
+    /// branches shallower than `depth`.
+    ///
+    /// The nodes are split into at most `std::thread::available_parallelism`
+    /// chunks, each mapped on a scoped thread of its own, spawned for this
+    /// call.
+    pub fn parallel_map_subtrees<T, F>(&self, depth: usize, f: F) -> Vec<T>
+    where
+        T: Send,
+        F: Fn(SyntaxNode) -> T + Sync,
+    {
+        let mut subtrees = vec![self.green().clone()];
+        for _ in 0..depth {
+            subtrees = subtrees
+                .iter()
+                .flat_map(|it| it.children())
+                .filter_map(|child| match child {
+                    GreenElement::Node(it) => Some(it.clone()),
+                    GreenElement::Token(_) => None,
+                })
+                .collect();
+        }
+        let map = |chunk: &[GreenNode]| {
+            chunk.iter().map(|it| f(SyntaxNode::new_root(it.clone()))).collect::<Vec<_>>()
+        };
+        let n_threads = std::thread::available_parallelism().map_or(1, |it| it.get());
+        if n_threads == 1 || subtrees.len() <= 1 {
+            return map(&subtrees);
+        }
+        let chunk_size = subtrees.len().div_ceil(n_threads);
+        std::thread::scope(|scope| {
+            let handles = subtrees
+                .chunks(chunk_size)
+                .map(|it| scope.spawn(move || map(it)))
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .flat_map(|it| it.join().unwrap_or_else(|err| std::panic::resume_unwind(err)))
+                .collect()
+        })
+    }
+
     /// Find a token in the subtree corresponding to this node, which covers the offset.
-    /// Precondition: offset must be withing node's range.
+    /// Precondition: offset must be withing node's range, see
+    /// `try_token_at_offset` for a non-panicking version.
     pub fn token_at_offset(&self, offset: TextUnit) -> TokenAtOffset<SyntaxToken> {
-        // TODO: this could be faster if we first drill-down to node, and only
-        // then switch to token search. We should also replace explicit
-        // recursion with a loop.
+        self.try_token_at_offset(offset).unwrap_or_else(|err| panic!("token_at_offset: {}", err))
+    }
+
+    /// Like `token_at_offset`, but returns an error instead of panicking if
+    /// the offset is outside of this node.
+    pub fn try_token_at_offset(
+        &self,
+        offset: TextUnit,
+    ) -> Result<TokenAtOffset<SyntaxToken>, OffsetOutOfRange> {
+        let range = self.text_range();
+        if !(range.start() <= offset && offset <= range.end()) {
+            return Err(OffsetOutOfRange { kind: self.kind(), range, offset });
+        }
+        if range.is_empty() {
+            return Ok(TokenAtOffset::None);
+        }
+
+        // Descend while a single child contains the offset. Below the node
+        // where two children touch it, each of them has a single path.
+        let mut split = None;
+        let common =
+            self.descend_by(|green, start| match children_at_offset(green, offset - start) {
+                TokenAtOffset::Single((index, rel_start)) => Some((index, start + rel_start)),
+                TokenAtOffset::Between(left, right) => {
+                    split = Some((left, right));
+                    None
+                }
+                TokenAtOffset::None => unreachable!(),
+            });
+        let node = match common {
+            SyntaxElement::Token(token) => return Ok(TokenAtOffset::Single(token)),
+            SyntaxElement::Node(node) => node,
+        };
+        let (left, right) = split.unwrap();
+        let token = |(index, rel_start): (usize, TextUnit)| {
+            let start = node.text_range().start();
+            let child = &node.green().children()[index];
+            let child =
+                SyntaxElement::new(child, node.clone(), child_index(index), start + rel_start);
+            let child = match child {
+                SyntaxElement::Token(token) => return token,
+                SyntaxElement::Node(it) => it,
+            };
+            match child.descend_by(|green, start| {
+                let (index, rel_start) =
+                    children_at_offset(green, offset - start).right_biased()?;
+                Some((index, start + rel_start))
+            }) {
+                SyntaxElement::Token(token) => token,
+                SyntaxElement::Node(_) => unreachable!(),
+            }
+        };
+        Ok(TokenAtOffset::Between(token(left), token(right)))
+    }
+
+    /// Zero-based number of the line the offset falls on, counting lines from
+    /// the start of this node. For the root, this is the line in the file.
+    ///
+    /// Only `\n` starts a new line, so `\r\n` works as expected, but lone
+    /// `\r`s do not count. Uses the newline counts cached in the green tree,
+    /// so only a single path from this node to a token is visited.
+    /// Precondition: offset must be withing node's range.
+    pub fn line_of_offset(&self, offset: TextUnit) -> u32 {
         let range = self.text_range();
         assert!(
             range.start() <= offset && offset <= range.end(),
@@ -399,58 +1680,175 @@ impl SyntaxNode {
             range,
             offset
         );
-        if range.is_empty() {
-            return TokenAtOffset::None;
-        }
-
-        let mut children = self.children_with_tokens().filter(|child| {
-            let child_range = child.text_range();
-            !child_range.is_empty()
-                && (child_range.start() <= offset && offset <= child_range.end())
-        });
-
-        let left = children.next().unwrap();
-        let right = children.next();
-        assert!(children.next().is_none());
-
-        if let Some(right) = right {
-            match (left.token_at_offset(offset), right.token_at_offset(offset)) {
-                (TokenAtOffset::Single(left), TokenAtOffset::Single(right)) => {
-                    TokenAtOffset::Between(left, right)
-                }
-                _ => unreachable!(),
-            }
-        } else {
-            left.token_at_offset(offset)
-        }
+        self.green().newlines_before(offset - range.start())
     }
 
     /// Return the deepest node or token in the current subtree that fully
     /// contains the range. If the range is empty and is contained in two leaf
     /// nodes, either one can be returned. Precondition: range must be contained
-    /// withing the current node
+    /// withing the current node, see `try_covering_node` for a non-panicking
+    /// version.
     pub fn covering_node(&self, range: TextRange) -> SyntaxElement {
+        self.try_covering_node(range).unwrap_or_else(|err| panic!("covering_node: {}", err))
+    }
+
+    /// Like `covering_node`, but returns an error instead of panicking if the
+    /// range is not within this node.
+    pub fn try_covering_node(&self, range: TextRange) -> Result<SyntaxElement, RangeOutOfBounds> {
+        let node_range = self.text_range();
+        if !range.is_subrange(&node_range) {
+            return Err(RangeOutOfBounds { kind: self.kind(), node_range, range });
+        }
+        let res = self.descend_by(|green, start| {
+            // The first child, which ends after the range, is the only one
+            // which can contain it.
+            let (index, rel_start) = green.first_child_ending_after(range.end() - start, true);
+            if index < green.children().len() && start + rel_start <= range.start() {
+                Some((index, start + rel_start))
+            } else {
+                None
+            }
+        });
+        Ok(res)
+    }
+
+    /// Walks down from this node to the children picked by `choose`, which
+    /// gets the green node and the offset of the current node, and returns
+    /// the index and the offset of the next one. Stops at a token, or when
+    /// `choose` returns `None`.
+    ///
+    /// The walk never goes back up, so red nodes are only created for the
+    /// path to the result, and its siblings are only looked at in the green
+    /// tree.
+    fn descend_by(
+        &self,
+        mut choose: impl FnMut(&GreenNode, TextUnit) -> Option<(usize, TextUnit)>,
+    ) -> SyntaxElement {
         let mut res: SyntaxElement = self.clone().into();
         loop {
-            assert!(
-                range.is_subrange(&res.text_range()),
-                "Bad range: node range {:?}, range {:?}",
-                res.text_range(),
-                range,
-            );
-            res = match &res {
+            let node = match &res {
+                SyntaxElement::Node(it) => it,
                 SyntaxElement::Token(_) => return res,
-                SyntaxElement::Node(node) => {
-                    match node
-                        .children_with_tokens()
-                        .find(|child| range.is_subrange(&child.text_range()))
-                    {
-                        Some(child) => child,
-                        None => return res,
+            };
+            let (index, offset) = match choose(node.green(), node.text_range().start()) {
+                Some(it) => it,
+                None => return res,
+            };
+            let child = &node.green().children()[index];
+            let next = SyntaxElement::new(child, node.clone(), child_index(index), offset);
+            res = next;
+        }
+    }
+}
+
+/// The non-empty children of `node`, whose ranges contain `rel_offset`,
+/// with their relative offsets.
+fn children_at_offset(node: &GreenNode, rel_offset: TextUnit) -> TokenAtOffset<(usize, TextUnit)> {
+    let children = node.children();
+    let (index, start) = node.first_child_ending_after(rel_offset, false);
+    let right = if index < children.len() { Some((index, start)) } else { None };
+    let left = if start == rel_offset {
+        children[..index]
+            .iter()
+            .rposition(|it| it.text_len() != 0.into())
+            .map(|i| (i, start - children[i].text_len()))
+    } else {
+        None
+    };
+    match (left, right) {
+        (Some(left), Some(right)) => TokenAtOffset::Between(left, right),
+        (Some(it), None) | (None, Some(it)) => TokenAtOffset::Single(it),
+        (None, None) => TokenAtOffset::None,
+    }
+}
+
+/// An edit of the element at some path, see `rebuild`.
+pub(crate) enum GreenEdit {
+    /// Replace the element, or remove it if the replacement is `None`.
+    Replace(Option<GreenElement>),
+    InsertBefore(Vec<GreenElement>),
+    InsertAfter(Vec<GreenElement>),
+}
+
+/// Rebuilds `node` with the edits applied to the elements at the given paths
+/// (relative to `node`), in a single bottom-up pass.
+///
+/// `edits` must be sorted by path (stably, insertions at the same place are
+/// applied in order), and there must be no edits inside of a replaced
+/// element, or two replacements of the same element.
+pub(crate) fn rebuild(node: &GreenNode, edits: &[(Vec<u32>, GreenEdit)]) -> GreenNode {
+    /// A node on the way to the current edit, with its remaining edits and
+    /// the children rebuilt so far.
+    struct Frame<'a> {
+        node: &'a GreenNode,
+        edits: &'a [(Vec<u32>, GreenEdit)],
+        index: usize,
+        children: Vec<GreenElement>,
+        /// The insertions after the child which is being rebuilt.
+        after: Vec<GreenElement>,
+    }
+    impl<'a> Frame<'a> {
+        fn new(node: &'a GreenNode, edits: &'a [(Vec<u32>, GreenEdit)]) -> Frame<'a> {
+            let children = Vec::with_capacity(node.children().len());
+            Frame { node, edits, index: 0, children, after: Vec::new() }
+        }
+    }
+
+    let mut stack = vec![Frame::new(node, edits)];
+    loop {
+        let depth = stack.len() - 1;
+        let frame = stack.last_mut().unwrap();
+        let (node, index) = (frame.node, frame.index);
+        let child = match node.children().get(index) {
+            Some(it) => it,
+            None => {
+                let frame = stack.pop().unwrap();
+                let new = GreenNode::from_vec(node.kind(), frame.children);
+                match stack.last_mut() {
+                    Some(parent) => {
+                        parent.children.push(new.into());
+                        parent.children.append(&mut parent.after);
+                        parent.index += 1;
                     }
+                    None => return new,
                 }
-            };
+                continue;
+            }
+        };
+        // `edits` are sorted, so the edits of each child are contiguous, and
+        // the edits of the child itself come before the edits inside of it.
+        let edits = frame.edits;
+        let n = edits.iter().take_while(|(path, _)| path[depth] as usize == index).count();
+        let (group, rest) = edits.split_at(n);
+        frame.edits = rest;
+        let n_own = group.iter().take_while(|(path, _)| path.len() == depth + 1).count();
+        let (own, nested) = group.split_at(n_own);
+
+        let mut after = Vec::new();
+        let mut replacement = None;
+        for (_, edit) in own {
+            match edit {
+                GreenEdit::InsertBefore(elements) => {
+                    frame.children.extend(elements.iter().cloned())
+                }
+                GreenEdit::InsertAfter(elements) => after.extend(elements.iter().cloned()),
+                GreenEdit::Replace(it) => replacement = Some(it.clone()),
+            }
+        }
+        match replacement {
+            Some(it) => frame.children.extend(it),
+            None if nested.is_empty() => frame.children.push(child.clone()),
+            None => match child {
+                GreenElement::Node(it) => {
+                    frame.after = after;
+                    stack.push(Frame::new(it, nested));
+                    continue;
+                }
+                GreenElement::Token(_) => unreachable!(),
+            },
         }
+        frame.children.extend(after);
+        frame.index += 1;
     }
 }
 
@@ -462,29 +1860,37 @@ impl SyntaxToken {
     /// Returns a green tree, equal to the green tree this token
     /// belongs two, except with this token substitute. The complexity
     /// of operation is proportional to the depth of the tree
+    ///
+    /// Panics if the kind of `replacement` differs from the kind of this
+    /// token, see `try_replace_with` for a non-panicking version.
     pub fn replace_with(&self, replacement: GreenToken) -> GreenNode {
-        assert_eq!(self.kind(), replacement.kind());
-        let mut replacement = Some(replacement);
+        self.try_replace_with(replacement).unwrap_or_else(|err| {
+            panic!("replace_with: {}, for {}", err, ElementDisplay(self.kind(), self.text_range()))
+        })
+    }
+
+    /// Like `replace_with`, but returns an error instead of panicking if the
+    /// kind of `replacement` differs from the kind of this token.
+    pub fn try_replace_with(&self, replacement: GreenToken) -> Result<GreenNode, KindMismatch> {
+        KindMismatch::check(self.kind(), replacement.kind())?;
         let parent = self.parent();
-        let me = self.index;
+        let new_parent = parent.green().replace_child(self.index as usize, replacement.into());
+        Ok(parent.replace_green(new_parent))
+    }
 
-        let children: Box<[_]> =
-            parent
-                .green()
-                .children()
-                .iter()
-                .enumerate()
-                .map(|(i, child)| {
-                    if i as u32 == me {
-                        replacement.take().unwrap().into()
-                    } else {
-                        child.clone()
-                    }
-                })
-                .collect();
-        assert!(replacement.is_none());
-        let new_parent = GreenNode::new(parent.kind(), children);
-        parent.replace_with(new_parent)
+    /// Returns a green tree, equal to the green tree this token belongs to,
+    /// except with the text of this token changed to `text`.
+    pub fn with_text(&self, text: impl Into<SmolStr>) -> GreenNode {
+        self.replace_with(GreenToken::new(self.kind(), text.into()))
+    }
+
+    /// Returns a green tree, equal to the green tree this token belongs to,
+    /// except with the kind of this token changed to `kind`.
+    pub fn with_kind(&self, kind: SyntaxKind) -> GreenNode {
+        let parent = self.parent();
+        let replacement = GreenToken::new(kind, self.text().into());
+        let new_parent = parent.green().replace_child(self.index as usize, replacement.into());
+        parent.replace_green(new_parent)
     }
 
     pub fn text_range(&self) -> TextRange {
@@ -495,10 +1901,16 @@ impl SyntaxToken {
         self.green().kind()
     }
 
-    pub fn text(&self) -> &SmolStr {
+    pub fn text(&self) -> &str {
         self.green().text()
     }
 
+    /// Text of this token, as a `SmolStr`.
+    #[deprecated(since = "0.5.7", note = "`text` now returns `&str`, use `text().into()`")]
+    pub fn text_smol_str(&self) -> SmolStr {
+        self.text().into()
+    }
+
     pub fn green(&self) -> &GreenToken {
         match &self.parent.green().children()[self.index as usize] {
             GreenElement::Token(it) => it,
@@ -506,55 +1918,143 @@ impl SyntaxToken {
         }
     }
 
+    /// An owned handle to the green token, as cheap as
+    /// `SyntaxNode::to_green`.
+    pub fn to_green(&self) -> GreenToken {
+        self.green().clone()
+    }
+
+    /// Whether both tokens have the same green token, see
+    /// `SyntaxNode::same_green`.
+    pub fn same_green(&self, other: &SyntaxToken) -> bool {
+        self.green().ptr_eq(other.green())
+    }
+
+    /// Whether both tokens are at the same position (the same child of the
+    /// same node) of the same tree, which is what `==` compares. Tokens of
+    /// different trees are never the same, even if the trees share the green
+    /// nodes, while the tokens found by two walks over one tree are, even
+    /// though each walk creates its own red nodes.
+    pub fn same_token(&self, other: &SyntaxToken) -> bool {
+        self == other
+    }
+
     pub fn parent(&self) -> SyntaxNode {
         self.parent.clone()
     }
 
-    pub fn next_sibling_or_token(&self) -> Option<SyntaxElement> {
-        let (element, (index, offset)) = self
-            .parent
-            .green()
-            .children_from((self.index + 1) as usize, self.text_range().end())
-            .next()?;
+    /// The index path of the parent, followed by the index of this token,
+    /// see `SyntaxNode::index_path`.
+    pub fn index_path(&self) -> Vec<u32> {
+        let mut res = self.parent.index_path();
+        res.push(self.index);
+        res
+    }
 
-        Some(SyntaxElement::new(element, self.parent(), index as u32, offset))
+    /// See `SyntaxElement::position_cmp`.
+    pub fn position_cmp(&self, other: &SyntaxToken) -> Option<std::cmp::Ordering> {
+        SyntaxElement::Token(self.clone()).position_cmp(&SyntaxElement::Token(other.clone()))
     }
 
-    pub fn prev_sibling_or_token(&self) -> Option<SyntaxElement> {
-        let parent = self.parent();
-        let (element, (index, offset)) = self
-            .parent
-            .green()
-            .children_to(self.index as usize, self.text_range().start())
-            .next()?;
+    pub fn next_sibling_or_token(&self) -> Option<SyntaxElement> {
+        let (element, (index, offset)) =
+            self.parent.children_from(self.index as usize + 1).next()?;
+        Some(SyntaxElement::new(element, self.parent(), child_index(index), offset))
+    }
 
-        Some(SyntaxElement::new(element, self.parent(), index as u32, offset))
+    pub fn prev_sibling_or_token(&self) -> Option<SyntaxElement> {
+        let (element, (index, offset)) = self.parent.children_to(self.index as usize).next()?;
+        Some(SyntaxElement::new(element, self.parent(), child_index(index), offset))
     }
 
     /// Next token in the file (i.e, not necessary a sibling)
     pub fn next_token(&self) -> Option<SyntaxToken> {
-        match self.next_sibling_or_token() {
-            Some(element) => element.first_token(),
-            None => self
-                .parent()
-                .ancestors()
-                .find_map(|it| it.next_sibling_or_token())
-                .and_then(|element| element.first_token()),
-        }
+        let next = match self.next_sibling_or_token() {
+            Some(it) => it,
+            None => next_element_after(&self.parent, None)?,
+        };
+        first_token_from(next, None)
     }
     /// Previous token in the file (i.e, not necessary a sibling)
     pub fn prev_token(&self) -> Option<SyntaxToken> {
-        match self.prev_sibling_or_token() {
-            Some(element) => element.last_token(),
-            None => self
-                .parent()
-                .ancestors()
-                .find_map(|it| it.prev_sibling_or_token())
-                .and_then(|element| element.last_token()),
+        let prev = match self.prev_sibling_or_token() {
+            Some(it) => it,
+            None => prev_element_before(&self.parent, None)?,
+        };
+        last_token_from(prev, None)
+    }
+
+    /// The next token in the file with a kind from `set`.
+    pub fn next_token_in(&self, set: SyntaxKindSet) -> Option<SyntaxToken> {
+        iter::successors(self.next_token(), SyntaxToken::next_token)
+            .find(|it| set.contains(it.kind()))
+    }
+
+    /// The previous token in the file with a kind from `set`.
+    pub fn prev_token_in(&self, set: SyntaxKindSet) -> Option<SyntaxToken> {
+        iter::successors(self.prev_token(), SyntaxToken::prev_token)
+            .find(|it| set.contains(it.kind()))
+    }
+}
+
+/// The first token in the subtree of `element` or after it, skipping empty
+/// nodes. Stops at the end of `within`, if given.
+fn first_token_from(element: SyntaxElement, within: Option<&SyntaxNode>) -> Option<SyntaxToken> {
+    let mut element = element;
+    loop {
+        element = match element.into_first_token() {
+            Ok(token) => return Some(token),
+            Err(empty) => next_element_after(&empty, within)?,
+        }
+    }
+}
+
+fn last_token_from(element: SyntaxElement, within: Option<&SyntaxNode>) -> Option<SyntaxToken> {
+    let mut element = element;
+    loop {
+        element = match element.into_last_token() {
+            Ok(token) => return Some(token),
+            Err(empty) => prev_element_before(&empty, within)?,
+        }
+    }
+}
+
+/// The next sibling of `node` or of its closest ancestor which has one (but
+/// is still inside of `within`). Walks up by reference, so that the ancestors
+/// are not cloned.
+fn next_element_after(node: &SyntaxNode, within: Option<&SyntaxNode>) -> Option<SyntaxElement> {
+    let mut node = node;
+    loop {
+        if within == Some(node) {
+            return None;
+        }
+        if let Some(sibling) = node.next_sibling_or_token() {
+            return Some(sibling);
+        }
+        node = node.0.kind.as_child()?.0;
+    }
+}
+
+fn prev_element_before(node: &SyntaxNode, within: Option<&SyntaxNode>) -> Option<SyntaxElement> {
+    let mut node = node;
+    loop {
+        if within == Some(node) {
+            return None;
         }
+        if let Some(sibling) = node.prev_sibling_or_token() {
+            return Some(sibling);
+        }
+        node = node.0.kind.as_child()?.0;
     }
 }
 
+/// Sorts `elements` in document order, see `SyntaxElement::position_cmp`.
+/// Elements of different trees are grouped by tree, the trees in the order
+/// in which they were created.
+pub fn sort_by_position(elements: &mut [SyntaxElement]) {
+    elements.sort_by(|a, b| a.tree_id().cmp(&b.tree_id()).then_with(|| a.position_order(b)))
+}
+
 impl SyntaxElement {
     fn new(
         element: &GreenElement,
@@ -563,10 +2063,8 @@ impl SyntaxElement {
         offset: TextUnit,
     ) -> SyntaxElement {
         match element {
-            GreenElement::Node(node) => {
-                SyntaxNode::new_child(node, parent, index as u32, offset).into()
-            }
-            GreenElement::Token(_) => SyntaxToken::new(parent, index as u32, offset).into(),
+            GreenElement::Node(node) => SyntaxNode::new_child(node, parent, index, offset).into(),
+            GreenElement::Token(_) => SyntaxToken::new(parent, index, offset).into(),
         }
     }
 
@@ -584,6 +2082,38 @@ impl SyntaxElement {
         }
     }
 
+    pub fn is_node(&self) -> bool {
+        matches!(self, SyntaxElement::Node(_))
+    }
+
+    pub fn is_token(&self) -> bool {
+        matches!(self, SyntaxElement::Token(_))
+    }
+
+    /// If this element is a node, return it.
+    pub fn as_node(&self) -> Option<&SyntaxNode> {
+        match self {
+            SyntaxElement::Node(it) => Some(it),
+            SyntaxElement::Token(_) => None,
+        }
+    }
+
+    /// If this element is a token, return it.
+    pub fn as_token(&self) -> Option<&SyntaxToken> {
+        match self {
+            SyntaxElement::Node(_) => None,
+            SyntaxElement::Token(it) => Some(it),
+        }
+    }
+
+    pub fn into_node(self) -> Option<SyntaxNode> {
+        SyntaxNode::try_from(self).ok()
+    }
+
+    pub fn into_token(self) -> Option<SyntaxToken> {
+        SyntaxToken::try_from(self).ok()
+    }
+
     pub fn parent(&self) -> Option<SyntaxNode> {
         match self {
             SyntaxElement::Node(it) => it.parent(),
@@ -591,6 +2121,62 @@ impl SyntaxElement {
         }
     }
 
+    /// See `SyntaxNode::index_path`.
+    pub fn index_path(&self) -> Vec<u32> {
+        match self {
+            SyntaxElement::Node(it) => it.index_path(),
+            SyntaxElement::Token(it) => it.index_path(),
+        }
+    }
+
+    /// Compares the positions of two elements of the same tree in the
+    /// document: by start offset, then by end offset, the longer one first,
+    /// so that an ancestor comes before its descendants. Elements with the
+    /// same range, like a node and its only child, or adjacent empty nodes,
+    /// are in preorder. `None` if the elements are from different trees,
+    /// even ones of the same green node.
+    pub fn position_cmp(&self, other: &SyntaxElement) -> Option<std::cmp::Ordering> {
+        if self.tree_id() != other.tree_id() {
+            return None;
+        }
+        Some(self.position_order(other))
+    }
+
+    fn tree_id(&self) -> u64 {
+        match self {
+            SyntaxElement::Node(it) => it.tree_id(),
+            SyntaxElement::Token(it) => it.parent.tree_id(),
+        }
+    }
+
+    fn position_order(&self, other: &SyntaxElement) -> std::cmp::Ordering {
+        let (range, other_range) = (self.text_range(), other.text_range());
+        range
+            .start()
+            .cmp(&other_range.start())
+            .then(other_range.end().cmp(&range.end()))
+            .then_with(|| self.index_path().cmp(&other.index_path()))
+    }
+
+    /// An owned handle to the green node or token, see
+    /// `SyntaxNode::to_green`.
+    pub fn to_green(&self) -> GreenElement {
+        match self {
+            SyntaxElement::Node(it) => it.to_green().into(),
+            SyntaxElement::Token(it) => it.to_green().into(),
+        }
+    }
+
+    /// Whether both elements have the same green element, see
+    /// `SyntaxNode::same_green`.
+    pub fn same_green(&self, other: &SyntaxElement) -> bool {
+        match (self, other) {
+            (SyntaxElement::Node(it), SyntaxElement::Node(other)) => it.same_green(other),
+            (SyntaxElement::Token(it), SyntaxElement::Token(other)) => it.same_green(other),
+            _ => false,
+        }
+    }
+
     #[inline]
     pub fn first_token(&self) -> Option<SyntaxToken> {
         match self {
@@ -599,6 +2185,34 @@ impl SyntaxElement {
         }
     }
 
+    /// Descends to the leftmost token, without cloning anything. If an empty
+    /// node is found on the way, returns it instead.
+    fn into_first_token(self) -> Result<SyntaxToken, SyntaxNode> {
+        let mut element = self;
+        loop {
+            element = match element {
+                SyntaxElement::Node(it) => match it.first_child_or_token() {
+                    Some(child) => child,
+                    None => return Err(it),
+                },
+                SyntaxElement::Token(it) => return Ok(it),
+            }
+        }
+    }
+
+    fn into_last_token(self) -> Result<SyntaxToken, SyntaxNode> {
+        let mut element = self;
+        loop {
+            element = match element {
+                SyntaxElement::Node(it) => match it.last_child_or_token() {
+                    Some(child) => child,
+                    None => return Err(it),
+                },
+                SyntaxElement::Token(it) => return Ok(it),
+            }
+        }
+    }
+
     #[inline]
     pub fn last_token(&self) -> Option<SyntaxToken> {
         match self {
@@ -620,20 +2234,14 @@ impl SyntaxElement {
             SyntaxElement::Token(it) => it.prev_sibling_or_token(),
         }
     }
-
-    fn token_at_offset(&self, offset: TextUnit) -> TokenAtOffset<SyntaxToken> {
-        assert!(self.text_range().start() <= offset && offset <= self.text_range().end());
-        match self {
-            SyntaxElement::Token(token) => TokenAtOffset::Single(token.clone()),
-            SyntaxElement::Node(node) => node.token_at_offset(offset),
-        }
-    }
 }
 
+/// The position of a children iterator: the green children are looked up
+/// through `parent` on each step, so that the iterator borrows nothing and
+/// clones cheaply.
 #[derive(Clone, Debug)]
 struct Iter {
     parent: SyntaxNode,
-    green: slice::Iter<'static, GreenElement>,
     offset: TextUnit,
     index: u32,
 }
@@ -641,22 +2249,38 @@ struct Iter {
 impl Iter {
     fn new(parent: SyntaxNode) -> Iter {
         let offset = parent.text_range().start();
-        let green: slice::Iter<'_, _> = parent.green().children().iter();
-        // Dirty lifetime laundering: the memory for a slice of nodes is
-        // indirectly owned by parent.
-        let green: slice::Iter<'static, _> =
-            unsafe { mem::transmute::<slice::Iter<'_, _>, slice::Iter<'static, _>>(green) };
-        Iter { parent, green, offset, index: 0 }
-    }
-
-    fn next(&mut self) -> Option<((&GreenElement, u32, TextUnit))> {
-        self.green.next().map(|element| {
-            let offset = self.offset;
-            let index = self.index;
+        Iter { parent, offset, index: 0 }
+    }
+
+    fn next(&mut self) -> Option<(&GreenElement, u32, TextUnit)> {
+        let element = self.parent.green().children().get(self.index as usize)?;
+        let offset = self.offset;
+        let index = self.index;
+        self.offset += element.text_len();
+        self.index += 1;
+        Some((element, index, offset))
+    }
+
+    /// The number of children left.
+    fn len(&self) -> usize {
+        self.parent.green().children().len() - self.index as usize
+    }
+
+    /// Skips `n` children (or `n` nodes, if `nodes_only`), without creating
+    /// red nodes for them.
+    fn skip(&mut self, n: usize, nodes_only: bool) {
+        let mut n = n;
+        while n > 0 {
+            let element = match self.parent.green().children().get(self.index as usize) {
+                Some(it) => it,
+                None => return,
+            };
             self.offset += element.text_len();
             self.index += 1;
-            (element, index, offset)
-        })
+            if !nodes_only || matches!(element, GreenElement::Node(_)) {
+                n -= 1;
+            }
+        }
     }
 }
 
@@ -672,14 +2296,65 @@ impl SyntaxNodeChildren {
 impl Iterator for SyntaxNodeChildren {
     type Item = SyntaxNode;
     fn next(&mut self) -> Option<Self::Item> {
-        let parent = self.0.parent.clone();
-        while let Some((element, index, offset)) = self.0.next() {
+        // Tokens are skipped without touching the reference count of the
+        // parent, which is only cloned for the returned node.
+        let iter = &mut self.0;
+        let children = iter.parent.green().children();
+        while let Some(element) = children.get(iter.index as usize) {
+            let (index, offset) = (iter.index, iter.offset);
+            iter.offset += element.text_len();
+            iter.index += 1;
             if let GreenElement::Node(node) = element {
-                return Some(SyntaxNode::new_child(node, parent, index, offset));
+                return Some(SyntaxNode::new_child(node, iter.parent.clone(), index, offset));
             }
         }
         None
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // Tokens are skipped.
+        (0, Some(self.0.len()))
+    }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        self.0.skip(n, true);
+        self.next()
+    }
+}
+
+impl iter::FusedIterator for SyntaxNodeChildren {}
+
+/// Iterates over the child nodes, like `children`:
+///
+/// ```
+/// # use rowan::{cursor::SyntaxNode, GreenNodeBuilder, SyntaxKind};
+/// let mut builder = GreenNodeBuilder::new();
+/// builder.start_node(SyntaxKind(1));
+/// for text in &["a", "b"] {
+///     builder.start_node(SyntaxKind(1));
+///     builder.token(SyntaxKind(0), (*text).into());
+///     builder.finish_node();
+///     builder.token(SyntaxKind(0), " ".into());
+/// }
+/// builder.finish_node();
+/// let root = SyntaxNode::new_root(builder.finish());
+///
+/// let mut starts = Vec::new();
+/// for child in &root {
+///     starts.push(child.text_range().start().to_usize());
+/// }
+/// assert_eq!(starts, [0, 2]);
+/// ```
+///
+/// There is no such impl for `&SyntaxElement`: a token has no children, and
+/// whether the children of a node should include tokens is better spelled
+/// out with `children_with_tokens`.
+impl IntoIterator for &SyntaxNode {
+    type Item = SyntaxNode;
+    type IntoIter = SyntaxNodeChildren;
+    fn into_iter(self) -> SyntaxNodeChildren {
+        self.children()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -697,30 +2372,95 @@ impl Iterator for SyntaxElementChildren {
         let parent = self.0.parent.clone();
         self.0.next().map(|(green, index, offset)| SyntaxElement::new(green, parent, index, offset))
     }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        self.0.skip(n, false);
+        self.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.0.len(), Some(self.0.len()))
+    }
+}
+
+impl ExactSizeIterator for SyntaxElementChildren {}
+
+impl iter::FusedIterator for SyntaxElementChildren {}
+
+/// Preorder traversal of the nodes of a subtree, see `SyntaxNode::preorder`.
+///
+/// The state is the next event, that is the current node and the direction.
+/// `Leave` events share the node of the matching `Enter` event (or the parent
+/// link of the last child), so new nodes are created only when descending to
+/// a child or moving to a sibling.
+#[derive(Debug, Clone)]
+pub struct Preorder {
+    start: SyntaxNode,
+    next: Option<WalkEvent<SyntaxNode>>,
+}
+
+impl Preorder {
+    fn new(start: SyntaxNode) -> Preorder {
+        let next = Some(WalkEvent::Enter(start.clone()));
+        Preorder { start, next }
+    }
+
+    /// Right after entering a node, leaves it without entering its children.
+    fn skip_subtree(&mut self) {
+        if let Some(WalkEvent::Enter(first_child)) = &self.next {
+            self.next = Some(WalkEvent::Leave(first_child.parent().unwrap()));
+        }
+    }
+}
+
+impl Iterator for Preorder {
+    type Item = WalkEvent<SyntaxNode>;
+    fn next(&mut self) -> Option<WalkEvent<SyntaxNode>> {
+        let event = self.next.take()?;
+        self.next = match &event {
+            WalkEvent::Enter(node) => Some(match node.first_child() {
+                Some(child) => WalkEvent::Enter(child),
+                None => WalkEvent::Leave(node.clone()),
+            }),
+            // Ancestors of the nodes of this traversal are shared, so the
+            // start node is recognized by its `NodeData`.
+            WalkEvent::Leave(node) if Rc::ptr_eq(&node.0, &self.start.0) => None,
+            WalkEvent::Leave(node) => Some(match node.next_sibling() {
+                Some(sibling) => WalkEvent::Enter(sibling),
+                None => WalkEvent::Leave(node.parent().unwrap()),
+            }),
+        };
+        Some(event)
+    }
 }
 
-impl GreenNode {
+impl SyntaxNode {
+    // The offsets of the children are derived from the green node, rather
+    // than from offsets passed in, which might be inconsistent with it.
+
+    /// Children of this node, starting with the one at `start_index`, with
+    /// their (absolute) offsets.
     fn children_from(
         &self,
         start_index: usize,
-        mut offset: TextUnit,
     ) -> impl Iterator<Item = (&GreenElement, (usize, TextUnit))> {
-        self.children()[start_index..].iter().enumerate().map(move |(index, element)| {
-            let element_offset = offset;
-            offset += element.text_len();
-            (element, (start_index + index, element_offset))
-        })
+        let base = self.text_range().start();
+        self.green()
+            .children_with_offsets_from(start_index)
+            .map(move |child| (child.element(), (child.index(), base + child.rel_offset())))
     }
 
+    /// Children of this node before `end_index` in reverse order, with their
+    /// (absolute) offsets.
     fn children_to(
         &self,
         end_index: usize,
-        mut offset: TextUnit,
     ) -> impl Iterator<Item = (&GreenElement, (usize, TextUnit))> {
-        self.children()[..end_index].iter().rev().enumerate().map(move |(index, element)| {
-            offset -= element.text_len();
-            (element, (end_index - index - 1, offset))
-        })
+        let base = self.text_range().start();
+        self.green()
+            .children_with_offsets_to(end_index)
+            .rev()
+            .map(move |child| (child.element(), (child.index(), base + child.rel_offset())))
     }
 }
 
@@ -732,3 +2472,2044 @@ fn filter_nodes<'a, I: Iterator<Item = (&'a GreenElement, T)>, T>(
         GreenElement::Token(_) => None,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::{
+        test_utils::{self, sample, text_of, LIST, ROOT, WORD, WS},
+        GreenNodeBuilder,
+    };
+
+    #[test]
+    fn navigation() {
+        let root = SyntaxNode::new_root(sample());
+        assert_eq!(root.kind(), ROOT);
+        assert_eq!(root.text_range(), TextRange::from_to(0.into(), 11.into()));
+        assert_eq!(root.children().count(), 1);
+        assert_eq!(root.children_with_tokens().count(), 3);
+
+        let list = root.first_child().unwrap();
+        assert_eq!(list.text_range(), TextRange::from_to(0.into(), 7.into()));
+        assert_eq!(list.parent(), Some(root.clone()));
+        assert!(list.next_sibling().is_none());
+        assert_eq!(list.next_sibling_or_token().unwrap().kind(), WS);
+
+        let inner = list.last_child().unwrap();
+        assert_eq!(inner.text_range(), TextRange::from_to(4.into(), 7.into()));
+        assert_eq!(inner.prev_sibling_or_token().unwrap().kind(), WS);
+        assert_eq!(inner.ancestors().count(), 3);
+
+        let baz = root.last_token().unwrap();
+        assert_eq!(baz.text(), "baz");
+        assert_eq!(baz.text_range(), TextRange::from_to(8.into(), 11.into()));
+        let bar = baz.prev_token().unwrap().prev_token().unwrap();
+        assert_eq!(bar.text(), "bar");
+        assert_eq!(bar.parent(), inner);
+        assert_eq!(bar.next_token().unwrap().kind(), WS);
+        assert_eq!(root.first_token().unwrap().text(), "foo");
+    }
+
+    #[test]
+    fn children_size_hint() {
+        let root = SyntaxNode::new_root(test_utils::wide_tree(1_000));
+
+        let mut children = root.children_with_tokens();
+        assert_eq!(children.len(), 1_000);
+        children.nth(99);
+        assert_eq!(children.len(), 900);
+        assert_eq!(children.clone().count(), 900);
+        children.by_ref().for_each(drop);
+        assert_eq!(children.len(), 0);
+        assert!(children.next().is_none());
+
+        // `collect` allocates once, with the exact size.
+        let elements = root.children_with_tokens().skip(10).collect::<Vec<_>>();
+        assert_eq!(elements.len(), 990);
+        assert_eq!(elements.capacity(), 990);
+
+        // Every other child is a token, so this is only an upper bound.
+        let mut nodes = root.children();
+        assert_eq!(nodes.size_hint(), (0, Some(1_000)));
+        // The tenth node is the nineteenth child.
+        nodes.nth(9);
+        assert_eq!(nodes.size_hint(), (0, Some(981)));
+        assert_eq!(nodes.count(), 490);
+    }
+
+    #[test]
+    fn element_accessors() {
+        let root = SyntaxNode::new_root(sample());
+        let first = |range: TextRange| {
+            root.covering_node(range).into_node()?.first_child_or_token()?.into_token()
+        };
+        let foo = first(TextRange::from_to(0.into(), 5.into())).unwrap();
+        assert_eq!(foo.text(), "foo");
+        assert_eq!(first(TextRange::from_to(1.into(), 2.into())), None);
+
+        let node = SyntaxElement::from(root.clone());
+        assert!(node.is_node() && !node.is_token());
+        assert_eq!(node.as_node(), Some(&root));
+        assert_eq!(node.as_token(), None);
+        assert_eq!(SyntaxToken::try_from(node.clone()), Err(root.clone()));
+        assert_eq!(node.into_node(), Some(root.clone()));
+
+        let token = SyntaxElement::from(foo.clone());
+        assert!(token.is_token() && !token.is_node());
+        assert_eq!(token.as_token(), Some(&foo));
+        assert_eq!(token.as_node(), None);
+        assert_eq!(SyntaxNode::try_from(token.clone()), Err(foo.clone()));
+        assert_eq!(token.clone().into_node(), None);
+        assert_eq!(token.into_token(), Some(foo));
+    }
+
+    #[test]
+    fn iterate_over_a_node() {
+        let root =
+            SyntaxNode::new_root(test_utils::mixed_tree(&mut test_utils::Rng::new(395), 500));
+        for node in root.preorder().filter_map(WalkEvent::enter) {
+            let mut children = Vec::new();
+            for child in &node {
+                children.push(child);
+            }
+            assert_eq!(children, node.children().collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn green_root() {
+        let green = test_utils::nested_tree(100);
+        let root = SyntaxNode::new_root(green.clone());
+        assert!(root.green().ptr_eq(&green));
+        assert!(root.green_root().ptr_eq(&green));
+        let token = root.last_token().unwrap();
+        assert!(token.parent().green_root().ptr_eq(&green));
+        assert!(root.first_child().unwrap().green_root().ptr_eq(&green));
+
+        let based = SyntaxNode::new_root_at(green.clone(), 5.into());
+        assert!(based.last_token().unwrap().parent().green_root().ptr_eq(&green));
+        // A copy of the tree would be equal, but not shared.
+        let copy = GreenNode::new(green.kind(), green.children().to_vec().into());
+        assert!(!SyntaxNode::new_root(copy).green_root().ptr_eq(&green));
+    }
+
+    #[test]
+    fn ancestors_by_index() {
+        let root = SyntaxNode::new_root(sample());
+        assert_eq!(root.depth(), 0);
+        assert_eq!(root.nth_ancestor(0), Some(root.clone()));
+        assert_eq!(root.nth_ancestor(1), None);
+        assert_eq!(root.ancestor_at_depth(0), Some(root.clone()));
+        assert_eq!(root.ancestor_at_depth(1), None);
+
+        // "bar" is in ROOT > LIST > LIST.
+        let bar = root.token_at_offset(5.into()).right_biased().unwrap();
+        let parent = bar.parent();
+        assert_eq!(parent.depth(), 2);
+        assert_eq!(parent.nth_ancestor(0), Some(parent.clone()));
+        assert_eq!(parent.nth_ancestor(1), root.first_child());
+        assert_eq!(parent.nth_ancestor(2), Some(root.clone()));
+        assert_eq!(parent.nth_ancestor(3), None);
+        assert_eq!(parent.ancestor_at_depth(0), Some(root.clone()));
+        assert_eq!(parent.ancestor_at_depth(1), root.first_child());
+        assert_eq!(parent.ancestor_at_depth(2), Some(parent.clone()));
+        assert_eq!(parent.ancestor_at_depth(3), None);
+
+        let deep = SyntaxNode::new_root(test_utils::deep_tree(200));
+        let leaf = deep.last_token().unwrap().parent();
+        let ancestors = leaf.ancestors().collect::<Vec<_>>();
+        assert_eq!(leaf.depth(), ancestors.len() - 1);
+        for (n, ancestor) in ancestors.iter().enumerate() {
+            assert_eq!(leaf.nth_ancestor(n).as_ref(), Some(ancestor));
+            assert_eq!(ancestor.depth(), leaf.depth() - n);
+            assert_eq!(leaf.ancestor_at_depth(ancestor.depth()).as_ref(), Some(ancestor));
+        }
+    }
+
+    #[test]
+    fn index_paths() {
+        let root = SyntaxNode::new_root(sample());
+        assert_eq!(root.index_path(), Vec::<u32>::new());
+        let bar = root.token_at_offset(5.into()).right_biased().unwrap();
+        assert_eq!(bar.index_path(), [0, 2, 0]);
+        assert_eq!(bar.parent().index_path(), [0, 2]);
+        let baz = root.last_token().unwrap();
+        assert_eq!(SyntaxElement::from(baz.clone()).index_path(), [2]);
+
+        let resolve = |path: &[u32]| SyntaxNode::resolve_index_path(&root, path);
+        assert_eq!(resolve(&[]), Some(root.clone().into()));
+        assert_eq!(resolve(&[0, 2, 0]), Some(bar.into()));
+        assert_eq!(resolve(&[2]), Some(baz.into()));
+        assert_eq!(resolve(&[3]), None);
+        assert_eq!(resolve(&[0, 3]), None);
+        // Below a token.
+        assert_eq!(resolve(&[2, 0]), None);
+        assert_eq!(resolve(&[0, 2, 0, 0]), None);
+
+        let root =
+            SyntaxNode::new_root(test_utils::mixed_tree(&mut test_utils::Rng::new(401), 2_000));
+        for element in root.preorder_with_tokens().filter_map(WalkEvent::enter) {
+            let path = element.index_path();
+            assert_eq!(SyntaxNode::resolve_index_path(&root, &path), Some(element.clone()));
+            // The same path in another tree of the same green is the same
+            // position, but not the same element.
+            let other = SyntaxNode::new_root(root.green().clone());
+            let resolved = SyntaxNode::resolve_index_path(&other, &path).unwrap();
+            assert_eq!(resolved.text_range(), element.text_range());
+            assert_ne!(resolved, element);
+        }
+
+        // A tree of another shape.
+        let other = SyntaxNode::new_root(test_utils::wide_tree(2));
+        assert_eq!(SyntaxNode::resolve_index_path(&other, &[0, 2, 0]), None);
+        assert_eq!(SyntaxNode::resolve_index_path(&other, &[2]), None);
+        assert!(SyntaxNode::resolve_index_path(&other, &[1]).is_some());
+    }
+
+    #[test]
+    fn document_order() {
+        use std::cmp::{Ordering, Reverse};
+
+        let root = SyntaxNode::new_root(sample());
+        let list = root.first_child().unwrap();
+        let foo = list.first_token().unwrap();
+        let inner = list.last_child().unwrap();
+        let bar = inner.first_token().unwrap();
+        let baz = root.last_token().unwrap();
+        // Nested nodes with the same start.
+        assert_eq!(root.position_cmp(&list), Some(Ordering::Less));
+        assert_eq!(list.position_cmp(&root), Some(Ordering::Greater));
+        assert_eq!(list.position_cmp(&root.first_child().unwrap()), Some(Ordering::Equal));
+        let element = |it: &SyntaxNode| SyntaxElement::from(it.clone());
+        assert_eq!(element(&list).position_cmp(&foo.clone().into()), Some(Ordering::Less));
+        // The same range.
+        assert_eq!(inner.text_range(), bar.text_range());
+        assert_eq!(element(&inner).position_cmp(&bar.clone().into()), Some(Ordering::Less));
+        // Disjoint ones.
+        assert_eq!(foo.position_cmp(&baz), Some(Ordering::Less));
+        assert_eq!(baz.position_cmp(&bar), Some(Ordering::Greater));
+        assert_eq!(inner.position_cmp(&list), Some(Ordering::Greater));
+
+        // Other trees, even of the same green node.
+        let other = SyntaxNode::new_root(root.green().clone());
+        assert_eq!(root.position_cmp(&other), None);
+        assert_eq!(other.last_token().unwrap().position_cmp(&baz), None);
+        assert_eq!(element(&list.clone_subtree()).position_cmp(&element(&list)), None);
+
+        let mut elements = vec![baz.clone().into(), element(&other), bar.clone().into()];
+        elements.push(element(&inner));
+        elements.push(element(&root));
+        sort_by_position(&mut elements);
+        assert_eq!(elements[..4], [element(&root), element(&inner), bar.into(), baz.into()]);
+        assert_eq!(elements[4], element(&other));
+
+        // Preorder, sorting ties stably, is the document order.
+        let mut rng = test_utils::Rng::new(403);
+        for _ in 0..20 {
+            let root = SyntaxNode::new_root(test_utils::mixed_tree(&mut rng, 200));
+            let mut expected =
+                root.preorder_with_tokens().filter_map(WalkEvent::enter).collect::<Vec<_>>();
+            expected.sort_by_key(|it| (it.text_range().start(), Reverse(it.text_range().end())));
+            let mut elements = expected.clone();
+            for i in (1..elements.len()).rev() {
+                elements.swap(i, rng.below(i as u64 + 1) as usize);
+            }
+            sort_by_position(&mut elements);
+            assert_eq!(elements, expected);
+        }
+    }
+
+    #[test]
+    fn backward_siblings() {
+        let ranges = |elements: &[SyntaxElement]| {
+            elements.iter().map(|it| it.text_range()).collect::<Vec<_>>()
+        };
+        let range = |start: u32, end: u32| TextRange::from_to(start.into(), end.into());
+        let root = SyntaxNode::new_root(sample());
+        let baz = root.last_token().unwrap();
+        let backward =
+            iter::successors(Some(SyntaxElement::from(baz)), |it| it.prev_sibling_or_token())
+                .collect::<Vec<_>>();
+        let list = root.first_child().unwrap();
+        assert_eq!(backward[2], list.clone().into());
+        assert_eq!(ranges(&backward), [(8, 11), (7, 8), (0, 7)].map(|(a, b)| range(a, b)));
+        let inner = list.last_child().unwrap();
+        let foo = inner.prev_sibling_or_token().unwrap().prev_sibling_or_token().unwrap();
+        assert_eq!(foo.text_range(), range(0, 3));
+        assert_eq!(foo.as_token().unwrap().prev_sibling_or_token(), None);
+
+        let root =
+            SyntaxNode::new_root(test_utils::mixed_tree(&mut test_utils::Rng::new(381), 500));
+        for node in root.preorder().filter_map(WalkEvent::enter) {
+            let forward = node.children_with_tokens().collect::<Vec<_>>();
+            let mut backward =
+                iter::successors(node.last_child_or_token(), |it| it.prev_sibling_or_token())
+                    .collect::<Vec<_>>();
+            backward.reverse();
+            assert_eq!(ranges(&backward), ranges(&forward));
+            assert_eq!(backward, forward);
+            let mut nodes =
+                iter::successors(node.last_child(), |it| it.prev_sibling()).collect::<Vec<_>>();
+            nodes.reverse();
+            assert!(nodes.iter().eq(&node.children().collect::<Vec<_>>()));
+            for (a, b) in nodes.iter().zip(node.children()) {
+                assert_eq!(a.text_range(), b.text_range());
+            }
+        }
+    }
+
+    #[test]
+    fn filtered_descendants() {
+        let root = SyntaxNode::new_root(sample());
+        let kinds = |it: &mut dyn Iterator<Item = SyntaxElement>| {
+            it.map(|it| (it.kind().0, it.text_range().start().to_usize())).collect::<Vec<_>>()
+        };
+        let mut entered = Vec::new();
+        let nodes = root.descendants_filtered(|it| {
+            entered.push(it.text_range());
+            it.parent().is_none()
+        });
+        assert_eq!(kinds(&mut nodes.map(SyntaxElement::from)), [(ROOT.0, 0), (LIST.0, 0)]);
+        assert_eq!(entered, [root.text_range(), root.first_child().unwrap().text_range()]);
+        let elements = &mut root.descendants_with_tokens_filtered(|it| it.kind() != LIST);
+        assert_eq!(kinds(elements), [(ROOT.0, 0), (LIST.0, 0), (WS.0, 7), (WORD.0, 8)]);
+        // The node itself is yielded, and consulted.
+        assert!(root.descendants_filtered(|_| false).eq(iter::once(root.clone())));
+        let list = root.first_child().unwrap();
+        let elements = &mut list.descendants_with_tokens_filtered(|it| it == &list);
+        assert_eq!(kinds(elements), [(LIST.0, 0), (WORD.0, 0), (WS.0, 3), (LIST.0, 4)]);
+
+        let mut rng = test_utils::Rng::new(404);
+        for _ in 0..20 {
+            let root = SyntaxNode::new_root(test_utils::mixed_tree(&mut rng, 300));
+            // Doesn't enter the nodes starting at an odd offset.
+            let enter = |it: &SyntaxNode| it.text_range().start().to_usize().is_multiple_of(2);
+            let skipped = |it: &SyntaxElement| {
+                let parent = match it {
+                    SyntaxElement::Node(it) => it.parent(),
+                    SyntaxElement::Token(it) => Some(it.parent()),
+                };
+                parent.into_iter().flat_map(|it| it.ancestors()).any(|it| !enter(&it))
+            };
+            let expected = root
+                .preorder_with_tokens()
+                .filter_map(WalkEvent::enter)
+                .filter(|it| !skipped(it))
+                .collect::<Vec<_>>();
+            assert!(expected.len() < root.preorder_with_tokens().count() / 2);
+            let elements = root.descendants_with_tokens_filtered(enter).collect::<Vec<_>>();
+            assert_eq!(elements, expected);
+            let nodes = root.descendants_filtered(enter).map(SyntaxElement::from);
+            let expected_nodes = expected.iter().filter(|it| it.is_node()).cloned();
+            assert!(nodes.eq(expected_nodes));
+        }
+    }
+
+    #[test]
+    fn owned_greens() {
+        let root = SyntaxNode::new_root(sample());
+        let list = root.first_child().unwrap();
+        let token = list.first_token().unwrap();
+        let green = list.to_green();
+        assert!(green.ptr_eq(list.green()));
+        let green_token = token.to_green();
+        assert!(green_token.ptr_eq(token.green()));
+        let element = SyntaxElement::from(token.clone()).to_green();
+        assert!(matches!(&element, GreenElement::Token(it) if it.ptr_eq(token.green())));
+        let element = SyntaxElement::from(list.clone()).to_green();
+        assert!(matches!(&element, GreenElement::Node(it) if it.ptr_eq(&green)));
+        drop((root, list, token));
+
+        assert_eq!(green.kind(), LIST);
+        assert_eq!(green.text_len(), 7.into());
+        assert_eq!(green_token.text(), "foo");
+        // The greens can go into a new tree.
+        let root = SyntaxNode::new_root(GreenNode::new(ROOT, vec![green.clone().into()].into()));
+        assert!(root.first_child().unwrap().green().ptr_eq(&green));
+    }
+
+    #[test]
+    fn clone_subtree() {
+        let root = SyntaxNode::new_root(sample());
+        let list = root.first_child().unwrap();
+        let inner = list.last_child().unwrap();
+        let subtree = list.clone_subtree();
+        assert!(subtree.parent().is_none());
+        assert_eq!(subtree.kind(), LIST);
+        assert_eq!(subtree.text_range(), TextRange::from_to(0.into(), 7.into()));
+        assert_ne!(subtree, list);
+        assert!(subtree.same_green(&list));
+        assert_eq!(inner.clone_subtree().text_range(), TextRange::from_to(0.into(), 3.into()));
+        let expected = text_of(&list);
+        drop((root, list, inner));
+
+        assert_eq!(text_of(&subtree), expected);
+        assert_eq!(subtree.last_child().unwrap().first_token().unwrap().text(), "bar");
+        assert_eq!(subtree.validate_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn children_outlive_their_parent() {
+        let root = SyntaxNode::new_root(sample());
+        let list = root.first_child().unwrap();
+        let mut children = list.children_with_tokens();
+        assert_eq!(children.next().unwrap().kind(), WORD);
+        let rest = children.clone();
+        let nodes = list.children();
+        drop((children, list, root));
+
+        let rest = rest.map(|it| (it.kind(), it.text_range())).collect::<Vec<_>>();
+        assert_eq!(
+            rest,
+            vec![
+                (WS, TextRange::from_to(3.into(), 4.into())),
+                (LIST, TextRange::from_to(4.into(), 7.into())),
+            ]
+        );
+        let inner = nodes.clone().next().unwrap();
+        drop(nodes);
+        assert_eq!(inner.first_token().unwrap().text(), "bar");
+    }
+
+    #[test]
+    fn preorder() {
+        let root = SyntaxNode::new_root(sample());
+        let events = root
+            .preorder()
+            .map(|event| match event {
+                WalkEvent::Enter(node) => format!("> {:?}", node.kind()),
+                WalkEvent::Leave(node) => format!("< {:?}", node.kind()),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                "> SyntaxKind(3)",
+                "> SyntaxKind(2)",
+                "> SyntaxKind(2)",
+                "< SyntaxKind(2)",
+                "< SyntaxKind(2)",
+                "< SyntaxKind(3)",
+            ]
+        );
+        assert_eq!(text_of(&root), "foo bar baz");
+        assert_eq!(root.preorder_with_tokens().count(), 2 * 8);
+    }
+
+    #[test]
+    fn offset_queries() {
+        let root = SyntaxNode::new_root(sample());
+        match root.token_at_offset(3.into()) {
+            TokenAtOffset::Between(left, right) => {
+                assert_eq!(left.text(), "foo");
+                assert_eq!(right.text(), " ");
+            }
+            _ => panic!(),
+        }
+        match root.token_at_offset(1.into()) {
+            TokenAtOffset::Single(token) => assert_eq!(token.text(), "foo"),
+            _ => panic!(),
+        }
+        let covering = root.covering_node(TextRange::from_to(4.into(), 6.into()));
+        assert_eq!(covering.text_range(), TextRange::from_to(4.into(), 7.into()));
+        assert_eq!(covering.kind(), WORD);
+        let covering = root.covering_node(TextRange::from_to(2.into(), 6.into()));
+        assert_eq!(covering.kind(), LIST);
+        assert_eq!(covering.text_range(), TextRange::from_to(0.into(), 7.into()));
+    }
+
+    #[test]
+    fn fallible_offset_queries() {
+        let root = SyntaxNode::new_root(sample());
+        let list = root.first_child().unwrap();
+        let len = root.text_range().len().to_usize();
+        for offset in (0..=len).map(TextUnit::from_usize) {
+            let expected = root.token_at_offset(offset).collect::<Vec<_>>();
+            assert_eq!(root.try_token_at_offset(offset).unwrap().collect::<Vec<_>>(), expected);
+            for end in offset.to_usize()..=len {
+                let range = TextRange::from_to(offset, TextUnit::from_usize(end));
+                assert_eq!(root.try_covering_node(range), Ok(root.covering_node(range)));
+            }
+        }
+
+        let err = list.try_token_at_offset(8.into()).unwrap_err();
+        assert_eq!(
+            err,
+            OffsetOutOfRange {
+                kind: LIST,
+                range: TextRange::from_to(0.into(), 7.into()),
+                offset: 8.into()
+            }
+        );
+        assert_eq!(err.to_string(), "offset 8 is outside of SyntaxKind(2)@[0; 7)");
+        let inner = list.last_child().unwrap();
+        assert!(inner.try_token_at_offset(3.into()).is_err());
+        assert!(inner.try_token_at_offset(4.into()).is_ok());
+
+        let range = TextRange::from_to(5.into(), 9.into());
+        let err = list.try_covering_node(range).unwrap_err();
+        assert_eq!(
+            err,
+            RangeOutOfBounds {
+                kind: LIST,
+                node_range: TextRange::from_to(0.into(), 7.into()),
+                range
+            }
+        );
+        assert_eq!(err.to_string(), "range [5; 9) is not within SyntaxKind(2)@[0; 7)");
+        assert!(root.try_covering_node(TextRange::from_to(11.into(), 12.into())).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "token_at_offset: offset 12 is outside of SyntaxKind(3)@[0; 11)")]
+    fn token_at_offset_out_of_range() {
+        SyntaxNode::new_root(sample()).token_at_offset(12.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "covering_node: range [2; 5) is not within SyntaxKind(2)@[4; 7)")]
+    fn covering_node_out_of_bounds() {
+        let inner = SyntaxNode::new_root(sample()).first_child().unwrap().last_child().unwrap();
+        inner.covering_node(TextRange::from_to(2.into(), 5.into()));
+    }
+
+    #[test]
+    fn generated_trees() {
+        let config = test_utils::TreeConfig::default();
+        let (mut empty_nodes, mut token_only_nodes, mut max_chain) = (0, 0, 0);
+        for seed in 0..200 {
+            let green = test_utils::gen_tree(seed, &config);
+            assert_eq!(green.validate(), Ok(()), "seed {}", seed);
+            assert_eq!(green, test_utils::gen_tree(seed, &config));
+            let root = SyntaxNode::new_root(green);
+            for event in root.preorder() {
+                let node = match event {
+                    WalkEvent::Enter(it) => it,
+                    WalkEvent::Leave(_) => continue,
+                };
+                assert!(config.kinds.contains(&node.kind().0), "seed {}", seed);
+                let children = node.green().children();
+                empty_nodes += children.is_empty() as usize;
+                token_only_nodes += (!children.is_empty()
+                    && children.iter().all(|it| matches!(it, GreenElement::Token(_))))
+                    as usize;
+                let chain = node.ancestors().take_while(|it| it.green().children().len() == 1);
+                max_chain = max_chain.max(chain.count());
+                assert!(node.ancestors().count() <= config.max_depth, "seed {}", seed);
+            }
+        }
+        assert!(empty_nodes > 0 && token_only_nodes > 0);
+        assert!(max_chain >= 5, "{}", max_chain);
+    }
+
+    #[test]
+    fn covering_nodes_of_generated_trees() {
+        let config = test_utils::TreeConfig { max_children: 8, ..Default::default() };
+        for seed in 0..200 {
+            let root = SyntaxNode::new_root(test_utils::gen_tree(seed, &config));
+            let mut rng = test_utils::Rng::new(seed);
+            for _ in 0..20 {
+                let range = rng.range(root.text_range().len());
+                let covering = root.covering_node(range);
+                assert!(range.is_subrange(&covering.text_range()), "seed {}", seed);
+                // The deepest one: no child covers the range.
+                if let SyntaxElement::Node(node) = covering {
+                    let mut children = node.children_with_tokens();
+                    assert!(
+                        !children.any(|it| range.is_subrange(&it.text_range())),
+                        "seed {}, {:?}",
+                        seed,
+                        range
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn roots_at_a_base() {
+        fn shape(node: &SyntaxNode) -> Vec<(bool, SyntaxKind, TextRange)> {
+            node.preorder_with_tokens()
+                .map(|event| match event {
+                    WalkEvent::Enter(it) => (true, it.kind(), it.text_range()),
+                    WalkEvent::Leave(it) => (false, it.kind(), it.text_range()),
+                })
+                .collect()
+        }
+        let ranges = |tokens: TokenAtOffset<SyntaxToken>| {
+            tokens.map(|it| (it.kind(), it.text_range())).collect::<Vec<_>>()
+        };
+
+        let config = test_utils::TreeConfig { max_children: 6, max_depth: 6, ..Default::default() };
+        for seed in 0..20 {
+            let root = SyntaxNode::new_root(test_utils::gen_tree(seed, &config));
+            let mut rng = test_utils::Rng::new(seed);
+            for node in root.preorder().filter_map(|event| match event {
+                WalkEvent::Enter(it) => Some(it),
+                WalkEvent::Leave(_) => None,
+            }) {
+                let range = node.text_range();
+                let rerooted = SyntaxNode::new_root_at(node.green().clone(), range.start());
+                assert_eq!(rerooted.text_range(), range);
+                assert!(rerooted.parent().is_none());
+                assert_eq!(shape(&rerooted), shape(&node), "seed {}", seed);
+
+                let offset = range.start() + rng.offset(range.len());
+                assert_eq!(
+                    ranges(rerooted.token_at_offset(offset)),
+                    ranges(node.token_at_offset(offset))
+                );
+                let sub = rng.range(range.len());
+                let sub = TextRange::offset_len(range.start() + sub.start(), sub.len());
+                let covering = rerooted.covering_node(sub);
+                assert_eq!(covering.text_range(), node.covering_node(sub).text_range());
+                if let SyntaxElement::Node(it) = covering {
+                    assert_eq!(it.handle().into_node().text_range(), it.text_range());
+                }
+                assert_eq!(rerooted.validate_invariants(), Ok(()));
+            }
+        }
+
+        let root =
+            SyntaxNode::new_root_at(SyntaxNode::new_root(sample()).green().clone(), 100.into());
+        assert_eq!(root.try_token_at_offset(3.into()).unwrap_err().range.start(), 100.into());
+        assert_ne!(root, SyntaxNode::new_root(root.green().clone()));
+    }
+
+    #[test]
+    #[should_panic(expected = "SyntaxNode::new_root_at: a SyntaxKind(3) tree of length 11 at \
+                               4294967290 ends past MAX_TEXT_LEN")]
+    fn roots_past_max_text_len() {
+        let base = TextUnit::from_usize(MAX_TEXT_LEN - 5);
+        SyntaxNode::new_root_at(SyntaxNode::new_root(sample()).green().clone(), base);
+    }
+
+    #[test]
+    fn offset_queries_in_a_wide_node() {
+        // The lookups, as they were done before the nodes had offset tables.
+        fn naive_token_at_offset(node: &SyntaxNode, offset: TextUnit) -> Vec<SyntaxToken> {
+            let mut children = node.children_with_tokens().filter(|child| {
+                let range = child.text_range();
+                !range.is_empty() && range.start() <= offset && offset <= range.end()
+            });
+            let left = children.next().unwrap();
+            let right = children.next();
+            let tokens = |element: SyntaxElement| match element {
+                SyntaxElement::Token(it) => vec![it],
+                SyntaxElement::Node(it) => naive_token_at_offset(&it, offset),
+            };
+            let mut res = tokens(left);
+            res.extend(right.map(tokens).unwrap_or_default());
+            res
+        }
+        fn naive_covering_node(node: &SyntaxNode, range: TextRange) -> SyntaxElement {
+            match node.children_with_tokens().find(|it| range.is_subrange(&it.text_range())) {
+                Some(SyntaxElement::Node(it)) => naive_covering_node(&it, range),
+                Some(token) => token,
+                None => node.clone().into(),
+            }
+        }
+
+        let texts = ["foo", "", " ", "", "", "bar"];
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(ROOT);
+        for i in 0..20_000 {
+            if i % 10 == 0 {
+                builder.start_node(LIST);
+                builder.token(WORD, "x".into());
+                builder.token(WS, "".into());
+                builder.finish_node();
+            } else {
+                builder.token(WORD, texts[i * 7 % texts.len()].into());
+            }
+        }
+        builder.finish_node();
+        let root = SyntaxNode::new_root(builder.finish());
+
+        let len = root.text_range().len().to_usize();
+        for offset in (0..=len).step_by(211).chain(len - 10..=len).map(TextUnit::from_usize) {
+            let actual: Vec<SyntaxToken> = root.token_at_offset(offset).collect();
+            assert_eq!(actual, naive_token_at_offset(&root, offset), "{:?}", offset);
+            let range = TextRange::from_to(offset, offset);
+            assert_eq!(root.covering_node(range), naive_covering_node(&root, range));
+            if offset.to_usize() + 2 <= len {
+                let range = TextRange::offset_len(offset, 2.into());
+                assert_eq!(root.covering_node(range), naive_covering_node(&root, range));
+            }
+        }
+    }
+
+    #[test]
+    fn handles() {
+        let root = SyntaxNode::new_root(test_utils::nested_tree(1_000));
+        let nodes = root
+            .preorder()
+            .filter_map(|event| match event {
+                WalkEvent::Enter(node) => Some(node),
+                WalkEvent::Leave(_) => None,
+            })
+            .collect::<Vec<_>>();
+        assert!(nodes.iter().any(|it| it.ancestors().count() == 3));
+        for node in nodes.iter() {
+            let handle = node.handle();
+            assert_eq!(handle.to_node(&root).as_ref(), Some(node));
+            // A new root is never equal to the old one, so compare the rest.
+            let resolved = handle.into_node();
+            assert_eq!(resolved.green(), node.green());
+            assert_eq!(resolved.text_range(), node.text_range());
+            assert_eq!(resolved.ancestors().count(), node.ancestors().count());
+        }
+
+        // Handles resolve in other trees as long as every node on the path
+        // is still there.
+        let list = SyntaxNode::new_root(sample()).first_child().unwrap();
+        let inner = list.last_child().unwrap();
+        let handle = inner.handle();
+        let renamed = SyntaxNode::new_root(inner.first_token().unwrap().with_text("qux"));
+        assert_eq!(handle.to_node(&renamed).unwrap().text_range(), inner.text_range());
+        let longer = SyntaxNode::new_root(list.first_token().unwrap().with_text("quux"));
+        assert!(handle.to_node(&longer).is_none());
+        let retagged = SyntaxNode::new_root(list.retag(ROOT));
+        assert!(handle.to_node(&retagged).is_none());
+        let removed = SyntaxNode::new_root(list.remove_children(2..3));
+        assert!(handle.to_node(&removed).is_none());
+        assert!(handle.to_node(&root).is_none());
+    }
+
+    #[test]
+    fn handles_cross_threads() {
+        let (send, recv) = std::sync::mpsc::channel();
+        let sender = std::thread::spawn(move || {
+            let root = SyntaxNode::new_root(sample());
+            let list = root.first_child().unwrap();
+            for node in [root.clone(), list.clone(), list.last_child().unwrap()] {
+                send.send((node.handle(), node.text_range(), text_of(&node))).unwrap();
+            }
+        });
+        let mut n = 0;
+        for (handle, range, text) in recv {
+            let node = handle.into_node();
+            assert_eq!(node.text_range(), range);
+            assert_eq!(text_of(&node), text);
+            n += 1;
+        }
+        assert_eq!(n, 3);
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn kind_set_navigation() {
+        const LISTS: SyntaxKindSet = SyntaxKindSet::new(&[LIST]);
+        const WORDS: SyntaxKindSet = SyntaxKindSet::new(&[WORD]);
+        let root = SyntaxNode::new_root(sample());
+        let list = root.first_child().unwrap();
+        let inner = list.last_child().unwrap();
+
+        assert_eq!(root.children_matching(LISTS).collect::<Vec<_>>(), vec![list.clone()]);
+        assert_eq!(list.children_matching(LISTS).collect::<Vec<_>>(), vec![inner.clone()]);
+        assert_eq!(root.children_matching(WORDS).count(), 0);
+        assert_eq!(inner.ancestor_matching(LISTS), Some(inner.clone()));
+        assert_eq!(inner.ancestor_matching(SyntaxKindSet::new(&[ROOT, WS])), Some(root.clone()));
+        assert_eq!(inner.ancestor_matching(WORDS), None);
+
+        // foo, " ", bar, " ", baz
+        let foo = root.first_token().unwrap();
+        let words = iter::successors(Some(foo.clone()), |it| it.next_token_in(WORDS))
+            .map(|it| it.text().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(words, ["foo", "bar", "baz"]);
+        assert_eq!(foo.next_token_in(SyntaxKindSet::new(&[ROOT])), None);
+        let baz = root.last_token().unwrap();
+        assert_eq!(baz.prev_token_in(WORDS).unwrap().text(), "bar");
+        assert_eq!(foo.prev_token_in(WORDS.union(LISTS)), None);
+    }
+
+    #[test]
+    fn ancestry() {
+        // ROOT@[0; 6)
+        //   LIST@[0; 3)
+        //     LIST@[0; 3)
+        //       WORD "foo"
+        //   WORD "bar"
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(ROOT);
+        builder.start_node(LIST);
+        builder.start_node(LIST);
+        builder.token(WORD, "foo".into());
+        builder.finish_node();
+        builder.finish_node();
+        builder.token(WORD, "bar".into());
+        builder.finish_node();
+        let green = builder.finish();
+        let root = SyntaxNode::new_root(green.clone());
+        let outer = root.first_child().unwrap();
+        let wrapped = outer.first_child().unwrap();
+        assert_eq!(outer.text_range(), wrapped.text_range());
+
+        assert!(root.is_ancestor_of(&wrapped));
+        assert!(outer.is_ancestor_of(&wrapped));
+        assert!(!wrapped.is_ancestor_of(&outer));
+        assert!(!outer.is_ancestor_of(&outer));
+        assert!(outer.contains_inclusive(&outer));
+        assert!(outer.contains_inclusive(&wrapped));
+        assert!(!wrapped.contains_inclusive(&outer));
+        assert!(!wrapped.contains_inclusive(&root));
+
+        let foo = wrapped.first_token().unwrap();
+        let bar = root.last_token().unwrap();
+        assert!(wrapped.contains_element(&foo.into()));
+        assert!(!outer.contains_element(&bar.clone().into()));
+        assert!(root.contains_element(&bar.into()));
+        assert!(root.contains_element(&root.clone().into()));
+
+        // Another tree of the same green nodes: the nodes are neither equal,
+        // nor related.
+        let other = SyntaxNode::new_root(green);
+        let other_wrapped = other.first_child().unwrap().first_child().unwrap();
+        assert_ne!(other_wrapped, wrapped);
+        assert!(!root.is_ancestor_of(&other_wrapped));
+        assert!(!outer.contains_inclusive(&other_wrapped));
+        assert!(!wrapped.contains_inclusive(&other_wrapped));
+        let other_foo = other_wrapped.first_token().unwrap();
+        assert!(!wrapped.contains_element(&other_foo.into()));
+    }
+
+    #[test]
+    fn filtered_content_hashes() {
+        fn tree(tokens: &[(SyntaxKind, &str)], inner: &[(SyntaxKind, &str)]) -> SyntaxNode {
+            let mut builder = GreenNodeBuilder::new();
+            builder.start_node(ROOT);
+            for &(kind, text) in tokens {
+                builder.token(kind, text.into());
+            }
+            builder.start_node(LIST);
+            for &(kind, text) in inner {
+                builder.token(kind, text.into());
+            }
+            builder.finish_node();
+            builder.finish_node();
+            SyntaxNode::new_root(builder.finish())
+        }
+        let hash = |node: &SyntaxNode| node.content_hash_filtered(92, |kind| kind == WS);
+        let foo_bar = tree(&[(WORD, "foo"), (WS, " "), (WORD, "bar")], &[(WORD, "x")]);
+        let spaced = tree(&[(WS, "\n"), (WORD, "foo"), (WS, "  "), (WORD, "bar")], &[(WORD, "x")]);
+        assert_eq!(hash(&foo_bar), hash(&spaced));
+        assert_ne!(foo_bar.green().content_hash(), spaced.green().content_hash());
+
+        let foo_baz = tree(&[(WORD, "foo"), (WS, " "), (WORD, "baz")], &[(WORD, "x")]);
+        assert_ne!(hash(&foo_bar), hash(&foo_baz));
+        let foobar = tree(&[(WORD, "foobar")], &[(WORD, "x")]);
+        assert_ne!(hash(&foo_bar), hash(&foobar));
+        // The shape counts, not only the tokens.
+        let moved = tree(&[(WORD, "foo"), (WORD, "bar"), (WORD, "x")], &[]);
+        assert_ne!(hash(&foo_bar), hash(&moved));
+        assert_ne!(foo_bar.content_hash_filtered(0, |_| false), hash(&foo_bar));
+        assert_ne!(foo_bar.content_hash_filtered(1, |kind| kind == WS), hash(&foo_bar));
+
+        // Skipping lists skips what's inside of them.
+        let hash = |node: &SyntaxNode| node.content_hash_filtered(92, |kind| kind == LIST);
+        let foo_bar_y = tree(&[(WORD, "foo"), (WS, " "), (WORD, "bar")], &[(WORD, "y")]);
+        assert_eq!(hash(&foo_bar), hash(&foo_bar_y));
+        assert_ne!(hash(&foo_bar), hash(&spaced));
+    }
+
+    #[test]
+    fn same_greens() {
+        let root = SyntaxNode::new_root(sample());
+        let list = root.first_child().unwrap();
+        assert!(list.same_green(&list.clone()));
+        assert_eq!(list.green_ptr_id(), list.clone().green_ptr_id());
+        // Another red node for the same position.
+        let again = root.first_child().unwrap();
+        assert!(!Rc::ptr_eq(&list.0, &again.0));
+        assert!(list.same_green(&again));
+        assert_eq!(list.green_ptr_id(), again.green_ptr_id());
+        let other_tree = SyntaxNode::new_root(root.green().clone());
+        assert!(other_tree.same_green(&root));
+        assert!(!list.same_green(&list.last_child().unwrap()));
+        assert_ne!(list.green_ptr_id(), root.green_ptr_id());
+
+        let foo = root.first_token().unwrap();
+        assert!(foo.same_green(&root.first_token().unwrap()));
+        assert!(!foo.same_green(&root.last_token().unwrap()));
+        let foo: SyntaxElement = foo.into();
+        assert!(foo.same_green(&list.first_child_or_token().unwrap()));
+        assert!(!foo.same_green(&list.clone().into()));
+        assert!(SyntaxElement::from(list.clone()).same_green(&again.into()));
+
+        // Equal but separately built trees.
+        let copy = SyntaxNode::new_root(sample());
+        assert_eq!(copy.green(), root.green());
+        assert!(!copy.same_green(&root));
+        assert_ne!(copy.green_ptr_id(), root.green_ptr_id());
+        assert!(!copy.first_child().unwrap().same_green(&list));
+        assert!(!copy.first_token().unwrap().same_green(&root.first_token().unwrap()));
+    }
+
+    #[test]
+    fn equality_of_shared_greens() {
+        fn hash(node: &SyntaxNode) -> u64 {
+            let mut hasher = FxHasher::default();
+            node.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        let root = SyntaxNode::new_root(sample());
+        let list = root.first_child().unwrap();
+        // Different red nodes for the same position.
+        let again = root.first_child().unwrap();
+        assert!(!Rc::ptr_eq(&list.0, &again.0));
+        assert_eq!(list, again);
+        assert_eq!(hash(&list), hash(&again));
+        let inner = list.last_child().unwrap();
+        assert_eq!(inner, again.last_child().unwrap());
+
+        // The same green node at the same offset of two trees.
+        let other_root = SyntaxNode::new_root(root.green().clone());
+        let other_list = other_root.first_child().unwrap();
+        assert_ne!(other_list, list);
+        assert_ne!(other_root, root);
+        let set = vec![list.clone(), other_list.clone()].into_iter().collect::<HashSet<_>>();
+        assert_eq!(set.len(), 2);
+        assert!(set.contains(&again));
+
+        // Adjacent empty lists, deduplicated by the builder into one green
+        // node, at the same offset of the same tree.
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(ROOT);
+        builder.start_node(LIST);
+        builder.finish_node();
+        builder.start_node(LIST);
+        builder.finish_node();
+        builder.finish_node();
+        let root = SyntaxNode::new_root(builder.finish());
+        let first = root.first_child().unwrap();
+        let second = root.last_child().unwrap();
+        assert!(first.same_green(&second));
+        assert_eq!(first.text_range(), second.text_range());
+        assert_ne!(first, second);
+        assert_eq!(second, first.next_sibling().unwrap());
+        assert!(!first.contains_inclusive(&second));
+    }
+
+    #[test]
+    fn equality_of_tokens() {
+        fn tokens(root: &SyntaxNode) -> Vec<SyntaxToken> {
+            root.preorder_with_tokens()
+                .filter_map(|event| match event {
+                    WalkEvent::Enter(SyntaxElement::Token(it)) => Some(it),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        let root = SyntaxNode::new_root(sample());
+        let (first, second) = (tokens(&root), tokens(&root));
+        assert_eq!(first, second);
+        assert!(first.iter().zip(second.iter()).all(|(a, b)| a.same_token(b)));
+        let set = first.iter().cloned().collect::<HashSet<_>>();
+        assert!(second.iter().all(|it| set.contains(it)));
+
+        // A subtree shared by two trees, at the same offset of both.
+        let list = root.first_child().unwrap().green().clone();
+        let mount = |ws: &str| {
+            let ws = GreenToken::new(WS, ws.into());
+            let root = GreenNode::new(ROOT, vec![list.clone().into(), ws.into()].into());
+            SyntaxNode::new_root(root)
+        };
+        let (a, b) = (mount(" "), mount("  "));
+        let (a_foo, b_foo) = (a.first_token().unwrap(), b.first_token().unwrap());
+        assert!(a_foo.same_green(&b_foo));
+        assert_eq!(a_foo.text_range(), b_foo.text_range());
+        assert!(!a_foo.same_token(&b_foo));
+        assert_ne!(a_foo, b_foo);
+        let set = tokens(&a).into_iter().collect::<HashSet<_>>();
+        assert!(tokens(&b).iter().all(|it| !set.contains(it)));
+        assert!(tokens(&a).iter().all(|it| set.contains(it)));
+    }
+
+    #[test]
+    fn invariants() {
+        let root = SyntaxNode::new_root(sample());
+        for event in root.preorder() {
+            if let WalkEvent::Enter(node) = event {
+                assert_eq!(node.validate_invariants(), Ok(()));
+            }
+        }
+
+        // The inner list really starts at 4.
+        let list = root.first_child().unwrap();
+        let inner = match &list.green().children()[2] {
+            GreenElement::Node(it) => it,
+            GreenElement::Token(_) => unreachable!(),
+        };
+        let bad = SyntaxNode::new_child(inner, list.clone(), 2, 5.into());
+        let err = bad.validate_invariants().unwrap_err();
+        assert_eq!(err.path(), &[(0, LIST), (2, LIST)]);
+        assert!(err.to_string().ends_with("wrong node offset, expected 4, got 5"));
+        // Only the ancestors are checked, not the siblings.
+        assert_eq!(list.validate_invariants(), Ok(()));
+    }
+
+    // The corrupt tree can't even be created with the checks enabled.
+    #[cfg(not(all(debug_assertions, feature = "validate-trees")))]
+    #[test]
+    fn invariants_of_corrupt_greens() {
+        let bad = GreenToken::with_corrupt_text_len(WORD, "bar".into(), 2.into());
+        let list = GreenNode::new(LIST, vec![bad.into()].into());
+        let ws = GreenToken::new(WS, " ".into());
+        let root = GreenNode::new(ROOT, vec![ws.into(), list.into()].into());
+        let root = SyntaxNode::new_root(root);
+        let err = root.last_child().unwrap().validate_invariants().unwrap_err();
+        assert_eq!(err.path(), &[(1, LIST), (0, WORD)]);
+        assert_eq!(root.validate_invariants().unwrap_err(), err);
+        assert_eq!(root.first_token().unwrap().parent().validate_invariants(), Err(err));
+    }
+
+    // The corrupt tree can't even be created with the checks enabled.
+    #[cfg(not(all(debug_assertions, feature = "validate-trees")))]
+    #[test]
+    #[should_panic(expected = "corrupt green tree: child 1 of a SyntaxKind(1001) LIST node, \
+                               a SyntaxKind(1000) WORD of length 3, \
+                               does not fit before its end at 2")]
+    fn children_of_corrupt_greens() {
+        // With the kinds which have names in the messages.
+        crate::diagnostics::set_test_kind_names();
+        let word = |text: &str| GreenToken::new(SyntaxKind(1000), text.into()).into();
+        let list = GreenNode::new(SyntaxKind(1001), vec![word("foo"), word("bar")].into());
+        // Claims to be shorter than its children.
+        let list = list.with_corrupt_text_len(2.into());
+        let root = SyntaxNode::new_root(GreenNode::new(ROOT, vec![list.into()].into()));
+        let list = root.first_child().unwrap();
+        assert_eq!(list.first_child_or_token().unwrap().text_range().len(), 3.into());
+        list.last_child_or_token();
+    }
+
+    #[cfg(all(debug_assertions, feature = "validate-trees"))]
+    #[test]
+    #[should_panic(expected = "token length is not the length of its text")]
+    fn new_root_validates() {
+        let bad = GreenToken::with_corrupt_text_len(WORD, "bar".into(), 2.into());
+        SyntaxNode::new_root(GreenNode::new(ROOT, vec![bad.into()].into()));
+    }
+
+    #[test]
+    fn node_ptrs() {
+        let root = SyntaxNode::new_root(sample());
+        let nodes = root
+            .preorder()
+            .filter_map(|event| match event {
+                WalkEvent::Enter(it) => Some(it),
+                WalkEvent::Leave(_) => None,
+            })
+            .collect::<Vec<_>>();
+        for node in nodes.iter() {
+            assert_eq!(SyntaxNodePtr::new(node).to_node(&root).as_ref(), Some(node));
+        }
+        let ptrs = nodes.iter().map(SyntaxNodePtr::new).collect::<Vec<_>>();
+        let mut sorted = ptrs.clone();
+        sorted.sort();
+        assert_eq!(sorted, ptrs);
+
+        // Growing `foo` moves everything after it, and resizes its ancestors.
+        let foo = root.first_token().unwrap();
+        let edited = SyntaxNode::new_root(foo.with_text("fooo"));
+        assert!(ptrs.iter().all(|ptr| ptr.to_node(&edited).is_none()));
+        // Replacing `baz` keeps the first list.
+        let baz = root.last_token().unwrap();
+        let edited = SyntaxNode::new_root(baz.with_text("qux"));
+        let list = ptrs[1].to_node(&edited).unwrap();
+        assert_eq!(text_of(&list), "foo bar");
+        assert!(SyntaxNodePtr::new(&edited).to_node(&edited).is_some());
+
+        // The first list is not within the second one, and the range of the
+        // root is too big for it.
+        let inner = nodes[2].clone();
+        assert!(ptrs[1].to_node(&inner).is_none());
+        assert!(ptrs[0].to_node(&nodes[1]).is_none());
+        // Neither is anything within a tree which is too short.
+        let short = SyntaxNode::new_root(test_utils::wide_tree(1));
+        assert!(ptrs.iter().all(|ptr| ptr.to_node(&short).is_none()));
+    }
+
+    #[test]
+    fn node_ptrs_from_str() {
+        let root = SyntaxNode::new_root(sample());
+        for event in root.preorder() {
+            let ptr = match event {
+                WalkEvent::Enter(node) => SyntaxNodePtr::new(&node),
+                WalkEvent::Leave(_) => continue,
+            };
+            assert_eq!(ptr.to_string().parse(), Ok(ptr));
+        }
+        let ptr = SyntaxNodePtr::new(&root.first_child().unwrap().last_child().unwrap());
+        assert_eq!(ptr.to_string(), "2@4..7");
+        assert_eq!(
+            "65535@0..4294967295".parse::<SyntaxNodePtr>().unwrap().to_string(),
+            "65535@0..4294967295"
+        );
+
+        let error = |text: &str| text.parse::<SyntaxNodePtr>().unwrap_err().to_string();
+        assert_eq!(
+            error("2@7..4"),
+            "invalid syntax node pointer: the start of the range is after the end"
+        );
+        assert_eq!(error("2@4-7"), "invalid syntax node pointer: expected `kind@start..end`");
+        assert_eq!(error("2:4..7"), "invalid syntax node pointer: expected `kind@start..end`");
+        assert_eq!(error("x@4..7"), "invalid syntax node pointer: expected a number");
+        assert_eq!(error("2@+4..7"), "invalid syntax node pointer: expected a number");
+        assert_eq!(error("2@4..7 "), "invalid syntax node pointer: expected a number");
+        assert_eq!(error("2@..7"), "invalid syntax node pointer: expected a number");
+        assert_eq!(error("65536@4..7"), "invalid syntax node pointer: number is too large");
+        assert_eq!(error("2@4..4294967296"), "invalid syntax node pointer: number is too large");
+    }
+
+    #[test]
+    fn node_ptrs_to_nested_nodes() {
+        // ROOT@[0; 3)
+        //   LIST@[0; 3)
+        //     LIST@[0; 3)
+        //       WORD "foo"
+        //   LIST@[3; 3)
+        //     LIST@[3; 3)
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(ROOT);
+        builder.start_node(LIST);
+        builder.start_node(LIST);
+        builder.token(WORD, "foo".into());
+        builder.finish_node();
+        builder.finish_node();
+        builder.start_node(LIST);
+        builder.start_node(LIST);
+        builder.finish_node();
+        builder.finish_node();
+        builder.finish_node();
+        let root = SyntaxNode::new_root(builder.finish());
+
+        let outer = root.first_child().unwrap();
+        let inner = outer.first_child().unwrap();
+        // Both lists have the same pointer, which resolves to the outer one.
+        assert_eq!(SyntaxNodePtr::new(&inner), SyntaxNodePtr::new(&outer));
+        assert_eq!(SyntaxNodePtr::new(&inner).to_node(&root), Some(outer.clone()));
+        assert_eq!(SyntaxNodePtr::new(&inner).to_node(&inner), Some(inner.clone()));
+
+        // Empty nodes touch the list before them.
+        let empty = root.last_child().unwrap();
+        assert_eq!(SyntaxNodePtr::new(&empty.first_child().unwrap()).to_node(&root), Some(empty));
+        let ptr = SyntaxNodePtr { kind: WORD, range: outer.text_range() };
+        assert_eq!(ptr.to_node(&root), None);
+    }
+
+    #[test]
+    fn parallel_map_subtrees() {
+        let mut rng = test_utils::Rng::new(92);
+        let root = SyntaxNode::new_root(test_utils::mixed_tree(&mut rng, 10_000));
+        for depth in 0..4 {
+            let sequential = root
+                .preorder()
+                .filter_map(|event| match event {
+                    WalkEvent::Enter(node) if node.ancestors().count() == depth + 1 => Some(node),
+                    _ => None,
+                })
+                .map(|node| (node.text_range().len(), node.subtree_token_count()))
+                .collect::<Vec<_>>();
+            let parallel = root.parallel_map_subtrees(depth, |node| {
+                // Subtrees are detached.
+                assert!(node.parent().is_none());
+                assert_eq!(node.text_range().start(), 0.into());
+                let n_tokens = node
+                    .preorder_with_tokens()
+                    .filter(|event| matches!(event, WalkEvent::Enter(SyntaxElement::Token(_))))
+                    .count();
+                (node.text_range().len(), n_tokens as u32)
+            });
+            assert!(!parallel.is_empty());
+            assert_eq!(parallel, sequential);
+        }
+        assert_eq!(root.parallel_map_subtrees(100, |node| node.kind()), vec![]);
+
+        // No more threads than cores, however many subtrees there are.
+        let threads = std::sync::Mutex::new(HashSet::new());
+        let n_subtrees = root
+            .parallel_map_subtrees(3, |_| {
+                threads.lock().unwrap().insert(std::thread::current().id())
+            })
+            .len();
+        let n_threads = std::thread::available_parallelism().map_or(1, |it| it.get());
+        assert!(threads.into_inner().unwrap().len() <= n_threads.min(n_subtrees));
+    }
+
+    #[test]
+    fn replace_with() {
+        let root = SyntaxNode::new_root(sample());
+        let bar = root.first_child().unwrap().last_child().unwrap();
+        let replacement = GreenNode::new(
+            LIST,
+            vec![GreenToken::new(WORD, "quux".into()).into()].into_boxed_slice(),
+        );
+        let new_root = SyntaxNode::new_root(bar.replace_with(replacement));
+        assert_eq!(text_of(&new_root), "foo quux baz");
+        // The untouched subtree is shared.
+        assert!(match (&root.green().children()[2], &new_root.green().children()[2]) {
+            (GreenElement::Token(old), GreenElement::Token(new)) => old == new,
+            _ => false,
+        });
+
+        let baz = root.last_token().unwrap();
+        let new_root = SyntaxNode::new_root(baz.replace_with(GreenToken::new(WORD, "qux".into())));
+        assert_eq!(text_of(&new_root), "foo bar qux");
+    }
+
+    #[test]
+    fn subtree_counts() {
+        let root = SyntaxNode::new_root(sample());
+        let nodes = root.preorder().filter_map(|event| match event {
+            WalkEvent::Enter(node) => Some(node),
+            WalkEvent::Leave(_) => None,
+        });
+        for node in nodes {
+            let (mut n_nodes, mut n_tokens) = (0, 0);
+            for event in node.preorder_with_tokens() {
+                match event {
+                    WalkEvent::Enter(SyntaxElement::Node(_)) => n_nodes += 1,
+                    WalkEvent::Enter(SyntaxElement::Token(_)) => n_tokens += 1,
+                    WalkEvent::Leave(_) => (),
+                }
+            }
+            assert_eq!(node.subtree_node_count(), n_nodes);
+            assert_eq!(node.subtree_token_count(), n_tokens);
+        }
+        assert_eq!((root.subtree_node_count(), root.subtree_token_count()), (3, 5));
+    }
+
+    #[test]
+    fn line_of_offset() {
+        fn parse(text: &str) -> SyntaxNode {
+            // One token per line, with the line breaks as separate tokens
+            // and every other line wrapped in a `LIST`.
+            let mut builder = GreenNodeBuilder::new();
+            builder.start_node(ROOT);
+            for (i, line) in text.split_inclusive('\n').enumerate() {
+                if i % 2 == 1 {
+                    builder.start_node(LIST);
+                }
+                let (line, nl) = match line.find(['\r', '\n']) {
+                    Some(idx) => line.split_at(idx),
+                    None => (line, ""),
+                };
+                builder.token(WORD, line.into());
+                if !nl.is_empty() {
+                    builder.token(WS, nl.into());
+                }
+                if i % 2 == 1 {
+                    builder.finish_node();
+                }
+            }
+            builder.finish_node();
+            SyntaxNode::new_root(builder.finish())
+        }
+        // The straightforward way: a sorted list of line starts.
+        fn line_index(text: &str, offset: usize) -> u32 {
+            let line_starts = std::iter::once(0)
+                .chain(text.match_indices('\n').map(|(idx, _)| idx + 1))
+                .collect::<Vec<_>>();
+            match line_starts.binary_search(&offset) {
+                Ok(line) => line as u32,
+                Err(next_line) => next_line as u32 - 1,
+            }
+        }
+
+        let fixtures = [
+            "",
+            "\n",
+            "one line",
+            "fn main() {\n    92\n}\n",
+            "no trailing\nnewline",
+            "crlf\r\nline\r\nendings\r\n",
+            "\n\n\nblank\n\nlines\n",
+            "lone\rcarriage return\n",
+        ];
+        for text in fixtures.iter() {
+            let root = parse(text);
+            assert_eq!(text_of(&root), *text);
+            assert_eq!(root.green().newline_count() as usize, text.matches('\n').count());
+            for offset in 0..=text.len() {
+                let expected = line_index(text, offset);
+                assert_eq!(root.line_of_offset(TextUnit::from_usize(offset)), expected);
+            }
+        }
+
+        let list = SyntaxNode::new_root(sample()).children().next().unwrap();
+        assert_eq!(list.line_of_offset(5.into()), 0);
+    }
+
+    #[test]
+    fn insert_children() {
+        let root = SyntaxNode::new_root(sample());
+        let list = root.first_child().unwrap();
+        let word = |text: &str| -> GreenElement { GreenToken::new(WORD, text.into()).into() };
+        let ws = || -> GreenElement { GreenToken::new(WS, " ".into()).into() };
+
+        let new_root = SyntaxNode::new_root(list.insert_children(0, vec![word("x"), ws()]));
+        assert_eq!(text_of(&new_root), "x foo bar baz");
+        let new_list = new_root.first_child().unwrap();
+        let offsets = new_list
+            .children_with_tokens()
+            .map(|it| it.text_range().start().to_usize())
+            .collect::<Vec<_>>();
+        assert_eq!(offsets, vec![0, 1, 2, 5, 6]);
+        assert_eq!(
+            new_root.last_token().unwrap().text_range(),
+            TextRange::from_to(10.into(), 13.into())
+        );
+
+        let new_root = SyntaxNode::new_root(root.insert_children(3, vec![ws(), word("quux")]));
+        assert_eq!(text_of(&new_root), "foo bar baz quux");
+        let last = new_root.last_token().unwrap();
+        assert_eq!(last.text_range(), TextRange::from_to(12.into(), 16.into()));
+        assert_eq!(
+            last.prev_sibling_or_token().unwrap().text_range(),
+            TextRange::from_to(11.into(), 12.into())
+        );
+
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(ROOT);
+        builder.token(WORD, "a".into());
+        builder.start_node(LIST);
+        builder.finish_node();
+        builder.token(WORD, "b".into());
+        builder.finish_node();
+        let root = SyntaxNode::new_root(builder.finish());
+        let empty = root.first_child().unwrap();
+        let new_root = SyntaxNode::new_root(empty.insert_children(0, vec![word("x"), word("y")]));
+        assert_eq!(text_of(&new_root), "axyb");
+        let new_list = new_root.first_child().unwrap();
+        assert_eq!(new_list.text_range(), TextRange::from_to(1.into(), 3.into()));
+        assert_eq!(
+            new_root.last_token().unwrap().text_range(),
+            TextRange::from_to(3.into(), 4.into())
+        );
+
+        let unchanged = empty.insert_children(0, Vec::new());
+        assert!(unchanged.ptr_eq(root.green()));
+    }
+
+    #[test]
+    #[should_panic(expected = "insert_children: index 4 is out of bounds, the node has 3 children")]
+    fn insert_children_out_of_bounds() {
+        let root = SyntaxNode::new_root(sample());
+        root.insert_children(4, Vec::new());
+    }
+
+    #[test]
+    fn remove_children() {
+        let root = SyntaxNode::new_root(sample());
+        let list = root.first_child().unwrap();
+
+        // Remove `foo` together with the trailing whitespace.
+        let new_root = SyntaxNode::new_root(list.remove_children(0..2));
+        assert_eq!(text_of(&new_root), "bar baz");
+        assert_eq!(
+            new_root.first_token().unwrap().text_range(),
+            TextRange::from_to(0.into(), 3.into())
+        );
+
+        let new_root = SyntaxNode::new_root(root.remove_children(1..3));
+        assert_eq!(text_of(&new_root), "foo bar");
+        assert!(new_root.green().children()[0] == root.green().children()[0]);
+
+        let new_root = SyntaxNode::new_root(list.remove_children(0..3));
+        assert_eq!(text_of(&new_root), " baz");
+        let new_list = new_root.first_child().unwrap();
+        assert_eq!(new_list.kind(), LIST);
+        assert!(new_list.first_child_or_token().is_none());
+
+        let new_root = SyntaxNode::new_root(root.remove_children(0..3));
+        assert_eq!(text_of(&new_root), "");
+        assert_eq!(new_root.kind(), ROOT);
+
+        assert!(list.remove_children(1..1).ptr_eq(root.green()));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "remove_children: range 2..4 is out of bounds, SyntaxKind(2) node has 3 children"
+    )]
+    fn remove_children_out_of_bounds() {
+        let root = SyntaxNode::new_root(sample());
+        root.first_child().unwrap().remove_children(2..4);
+    }
+
+    #[test]
+    fn splice_children() {
+        let root = SyntaxNode::new_root(sample());
+        let word = |text: &str| -> GreenElement { GreenToken::new(WORD, text.into()).into() };
+        let node_ptr_eq = |left: &GreenElement, right: &GreenElement| match (left, right) {
+            (GreenElement::Node(left), GreenElement::Node(right)) => left.ptr_eq(right),
+            (GreenElement::Token(left), GreenElement::Token(right)) => left.ptr_eq(right),
+            _ => false,
+        };
+
+        // Replace ` baz` with `!` at the root.
+        let new_root = SyntaxNode::new_root(root.splice_children(1..3, vec![word("!")]));
+        assert_eq!(text_of(&new_root), "foo bar!");
+        assert!(node_ptr_eq(&new_root.green().children()[0], &root.green().children()[0]));
+
+        // Replace `foo ` in the nested list, the siblings of the list are
+        // shared, as is the untouched `bar` list.
+        let list = root.first_child().unwrap();
+        let new_root = SyntaxNode::new_root(list.splice_children(0..2, vec![word("x"), word("y")]));
+        assert_eq!(text_of(&new_root), "xybar baz");
+        for i in 1..3 {
+            assert!(node_ptr_eq(&new_root.green().children()[i], &root.green().children()[i]));
+        }
+        let new_list = new_root.first_child().unwrap();
+        assert!(node_ptr_eq(&new_list.green().children()[2], &list.green().children()[2]));
+        assert_eq!(
+            new_list.last_child().unwrap().text_range(),
+            TextRange::from_to(2.into(), 5.into())
+        );
+
+        let removed = SyntaxNode::new_root(list.splice_children(1..3, Vec::new()));
+        assert_eq!(text_of(&removed), "foo baz");
+        let inserted = SyntaxNode::new_root(list.splice_children(1..1, vec![word("!")]));
+        assert_eq!(text_of(&inserted), "foo! bar baz");
+        assert!(list.splice_children(2..2, Vec::new()).ptr_eq(root.green()));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "splice_children: range 3..4 is out of bounds, SyntaxKind(3) node has 3 children"
+    )]
+    fn splice_children_out_of_bounds() {
+        SyntaxNode::new_root(sample()).splice_children(3..4, Vec::new());
+    }
+
+    #[test]
+    fn detach() {
+        // ROOT@[0; 11)
+        //   LIST "a"
+        //   WS " "
+        //   LIST "b"
+        //   WS " "
+        //   LIST "c"
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(ROOT);
+        for (i, text) in ["a", "b", "c"].iter().enumerate() {
+            if i > 0 {
+                builder.token(WS, " ".into());
+            }
+            builder.start_node(LIST);
+            builder.token(WORD, (*text).into());
+            builder.finish_node();
+        }
+        builder.finish_node();
+        let root = SyntaxNode::new_root(builder.finish());
+        let lists = root.children().collect::<Vec<_>>();
+        let is_ws = |kind| kind == WS;
+
+        let detached = |green: GreenNode| text_of(&SyntaxNode::new_root(green));
+        assert_eq!(detached(lists[0].detach()), " b c");
+        assert_eq!(detached(lists[1].detach()), "a  c");
+        assert_eq!(detached(lists[2].detach()), "a b ");
+        assert_eq!(detached(lists[0].detach_with_adjacent_trivia(is_ws)), "b c");
+        assert_eq!(detached(lists[1].detach_with_adjacent_trivia(is_ws)), "a c");
+        // Nothing follows the last node, so only the node itself goes away.
+        assert_eq!(detached(lists[2].detach_with_adjacent_trivia(is_ws)), "a b ");
+        // A following node is never removed.
+        assert_eq!(detached(lists[1].detach_with_adjacent_trivia(|_| true)), "a c");
+
+        let nested = SyntaxNode::new_root(sample()).first_child().unwrap().last_child().unwrap();
+        assert_eq!(detached(nested.detach()), "foo  baz");
+
+        let empty = root.detach();
+        assert_eq!(empty.kind(), ROOT);
+        assert!(empty.children().is_empty());
+    }
+
+    #[test]
+    fn with_text_and_kind() {
+        let root = SyntaxNode::new_root(sample());
+        let foo = root.first_token().unwrap();
+        let new_root = SyntaxNode::new_root(foo.with_text("quux"));
+        assert_eq!(text_of(&new_root), "quux bar baz");
+        let new_list = new_root.first_child().unwrap();
+        let old_list = root.first_child().unwrap();
+        // The nested list and the siblings of the parent are shared.
+        assert!(new_list
+            .last_child()
+            .unwrap()
+            .green()
+            .ptr_eq(old_list.last_child().unwrap().green()));
+        for i in 1..3 {
+            assert!(match (&root.green().children()[i], &new_root.green().children()[i]) {
+                (GreenElement::Token(old), GreenElement::Token(new)) => old.ptr_eq(new),
+                _ => false,
+            });
+        }
+        let ranges = new_root
+            .preorder_with_tokens()
+            .filter_map(|event| match event {
+                WalkEvent::Enter(SyntaxElement::Token(it)) => Some(it.text_range()),
+                _ => None,
+            })
+            .map(|it| (it.start().to_usize(), it.end().to_usize()))
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, vec![(0, 4), (4, 5), (5, 8), (8, 9), (9, 12)]);
+
+        let bar = new_root.first_child().unwrap().last_child().unwrap().first_token().unwrap();
+        let new_root = SyntaxNode::new_root(bar.with_text(""));
+        assert_eq!(text_of(&new_root), "quux  baz");
+        assert_eq!(
+            new_root.last_token().unwrap().text_range(),
+            TextRange::from_to(6.into(), 9.into())
+        );
+
+        let baz = root.last_token().unwrap();
+        let new_root = SyntaxNode::new_root(baz.with_kind(WS));
+        assert_eq!(text_of(&new_root), "foo bar baz");
+        assert_eq!(new_root.last_token().unwrap().kind(), WS);
+        assert!(match (&root.green().children()[0], &new_root.green().children()[0]) {
+            (GreenElement::Node(old), GreenElement::Node(new)) => old.ptr_eq(new),
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn replace_descendants() {
+        let root = SyntaxNode::new_root(sample());
+        let foo = root.first_token().unwrap();
+        let bar = root.first_child().unwrap().last_child().unwrap();
+        let baz = root.last_token().unwrap();
+        let word = |text: &str| -> GreenElement { GreenToken::new(WORD, text.into()).into() };
+        let list = GreenNode::new(LIST, vec![word("x")].into_boxed_slice());
+
+        let new_root = root.replace_descendants(vec![
+            (baz.clone().into(), word("qux")),
+            (foo.clone().into(), word("f")),
+            (bar.clone().into(), list.clone().into()),
+        ]);
+        let new_root = SyntaxNode::new_root(new_root);
+        assert_eq!(text_of(&new_root), "f x qux");
+        let expected = root.first_child().unwrap().replace_with(GreenNode::new(
+            LIST,
+            vec![word("f"), GreenToken::new(WS, " ".into()).into(), list.clone().into()]
+                .into_boxed_slice(),
+        ));
+        let expected = SyntaxNode::new_root(expected).last_token().unwrap().with_text("qux");
+        assert_eq!(*new_root.green(), expected);
+        // The whitespace between the edits is shared.
+        assert!(match (&root.green().children()[1], &new_root.green().children()[1]) {
+            (GreenElement::Token(old), GreenElement::Token(new)) => old.ptr_eq(new),
+            _ => false,
+        });
+
+        // Edits relative to a nested node.
+        let inner = root.first_child().unwrap();
+        let new_root = inner.replace_descendants(vec![(foo.clone().into(), word("oof"))]);
+        assert_eq!(text_of(&SyntaxNode::new_root(new_root)), "oof bar baz");
+
+        let new_root = bar.replace_descendants(vec![(bar.clone().into(), list.clone().into())]);
+        assert_eq!(text_of(&SyntaxNode::new_root(new_root)), "foo x baz");
+        assert!(root.replace_descendants(Vec::new()).ptr_eq(root.green()));
+    }
+
+    #[test]
+    #[should_panic(expected = "replace_descendants: overlapping replacements")]
+    fn replace_descendants_nested() {
+        let root = SyntaxNode::new_root(sample());
+        let list = root.first_child().unwrap();
+        let foo = root.first_token().unwrap();
+        let word: GreenElement = GreenToken::new(WORD, "x".into()).into();
+        root.replace_descendants(vec![
+            (list.clone().into(), list.green().clone().into()),
+            (foo.into(), word),
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "is not a descendant")]
+    fn replace_descendants_outside() {
+        let root = SyntaxNode::new_root(sample());
+        let bar = root.first_child().unwrap().last_child().unwrap();
+        let word: GreenElement = GreenToken::new(WORD, "x".into()).into();
+        bar.replace_descendants(vec![(root.last_token().unwrap().into(), word)]);
+    }
+
+    #[test]
+    fn try_replace_descendants() {
+        let root = SyntaxNode::new_root(sample());
+        let list = root.first_child().unwrap();
+        let foo = root.first_token().unwrap();
+        let bar = list.last_child().unwrap();
+        let word = |text: &str| -> GreenElement { GreenToken::new(WORD, text.into()).into() };
+
+        assert_eq!(
+            bar.try_replace_descendants(vec![(foo.clone().into(), word("x"))]),
+            Err(InvalidReplacement::NotADescendant(foo.clone().into()))
+        );
+        assert_eq!(
+            root.try_replace_descendants(vec![
+                (foo.clone().into(), word("x")),
+                (list.clone().into(), list.green().clone().into()),
+            ]),
+            Err(InvalidReplacement::InsideOfReplaced {
+                element: foo.into(),
+                replaced: list.clone().into()
+            })
+        );
+        let err = root
+            .try_replace_descendants(vec![
+                (bar.clone().into(), word("x")),
+                (bar.clone().into(), word("y")),
+            ])
+            .unwrap_err();
+        assert_eq!(err, InvalidReplacement::ReplacedTwice(bar.into()));
+        assert_eq!(
+            err.to_string(),
+            "overlapping replacements, SyntaxKind(2)@[4; 7) is replaced more than once"
+        );
+        assert_eq!(
+            list.try_replace_descendants(vec![(list.clone().into(), word("x"))]),
+            Err(InvalidReplacement::NodeToToken(WORD))
+        );
+        let ws = GreenNode::new(WS, Box::new([]));
+        assert_eq!(
+            list.try_replace_descendants(vec![(list.clone().into(), ws.into())]),
+            Err(InvalidReplacement::KindMismatch(KindMismatch { expected: LIST, actual: WS }))
+        );
+    }
+
+    #[test]
+    fn replace_with_in_a_deep_tree() {
+        const DEPTH: usize = 100_000;
+        let root = SyntaxNode::new_root(test_utils::deep_tree(DEPTH));
+        let mut leaf = root.clone();
+        while let Some(child) = leaf.first_child() {
+            leaf = child;
+        }
+        let token = leaf.first_token().unwrap();
+
+        let new_root = token.replace_with(GreenToken::new(test_utils::WORD, "yy".into()));
+        assert_eq!(new_root.text_len(), 2.into());
+        let mut node = &new_root;
+        let mut depth = 0;
+        while let Some(GreenElement::Node(child)) = node.children().first() {
+            node = child;
+            depth += 1;
+        }
+        assert_eq!(depth, DEPTH - 1);
+        match node.children().first() {
+            Some(GreenElement::Token(it)) => assert_eq!(it.text(), "yy"),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn drop_the_bottom_of_a_deep_tree() {
+        const DEPTH: usize = 100_000;
+        let green = test_utils::deep_tree(DEPTH);
+        // Freeing the red nodes one by one takes a frame or two of the stack,
+        // but freeing them recursively would take thousands.
+        let thread = std::thread::Builder::new().stack_size(64 * 1024).spawn(move || {
+            let root = SyntaxNode::new_root(green);
+            let mut leaf = root.clone();
+            while let Some(child) = leaf.first_child() {
+                leaf = child;
+            }
+            let token = leaf.first_token().unwrap();
+            drop(root);
+            drop(leaf);
+            assert_eq!(token.parent().ancestors().count(), DEPTH);
+            drop(token);
+
+            // With some of the ancestors still alive.
+            let root = SyntaxNode::new_root(test_utils::deep_tree(DEPTH));
+            let mut path = vec![root];
+            while let Some(child) = path.last().unwrap().first_child() {
+                path.push(child);
+            }
+            let middle = path[DEPTH / 2].clone();
+            drop(path);
+            assert_eq!(middle.ancestors().count(), DEPTH / 2 + 1);
+        });
+        thread.unwrap().join().unwrap();
+    }
+
+    #[test]
+    fn try_replace_with() {
+        let root = SyntaxNode::new_root(sample());
+        let bar = root.first_child().unwrap().last_child().unwrap();
+        let word: GreenElement = GreenToken::new(WORD, "x".into()).into();
+
+        let wrong_kind = GreenNode::new(ROOT, vec![word.clone()].into_boxed_slice());
+        assert_eq!(
+            bar.try_replace_with(wrong_kind),
+            Err(KindMismatch { expected: LIST, actual: ROOT })
+        );
+        let baz = root.last_token().unwrap();
+        let err = baz.try_replace_with(GreenToken::new(WS, " ".into())).unwrap_err();
+        assert_eq!(err, KindMismatch { expected: WORD, actual: WS });
+        assert_eq!(
+            err.to_string(),
+            "expected a replacement of kind SyntaxKind(0), got SyntaxKind(1)"
+        );
+
+        // Same as rebuilding the ancestors by hand.
+        let replacement = GreenNode::new(LIST, vec![word].into_boxed_slice());
+        let list = root.first_child().unwrap();
+        let expected = root
+            .green()
+            .replace_child(0, list.green().replace_child(2, replacement.clone().into()).into());
+        let new_root = bar.try_replace_with(replacement.clone()).unwrap();
+        assert_eq!(new_root, expected);
+        assert_eq!(new_root.content_hash(), expected.content_hash());
+        assert_eq!(bar.replace_with(replacement), expected);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "replace_with: expected a replacement of kind SyntaxKind(0), got SyntaxKind(1)"
+    )]
+    fn replace_with_kind_mismatch() {
+        SyntaxNode::new_root(sample())
+            .last_token()
+            .unwrap()
+            .replace_with(GreenToken::new(WS, " ".into()));
+    }
+
+    #[test]
+    fn retag() {
+        let root = SyntaxNode::new_root(sample());
+        let list = root.first_child().unwrap();
+        let new_root = SyntaxNode::new_root(list.retag(ROOT));
+        assert_eq!(text_of(&new_root), "foo bar baz");
+        let new_list = new_root.first_child().unwrap();
+        assert_eq!(new_list.kind(), ROOT);
+        assert_eq!(new_list.text_range(), list.text_range());
+        for (old, new) in list.children_with_tokens().zip(new_list.children_with_tokens()) {
+            assert_eq!(old.text_range(), new.text_range());
+            assert!(match (old, new) {
+                (SyntaxElement::Node(old), SyntaxElement::Node(new)) =>
+                    old.green().ptr_eq(new.green()),
+                (SyntaxElement::Token(old), SyntaxElement::Token(new)) =>
+                    old.green().ptr_eq(new.green()),
+                _ => false,
+            });
+        }
+        assert_eq!(new_list.last_child().unwrap().parent().unwrap().kind(), ROOT);
+
+        let new_root = SyntaxNode::new_root(root.retag(LIST));
+        assert_eq!(new_root.kind(), LIST);
+        assert!(new_root.first_child().unwrap().green().ptr_eq(list.green()));
+    }
+
+    #[test]
+    fn map_trivia() {
+        // ROOT
+        //   WS "  "
+        //   LIST
+        //     WORD "a"
+        //     WS "   "
+        //     WORD "b"
+        //     WS "\t"
+        //   WS "\n"
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(ROOT);
+        builder.token(WS, "  ".into());
+        builder.start_node(LIST);
+        builder.token(WORD, "a".into());
+        builder.token(WS, "   ".into());
+        builder.token(WORD, "b".into());
+        builder.token(WS, "\t".into());
+        builder.finish_node();
+        builder.token(WS, "\n".into());
+        builder.finish_node();
+        let root = SyntaxNode::new_root(builder.finish());
+        let is_ws = |kind| kind == WS;
+
+        let mut seen = Vec::new();
+        let new_root = root.map_trivia(is_ws, |token| {
+            seen.push(token.text().to_string());
+            if token.text().chars().all(|c| c == ' ') {
+                Some(" ".into())
+            } else {
+                None
+            }
+        });
+        assert_eq!(seen, vec!["  ", "   ", "\t", "\n"]);
+        let new_root = SyntaxNode::new_root(new_root);
+        assert_eq!(text_of(&new_root), " a b\t\n");
+        assert_eq!(
+            new_root.last_token().unwrap().text_range(),
+            TextRange::from_to(5.into(), 6.into())
+        );
+
+        // Delete the whitespace at the end of each node.
+        let new_root = root.edit_trivia(is_ws, |token| {
+            if token.next_sibling_or_token().is_none() {
+                TriviaEdit::Delete
+            } else {
+                TriviaEdit::Keep
+            }
+        });
+        let new_root = SyntaxNode::new_root(new_root);
+        assert_eq!(text_of(&new_root), "  a   b");
+        assert_eq!(new_root.green().children().len(), 2);
+
+        // Only the subtree of the receiver is visited.
+        let list = root.first_child().unwrap();
+        let new_root = list.map_trivia(is_ws, |_| Some("".into()));
+        assert_eq!(text_of(&SyntaxNode::new_root(new_root)), "  ab\n");
+
+        let unchanged = root.map_trivia(is_ws, |token| Some(token.text().into()));
+        assert!(unchanged.ptr_eq(root.green()));
+        assert!(root.edit_trivia(|_| false, |_| TriviaEdit::Delete).ptr_eq(root.green()));
+    }
+
+    #[test]
+    fn token_iteration_order() {
+        for seed in 0..200 {
+            let root = SyntaxNode::new_root(test_utils::gen_tree(
+                seed,
+                &test_utils::TreeConfig::default(),
+            ));
+            let expected = root
+                .preorder_with_tokens()
+                .filter_map(|event| match event {
+                    WalkEvent::Enter(SyntaxElement::Token(it)) => Some(it),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            let forward =
+                iter::successors(root.first_token(), |it| it.next_token()).collect::<Vec<_>>();
+            assert_eq!(forward, expected);
+            let mut backward =
+                iter::successors(root.last_token(), |it| it.prev_token()).collect::<Vec<_>>();
+            backward.reverse();
+            assert_eq!(backward, expected);
+        }
+    }
+
+    #[test]
+    fn sibling_offsets() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(ROOT);
+        for i in 0..100 {
+            if i % 3 == 0 {
+                builder.token(WS, " ".repeat(i % 4).into());
+            } else {
+                builder.start_node(LIST);
+                builder.token(WORD, "x".repeat(i % 5).into());
+                builder.finish_node();
+            }
+        }
+        builder.finish_node();
+        let root = SyntaxNode::new_root(builder.finish());
+
+        let children = root.children().map(|it| it.text_range()).collect::<Vec<_>>();
+        let forward = iter::successors(root.first_child(), |it| it.next_sibling())
+            .map(|it| it.text_range())
+            .collect::<Vec<_>>();
+        let mut backward = iter::successors(root.last_child(), |it| it.prev_sibling())
+            .map(|it| it.text_range())
+            .collect::<Vec<_>>();
+        backward.reverse();
+        assert_eq!(forward, children);
+        assert_eq!(backward, children);
+
+        let elements = root.children_with_tokens().map(|it| it.text_range()).collect::<Vec<_>>();
+        let forward =
+            iter::successors(root.first_child_or_token(), |it| it.next_sibling_or_token())
+                .map(|it| it.text_range())
+                .collect::<Vec<_>>();
+        let mut backward =
+            iter::successors(root.last_child_or_token(), |it| it.prev_sibling_or_token())
+                .map(|it| it.text_range())
+                .collect::<Vec<_>>();
+        backward.reverse();
+        assert_eq!(forward, elements);
+        assert_eq!(backward, elements);
+    }
+
+    #[test]
+    fn children_nth() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(ROOT);
+        for i in 0..20 {
+            if i % 3 == 0 {
+                builder.token(WORD, "w".into());
+            } else {
+                builder.start_node(LIST);
+                builder.token(WORD, "x".repeat(i).into());
+                builder.finish_node();
+            }
+        }
+        builder.finish_node();
+        let root = SyntaxNode::new_root(builder.finish());
+
+        let nodes = root.children().collect::<Vec<_>>();
+        for k in 0..=nodes.len() {
+            assert_eq!(root.children().nth(k), nodes.get(k).cloned());
+            let mut children = root.children();
+            children.next();
+            assert_eq!(children.nth(k), nodes.get(k + 1).cloned());
+        }
+        let elements = root.children_with_tokens().collect::<Vec<_>>();
+        for k in 0..=elements.len() {
+            assert_eq!(root.children_with_tokens().nth(k), elements.get(k).cloned());
+            let mut children = root.children_with_tokens();
+            children.nth(2);
+            assert_eq!(children.next(), elements.get(3).cloned());
+            assert_eq!(children.nth(k), elements.get(k + 4).cloned());
+        }
+        assert_eq!(root.children_with_tokens().skip(5).step_by(3).count(), 5);
+    }
+
+    fn free_list_len() -> usize {
+        FreeList::with(|it| it.len)
+    }
+
+    /// A root with `n` children nodes.
+    fn wide(n: usize) -> SyntaxNode {
+        SyntaxNode::new_root(test_utils::wide_tree(2 * n))
+    }
+
+    #[cfg(not(feature = "no-free-list"))]
+    #[test]
+    fn free_list_capacity() {
+        // Tests run on separate threads, so the free list is fresh here.
+        assert_eq!(super::free_list_capacity(), DEFAULT_FREE_LIST_CAPACITY);
+        assert_eq!(free_list_len(), 0);
+
+        let root = wide(1000);
+        drop(root.children().collect::<Vec<_>>());
+        assert_eq!(free_list_len(), DEFAULT_FREE_LIST_CAPACITY);
+
+        set_free_list_capacity(0);
+        assert_eq!(free_list_len(), 0);
+        drop(root.children().collect::<Vec<_>>());
+        assert_eq!(free_list_len(), 0);
+
+        set_free_list_capacity(10_000);
+        drop(root.children().collect::<Vec<_>>());
+        assert_eq!(free_list_len(), 1000);
+        // Reused nodes come back from the list.
+        let children = root.children().take(300).collect::<Vec<_>>();
+        assert_eq!(free_list_len(), 700);
+        // Each child is followed by a space.
+        assert_eq!(children[299].text_range(), TextRange::offset_len((299 * 5).into(), 4.into()));
+        drop(children);
+        assert_eq!(free_list_len(), 1000);
+
+        set_free_list_capacity(10);
+        assert_eq!(free_list_len(), 10);
+        assert_eq!(super::free_list_capacity(), 10);
+    }
+
+    #[cfg(not(feature = "no-free-list"))]
+    #[test]
+    fn clear_free_list() {
+        let root = wide(100);
+        drop(root.children().collect::<Vec<_>>());
+        assert_eq!(free_list_len(), 100);
+
+        shrink_free_list_to(200);
+        assert_eq!(free_list_len(), 100);
+        shrink_free_list_to(30);
+        assert_eq!(free_list_len(), 30);
+        // Live nodes are not in the list.
+        let children = root.children().take(10).collect::<Vec<_>>();
+        assert_eq!(free_list_len(), 20);
+        super::clear_free_list();
+        assert_eq!(free_list_len(), 0);
+        assert_eq!(children[9].text_range().start(), (9 * 5).into());
+        assert_eq!(super::free_list_capacity(), DEFAULT_FREE_LIST_CAPACITY);
+        drop(children);
+        assert_eq!(free_list_len(), 10);
+    }
+
+    #[cfg(all(feature = "freelist-stats", not(feature = "no-free-list")))]
+    #[test]
+    fn nodes_after_clear_free_list() {
+        let root = wide(100);
+        drop(root.children().collect::<Vec<_>>());
+        super::clear_free_list();
+        reset_free_list_stats();
+        let _children = root.children().collect::<Vec<_>>();
+        assert_eq!(
+            super::free_list_stats(),
+            FreeListStats { hits: 0, misses: 100, pushes: 0, rejected_pushes: 0, len: 0 }
+        );
+    }
+
+    #[cfg(feature = "node-count")]
+    #[test]
+    fn live_node_counts() {
+        let guard = AssertNoNodesLeaked::new();
+        assert_eq!(live_node_count(), 0);
+        let root = SyntaxNode::new_root(sample());
+        let list = root.first_child().unwrap();
+        let inner = list.last_child().unwrap();
+        assert_eq!(live_node_count(), 3);
+        let again = root.clone();
+        drop(list);
+        // Still the parent of `inner`.
+        assert_eq!(live_node_count(), 3);
+        let token = inner.first_token().unwrap();
+        drop(inner);
+        assert_eq!(live_node_count(), 3);
+        drop(token);
+        assert_eq!(live_node_count(), 1);
+        drop((root, again));
+        assert_eq!(live_node_count(), 0);
+        drop(guard);
+    }
+
+    #[cfg(feature = "node-count")]
+    #[test]
+    #[should_panic(expected = "1 SyntaxNodes leaked: 2 alive, 1 when the guard was created")]
+    fn leaked_nodes() {
+        let root = SyntaxNode::new_root(sample());
+        let _guard = AssertNoNodesLeaked::new();
+        let cached = root.first_child().unwrap().last_child().unwrap();
+        let leaked: &'static mut Option<SyntaxNode> = Box::leak(Box::new(None));
+        *leaked = Some(cached.parent().unwrap());
+        drop(cached);
+    }
+
+    #[cfg(all(feature = "freelist-stats", not(feature = "no-free-list")))]
+    #[test]
+    fn free_list_stats() {
+        let root = wide(100);
+        set_free_list_capacity(50);
+        reset_free_list_stats();
+        assert_eq!(super::free_list_stats(), FreeListStats::default());
+
+        // 100 fresh nodes, half of which fit into the list when dropped.
+        drop(root.children().collect::<Vec<_>>());
+        assert_eq!(
+            super::free_list_stats(),
+            FreeListStats { hits: 0, misses: 100, pushes: 50, rejected_pushes: 50, len: 50 }
+        );
+
+        // 30 of them are reused and returned.
+        reset_free_list_stats();
+        drop(root.children().take(30).collect::<Vec<_>>());
+        assert_eq!(
+            super::free_list_stats(),
+            FreeListStats { hits: 30, misses: 0, pushes: 30, rejected_pushes: 0, len: 50 }
+        );
+
+        // Shrinking doesn't count as reuse.
+        set_free_list_capacity(0);
+        reset_free_list_stats();
+        drop(root.first_child());
+        assert_eq!(
+            super::free_list_stats(),
+            FreeListStats { hits: 0, misses: 1, pushes: 0, rejected_pushes: 1, len: 0 }
+        );
+    }
+}
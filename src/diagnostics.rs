@@ -0,0 +1,176 @@
+//! Names of the kinds in the messages of panics and errors.
+use std::{fmt, sync::RwLock};
+
+use crate::{SyntaxKind, TextRange};
+
+type KindNames = fn(SyntaxKind) -> Option<&'static str>;
+
+static KIND_NAMES: RwLock<Option<KindNames>> = RwLock::new(None);
+
+/// Registers a function naming the kinds, so that the messages of panics and
+/// errors, like those of `token_at_offset` with an offset out of range, tell
+/// which kinds of nodes were involved: `SyntaxKind(2) LIST@[0; 7)` instead of
+/// just `SyntaxKind(2)@[0; 7)`.
+///
+/// The names are global, for all threads, and replace the previously
+/// registered ones.
+pub fn set_kind_names(names: fn(SyntaxKind) -> Option<&'static str>) {
+    // The lock only guards a copy of a pointer, so it can't be poisoned in a
+    // harmful way.
+    *KIND_NAMES.write().unwrap_or_else(|err| err.into_inner()) = Some(names);
+}
+
+pub(crate) fn kind_name(kind: SyntaxKind) -> Option<&'static str> {
+    let names = *KIND_NAMES.read().unwrap_or_else(|err| err.into_inner());
+    names.and_then(|it| it(kind))
+}
+
+/// A kind, with its name if there is one: `SyntaxKind(2) LIST`.
+pub(crate) struct KindDisplay(pub(crate) SyntaxKind);
+
+impl fmt::Display for KindDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.0)?;
+        if let Some(name) = kind_name(self.0) {
+            write!(f, " {}", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// A node or a token: `SyntaxKind(2) LIST@[0; 7)`.
+pub(crate) struct ElementDisplay(pub(crate) SyntaxKind, pub(crate) TextRange);
+
+impl fmt::Display for ElementDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{:?}", KindDisplay(self.0), self.1)
+    }
+}
+
+/// Registers the names of the kinds from 1000 up used by tests. The names
+/// are global, so all tests which need them share these.
+#[cfg(test)]
+pub(crate) fn set_test_kind_names() {
+    set_kind_names(|kind| match kind.0 {
+        1000 => Some("WORD"),
+        1001 => Some("LIST"),
+        1002 => Some("WS"),
+        1003 => Some("STRING"),
+        _ => None,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, UnwindSafe};
+
+    use super::*;
+    use crate::{cursor::SyntaxNode, GreenNode, GreenNodeBuilder, GreenToken, TextUnit};
+
+    // Named by `set_test_kind_names`.
+    const WORD: SyntaxKind = SyntaxKind(1000);
+    const LIST: SyntaxKind = SyntaxKind(1001);
+
+    fn panic_message(f: impl FnOnce() + UnwindSafe) -> String {
+        let err = panic::catch_unwind(f).unwrap_err();
+        match err.downcast::<String>() {
+            Ok(it) => *it,
+            Err(err) => err.downcast_ref::<&str>().unwrap().to_string(),
+        }
+    }
+
+    #[test]
+    fn kind_names_in_panics() {
+        set_test_kind_names();
+        let root = || {
+            let mut builder = GreenNodeBuilder::new();
+            builder.start_node(LIST);
+            builder.token(WORD, "foo".into());
+            builder.finish_node();
+            SyntaxNode::new_root(builder.finish())
+        };
+
+        assert_eq!(
+            panic_message(|| drop(root().token_at_offset(4.into()))),
+            "token_at_offset: offset 4 is outside of SyntaxKind(1001) LIST@[0; 3)"
+        );
+        let range = TextRange::from_to(1.into(), 5.into());
+        assert_eq!(
+            panic_message(move || drop(root().covering_node(range))),
+            "covering_node: range [1; 5) is not within SyntaxKind(1001) LIST@[0; 3)"
+        );
+        // The same through arena trees.
+        assert_eq!(
+            panic_message(|| {
+                let arena = crate::arena::Arena::new();
+                let root = crate::arena::SyntaxNode::new_root(root().green().clone(), &arena);
+                root.token_at_offset(4.into());
+            }),
+            "token_at_offset: offset 4 is outside of SyntaxKind(1001) LIST@[0; 3)"
+        );
+        assert_eq!(
+            panic_message(move || {
+                let arena = crate::arena::Arena::new();
+                let root = crate::arena::SyntaxNode::new_root(root().green().clone(), &arena);
+                root.covering_node(range);
+            }),
+            "covering_node: range [1; 5) is not within SyntaxKind(1001) LIST@[0; 3)"
+        );
+        assert_eq!(
+            panic_message(|| {
+                let token = root().first_token().unwrap();
+                token.replace_with(GreenToken::new(SyntaxKind(7), "x".into()));
+            }),
+            "replace_with: expected a replacement of kind SyntaxKind(1000) WORD, \
+             got SyntaxKind(7), for SyntaxKind(1000) WORD@[0; 3)"
+        );
+        assert_eq!(
+            panic_message(|| drop(root().remove_children(0..2))),
+            "remove_children: range 0..2 is out of bounds, SyntaxKind(1001) LIST node has 1 children"
+        );
+        assert_eq!(
+            panic_message(|| drop(root().splice_children(2..2, Vec::new()))),
+            "splice_children: range 2..2 is out of bounds, SyntaxKind(1001) LIST node has 1 children"
+        );
+        // The same through the exported, legacy, trees.
+        assert_eq!(
+            panic_message(|| {
+                let root = crate::SyntaxNode::new(root().green().clone(), None);
+                root.first_token()
+                    .unwrap()
+                    .replace_with(GreenToken::new(SyntaxKind(7), "x".into()));
+            }),
+            "replace_with: expected a replacement of kind SyntaxKind(1000) WORD, \
+             got SyntaxKind(7), for SyntaxKind(1000) WORD@[0; 3)"
+        );
+        assert_eq!(
+            panic_message(|| {
+                let root = crate::SyntaxNode::new(root().green().clone(), None);
+                root.replace_with(GreenNode::new(WORD, Box::new([])));
+            }),
+            "replace_with: expected a replacement of kind SyntaxKind(1001) LIST, \
+             got SyntaxKind(1000) WORD, for SyntaxKind(1001) LIST@[0; 3)"
+        );
+
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(LIST);
+        builder.token(WORD, "foo".into());
+        assert_eq!(
+            panic_message(|| drop(builder.finish())),
+            "GreenNodeBuilder::finish: unfinished SyntaxKind(1001) LIST node, at offset 3"
+        );
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(LIST);
+        builder.token(WORD, "a".into());
+        builder.token(WORD, "b".into());
+        let checkpoint = builder.checkpoint();
+        builder.finish_node();
+        assert_eq!(
+            panic_message(move || builder.start_node_at(checkpoint, WORD)),
+            "GreenNodeBuilder::start_node_at: checkpoint no longer valid, was finish_node \
+             called early? (starting a SyntaxKind(1000) WORD node at offset 2)"
+        );
+        // Without a name.
+        assert_eq!(KindDisplay(SyntaxKind(7)).to_string(), "SyntaxKind(7)");
+    }
+}
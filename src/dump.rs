@@ -0,0 +1,1016 @@
+//! Text dumps of syntax trees, for debugging and test fixtures.
+use std::{error::Error, fmt, io};
+
+use crate::{
+    cursor::{SyntaxElement, SyntaxNode, SyntaxToken},
+    diagnostics::kind_name,
+    GreenNode, GreenNodeBuilder, SyntaxKind, TextRange, WalkEvent,
+};
+
+/// Token texts longer than this are truncated in DOT labels and in `debug`.
+const PREVIEW_LEN: usize = 20;
+
+impl SyntaxNode {
+    /// Renders the subtree of this node as a Graphviz digraph, see
+    /// `write_dot`.
+    pub fn to_dot(&self, kind_name: impl Fn(SyntaxKind) -> String) -> String {
+        let mut buf = Vec::new();
+        self.write_dot(kind_name, &mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    /// Writes the subtree of this node as a Graphviz digraph to `out`.
+    ///
+    /// Nodes are boxes labeled with the kind and the range. Tokens are
+    /// ellipses, which also show the text, escaped and truncated to 20
+    /// characters. The edges of each node are in the order of its children.
+    pub fn write_dot(
+        &self,
+        kind_name: impl Fn(SyntaxKind) -> String,
+        out: &mut impl io::Write,
+    ) -> io::Result<()> {
+        writeln!(out, "digraph {{")?;
+        writeln!(out, "    node [shape=box];")?;
+        let mut n_elements = 0;
+        // Ids of the nodes on the path from `self`.
+        let mut parents = Vec::new();
+        for event in self.preorder_with_tokens() {
+            let element = match event {
+                WalkEvent::Enter(it) => it,
+                WalkEvent::Leave(SyntaxElement::Node(_)) => {
+                    parents.pop();
+                    continue;
+                }
+                WalkEvent::Leave(SyntaxElement::Token(_)) => continue,
+            };
+            let id = n_elements;
+            n_elements += 1;
+            let (kind, range) = (element.kind(), element.text_range());
+            let label = escape_dot(&format!("{}@{:?}", kind_name(kind), range));
+            match &element {
+                SyntaxElement::Node(_) => writeln!(out, "    n{} [label=\"{}\"];", id, label)?,
+                SyntaxElement::Token(token) => writeln!(
+                    out,
+                    "    n{} [label=\"{}\\n\\\"{}\\\"\" shape=ellipse];",
+                    id,
+                    label,
+                    escape_dot(&preview(token.text()))
+                )?,
+            }
+            if let Some(parent) = parents.last() {
+                writeln!(out, "    n{} -> n{};", parent, id)?;
+            }
+            if let SyntaxElement::Node(_) = element {
+                parents.push(id);
+            }
+        }
+        writeln!(out, "}}")
+    }
+}
+
+impl SyntaxNode {
+    /// Dumps the subtree of this node as an s-expression, with an element
+    /// per line:
+    ///
+    /// ```text
+    /// (LIST
+    ///   (WORD "foo")
+    ///   (WS " "))
+    /// ```
+    ///
+    /// Without `include_text`, tokens are just `(WORD)`. Such a dump only
+    /// shows the shape of the tree: `GreenNode::from_sexpr` reads `(WORD)`
+    /// as a node without children.
+    pub fn to_sexpr(
+        &self,
+        kind_name: &impl Fn(SyntaxKind) -> &'static str,
+        include_text: bool,
+    ) -> String {
+        let mut res = String::new();
+        let mut depth = 0;
+        for event in self.preorder_with_tokens() {
+            match event {
+                WalkEvent::Enter(element) => {
+                    if depth > 0 {
+                        res.push('\n');
+                    }
+                    for _ in 0..depth {
+                        res.push_str("  ");
+                    }
+                    res.push('(');
+                    res.push_str(kind_name(element.kind()));
+                    match element {
+                        SyntaxElement::Node(_) => depth += 1,
+                        SyntaxElement::Token(token) => {
+                            if include_text {
+                                res.push_str(" \"");
+                                escape_sexpr(token.text(), &mut res);
+                                res.push('"');
+                            }
+                            res.push(')');
+                        }
+                    }
+                }
+                WalkEvent::Leave(SyntaxElement::Node(_)) => {
+                    depth -= 1;
+                    res.push(')');
+                }
+                WalkEvent::Leave(SyntaxElement::Token(_)) => (),
+            }
+        }
+        res
+    }
+}
+
+impl SyntaxNode {
+    /// Dumps the subtree of this node with an element per line, indented by
+    /// two spaces per level, and the full text of the tokens:
+    ///
+    /// ```text
+    /// LIST@0..7
+    ///   WORD@0..3 "foo"
+    ///   WS@3..4 "\n"
+    /// ```
+    ///
+    /// Kinds are named by the function registered with `set_kind_names`, if
+    /// any, and printed as `SyntaxKind(1)` otherwise.
+    pub fn debug_dump(&self) -> String {
+        self.dump().to_string()
+    }
+
+    /// `debug_dump` as a value which compares by its dump, and prints it
+    /// with both `Debug` and `Display`, so that `assert_eq!(a.dump(),
+    /// b.dump())` shows two readable trees when it fails.
+    pub fn dump(&self) -> impl fmt::Debug + fmt::Display + PartialEq + '_ {
+        Dump(self)
+    }
+}
+
+struct Dump<'a>(&'a SyntaxNode);
+
+impl fmt::Display for Dump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut depth = 0;
+        for event in self.0.preorder_with_tokens() {
+            let element = match event {
+                WalkEvent::Enter(it) => it,
+                WalkEvent::Leave(SyntaxElement::Node(_)) => {
+                    depth -= 1;
+                    continue;
+                }
+                WalkEvent::Leave(SyntaxElement::Token(_)) => continue,
+            };
+            let range = element.text_range();
+            write!(f, "{:indent$}", "", indent = 2 * depth)?;
+            match kind_name(element.kind()) {
+                Some(name) => f.write_str(name)?,
+                None => write!(f, "{:?}", element.kind())?,
+            }
+            write!(f, "@{}..{}", range.start(), range.end())?;
+            match element {
+                SyntaxElement::Node(_) => depth += 1,
+                SyntaxElement::Token(token) => {
+                    let mut text = String::new();
+                    escape_sexpr(token.text(), &mut text);
+                    write!(f, " \"{}\"", text)?;
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Dump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // A `Debug` of the string would put it on a single line.
+        writeln!(f)?;
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl PartialEq for Dump<'_> {
+    fn eq(&self, other: &Dump<'_>) -> bool {
+        self.to_string() == other.to_string()
+    }
+}
+
+/// The error returned by `GreenNode::from_sexpr`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SexprError {
+    offset: usize,
+    message: String,
+}
+
+impl SexprError {
+    /// Byte offset of the error in the input.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl fmt::Display for SexprError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte {}", self.message, self.offset)
+    }
+}
+
+impl Error for SexprError {}
+
+impl GreenNode {
+    /// Parses a tree from an s-expression, in the format of
+    /// `SyntaxNode::to_sexpr`: `(KIND "text")` is a token, and
+    /// `(KIND children...)` is a node. Whitespace between atoms doesn't
+    /// matter.
+    pub fn from_sexpr(
+        text: &str,
+        kind_by_name: &impl Fn(&str) -> Option<SyntaxKind>,
+    ) -> Result<GreenNode, SexprError> {
+        let mut parser = SexprParser { text, pos: 0 };
+        let mut builder = GreenNodeBuilder::new();
+        let mut depth = 0;
+        loop {
+            parser.skip_whitespace();
+            let start = parser.pos;
+            match parser.bump() {
+                Some('(') => (),
+                Some(')') if depth > 0 => {
+                    builder.finish_node();
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                    continue;
+                }
+                Some(c) => return Err(parser.error_at(start, format!("unexpected `{}`", c))),
+                None => return Err(parser.error_at(start, "unexpected end of input".into())),
+            }
+            parser.skip_whitespace();
+            let name_start = parser.pos;
+            let name = parser.name();
+            if name.is_empty() {
+                return Err(parser.error_at(name_start, "expected a kind".into()));
+            }
+            let kind = match kind_by_name(name) {
+                Some(it) => it,
+                None => return Err(parser.error_at(name_start, format!("unknown kind `{}`", name))),
+            };
+            parser.skip_whitespace();
+            if parser.peek() == Some('"') {
+                if depth == 0 {
+                    return Err(parser.error_at(start, "the root must be a node".into()));
+                }
+                let text = parser.string()?;
+                parser.skip_whitespace();
+                let close = parser.pos;
+                if parser.bump() != Some(')') {
+                    return Err(parser.error_at(close, "expected `)` after the token text".into()));
+                }
+                builder.token(kind, text.into());
+            } else {
+                builder.start_node(kind);
+                depth += 1;
+            }
+        }
+        parser.skip_whitespace();
+        if parser.pos != text.len() {
+            return Err(parser.error_at(parser.pos, "trailing input after the root".into()));
+        }
+        Ok(builder.finish())
+    }
+}
+
+struct SexprParser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> SexprParser<'a> {
+    fn error_at(&self, offset: usize, message: String) -> SexprError {
+        SexprError { offset, message }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.bump();
+        }
+    }
+
+    fn name(&mut self) -> &'a str {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| !c.is_whitespace() && !"()\"".contains(c)) {
+            self.bump();
+        }
+        &self.text[start..self.pos]
+    }
+
+    /// A quoted string, with the escapes of `escape_sexpr`.
+    fn string(&mut self) -> Result<String, SexprError> {
+        let start = self.pos;
+        self.bump();
+        let mut res = String::new();
+        loop {
+            let escape = self.pos;
+            match self.bump() {
+                Some('"') => return Ok(res),
+                Some('\\') => match self.bump() {
+                    Some('"') => res.push('"'),
+                    Some('\\') => res.push('\\'),
+                    Some('n') => res.push('\n'),
+                    Some('r') => res.push('\r'),
+                    Some('t') => res.push('\t'),
+                    Some('u') => res.push(self.unicode_escape(escape)?),
+                    _ => return Err(self.error_at(escape, "invalid escape".into())),
+                },
+                Some(c) => res.push(c),
+                None => return Err(self.error_at(start, "unterminated string".into())),
+            }
+        }
+    }
+
+    /// The rest of a `\u{XXXX}` escape.
+    fn unicode_escape(&mut self, start: usize) -> Result<char, SexprError> {
+        let rest = &self.text[self.pos..];
+        let digits = rest.strip_prefix('{').and_then(|it| it.split('}').next());
+        let c = digits
+            .filter(|it| !it.is_empty() && it.len() <= 6)
+            .and_then(|it| u32::from_str_radix(it, 16).ok())
+            .and_then(std::char::from_u32);
+        match (c, digits) {
+            (Some(c), Some(digits)) => {
+                self.pos += digits.len() + 2;
+                Ok(c)
+            }
+            _ => Err(self.error_at(start, "invalid unicode escape".into())),
+        }
+    }
+}
+
+impl SyntaxNode {
+    /// Dumps the subtree of this node as JSON, on a single line. Every
+    /// element is an object with
+    ///
+    /// * `"kind"`: the numeric kind,
+    /// * `"name"`: `kind_name` of the kind, only if `kind_name` is given,
+    /// * `"range"`: `[start, end]`, the byte offsets of the element from the
+    ///   start of the whole tree, not of this node,
+    ///
+    /// and, for nodes, `"children"`: an array of elements, or for tokens,
+    /// `"text"`: the full text of the token. Keys come in this order.
+    pub fn to_json(&self, kind_name: Option<&dyn Fn(SyntaxKind) -> String>) -> String {
+        let mut res = String::new();
+        let mut first = true;
+        for event in self.preorder_with_tokens() {
+            match event {
+                WalkEvent::Enter(element) => {
+                    if !first {
+                        res.push(',');
+                    }
+                    first = false;
+                    let range = element.text_range();
+                    res.push_str(&format!("{{\"kind\":{}", element.kind().0));
+                    if let Some(kind_name) = kind_name {
+                        res.push_str(",\"name\":\"");
+                        escape_json(&kind_name(element.kind()), &mut res);
+                        res.push('"');
+                    }
+                    res.push_str(&format!(",\"range\":[{},{}]", range.start(), range.end()));
+                    match element {
+                        SyntaxElement::Node(_) => {
+                            res.push_str(",\"children\":[");
+                            first = true;
+                        }
+                        SyntaxElement::Token(token) => {
+                            res.push_str(",\"text\":\"");
+                            escape_json(token.text(), &mut res);
+                            res.push_str("\"}");
+                        }
+                    }
+                }
+                WalkEvent::Leave(SyntaxElement::Node(_)) => {
+                    res.push_str("]}");
+                    first = false;
+                }
+                WalkEvent::Leave(SyntaxElement::Token(_)) => (),
+            }
+        }
+        res
+    }
+}
+
+/// Escapes `text` for a JSON string.
+fn escape_json(text: &str, buf: &mut String) {
+    for c in text.chars() {
+        match c {
+            '"' => buf.push_str("\\\""),
+            '\\' => buf.push_str("\\\\"),
+            '\n' => buf.push_str("\\n"),
+            '\r' => buf.push_str("\\r"),
+            '\t' => buf.push_str("\\t"),
+            c if (c as u32) < 0x20 => buf.push_str(&format!("\\u{:04x}", c as u32)),
+            c => buf.push(c),
+        }
+    }
+}
+
+impl SyntaxNode {
+    /// Formats this node like `Debug`, but with the kind named by
+    /// `kind_name`: `LIST@[0; 11)`. Kinds without a name are printed as
+    /// usual.
+    pub fn debug<'a>(
+        &'a self,
+        kind_name: &'a impl Fn(SyntaxKind) -> Option<&'static str>,
+    ) -> impl fmt::Debug + fmt::Display + 'a {
+        DebugWith { kind: self.kind(), range: self.text_range(), text: None, kind_name }
+    }
+}
+
+impl SyntaxToken {
+    /// Formats this token like `Debug`, but with the kind named by
+    /// `kind_name`, followed by the text, truncated to 20 characters:
+    /// `WORD@[0; 3) "foo"`.
+    pub fn debug<'a>(
+        &'a self,
+        kind_name: &'a impl Fn(SyntaxKind) -> Option<&'static str>,
+    ) -> impl fmt::Debug + fmt::Display + 'a {
+        DebugWith {
+            kind: self.kind(),
+            range: self.text_range(),
+            text: Some(self.text()),
+            kind_name,
+        }
+    }
+}
+
+impl SyntaxElement {
+    /// `debug` of the node or the token.
+    pub fn debug<'a>(
+        &'a self,
+        kind_name: &'a impl Fn(SyntaxKind) -> Option<&'static str>,
+    ) -> impl fmt::Debug + fmt::Display + 'a {
+        let text = match self {
+            SyntaxElement::Node(_) => None,
+            SyntaxElement::Token(it) => Some(it.text()),
+        };
+        DebugWith { kind: self.kind(), range: self.text_range(), text, kind_name }
+    }
+}
+
+struct DebugWith<'a, F> {
+    kind: SyntaxKind,
+    range: TextRange,
+    text: Option<&'a str>,
+    kind_name: &'a F,
+}
+
+impl<F: Fn(SyntaxKind) -> Option<&'static str>> fmt::Debug for DebugWith<'_, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.kind_name)(self.kind) {
+            Some(name) => f.write_str(name)?,
+            None => write!(f, "{:?}", self.kind)?,
+        }
+        write!(f, "@{:?}", self.range)?;
+        if let Some(text) = self.text {
+            write!(f, " {:?}", preview(text))?;
+        }
+        Ok(())
+    }
+}
+
+impl<F: Fn(SyntaxKind) -> Option<&'static str>> fmt::Display for DebugWith<'_, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// The first `PREVIEW_LEN` characters of `text`, with an ellipsis if there
+/// are more.
+fn preview(text: &str) -> String {
+    let mut res = text.chars().take(PREVIEW_LEN).collect::<String>();
+    if res.len() < text.len() {
+        res.push('…');
+    }
+    res
+}
+
+/// Escapes quotes, backslashes and control characters.
+fn escape_sexpr(text: &str, buf: &mut String) {
+    for c in text.chars() {
+        match c {
+            '"' => buf.push_str("\\\""),
+            '\\' => buf.push_str("\\\\"),
+            '\n' => buf.push_str("\\n"),
+            '\r' => buf.push_str("\\r"),
+            '\t' => buf.push_str("\\t"),
+            c if c.is_control() => buf.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => buf.push(c),
+        }
+    }
+}
+
+/// Escapes `text` for a quoted DOT string. Newlines and tabs are shown as
+/// escapes, rather than breaking the label.
+fn escape_dot(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\\\n"),
+            '\r' => res.push_str("\\\\r"),
+            '\t' => res.push_str("\\\\t"),
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{LIST, WORD, WS};
+
+    const STRING: SyntaxKind = SyntaxKind(3);
+
+    const NAMES: [&str; 4] = ["WORD", "WS", "LIST", "STRING"];
+
+    fn kind_name(kind: SyntaxKind) -> String {
+        NAMES[kind.0 as usize].to_string()
+    }
+
+    // LIST@[0; 30)
+    //   WORD "foo"
+    //   WS "\n"
+    //   LIST@[4; 30)
+    //     STRING "\"a\\b\" and a very long tail"
+    fn sample() -> SyntaxNode {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(LIST);
+        builder.token(WORD, "foo".into());
+        builder.token(WS, "\n".into());
+        builder.start_node(LIST);
+        builder.token(STRING, "\"a\\b\" and a very long tail".into());
+        builder.finish_node();
+        builder.finish_node();
+        SyntaxNode::new_root(builder.finish())
+    }
+
+    #[test]
+    fn dot() {
+        let dot = sample().to_dot(kind_name);
+        assert_eq!(
+            dot,
+            r#"digraph {
+    node [shape=box];
+    n0 [label="LIST@[0; 30)"];
+    n1 [label="WORD@[0; 3)\n\"foo\"" shape=ellipse];
+    n0 -> n1;
+    n2 [label="WS@[3; 4)\n\"\\n\"" shape=ellipse];
+    n0 -> n2;
+    n3 [label="LIST@[4; 30)"];
+    n0 -> n3;
+    n4 [label="STRING@[4; 30)\n\"\"a\\b\" and a very lon…\"" shape=ellipse];
+    n3 -> n4;
+}
+"#
+        );
+    }
+
+    #[test]
+    fn dot_is_well_formed() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(LIST);
+        for text in
+            ["{", "}", "\"", "\\", "\n\r\t", "\\\"", "}\"{", "ünïcödé ünïcödé ünïcödé"].iter()
+        {
+            builder.start_node(LIST);
+            builder.token(STRING, (*text).into());
+            builder.finish_node();
+        }
+        builder.finish_node();
+        let dot = SyntaxNode::new_root(builder.finish()).to_dot(kind_name);
+
+        let (mut depth, mut in_string, mut escaped) = (0, false, false);
+        for c in dot.chars() {
+            if in_string {
+                assert_ne!(c, '\n', "raw newline in a label:\n{}", dot);
+                match (escaped, c) {
+                    (false, '\\') => escaped = true,
+                    (false, '"') => in_string = false,
+                    _ => escaped = false,
+                }
+                continue;
+            }
+            match c {
+                '"' => in_string = true,
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ => (),
+            }
+            assert!(depth >= 0);
+        }
+        assert!(!in_string);
+        assert_eq!(depth, 0);
+        for line in dot.lines().skip(1) {
+            assert!(line.ends_with(';') || line == "}", "{}", line);
+        }
+        // 17 elements, 16 edges.
+        assert_eq!(dot.matches(" -> ").count(), 16);
+    }
+
+    fn static_kind_name(kind: SyntaxKind) -> &'static str {
+        NAMES[kind.0 as usize]
+    }
+
+    fn kind_by_name(name: &str) -> Option<SyntaxKind> {
+        NAMES.iter().position(|&it| it == name).map(|it| SyntaxKind(it as u16))
+    }
+
+    #[test]
+    fn sexpr() {
+        let root = sample();
+        let sexpr = root.to_sexpr(&static_kind_name, true);
+        assert_eq!(
+            sexpr,
+            r#"(LIST
+  (WORD "foo")
+  (WS "\n")
+  (LIST
+    (STRING "\"a\\b\" and a very long tail")))"#
+        );
+        assert_eq!(
+            root.to_sexpr(&static_kind_name, false),
+            "(LIST\n  (WORD)\n  (WS)\n  (LIST\n    (STRING)))"
+        );
+
+        let back = GreenNode::from_sexpr(&sexpr, &kind_by_name).unwrap();
+        assert!(back.structurally_eq(root.green()));
+        // Whitespace doesn't matter.
+        let squashed = "( LIST(WORD \"foo\")(WS\"\\n\")\t(LIST (STRING \"\\\"a\\\\b\\\" and a very long tail\" ) ) )";
+        let back = GreenNode::from_sexpr(squashed, &kind_by_name).unwrap();
+        assert!(back.structurally_eq(root.green()));
+    }
+
+    #[test]
+    fn sexpr_round_trip() {
+        use crate::test_utils::{deep_tree, mixed_tree, Rng};
+
+        let names = |kind: SyntaxKind| ["WORD", "WS", "LIST"][kind.0 as usize];
+        let kinds = |name: &str| {
+            ["WORD", "WS", "LIST"].iter().position(|&it| it == name).map(|it| SyntaxKind(it as u16))
+        };
+        for green in [mixed_tree(&mut Rng::new(92), 1_000), deep_tree(1_000)] {
+            let sexpr = SyntaxNode::new_root(green.clone()).to_sexpr(&names, true);
+            let back = GreenNode::from_sexpr(&sexpr, &kinds).unwrap();
+            assert!(back.structurally_eq(&green));
+        }
+
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(LIST);
+        for text in ["", "\u{0}\u{7f}\u{1b}[0m", "ünïcödé", "(\")", "\\u{41}"].iter() {
+            builder.token(STRING, (*text).into());
+        }
+        builder.start_node(LIST);
+        builder.finish_node();
+        builder.finish_node();
+        let green = builder.finish();
+        let sexpr = SyntaxNode::new_root(green.clone()).to_sexpr(&static_kind_name, true);
+        assert!(!sexpr.contains('\u{1b}'));
+        let back = GreenNode::from_sexpr(&sexpr, &kind_by_name).unwrap();
+        assert!(back.structurally_eq(&green));
+    }
+
+    #[test]
+    fn sexpr_errors() {
+        let error = |text: &str| GreenNode::from_sexpr(text, &kind_by_name).unwrap_err();
+
+        assert_eq!(error("(LIST (WORD \"x\")").to_string(), "unexpected end of input at byte 16");
+        assert_eq!(error("(LIST))").to_string(), "trailing input after the root at byte 6");
+        assert_eq!(error(")").offset(), 0);
+        assert_eq!(error("").offset(), 0);
+        assert_eq!(error("(LIST (FOO))").to_string(), "unknown kind `FOO` at byte 7");
+        assert_eq!(error("(LIST ())").to_string(), "expected a kind at byte 7");
+        assert_eq!(error("(WORD \"x\")").to_string(), "the root must be a node at byte 0");
+        assert_eq!(error("(LIST (WORD \"x\" \"y\"))").offset(), 16);
+        assert_eq!(error("(LIST (WORD \"x))").to_string(), "unterminated string at byte 12");
+        assert_eq!(error("(LIST (WORD \"\\q\"))").to_string(), "invalid escape at byte 13");
+        assert_eq!(error("(LIST (WORD \"\\u{d800}\"))").offset(), 13);
+        assert_eq!(error("(LIST (WORD \"\\u{}\"))").offset(), 13);
+        assert_eq!(error("(LIST (WORD) \"x\")").to_string(), "unexpected `\"` at byte 13");
+        assert_eq!(error("(LIST) (LIST)").offset(), 7);
+    }
+
+    #[test]
+    fn json() {
+        let root = sample();
+        assert_eq!(
+            root.to_json(Some(&kind_name)),
+            concat!(
+                r#"{"kind":2,"name":"LIST","range":[0,30],"children":["#,
+                r#"{"kind":0,"name":"WORD","range":[0,3],"text":"foo"},"#,
+                r#"{"kind":1,"name":"WS","range":[3,4],"text":"\n"},"#,
+                r#"{"kind":2,"name":"LIST","range":[4,30],"children":["#,
+                r#"{"kind":3,"name":"STRING","range":[4,30],"text":"\"a\\b\" and a very long tail"}"#,
+                r#"]}]}"#,
+            )
+        );
+        let inner = root.last_child().unwrap();
+        assert_eq!(
+            inner.to_json(None),
+            r#"{"kind":2,"range":[4,30],"children":[{"kind":3,"range":[4,30],"text":"\"a\\b\" and a very long tail"}]}"#
+        );
+    }
+
+    #[test]
+    fn json_is_valid() {
+        use crate::test_utils::{mixed_tree, Rng};
+
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(LIST);
+        builder.token(STRING, "\u{0}\u{1f}\u{7f}\"\\/ ünïcödé \r\n\t".into());
+        builder.start_node(LIST);
+        builder.finish_node();
+        builder.token(WORD, "x".repeat(1_000).into());
+        builder.finish_node();
+        let odd = SyntaxNode::new_root(builder.finish());
+        let mixed = SyntaxNode::new_root(mixed_tree(&mut Rng::new(92), 1_000));
+
+        for root in [odd, mixed].iter() {
+            for kind_name in [None, Some(&kind_name as &dyn Fn(SyntaxKind) -> String)].iter() {
+                let json = root.to_json(*kind_name);
+                let value = json::parse(&json).unwrap_or_else(|e| panic!("{}: {}", e, json));
+                check_json(&SyntaxElement::Node(root.clone()), &value, kind_name.is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn debug_with_kind_names() {
+        let names = |kind: SyntaxKind| match kind {
+            WS => None,
+            _ => Some(NAMES[kind.0 as usize]),
+        };
+        let root = sample();
+        let lines = root
+            .preorder_with_tokens()
+            .filter_map(|event| match event {
+                WalkEvent::Enter(it) => Some(it.debug(&names).to_string()),
+                WalkEvent::Leave(_) => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                "LIST@[0; 30)",
+                r#"WORD@[0; 3) "foo""#,
+                r#"SyntaxKind(1)@[3; 4) "\n""#,
+                "LIST@[4; 30)",
+                r#"STRING@[4; 30) "\"a\\b\" and a very lon…""#,
+            ]
+        );
+        let token = root.first_token().unwrap();
+        assert_eq!(format!("{:?}", token.debug(&names)), r#"WORD@[0; 3) "foo""#);
+        assert_eq!(format!("{}", root.debug(&names)), "LIST@[0; 30)");
+
+        // The usual `Debug` is unchanged.
+        let default = format!("{:?}", token);
+        assert!(default.contains("SyntaxKind(1)"), "{}", default);
+        assert!(!default.contains("WORD"), "{}", default);
+    }
+
+    #[test]
+    fn debug_dump() {
+        crate::diagnostics::set_test_kind_names();
+        let (word, list, ws, string) =
+            (SyntaxKind(1000), SyntaxKind(1001), SyntaxKind(1002), SyntaxKind(1003));
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(list);
+        builder.token(word, "foo".into());
+        builder.token(ws, "\n".into());
+        builder.start_node(SyntaxKind(7));
+        builder.token(string, "\"a\\b\"\tand a long tail after the escapes".into());
+        builder.start_node(list);
+        builder.finish_node();
+        builder.finish_node();
+        builder.finish_node();
+        let root = SyntaxNode::new_root(builder.finish());
+        assert_eq!(
+            root.debug_dump(),
+            r#"LIST@0..43
+  WORD@0..3 "foo"
+  WS@3..4 "\n"
+  SyntaxKind(7)@4..43
+    STRING@4..43 "\"a\\b\"\tand a long tail after the escapes"
+    LIST@43..43
+"#
+        );
+        assert_eq!(format!("{:?}", root.dump()), format!("\n{}", root.debug_dump()));
+        let inner = root.last_child().unwrap();
+        assert!(inner.debug_dump().starts_with("SyntaxKind(7)@4..43\n  STRING@4..43 "));
+
+        let names = |kind: SyntaxKind| match kind.0 {
+            7 => "SEVEN",
+            _ => crate::diagnostics::kind_name(kind).unwrap(),
+        };
+        let kinds = |name: &str| match name {
+            "SEVEN" => Some(SyntaxKind(7)),
+            _ => (1000..1004)
+                .map(SyntaxKind)
+                .find(|&it| crate::diagnostics::kind_name(it) == Some(name)),
+        };
+        let back = GreenNode::from_sexpr(&root.to_sexpr(&names, true), &kinds).unwrap();
+        assert_eq!(SyntaxNode::new_root(back).dump(), root.dump());
+    }
+
+    #[test]
+    fn debug_dump_round_trip() {
+        use crate::test_utils::{mixed_tree, Rng};
+
+        let names = |kind: SyntaxKind| ["WORD", "WS", "LIST"][kind.0 as usize];
+        let kinds = |name: &str| {
+            ["WORD", "WS", "LIST"].iter().position(|&it| it == name).map(|it| SyntaxKind(it as u16))
+        };
+        let root = SyntaxNode::new_root(mixed_tree(&mut Rng::new(396), 500));
+        let back = GreenNode::from_sexpr(&root.to_sexpr(&names, true), &kinds).unwrap();
+        let back = SyntaxNode::new_root(back);
+        assert_eq!(back.dump(), root.dump());
+        let dump = root.debug_dump();
+        assert_eq!(dump.lines().count(), root.preorder_with_tokens().count() / 2);
+        assert!(dump.lines().all(|it| it.starts_with("SyntaxKind(") || it.starts_with("  ")));
+    }
+
+    fn check_json(element: &SyntaxElement, value: &json::Value, named: bool) {
+        use json::Value;
+
+        let fields = match value {
+            Value::Object(it) => it,
+            _ => panic!("not an object: {:?}", value),
+        };
+        let keys: Vec<&str> = fields.iter().map(|(key, _)| key.as_str()).collect();
+        let last = match element {
+            SyntaxElement::Node(_) => "children",
+            SyntaxElement::Token(_) => "text",
+        };
+        if named {
+            assert_eq!(keys, ["kind", "name", "range", last]);
+            assert_eq!(fields[1].1, Value::String(kind_name(element.kind())));
+        } else {
+            assert_eq!(keys, ["kind", "range", last]);
+        }
+        assert_eq!(fields[0].1, Value::Number(element.kind().0.into()));
+        let range = element.text_range();
+        assert_eq!(
+            fields[keys.len() - 2].1,
+            Value::Array(vec![
+                Value::Number(range.start().to_usize() as f64),
+                Value::Number(range.end().to_usize() as f64),
+            ])
+        );
+        match (element, &fields[keys.len() - 1].1) {
+            (SyntaxElement::Node(node), Value::Array(children)) => {
+                assert_eq!(node.children_with_tokens().count(), children.len());
+                for (child, value) in node.children_with_tokens().zip(children) {
+                    check_json(&child, value, named);
+                }
+            }
+            (SyntaxElement::Token(token), Value::String(text)) => {
+                assert_eq!(&token.text().to_string(), text)
+            }
+            (_, value) => panic!("unexpected {:?}", value),
+        }
+    }
+
+    /// Just enough of a JSON parser to check that `to_json` is valid.
+    mod json {
+        #[derive(Debug, PartialEq)]
+        pub(super) enum Value {
+            Null,
+            Bool(bool),
+            Number(f64),
+            String(String),
+            Array(Vec<Value>),
+            Object(Vec<(String, Value)>),
+        }
+
+        pub(super) fn parse(text: &str) -> Result<Value, String> {
+            let mut chars = text.chars().peekable();
+            let value = value(&mut chars)?;
+            skip_whitespace(&mut chars);
+            match chars.next() {
+                None => Ok(value),
+                Some(c) => Err(format!("trailing `{}`", c)),
+            }
+        }
+
+        type Chars<'a> = std::iter::Peekable<std::str::Chars<'a>>;
+
+        fn skip_whitespace(chars: &mut Chars) {
+            while chars.peek().is_some_and(|c| " \t\r\n".contains(*c)) {
+                chars.next();
+            }
+        }
+
+        fn expect(chars: &mut Chars, expected: &str) -> Result<(), String> {
+            for c in expected.chars() {
+                if chars.next() != Some(c) {
+                    return Err(format!("expected `{}`", expected));
+                }
+            }
+            Ok(())
+        }
+
+        fn value(chars: &mut Chars) -> Result<Value, String> {
+            skip_whitespace(chars);
+            match chars.peek().copied() {
+                Some('n') => expect(chars, "null").map(|()| Value::Null),
+                Some('t') => expect(chars, "true").map(|()| Value::Bool(true)),
+                Some('f') => expect(chars, "false").map(|()| Value::Bool(false)),
+                Some('"') => string(chars).map(Value::String),
+                Some('[') => {
+                    chars.next();
+                    let mut items = Vec::new();
+                    skip_whitespace(chars);
+                    if chars.peek() == Some(&']') {
+                        chars.next();
+                        return Ok(Value::Array(items));
+                    }
+                    loop {
+                        items.push(value(chars)?);
+                        skip_whitespace(chars);
+                        match chars.next() {
+                            Some(',') => (),
+                            Some(']') => return Ok(Value::Array(items)),
+                            _ => return Err("expected `,` or `]`".into()),
+                        }
+                    }
+                }
+                Some('{') => {
+                    chars.next();
+                    let mut fields = Vec::new();
+                    skip_whitespace(chars);
+                    if chars.peek() == Some(&'}') {
+                        chars.next();
+                        return Ok(Value::Object(fields));
+                    }
+                    loop {
+                        skip_whitespace(chars);
+                        let key = string(chars)?;
+                        skip_whitespace(chars);
+                        expect(chars, ":")?;
+                        fields.push((key, value(chars)?));
+                        skip_whitespace(chars);
+                        match chars.next() {
+                            Some(',') => (),
+                            Some('}') => return Ok(Value::Object(fields)),
+                            _ => return Err("expected `,` or `}`".into()),
+                        }
+                    }
+                }
+                Some(c) if c == '-' || c.is_ascii_digit() => {
+                    let mut number = String::new();
+                    while chars.peek().is_some_and(|c| "+-.eE".contains(*c) || c.is_ascii_digit()) {
+                        number.push(chars.next().unwrap());
+                    }
+                    number.parse().map(Value::Number).map_err(|e| e.to_string())
+                }
+                _ => Err("expected a value".into()),
+            }
+        }
+
+        fn string(chars: &mut Chars) -> Result<String, String> {
+            expect(chars, "\"")?;
+            let mut res = String::new();
+            loop {
+                match chars.next().ok_or("unterminated string")? {
+                    '"' => return Ok(res),
+                    '\\' => match chars.next().ok_or("unterminated string")? {
+                        c @ ('"' | '\\' | '/') => res.push(c),
+                        'b' => res.push('\u{8}'),
+                        'f' => res.push('\u{c}'),
+                        'n' => res.push('\n'),
+                        'r' => res.push('\r'),
+                        't' => res.push('\t'),
+                        'u' => {
+                            let hex: String = chars.by_ref().take(4).collect();
+                            let code = u32::from_str_radix(&hex, 16).map_err(|e| e.to_string())?;
+                            res.push(std::char::from_u32(code).ok_or("surrogate escape")?);
+                        }
+                        c => return Err(format!("invalid escape `{}`", c)),
+                    },
+                    c if (c as u32) < 0x20 => return Err("unescaped control character".into()),
+                    c => res.push(c),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! Random syntax trees for property tests.
+//!
+//! `gen_tree` builds a random `GreenNode` from a seed and a `TreeConfig`.
+//! The trees are deterministic: printing the seed of a failing case is
+//! enough to reproduce it.
+//!
+//! ```
+//! use rowan::{cursor::SyntaxNode, generate::{gen_tree, Rng, TreeConfig}};
+//!
+//! let config = TreeConfig { max_depth: 6, ..TreeConfig::default() };
+//! for seed in 0..100 {
+//!     let root = SyntaxNode::new_root(gen_tree(seed, &config));
+//!     let range = Rng::new(seed).range(root.text_range().len());
+//!     let covering = root.covering_node(range);
+//!     assert!(range.is_subrange(&covering.text_range()), "seed {}", seed);
+//! }
+//! ```
+use std::{iter, ops::Range};
+
+use crate::{GreenNode, GreenNodeBuilder, SyntaxKind, TextRange, TextUnit};
+
+/// A small deterministic random number generator (xorshift), good enough for
+/// picking random trees, offsets and edits. The same seed always gives the
+/// same numbers, on every platform and in every version of the crate.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    /// A generator seeded with `seed`; any seed, including zero, is fine.
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed | 1)
+    }
+
+    /// A random number in `0..n`. Panics if `n` is zero.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+
+    /// A random offset in `0..=len`.
+    pub fn offset(&mut self, len: TextUnit) -> TextUnit {
+        TextUnit::from(self.below(len.to_usize() as u64 + 1) as u32)
+    }
+
+    /// A random subrange of `0..len`.
+    pub fn range(&mut self, len: TextUnit) -> TextRange {
+        let (a, b) = (self.offset(len), self.offset(len));
+        TextRange::from_to(a.min(b), a.max(b))
+    }
+}
+
+/// The shape of the trees of `gen_tree`.
+#[derive(Debug, Clone)]
+pub struct TreeConfig {
+    /// The maximal depth of a node, the root being at depth 1.
+    pub max_depth: usize,
+    /// The maximal number of children of a node, except for the nodes of
+    /// chains, which have one.
+    pub max_children: usize,
+    /// The kinds of the nodes and tokens.
+    pub kinds: Range<u16>,
+    /// The texts of the tokens.
+    pub alphabet: Vec<&'static str>,
+}
+
+impl Default for TreeConfig {
+    fn default() -> TreeConfig {
+        TreeConfig {
+            max_depth: 12,
+            max_children: 5,
+            kinds: 0..4,
+            alphabet: vec!["", "a", "bc", " ", "\n", "\u{e9}"],
+        }
+    }
+}
+
+/// A random tree of the given shape, the same for the same `seed`, so that
+/// a failing property test can print the seed to reproduce the failure.
+///
+/// Besides the typical nodes, with a mix of nodes and tokens, each tree is
+/// likely to have empty nodes, nodes of tokens only, chains of nested nodes
+/// with a single child, and empty tokens.
+pub fn gen_tree(seed: u64, config: &TreeConfig) -> GreenNode {
+    assert!(config.max_depth > 0 && !config.kinds.is_empty() && !config.alphabet.is_empty());
+    let mut rng = Rng::new(seed);
+    let kind = |rng: &mut Rng| {
+        let n_kinds = config.kinds.end - config.kinds.start;
+        SyntaxKind(config.kinds.start + rng.below(u64::from(n_kinds)) as u16)
+    };
+    let n_children = |rng: &mut Rng| rng.below(config.max_children as u64 + 1) as usize;
+
+    let mut builder = GreenNodeBuilder::new();
+    builder.start_node(kind(&mut rng));
+    // The number of children left to add to each of the open nodes.
+    let mut stack = vec![n_children(&mut rng)];
+    while let Some(left) = stack.last_mut() {
+        if *left == 0 {
+            builder.finish_node();
+            stack.pop();
+            continue;
+        }
+        *left -= 1;
+        let room = config.max_depth - stack.len();
+        match rng.below(16) {
+            // A chain: nodes with a single child, but for the innermost.
+            0 if room > 0 => {
+                let len = 1 + rng.below(room as u64) as usize;
+                for _ in 0..len {
+                    builder.start_node(kind(&mut rng));
+                }
+                stack.extend(iter::repeat_n(0, len - 1));
+                stack.push(n_children(&mut rng));
+            }
+            1..=5 if room > 0 => {
+                builder.start_node(kind(&mut rng));
+                stack.push(n_children(&mut rng));
+            }
+            _ => {
+                let text = config.alphabet[rng.below(config.alphabet.len() as u64) as usize];
+                builder.token(kind(&mut rng), text.into());
+            }
+        }
+    }
+    builder.finish()
+}
@@ -1,62 +1,34 @@
-use std::{mem::size_of, sync::Arc};
+mod node;
+mod token;
+mod builder;
+mod encoding;
+mod validate;
+#[cfg(feature = "serde1")]
+mod serde_impls;
 
-use crate::{SmolStr, TextUnit, SyntaxKind};
+use std::convert::TryFrom;
 
-/// Internal node in the immutable tree.
-/// It has other nodes and tokens as children.
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub struct GreenNode {
-    kind: SyntaxKind,
-    text_len: TextUnit,
-    //TODO: implement llvm::trailing_objects trick
-    children: Arc<[GreenElement]>,
-}
+use crate::{SyntaxKind, TextUnit};
 
-impl GreenNode {
-    /// Creates new Node.
-    #[inline]
-    pub fn new(kind: SyntaxKind, children: Box<[GreenElement]>) -> GreenNode {
-        let text_len = children.iter().map(|x| x.text_len()).sum::<TextUnit>();
-        GreenNode { kind, text_len, children: children.into() }
-    }
+pub use self::{
+    node::{GreenNode, GreenChild, GreenChildren, MemoryStats},
+    token::GreenToken,
+    builder::{GreenNodeBuilder, Checkpoint},
+    encoding::{DecodeError, LazyGreenTree, LazyNode, LazyElement},
+    validate::ValidationError,
+};
 
-    /// Kind of this node.
-    #[inline]
-    pub fn kind(&self) -> SyntaxKind {
-        self.kind
-    }
-
-    /// Length of the text, covered by this node.
-    #[inline]
-    pub fn text_len(&self) -> TextUnit {
-        self.text_len
-    }
-    /// Children of this node.
-    #[inline]
-    pub fn children(&self) -> &[GreenElement] {
-        &self.children
-    }
-
-    /// Gets the child at index.
-    pub(crate) fn get_child(&self, index: GreenIndex) -> Option<&GreenElement> {
-        self.children.get(index.0 as usize)
-    }
-
-    /// Number of memory bytes of occupied by subtree rooted at `self`.
-    pub(crate) fn memory_size_of_subtree(&self) -> usize {
-        let mut res = size_of::<Self>();
-        self.children().iter().for_each(|el| match el {
-            GreenElement::Token(token) => {
-                res += size_of::<GreenToken>();
-                if token.text.is_heap_allocated() {
-                    res += token.text.len();
-                }
-            }
-            GreenElement::Node(node) => res += node.memory_size_of_subtree(),
-        });
+/// Mirrors `std::sync::Arc`: a refcount this large means that someone is
+/// leaking clones, and aborting is better than overflowing.
+const MAX_REFCOUNT: usize = isize::MAX as usize;
 
-        res
-    }
+/// Converts the index of a child of a green node to the `u32` stored by
+/// the red layers. Nodes have at most `MAX_CHILDREN` children, so this only
+/// fails for an index which doesn't come from a node.
+#[inline]
+pub(crate) fn child_index(index: usize) -> u32 {
+    u32::try_from(index)
+        .unwrap_or_else(|_| panic!("child index {} is larger than MAX_CHILDREN", index))
 }
 
 /// Index into a green node, which might refer to either Token or Node
@@ -74,36 +46,6 @@ impl GreenIndex {
     }
 }
 
-/// Leaf node in the immutable tree.
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub struct GreenToken {
-    kind: SyntaxKind,
-    text: SmolStr,
-}
-
-impl GreenToken {
-    /// Creates new Token.
-    #[inline]
-    pub fn new(kind: SyntaxKind, text: SmolStr) -> GreenToken {
-        GreenToken { kind, text }
-    }
-    /// Kind of this Token.
-    #[inline]
-    pub fn kind(&self) -> SyntaxKind {
-        self.kind
-    }
-    /// Text of this Token.
-    #[inline]
-    pub fn text(&self) -> &SmolStr {
-        &self.text
-    }
-    /// Text of this Token.
-    #[inline]
-    pub fn text_len(&self) -> TextUnit {
-        TextUnit::from_usize(self.text.len())
-    }
-}
-
 /// Leaf or internal node in the immutable tree.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum GreenElement {
@@ -146,116 +88,195 @@ impl GreenElement {
     }
 }
 
-/// A checkpoint for maybe wrapping a node. See `GreenNodeBuilder::checkpoint` for details.
-#[derive(Clone, Copy, Debug)]
-pub struct Checkpoint(usize);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{mem::size_of, sync::Arc};
+    use crate::{SmolStr, TextRange};
 
-/// A builder for a green tree.
-#[derive(Default, Debug)]
-pub struct GreenNodeBuilder {
-    cache: rustc_hash::FxHashSet<GreenNode>,
-    parents: Vec<(SyntaxKind, usize)>,
-    children: Vec<GreenElement>,
-}
+    #[test]
+    fn green_element_is_two_words() {
+        assert_eq!(size_of::<GreenNode>(), size_of::<usize>());
+        assert_eq!(size_of::<GreenToken>(), size_of::<usize>());
+        assert_eq!(size_of::<GreenElement>(), 2 * size_of::<usize>());
+    }
 
-impl GreenNodeBuilder {
-    /// Creates new builder.
-    #[inline]
-    pub fn new() -> GreenNodeBuilder {
-        GreenNodeBuilder::default()
+    #[test]
+    fn memory_usage_of_a_large_tree() {
+        // 1k nodes with 1k single-character tokens each.
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        for i in 0..1000 {
+            builder.start_node(SyntaxKind(1));
+            for j in 0..1000 {
+                builder
+                    .token(SyntaxKind(2), if (i + j) % 2 == 0 { "a".into() } else { " ".into() });
+            }
+            builder.finish_node();
+        }
+        builder.finish_node();
+        let root = builder.finish();
+        assert_eq!(root.text_len(), 1_000_000.into());
+
+        let stats = root.memory_usage();
+        assert_eq!((stats.nodes, stats.tokens, stats.text_bytes), (1001, 1_000_000, 1_000_000));
+        assert_eq!(stats.bytes, stats.unshared_bytes);
+        assert_eq!(stats.bytes, root.memory_size_of_subtree());
+
+        // The old layout paid for a 24 byte node header, a separate `Arc`
+        // allocation for children and 40 bytes per child element. Now a
+        // child takes half of that, offset table included, on top of a
+        // header of at most 64 bytes per node.
+        let token_size = GreenToken::new(SyntaxKind(2), "a".into()).memory_size();
+        let node_bytes = stats.bytes - stats.tokens * token_size;
+        let n_children = stats.nodes - 1 + stats.tokens;
+        let old_children_only = n_children * 40;
+        assert!(
+            (node_bytes - stats.nodes * 64) * 2 <= old_children_only,
+            "{} bytes in nodes",
+            node_bytes
+        );
     }
-    /// Adds new token to the current branch.
-    #[inline]
-    pub fn token(&mut self, kind: SyntaxKind, text: SmolStr) {
-        let token = GreenToken { kind, text };
-        self.children.push(token.into());
+
+    fn build(words: &[&str]) -> GreenNode {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        for &word in words {
+            builder.start_node(SyntaxKind(1));
+            builder.token(SyntaxKind(2), word.into());
+            builder.finish_node();
+        }
+        builder.finish_node();
+        builder.finish()
     }
-    /// Start new node and make it current.
-    #[inline]
-    pub fn start_node(&mut self, kind: SyntaxKind) {
-        let len = self.children.len();
-        self.parents.push((kind, len));
+
+    #[test]
+    fn content_hash() {
+        let foo_bar = build(&["foo", "bar"]);
+        assert_eq!(foo_bar.content_hash(), build(&["foo", "bar"]).content_hash());
+        assert_ne!(foo_bar.content_hash(), build(&["foo", "baz"]).content_hash());
+        assert_ne!(foo_bar.content_hash(), build(&["foobar"]).content_hash());
+
+        // Same tree, but obtained via `replace_with`.
+        let root = crate::cursor::SyntaxNode::new_root(build(&["foo", "baz"]));
+        let baz = root.last_token().unwrap();
+        let replaced = baz.replace_with(GreenToken::new(SyntaxKind(2), "bar".into()));
+        assert_eq!(replaced, foo_bar);
+        assert_eq!(replaced.content_hash(), foo_bar.content_hash());
     }
-    /// Finish current branch and restore previous
-    /// branch as current.
-    #[inline]
-    pub fn finish_node(&mut self) {
-        let (kind, first_child) = self.parents.pop().unwrap();
-        let children: Vec<_> = self.children.drain(first_child..).collect();
-        let mut node = GreenNode::new(kind, children.into_boxed_slice());
-        // Green nodes are fully immutable, so it's ok to deduplicate them.
-        // This is the same optimization that Roslyn does
-        // https://github.com/KirillOsenkov/Bliki/wiki/Roslyn-Immutable-Trees
-        //
-        // For example, all `#[inline]` in this file share the same green node!
-        // For `libsyntax/parse/parser.rs`, measurements show that deduping saves
-        // 17% of the memory for green nodes!
-        // Future work: make hashing faster by avoiding rehashing of subtrees.
-        if node.children.len() <= 3 {
-            match self.cache.get(&node) {
-                Some(existing) => node = existing.clone(),
-                None => assert!(self.cache.insert(node.clone())),
-            }
+
+    fn nested(depth: usize, leaf: &str) -> GreenNode {
+        let mut builder = GreenNodeBuilder::new();
+        for _ in 0..depth {
+            builder.start_node(SyntaxKind(1));
         }
-        self.children.push(node.into());
+        builder.token(SyntaxKind(2), leaf.into());
+        for _ in 0..depth {
+            builder.finish_node();
+        }
+        builder.finish()
     }
-    /// Prepare for maybe wrapping the next node.
-    /// The way wrapping works is that you first of all get a checkpoint,
-    /// then you place all tokens you want to wrap, and then *maybe* call
-    /// `start_node_at`.
-    /// Example:
-    /// ```rust
-    /// # use rowan::{GreenNodeBuilder, SyntaxKind};
-    /// # const PLUS: SyntaxKind = SyntaxKind(0);
-    /// # const OPERATION: SyntaxKind = SyntaxKind(1);
-    /// # struct Parser;
-    /// # impl Parser {
-    /// #     fn peek(&self) -> Option<SyntaxKind> { None }
-    /// #     fn parse_expr(&mut self) {}
-    /// # }
-    /// # let mut builder = GreenNodeBuilder::new();
-    /// # let mut parser = Parser;
-    /// let checkpoint = builder.checkpoint();
-    /// parser.parse_expr();
-    /// if parser.peek() == Some(PLUS) {
-    ///   // 1 + 2 = Add(1, 2)
-    ///   builder.start_node_at(checkpoint, OPERATION);
-    ///   parser.parse_expr();
-    ///   builder.finish_node();
-    /// }
-    /// ```
-    #[inline]
-    pub fn checkpoint(&self) -> Checkpoint {
-        Checkpoint(self.children.len())
+
+    #[test]
+    fn structural_equality() {
+        let tree = build(&["foo", "bar"]);
+        assert!(tree.structurally_eq(&tree.clone()));
+        assert!(tree.structurally_eq(&build(&["foo", "bar"])));
+        assert!(!tree.structurally_eq(&build(&["foo", "baz"])));
+        assert_ne!(tree, build(&["foo", "bar", "baz"]));
+
+        assert_eq!(nested(50, "x"), nested(50, "x"));
+        assert_ne!(nested(50, "x"), nested(50, "y"));
+        assert_ne!(nested(50, "x"), nested(49, "x"));
+
+        // Same text, different kinds.
+        let node = |kind| {
+            GreenNode::new(
+                SyntaxKind(kind),
+                vec![GreenToken::new(SyntaxKind(2), "x".into()).into()].into_boxed_slice(),
+            )
+        };
+        assert_ne!(node(0), node(1));
     }
-    /// Wrap the previous branch marked by `checkpoint` in a new branch and
-    /// make it current.
-    #[inline]
-    pub fn start_node_at(&mut self, checkpoint: Checkpoint, kind: SyntaxKind) {
-        let Checkpoint(checkpoint) = checkpoint;
-        assert!(
-            checkpoint <= self.children.len(),
-            "checkpoint no longer valid, was finish_node called early?"
-        );
 
-        if let Some(&(_, first_child)) = self.parents.last() {
-            assert!(
-                checkpoint >= first_child,
-                "checkpoint no longer valid, was an unmatched start_node_at called?"
-            );
+    #[test]
+    fn structural_equality_of_deep_trees() {
+        let depth = 10_000;
+        assert!(nested(depth, "x").structurally_eq(&nested(depth, "x")));
+        assert!(!nested(depth, "x").structurally_eq(&nested(depth, "y")));
+    }
+
+    #[test]
+    fn tokens_referring_to_the_source() {
+        let comment = format!("/*{}*/", " lorem ipsum".repeat(10_000));
+        let text = format!("{}\nfn f() {{}}\n", comment);
+        let source: Arc<str> = text.as_str().into();
+        let tokens = [(0, comment.len()), (comment.len(), comment.len() + 1)];
+        let words = ["fn", " ", "f", "()", " ", "{}", "\n"];
+
+        let mut owned = GreenNodeBuilder::new();
+        let mut shared = GreenNodeBuilder::with_source(source.clone());
+        owned.start_node(SyntaxKind(0));
+        shared.start_node(SyntaxKind(0));
+        for &(start, end) in tokens.iter() {
+            let range = TextRange::from_to(TextUnit::from_usize(start), TextUnit::from_usize(end));
+            owned.token(SyntaxKind(1), text[start..end].into());
+            shared.source_token(SyntaxKind(1), range);
         }
+        let mut offset = comment.len() + 1;
+        for &word in words.iter() {
+            let range = TextRange::offset_len(TextUnit::from_usize(offset), TextUnit::of_str(word));
+            // Mix owned and borrowed tokens in one tree.
+            owned.token(SyntaxKind(2), word.into());
+            shared.source_token(SyntaxKind(2), range);
+            offset += word.len();
+        }
+        owned.finish_node();
+        shared.finish_node();
+        let (owned, shared) = (owned.finish(), shared.finish());
+
+        assert_eq!(owned, shared);
+        assert_eq!(owned.content_hash(), shared.content_hash());
+        let root = crate::cursor::SyntaxNode::new_root(shared.clone());
+        let shared_text: String = std::iter::successors(root.first_token(), |it| it.next_token())
+            .map(|it| it.text().to_string())
+            .collect();
+        assert_eq!(shared_text, text);
 
-        self.parents.push((kind, checkpoint));
+        assert!(shared.memory_size_of_subtree() * 100 < owned.memory_size_of_subtree());
     }
-    /// Complete tree building. Make sure that
-    /// `start_node_at` and `finish_node` calls
-    /// are paired!
-    #[inline]
-    pub fn finish(mut self) -> GreenNode {
-        assert_eq!(self.children.len(), 1);
-        match self.children.pop().unwrap() {
-            GreenElement::Node(node) => node,
-            GreenElement::Token(_) => panic!(),
+
+    #[test]
+    fn green_trees_cross_threads() {
+        // Parse on a worker, shared text included, and use the tree elsewhere.
+        let green = std::thread::spawn(|| {
+            let source: Arc<str> = "fn f() {}".into();
+            let mut builder = GreenNodeBuilder::with_source(source);
+            builder.start_node(SyntaxKind(0));
+            for &(start, end) in [(0, 2), (2, 3), (3, 4), (4, 6), (6, 7), (7, 9)].iter() {
+                builder.source_token(SyntaxKind(1), TextRange::from_to(start.into(), end.into()));
+            }
+            builder.finish_node();
+            builder.finish()
+        })
+        .join()
+        .unwrap();
+
+        // Several threads can look at the same tree, each with its own red
+        // nodes.
+        let handles = (0..4)
+            .map(|_| {
+                let green = green.clone();
+                std::thread::spawn(move || {
+                    let root = crate::cursor::SyntaxNode::new_root(green);
+                    std::iter::successors(root.first_token(), |it| it.next_token())
+                        .map(|it| it.text().to_string())
+                        .collect::<String>()
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), "fn f() {}");
         }
     }
 }
@@ -0,0 +1,588 @@
+//! A compact binary encoding of green trees, see `GreenNode::to_bytes`.
+//!
+//! The layout is:
+//!
+//! * the magic bytes `rwn` and a format version byte,
+//! * the text length of the tree,
+//! * the string table: the number of strings, and then the length and the
+//!   bytes of each of them,
+//! * the number of records, and then the records for all nodes and tokens
+//!   in postorder. A record starts with `kind << 1 | is_node`, followed by
+//!   the index of the text in the string table for tokens, and by the number
+//!   of children for nodes, which are the preceding records.
+//!
+//! All numbers are LEB128 varints.
+use std::{
+    cell::{Cell, RefCell},
+    error::Error,
+    fmt, str,
+};
+
+use rustc_hash::FxHashMap;
+
+use crate::{GreenElement, GreenNode, GreenToken, SyntaxKind, TextUnit};
+
+const MAGIC: &[u8] = b"rwn";
+const VERSION: u8 = 1;
+
+/// The error returned by `GreenNode::from_bytes` for bytes which are not a
+/// valid encoding of a green tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    offset: usize,
+    message: &'static str,
+}
+
+impl DecodeError {
+    /// Offset of the first offending byte.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid green tree at byte {}: {}", self.offset, self.message)
+    }
+}
+
+impl Error for DecodeError {}
+
+impl GreenNode {
+    /// Encodes this tree in a compact binary format, which `from_bytes`
+    /// decodes. Equal token texts are stored only once.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut strings = FxHashMap::<&str, u64>::default();
+        let mut string_table = Vec::new();
+        let mut records = Vec::new();
+        let mut n_records = 0u64;
+        let mut stack = vec![(self, 0)];
+        while let Some((node, index)) = stack.last_mut() {
+            let node: &GreenNode = node;
+            match node.children().get(*index) {
+                Some(child) => {
+                    *index += 1;
+                    match child {
+                        GreenElement::Node(it) => stack.push((it, 0)),
+                        GreenElement::Token(it) => {
+                            let next = strings.len() as u64;
+                            let string = *strings.entry(it.text()).or_insert_with(|| {
+                                write_varint(&mut string_table, it.text().len() as u64);
+                                string_table.extend_from_slice(it.text().as_bytes());
+                                next
+                            });
+                            write_varint(&mut records, u64::from(it.kind().0) << 1);
+                            write_varint(&mut records, string);
+                            n_records += 1;
+                        }
+                    }
+                }
+                None => {
+                    write_varint(&mut records, u64::from(node.kind().0) << 1 | 1);
+                    write_varint(&mut records, node.children().len() as u64);
+                    n_records += 1;
+                    stack.pop();
+                }
+            }
+        }
+
+        let mut res = Vec::with_capacity(string_table.len() + records.len() + 32);
+        res.extend_from_slice(MAGIC);
+        res.push(VERSION);
+        write_varint(&mut res, self.text_len().to_usize() as u64);
+        write_varint(&mut res, strings.len() as u64);
+        res.extend_from_slice(&string_table);
+        write_varint(&mut res, n_records);
+        res.extend_from_slice(&records);
+        res
+    }
+
+    /// Decodes a tree encoded with `to_bytes`.
+    ///
+    /// Fails, instead of panicking, on any truncated or corrupted input, or
+    /// on input of a different format version. Tokens of the same kind and
+    /// text share a single `GreenToken`. See also `LazyGreenTree`, which
+    /// decodes nodes only when they are needed.
+    pub fn from_bytes(bytes: &[u8]) -> Result<GreenNode, DecodeError> {
+        let mut tokens = FxHashMap::<(u16, u32), GreenToken>::default();
+        let root = decode(bytes, |record| match record {
+            Record::Token { kind, string, text } => tokens
+                .entry((kind.0, string))
+                .or_insert_with(|| GreenToken::new(kind, text.into()))
+                .clone()
+                .into(),
+            Record::Node { kind, children } => GreenNode::from_vec(kind, children).into(),
+        })?;
+        match root.1 {
+            GreenElement::Node(it) => Ok(it),
+            GreenElement::Token(_) => unreachable!(),
+        }
+    }
+}
+
+/// A decoded record, with the values for the preceding records which are
+/// the children, for nodes.
+enum Record<'a, T> {
+    Token { kind: SyntaxKind, string: u32, text: &'a str },
+    Node { kind: SyntaxKind, children: Vec<T> },
+}
+
+/// Validates `bytes`, and builds a value for each record, returning the
+/// string table and the value for the root.
+fn decode<'a, T>(
+    bytes: &'a [u8],
+    mut f: impl FnMut(Record<'a, T>) -> T,
+) -> Result<(Vec<&'a str>, T), DecodeError> {
+    let mut reader = Reader { bytes, pos: 0 };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(reader.error_at(0, "not a green tree"));
+    }
+    if reader.take(1)?[0] != VERSION {
+        return Err(reader.error_at(MAGIC.len(), "unsupported format version"));
+    }
+    let text_len = reader.varint()?;
+    if text_len > u64::from(u32::MAX) {
+        return Err(reader.error("the text is longer than 4 GiB"));
+    }
+
+    let n_strings = reader.varint()?;
+    if n_strings > u64::from(u32::MAX) {
+        return Err(reader.error("too many strings"));
+    }
+    // Each string takes at least a byte, so this doesn't trust the length in
+    // the input more than the input itself.
+    let mut strings = Vec::with_capacity(reader.capacity_for(n_strings));
+    for _ in 0..n_strings {
+        let len = reader.varint()?;
+        let start = reader.pos;
+        let string = reader.take(len as usize)?;
+        match str::from_utf8(string) {
+            Ok(it) => strings.push(it),
+            Err(_) => return Err(reader.error_at(start, "string is not UTF-8")),
+        }
+    }
+
+    let n_records = reader.varint()?;
+    // Values, and whether they are nodes.
+    let mut stack = Vec::<(bool, T)>::new();
+    let mut total_len = 0u64;
+    for _ in 0..n_records {
+        let start = reader.pos;
+        let tag = reader.varint()?;
+        if tag >> 1 > u64::from(u16::MAX) {
+            return Err(reader.error_at(start, "kind is out of range"));
+        }
+        let kind = SyntaxKind((tag >> 1) as u16);
+        let is_node = tag & 1 == 1;
+        if is_node {
+            let n_children = reader.varint()?;
+            if n_children > stack.len() as u64 {
+                return Err(reader.error_at(start, "node has more children than records"));
+            }
+            let children = stack.split_off(stack.len() - n_children as usize);
+            let children = children.into_iter().map(|(_, it)| it).collect();
+            stack.push((true, f(Record::Node { kind, children })));
+        } else {
+            let string = reader.varint()?;
+            let text = match strings.get(string as usize) {
+                Some(it) => *it,
+                None => return Err(reader.error_at(start, "string index is out of range")),
+            };
+            total_len += text.len() as u64;
+            if total_len > text_len {
+                return Err(reader.error_at(start, "text is longer than in the header"));
+            }
+            stack.push((false, f(Record::Token { kind, string: string as u32, text })));
+        }
+    }
+    if reader.pos != bytes.len() {
+        return Err(reader.error("trailing bytes"));
+    }
+    let root = match (stack.pop(), stack.is_empty()) {
+        (Some((true, it)), true) => it,
+        _ => return Err(reader.error("records don't form a single tree")),
+    };
+    if total_len != text_len {
+        return Err(reader.error("text is shorter than in the header"));
+    }
+    Ok((strings, root))
+}
+
+/// A tree encoded with `GreenNode::to_bytes`, whose nodes are decoded only
+/// when they are needed.
+///
+/// `new` validates the whole input, and indexes the records, borrowing the
+/// token texts from the buffer, but doesn't create any green nodes. Green
+/// nodes are created by `LazyNode::green`, and are cached, so each is
+/// decoded at most once.
+pub struct LazyGreenTree<'a> {
+    strings: Vec<&'a str>,
+    records: Vec<LazyRecord>,
+    /// Record indices of the children of each node, see `LazyRecord`.
+    children: Vec<u32>,
+    root: u32,
+    nodes: RefCell<FxHashMap<u32, GreenNode>>,
+    tokens: RefCell<FxHashMap<(u16, u32), GreenToken>>,
+    /// Number of green nodes created so far.
+    n_decoded: Cell<usize>,
+}
+
+struct LazyRecord {
+    kind: SyntaxKind,
+    text_len: TextUnit,
+    /// The string of a token, or the range of `children` of a node.
+    data: (u32, u32),
+    is_node: bool,
+}
+
+impl<'a> LazyGreenTree<'a> {
+    /// Checks that `bytes` are a valid encoding, see `GreenNode::from_bytes`.
+    pub fn new(bytes: &'a [u8]) -> Result<LazyGreenTree<'a>, DecodeError> {
+        let mut records = Vec::<LazyRecord>::new();
+        let mut children = Vec::new();
+        let (strings, root) = decode(bytes, |record| {
+            let record = match record {
+                Record::Token { kind, string, text } => LazyRecord {
+                    kind,
+                    text_len: TextUnit::of_str(text),
+                    data: (string, 0),
+                    is_node: false,
+                },
+                Record::Node { kind, children: ids } => {
+                    let text_len = ids.iter().map(|&it| records[it as usize].text_len).sum();
+                    let start = children.len() as u32;
+                    children.extend_from_slice(&ids);
+                    LazyRecord {
+                        kind,
+                        text_len,
+                        data: (start, children.len() as u32),
+                        is_node: true,
+                    }
+                }
+            };
+            records.push(record);
+            records.len() as u32 - 1
+        })?;
+        Ok(LazyGreenTree {
+            strings,
+            records,
+            children,
+            root,
+            nodes: Default::default(),
+            tokens: Default::default(),
+            n_decoded: Cell::new(0),
+        })
+    }
+
+    /// The root node, which is not decoded yet.
+    pub fn root(&self) -> LazyNode<'_> {
+        LazyNode { tree: self, index: self.root }
+    }
+
+    fn children(&self, index: u32) -> &[u32] {
+        let (start, end) = self.records[index as usize].data;
+        &self.children[start as usize..end as usize]
+    }
+
+    fn token(&self, index: u32) -> GreenToken {
+        let record = &self.records[index as usize];
+        let string = record.data.0;
+        let text = self.strings[string as usize];
+        let mut tokens = self.tokens.borrow_mut();
+        let token = tokens
+            .entry((record.kind.0, string))
+            .or_insert_with(|| GreenToken::new(record.kind, text.into()));
+        token.clone()
+    }
+
+    fn node(&self, index: u32) -> GreenNode {
+        if let Some(it) = self.nodes.borrow().get(&index) {
+            return it.clone();
+        }
+        // Nodes being decoded, with their children so far.
+        let mut stack = vec![(index, Vec::new())];
+        loop {
+            let (index, children) = stack.last_mut().unwrap();
+            let ids = self.children(*index);
+            if let Some(&child) = ids.get(children.len()) {
+                if !self.records[child as usize].is_node {
+                    children.push(self.token(child).into());
+                    continue;
+                }
+                match self.nodes.borrow().get(&child) {
+                    Some(it) => children.push(it.clone().into()),
+                    None => stack.push((child, Vec::new())),
+                }
+                continue;
+            }
+            let (index, children) = stack.pop().unwrap();
+            let node = GreenNode::from_vec(self.records[index as usize].kind, children);
+            self.n_decoded.set(self.n_decoded.get() + 1);
+            self.nodes.borrow_mut().insert(index, node.clone());
+            match stack.last_mut() {
+                Some((_, children)) => children.push(node.into()),
+                None => return node,
+            }
+        }
+    }
+}
+
+impl fmt::Debug for LazyGreenTree<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LazyGreenTree")
+            .field("records", &self.records.len())
+            .field("decoded", &self.n_decoded.get())
+            .finish()
+    }
+}
+
+/// A node of a `LazyGreenTree`.
+#[derive(Clone, Copy)]
+pub struct LazyNode<'t> {
+    tree: &'t LazyGreenTree<'t>,
+    index: u32,
+}
+
+/// A child of a `LazyNode`. Tokens are cheap, so they are decoded right away.
+#[derive(Debug, Clone)]
+pub enum LazyElement<'t> {
+    Node(LazyNode<'t>),
+    Token(GreenToken),
+}
+
+impl<'t> LazyNode<'t> {
+    pub fn kind(&self) -> SyntaxKind {
+        self.record().kind
+    }
+
+    pub fn text_len(&self) -> TextUnit {
+        self.record().text_len
+    }
+
+    /// Children of this node, without decoding child nodes.
+    pub fn children(&self) -> impl Iterator<Item = LazyElement<'t>> + 't {
+        let tree = self.tree;
+        tree.children(self.index).iter().map(move |&index| {
+            if tree.records[index as usize].is_node {
+                LazyElement::Node(LazyNode { tree, index })
+            } else {
+                LazyElement::Token(tree.token(index))
+            }
+        })
+    }
+
+    /// Decodes the subtree of this node.
+    pub fn green(&self) -> GreenNode {
+        self.tree.node(self.index)
+    }
+
+    fn record(&self) -> &'t LazyRecord {
+        &self.tree.records[self.index as usize]
+    }
+}
+
+impl fmt::Debug for LazyNode<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}@{:?}", self.kind(), self.text_len())
+    }
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error(&self, message: &'static str) -> DecodeError {
+        self.error_at(self.pos, message)
+    }
+
+    fn error_at(&self, offset: usize, message: &'static str) -> DecodeError {
+        DecodeError { offset, message }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() - self.pos < len {
+            return Err(self.error("unexpected end of input"));
+        }
+        let res = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(res)
+    }
+
+    fn varint(&mut self) -> Result<u64, DecodeError> {
+        let start = self.pos;
+        let mut res = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            let bits = u64::from(byte & 0x7f);
+            if bits << shift >> shift != bits {
+                return Err(self.error_at(start, "varint overflows 64 bits"));
+            }
+            res |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(res);
+            }
+        }
+        Err(self.error_at(start, "varint overflows 64 bits"))
+    }
+
+    /// Capacity for `n` items, where each item takes at least a byte.
+    fn capacity_for(&self, n: u64) -> usize {
+        n.min((self.bytes.len() - self.pos) as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_utils::{deep_tree, mixed_tree, nested_tree, Rng},
+        GreenNodeBuilder, TextRange,
+    };
+    use std::sync::Arc;
+
+    fn round_trip(green: &GreenNode) -> GreenNode {
+        let bytes = green.to_bytes();
+        let back = GreenNode::from_bytes(&bytes).unwrap();
+        assert!(back.structurally_eq(green));
+        assert_eq!(back.text_len(), green.text_len());
+        assert_eq!(back.to_bytes(), bytes);
+        back
+    }
+
+    #[test]
+    fn round_trips() {
+        round_trip(&mixed_tree(&mut Rng::new(92), 10_000));
+        round_trip(&nested_tree(10_000));
+        // `to_bytes` and `from_bytes` don't recurse.
+        round_trip(&deep_tree(100_000));
+
+        let empty = GreenNode::new(SyntaxKind(u16::MAX), Vec::new().into_boxed_slice());
+        round_trip(&empty);
+
+        let source: Arc<str> = format!("{}\n", "ü".repeat(1_000)).into();
+        let mut builder = GreenNodeBuilder::with_source(source);
+        builder.start_node(SyntaxKind(0));
+        builder.source_token(SyntaxKind(1), TextRange::from_to(0.into(), 2_000.into()));
+        builder.source_token(SyntaxKind(2), TextRange::from_to(2_000.into(), 2_001.into()));
+        builder.finish_node();
+        round_trip(&builder.finish());
+    }
+
+    #[test]
+    fn texts_are_stored_once() {
+        let green = nested_tree(10_000);
+        let bytes = green.to_bytes();
+        // Two strings, and two bytes per record.
+        let n_records = green.descendant_node_count() + green.descendant_token_count() + 1;
+        assert!(bytes.len() <= 2 * n_records as usize + 32, "{}", bytes.len());
+        let back = GreenNode::from_bytes(&bytes).unwrap();
+        assert_eq!(back.memory_usage().tokens, 2);
+    }
+
+    #[test]
+    fn corrupted_input_is_an_error() {
+        let green = mixed_tree(&mut Rng::new(1), 200);
+        let bytes = green.to_bytes();
+
+        for len in 0..bytes.len() {
+            assert!(GreenNode::from_bytes(&bytes[..len]).is_err(), "{}", len);
+        }
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(GreenNode::from_bytes(&trailing).is_err());
+        let mut version = bytes.clone();
+        version[MAGIC.len()] += 1;
+        assert_eq!(GreenNode::from_bytes(&version).unwrap_err().offset(), MAGIC.len());
+
+        // Whatever decodes is a consistent tree.
+        let mut rng = Rng::new(92);
+        for _ in 0..10_000 {
+            let mut corrupted = bytes.clone();
+            for _ in 0..=rng.below(3) {
+                let i = rng.below(corrupted.len() as u64) as usize;
+                corrupted[i] = rng.below(256) as u8;
+            }
+            if let Ok(it) = GreenNode::from_bytes(&corrupted) {
+                round_trip(&it);
+            }
+        }
+
+        let mut huge = MAGIC.to_vec();
+        huge.push(VERSION);
+        write_varint(&mut huge, 0);
+        write_varint(&mut huge, u64::MAX);
+        assert!(GreenNode::from_bytes(&huge).is_err());
+        huge.extend_from_slice(&[0xff; 11]);
+        assert!(GreenNode::from_bytes(&huge).is_err());
+    }
+
+    #[test]
+    fn lazy_decoding() {
+        let green = mixed_tree(&mut Rng::new(92), 10_000);
+        let bytes = green.to_bytes();
+        let tree = LazyGreenTree::new(&bytes).unwrap();
+        let root = tree.root();
+        assert_eq!(root.kind(), green.kind());
+        assert_eq!(root.text_len(), green.text_len());
+        assert_eq!(tree.n_decoded.get(), 0);
+
+        // Decoding the first child node doesn't touch its siblings.
+        let (index, first) = root
+            .children()
+            .enumerate()
+            .find_map(|(i, it)| match it {
+                LazyElement::Node(it) => Some((i, it)),
+                LazyElement::Token(_) => None,
+            })
+            .unwrap();
+        let expected = match &green.children()[index] {
+            GreenElement::Node(it) => it,
+            GreenElement::Token(_) => unreachable!(),
+        };
+        assert_eq!(first.text_len(), expected.text_len());
+        assert!(first.green().structurally_eq(expected));
+        let n_nodes = expected.descendant_node_count() as usize + 1;
+        assert_eq!(tree.n_decoded.get(), n_nodes);
+        assert!(n_nodes * 10 < green.descendant_node_count() as usize);
+        first.green();
+        assert_eq!(tree.n_decoded.get(), n_nodes);
+
+        // So does decoding everything.
+        let all = root.green();
+        assert!(all.structurally_eq(&GreenNode::from_bytes(&bytes).unwrap()));
+        assert_eq!(tree.n_decoded.get(), green.descendant_node_count() as usize + 1);
+        match &all.children()[index] {
+            GreenElement::Node(it) => assert!(it.ptr_eq(&first.green())),
+            GreenElement::Token(_) => unreachable!(),
+        }
+
+        // The index and the texts are ready before any node is decoded.
+        let deep = deep_tree(100_000).to_bytes();
+        let tree = LazyGreenTree::new(&deep).unwrap();
+        let mut node = tree.root();
+        while let Some(LazyElement::Node(it)) = node.children().next() {
+            node = it;
+        }
+        assert_eq!(node.text_len(), 1.into());
+        assert_eq!(tree.n_decoded.get(), 0);
+        assert_eq!(node.green().text_len(), 1.into());
+
+        for len in (0..bytes.len()).step_by(97) {
+            assert!(LazyGreenTree::new(&bytes[..len]).is_err());
+        }
+    }
+}
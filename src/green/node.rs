@@ -0,0 +1,1445 @@
+use std::{
+    alloc::{self, Layout},
+    fmt,
+    hash::{Hash, Hasher},
+    ops::Range,
+    mem, ptr, slice,
+    sync::atomic::{self, AtomicUsize, Ordering},
+};
+
+use rustc_hash::{FxHashMap, FxHashSet, FxHasher};
+
+use crate::{
+    diagnostics::KindDisplay,
+    green::{GreenElement, GreenIndex, GreenToken, MAX_REFCOUNT},
+    SyntaxKind, TextRange, TextUnit, MAX_CHILDREN,
+};
+
+/// Internal node in the immutable tree.
+/// It has other nodes and tokens as children.
+///
+/// `GreenNode` is a thin pointer to a single reference counted allocation,
+/// which holds the header (kind, text length, content hash, subtree sizes and
+/// the number of children) immediately followed by the children array. A
+/// clone is a cheap handle to the same allocation: it is O(1), and the clones
+/// are `ptr_eq`.
+///
+/// Green trees are `Send + Sync`: a tree built on one thread can be moved to,
+/// or shared with, others, each of which creates its own red nodes on top.
+/// The price is that the reference count is atomic, so a clone and a drop
+/// take about 15ns instead of 1ns for an `Rc` (see the `refcount` benchmarks
+/// in `benches/operations.rs`). Red nodes point into the tree without
+/// touching the counts, so only building and editing trees pay it.
+pub struct GreenNode {
+    ptr: ptr::NonNull<GreenNodeHead>,
+}
+
+#[repr(C)]
+struct GreenNodeHead {
+    rc: AtomicUsize,
+    content_hash: u64,
+    // Number of descendant nodes in the high half, of descendant tokens in
+    // the low half.
+    descendant_counts: u64,
+    kind: SyntaxKind,
+    text_len: TextUnit,
+    n_children: u32,
+    newline_count: u32,
+}
+
+// The actual layout of the allocation `GreenNode` points to. We never create
+// values of this type, we only materialize fat pointers to it to get
+// correctly aligned access to the trailing children.
+#[repr(C)]
+struct GreenNodeData {
+    head: GreenNodeHead,
+    children: [GreenElement],
+}
+
+// `GreenNode` is conceptually an `Arc<(GreenNodeHead, [GreenElement])>`.
+unsafe impl Send for GreenNode {}
+unsafe impl Sync for GreenNode {}
+
+// Separate from `from_vec` to test it without billions of children.
+fn n_children(kind: SyntaxKind, len: usize) -> u32 {
+    if len > MAX_CHILDREN {
+        panic!("a {} node has {} children, more than MAX_CHILDREN", KindDisplay(kind), len)
+    }
+    len as u32
+}
+
+impl GreenNode {
+    /// Creates new Node.
+    ///
+    /// Panics if the text of the node is longer than `MAX_TEXT_LEN`, or if it
+    /// has more than `MAX_CHILDREN` children.
+    #[inline]
+    pub fn new(kind: SyntaxKind, children: Box<[GreenElement]>) -> GreenNode {
+        GreenNode::from_vec(kind, children.into_vec())
+    }
+
+    /// Panics if the text of the node is longer than `MAX_TEXT_LEN`, or if it
+    /// has more than `MAX_CHILDREN` children.
+    pub(crate) fn from_vec(kind: SyntaxKind, mut children: Vec<GreenElement>) -> GreenNode {
+        let n_children = n_children(kind, children.len());
+        let text_len = children
+            .iter()
+            .try_fold(0u32, |acc, x| acc.checked_add(x.text_len().to_usize() as u32))
+            .unwrap_or_else(|| {
+                panic!("the text of a {} node is longer than MAX_TEXT_LEN bytes", KindDisplay(kind))
+            });
+        let text_len = TextUnit::from(text_len);
+        let content_hash = {
+            let mut h = FxHasher::default();
+            kind.hash(&mut h);
+            for child in children.iter() {
+                match child {
+                    GreenElement::Node(it) => h.write_u64(it.content_hash()),
+                    GreenElement::Token(it) => {
+                        it.kind().hash(&mut h);
+                        it.text().hash(&mut h);
+                    }
+                }
+            }
+            h.finish()
+        };
+        let (nodes, tokens) =
+            children.iter().fold((0u32, 0u32), |(nodes, tokens), child| match child {
+                GreenElement::Node(it) => (
+                    nodes.saturating_add(it.descendant_node_count()).saturating_add(1),
+                    tokens.saturating_add(it.descendant_token_count()),
+                ),
+                GreenElement::Token(_) => (nodes, tokens.saturating_add(1)),
+            });
+        let descendant_counts = (u64::from(nodes) << 32) | u64::from(tokens);
+        let newline_count = children.iter().fold(0u32, |acc, child| {
+            let n = match child {
+                GreenElement::Node(it) => it.newline_count(),
+                GreenElement::Token(it) => it.newline_count(),
+            };
+            acc.saturating_add(n)
+        });
+        let (layout, table_offset) = GreenNodeHead::layout_with_table(children.len());
+        unsafe {
+            let ptr = alloc::alloc(layout) as *mut GreenNodeHead;
+            let ptr = match ptr::NonNull::new(ptr) {
+                Some(it) => it,
+                None => alloc::handle_alloc_error(layout),
+            };
+            ptr.as_ptr().write(GreenNodeHead {
+                rc: AtomicUsize::new(1),
+                content_hash,
+                descendant_counts,
+                kind,
+                text_len,
+                n_children,
+                newline_count,
+            });
+            let res = GreenNode { ptr };
+            if let Some(table_offset) = table_offset {
+                let table = (ptr.as_ptr() as *mut u8).add(table_offset) as *mut TextUnit;
+                let mut start = TextUnit::from(0);
+                for (i, child) in children.iter().enumerate() {
+                    table.add(i).write(start);
+                    start += child.text_len();
+                }
+            }
+            // Move the children into the allocation; the vector keeps only
+            // its (now empty) buffer.
+            let dst = (*res.data_ptr()).children.as_mut_ptr();
+            ptr::copy_nonoverlapping(children.as_ptr(), dst, children.len());
+            children.set_len(0);
+            res
+        }
+    }
+
+    /// Kind of this node.
+    #[inline]
+    pub fn kind(&self) -> SyntaxKind {
+        self.head().kind
+    }
+
+    /// Length of the text, covered by this node.
+    #[inline]
+    pub fn text_len(&self) -> TextUnit {
+        self.head().text_len
+    }
+    /// A 64-bit hash of the kinds and texts in this subtree, computed once
+    /// when the node is constructed.
+    ///
+    /// Structurally equal trees have equal hashes, but equal hashes don't
+    /// imply equal trees.
+    #[inline]
+    pub fn content_hash(&self) -> u64 {
+        self.head().content_hash
+    }
+
+    /// Number of nodes in this subtree, not counting the node itself.
+    ///
+    /// Computed once when the node is constructed; saturates at `u32::MAX`.
+    #[inline]
+    pub fn descendant_node_count(&self) -> u32 {
+        (self.head().descendant_counts >> 32) as u32
+    }
+
+    /// Number of tokens in this subtree.
+    ///
+    /// Computed once when the node is constructed; saturates at `u32::MAX`.
+    #[inline]
+    pub fn descendant_token_count(&self) -> u32 {
+        self.head().descendant_counts as u32
+    }
+
+    /// Number of `\n` characters in the text of this node. Like
+    /// `GreenToken::newline_count`, this doesn't treat `\r` specially.
+    #[inline]
+    pub fn newline_count(&self) -> u32 {
+        self.head().newline_count
+    }
+
+    /// Number of `\n` characters in `text[..rel_offset]`, where `text` is the
+    /// text of this node. Descends into a single child on each level, using
+    /// the cached counts of the preceding siblings.
+    pub(crate) fn newlines_before(&self, rel_offset: TextUnit) -> u32 {
+        assert!(
+            rel_offset <= self.text_len(),
+            "offset {:?} is out of bounds, the node is {:?} long",
+            rel_offset,
+            self.text_len()
+        );
+        let mut res = 0u32;
+        let mut node = self;
+        let mut rel_offset = rel_offset;
+        'descend: loop {
+            let mut start = TextUnit::from(0);
+            for child in node.children() {
+                let end = start + child.text_len();
+                if rel_offset < end {
+                    rel_offset -= start;
+                    match child {
+                        GreenElement::Node(it) => {
+                            node = it;
+                            continue 'descend;
+                        }
+                        GreenElement::Token(it) => {
+                            let prefix = &it.text()[..rel_offset.to_usize()];
+                            let n = prefix.bytes().filter(|&it| it == b'\n').count() as u32;
+                            return res.saturating_add(n);
+                        }
+                    }
+                }
+                res = res.saturating_add(match child {
+                    GreenElement::Node(it) => it.newline_count(),
+                    GreenElement::Token(it) => it.newline_count(),
+                });
+                start = end;
+            }
+            return res;
+        }
+    }
+
+    /// Checks if the two trees have the same shape, kinds and texts.
+    ///
+    /// This is what `==` does as well. Shared subtrees are compared in O(1),
+    /// and the comparison doesn't recurse, so it works for arbitrary deep
+    /// trees.
+    pub fn structurally_eq(&self, other: &GreenNode) -> bool {
+        let mut stack = vec![(self, other)];
+        while let Some((left, right)) = stack.pop() {
+            if left.ptr_eq(right) {
+                continue;
+            }
+            if left.kind() != right.kind()
+                || left.text_len() != right.text_len()
+                || left.content_hash() != right.content_hash()
+                || left.children().len() != right.children().len()
+            {
+                return false;
+            }
+            for pair in left.children().iter().zip(right.children().iter()) {
+                match pair {
+                    (GreenElement::Node(l), GreenElement::Node(r)) => stack.push((l, r)),
+                    (GreenElement::Token(l), GreenElement::Token(r)) => {
+                        if l != r {
+                            return false;
+                        }
+                    }
+                    _ => return false,
+                }
+            }
+        }
+        true
+    }
+
+    /// Checks if both nodes point to the same allocation, which implies that
+    /// they are equal.
+    #[inline]
+    pub fn ptr_eq(&self, other: &GreenNode) -> bool {
+        self.ptr == other.ptr
+    }
+
+    /// The address of the allocation, which identifies shared nodes.
+    pub(crate) fn as_ptr(&self) -> *const u8 {
+        self.ptr.as_ptr() as *const u8
+    }
+
+    /// Children of this node.
+    #[inline]
+    pub fn children(&self) -> &[GreenElement] {
+        unsafe { &(*self.data_ptr()).children }
+    }
+
+    /// Finds the child which contains `rel_offset`, an offset relative to the
+    /// start of this node. Returns the index of the child, its offset relative
+    /// to this node, and the child itself.
+    ///
+    /// If the offset is exactly at the boundary between two children, the
+    /// right one is returned, except at the very end of the node, where the
+    /// last child is returned. Empty children are never returned, unless they
+    /// are last. Returns `None` if the offset is outside of the node or if
+    /// the node has no children.
+    pub fn child_at_offset(
+        &self,
+        rel_offset: TextUnit,
+    ) -> Option<(usize, TextUnit, &GreenElement)> {
+        if rel_offset > self.text_len() {
+            return None;
+        }
+        if rel_offset == self.text_len() {
+            let index = self.children().len().checked_sub(1)?;
+            let child = &self.children()[index];
+            return Some((index, self.text_len() - child.text_len(), child));
+        }
+        let (index, start) = self.first_child_ending_after(rel_offset, false);
+        Some((index, start, &self.children()[index]))
+    }
+
+    /// Index of the first child, which ends after `rel_offset` (or at it, if
+    /// `inclusive`), together with its relative start offset. If there's no
+    /// such child, returns the number of children and the text length.
+    ///
+    /// This is a binary search for nodes with many children, and a linear
+    /// scan otherwise.
+    pub(crate) fn first_child_ending_after(
+        &self,
+        rel_offset: TextUnit,
+        inclusive: bool,
+    ) -> (usize, TextUnit) {
+        let children = self.children();
+        let ends_before = |start: TextUnit, child: &GreenElement| {
+            let end = start + child.text_len();
+            end < rel_offset || (end == rel_offset && !inclusive)
+        };
+        match self.offset_table() {
+            Some(starts) => {
+                let (mut lo, mut hi) = (0, children.len());
+                while lo < hi {
+                    let mid = lo + (hi - lo) / 2;
+                    if ends_before(starts[mid], &children[mid]) {
+                        lo = mid + 1;
+                    } else {
+                        hi = mid;
+                    }
+                }
+                (lo, starts.get(lo).copied().unwrap_or_else(|| self.text_len()))
+            }
+            None => {
+                let mut start = TextUnit::from(0);
+                for (index, child) in children.iter().enumerate() {
+                    if !ends_before(start, child) {
+                        return (index, start);
+                    }
+                    start += child.text_len();
+                }
+                (children.len(), start)
+            }
+        }
+    }
+
+    /// Start offset of the child at `index`, relative to the start of this
+    /// node.
+    pub(crate) fn child_offset(&self, index: usize) -> TextUnit {
+        match self.offset_table() {
+            Some(starts) => starts[index],
+            None => self.children()[..index].iter().map(|it| it.text_len()).sum(),
+        }
+    }
+
+    /// Start offsets of the children, for nodes with more than
+    /// `OFFSET_TABLE_THRESHOLD` children.
+    fn offset_table(&self) -> Option<&[TextUnit]> {
+        let n_children = self.children().len();
+        let (_, table_offset) = GreenNodeHead::layout_with_table(n_children);
+        let table_offset = table_offset?;
+        // The table is a part of the allocation, and is immutable.
+        unsafe {
+            let table = (self.ptr.as_ptr() as *const u8).add(table_offset) as *const TextUnit;
+            Some(slice::from_raw_parts(table, n_children))
+        }
+    }
+
+    /// Children of this node, together with their offsets relative to the
+    /// start of this node. Iterating from the back yields the same offsets
+    /// as iterating from the front.
+    #[inline]
+    pub fn children_with_offsets(&self) -> GreenChildren<'_> {
+        GreenChildren {
+            kind: self.kind(),
+            inner: self.children().iter(),
+            front_index: 0,
+            front_offset: 0.into(),
+            back_offset: self.text_len(),
+        }
+    }
+
+    /// Children at `start_index..`.
+    pub(crate) fn children_with_offsets_from(&self, start_index: usize) -> GreenChildren<'_> {
+        GreenChildren {
+            kind: self.kind(),
+            inner: self.children()[start_index..].iter(),
+            front_index: start_index,
+            front_offset: self.child_boundary(start_index),
+            back_offset: self.text_len(),
+        }
+    }
+
+    /// Children at `..end_index`.
+    pub(crate) fn children_with_offsets_to(&self, end_index: usize) -> GreenChildren<'_> {
+        GreenChildren {
+            kind: self.kind(),
+            inner: self.children()[..end_index].iter(),
+            front_index: 0,
+            front_offset: 0.into(),
+            back_offset: self.child_boundary(end_index),
+        }
+    }
+
+    /// The relative offset of the child at `index`, or the length of this
+    /// node if `index` is the number of children.
+    fn child_boundary(&self, index: usize) -> TextUnit {
+        if index == self.children().len() {
+            self.text_len()
+        } else {
+            self.child_offset(index)
+        }
+    }
+
+    /// Returns a new node of the given `kind` with the same children as
+    /// `self`. The children themselves are shared, not copied.
+    pub fn with_kind(&self, kind: SyntaxKind) -> GreenNode {
+        if kind == self.kind() {
+            return self.clone();
+        }
+        GreenNode::from_vec(kind, self.children().to_vec())
+    }
+
+    /// A copy of this node which claims to be `text_len` long, for testing
+    /// `validate` on corrupt trees.
+    #[cfg(test)]
+    pub(crate) fn with_corrupt_text_len(&self, text_len: TextUnit) -> GreenNode {
+        let res = GreenNode::from_vec(self.kind(), self.children().to_vec());
+        // The node is not shared yet, and `text_len` is not used to free it.
+        unsafe { (*res.ptr.as_ptr()).text_len = text_len }
+        res
+    }
+
+    /// Returns a new node with the child at `index` replaced by `new_child`.
+    /// All other children are shared with `self`.
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn replace_child(&self, index: usize, new_child: GreenElement) -> GreenNode {
+        let n_children = self.children().len();
+        assert!(
+            index < n_children,
+            "replace_child: index {} is out of bounds, the node has {} children",
+            index,
+            n_children
+        );
+        let mut children = self.children().to_vec();
+        children[index] = new_child;
+        GreenNode::from_vec(self.kind(), children)
+    }
+
+    /// Returns a new node with `new_child` inserted at `index`, shifting all
+    /// children after it to the right. All other children are shared with
+    /// `self`.
+    ///
+    /// Panics if `index > children().len()`.
+    pub fn insert_child(&self, index: usize, new_child: GreenElement) -> GreenNode {
+        let n_children = self.children().len();
+        assert!(
+            index <= n_children,
+            "insert_child: index {} is out of bounds, the node has {} children",
+            index,
+            n_children
+        );
+        let mut children = Vec::with_capacity(n_children + 1);
+        children.extend_from_slice(&self.children()[..index]);
+        children.push(new_child);
+        children.extend_from_slice(&self.children()[index..]);
+        GreenNode::from_vec(self.kind(), children)
+    }
+
+    /// Returns a new node with the child at `index` removed. All other
+    /// children are shared with `self`.
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn remove_child(&self, index: usize) -> GreenNode {
+        let n_children = self.children().len();
+        assert!(
+            index < n_children,
+            "remove_child: index {} is out of bounds, the node has {} children",
+            index,
+            n_children
+        );
+        let mut children = self.children().to_vec();
+        children.remove(index);
+        GreenNode::from_vec(self.kind(), children)
+    }
+
+    /// Returns a new node with children in `range` replaced by `replace_with`.
+    /// Children before and after `range` are shared with `self`.
+    ///
+    /// An empty `range` inserts `replace_with` at `range.start`, an empty
+    /// `replace_with` removes `range`.
+    ///
+    /// Panics if `range` is out of bounds.
+    pub fn splice_children(
+        &self,
+        range: Range<usize>,
+        replace_with: impl IntoIterator<Item = GreenElement>,
+    ) -> GreenNode {
+        let n_children = self.children().len();
+        assert!(
+            range.start <= range.end && range.end <= n_children,
+            "splice_children: range {:?} is out of bounds, the node has {} children",
+            range,
+            n_children
+        );
+        let replace_with = replace_with.into_iter();
+        let mut children =
+            Vec::with_capacity(n_children - range.len() + replace_with.size_hint().0);
+        children.extend_from_slice(&self.children()[..range.start]);
+        children.extend(replace_with);
+        children.extend_from_slice(&self.children()[range.end..]);
+        GreenNode::from_vec(self.kind(), children)
+    }
+
+    /// Returns a new node, where each child for which `f` returns `Some` is
+    /// replaced with the returned element.
+    ///
+    /// If `f` returns `None` for all children, `self` is returned as is (that
+    /// is, the result is `ptr_eq` to `self`), and nothing is allocated.
+    pub fn map_children(
+        &self,
+        mut f: impl FnMut(&GreenElement) -> Option<GreenElement>,
+    ) -> GreenNode {
+        let mut new_children: Option<Vec<GreenElement>> = None;
+        for (index, child) in self.children().iter().enumerate() {
+            if let Some(new_child) = f(child) {
+                let children = new_children.get_or_insert_with(|| self.children().to_vec());
+                children[index] = new_child;
+            }
+        }
+        match new_children {
+            Some(children) => GreenNode::from_vec(self.kind(), children),
+            None => self.clone(),
+        }
+    }
+
+    /// Returns a new tree, where each token for which `f` returns `Some` is
+    /// replaced with the returned token. Tokens are visited in order.
+    ///
+    /// Only nodes on the paths from the root to the changed tokens are
+    /// rebuilt, all other subtrees are shared with `self`. If nothing
+    /// changed, this returns `self`. The traversal doesn't recurse, so it
+    /// works for arbitrary deep trees.
+    pub fn map_descendant_tokens(
+        &self,
+        mut f: impl FnMut(&GreenToken) -> Option<GreenToken>,
+    ) -> GreenNode {
+        // The nodes on the path to the current token, with the index of the
+        // next child to visit, and the new children if any of them changed.
+        let mut stack: Vec<(&GreenNode, usize, Option<Vec<GreenElement>>)> = vec![(self, 0, None)];
+        loop {
+            let (node, index, new_children) = stack.last_mut().unwrap();
+            let node: &GreenNode = node;
+            match node.children().get(*index) {
+                Some(GreenElement::Token(token)) => {
+                    if let Some(new_token) = f(token) {
+                        let children = new_children.get_or_insert_with(|| node.children().to_vec());
+                        children[*index] = new_token.into();
+                    }
+                    *index += 1;
+                }
+                Some(GreenElement::Node(child)) => stack.push((child, 0, None)),
+                None => {
+                    let (node, _, new_children) = stack.pop().unwrap();
+                    let new_node = new_children.map(|it| GreenNode::from_vec(node.kind(), it));
+                    let (parent, index, new_children) = match stack.last_mut() {
+                        Some(it) => it,
+                        None => return new_node.unwrap_or_else(|| node.clone()),
+                    };
+                    if let Some(new_node) = new_node {
+                        let children =
+                            new_children.get_or_insert_with(|| parent.children().to_vec());
+                        children[*index] = new_node.into();
+                    }
+                    *index += 1;
+                }
+            }
+        }
+    }
+
+    /// Gets the child at index.
+    pub(crate) fn get_child(&self, index: GreenIndex) -> Option<&GreenElement> {
+        self.children().get(index.0 as usize)
+    }
+
+    /// Number of memory bytes of occupied by subtree rooted at `self`, if
+    /// nothing in it was shared, see `memory_usage`.
+    pub(crate) fn memory_size_of_subtree(&self) -> usize {
+        self.memory_usage().unshared_bytes
+    }
+
+    /// Counts the memory used by the subtree rooted at `self`, telling apart
+    /// the memory which is actually allocated from the memory the tree would
+    /// take if shared nodes and tokens (for example, deduplicated by
+    /// `GreenNodeBuilder`) were separate copies.
+    ///
+    /// The traversal visits each shared node once, and needs memory
+    /// proportional to the number of distinct nodes.
+    pub fn memory_usage(&self) -> MemoryStats {
+        let mut res = MemoryStats::default();
+        let mut tokens = FxHashSet::default();
+        // The unshared size of each visited node, which is also the set of
+        // visited nodes.
+        let mut unshared = FxHashMap::<*const GreenNodeHead, usize>::default();
+        let mut stack = vec![(self, false)];
+        while let Some((node, children_done)) = stack.pop() {
+            let key = node.ptr.as_ptr() as *const GreenNodeHead;
+            if unshared.contains_key(&key) {
+                continue;
+            }
+            if !children_done {
+                stack.push((node, true));
+                stack.extend(node.children().iter().filter_map(|child| match child {
+                    GreenElement::Node(it) => Some((it, false)),
+                    GreenElement::Token(_) => None,
+                }));
+                continue;
+            }
+            let own_size = GreenNodeHead::layout(node.children().len()).size();
+            res.nodes += 1;
+            res.bytes += own_size;
+            let mut size = own_size;
+            for child in node.children() {
+                size += match child {
+                    GreenElement::Node(it) => unshared[&(it.ptr.as_ptr() as *const _)],
+                    GreenElement::Token(it) => {
+                        if tokens.insert(it.as_ptr()) {
+                            res.tokens += 1;
+                            res.text_bytes += it.text().len();
+                            res.bytes += it.memory_size();
+                        }
+                        it.memory_size()
+                    }
+                }
+            }
+            unshared.insert(key, size);
+        }
+        res.unshared_bytes = unshared[&(self.ptr.as_ptr() as *const _)];
+        res
+    }
+
+    #[inline]
+    fn head(&self) -> &GreenNodeHead {
+        // The allocation is alive as long as we hold a reference.
+        unsafe { self.ptr.as_ref() }
+    }
+
+    #[inline]
+    fn data_ptr(&self) -> *mut GreenNodeData {
+        let n_children = self.head().n_children as usize;
+        let children =
+            ptr::slice_from_raw_parts_mut(self.ptr.as_ptr() as *mut GreenElement, n_children);
+        children as *mut GreenNodeData
+    }
+}
+
+/// Memory used by a green tree, see `GreenNode::memory_usage`.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Number of distinct node allocations.
+    pub nodes: usize,
+    /// Number of distinct token allocations.
+    pub tokens: usize,
+    /// Bytes of text of the distinct tokens.
+    pub text_bytes: usize,
+    /// Bytes of all the distinct allocations, including the text.
+    pub bytes: usize,
+    /// Bytes the tree would take if nothing was shared.
+    pub unshared_bytes: usize,
+}
+
+impl fmt::Debug for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryStats")
+            .field("nodes", &self.nodes)
+            .field("tokens", &self.tokens)
+            .field("text", &Size(self.text_bytes))
+            .field("total", &Size(self.bytes))
+            .field("unshared", &Size(self.unshared_bytes))
+            .finish()
+    }
+}
+
+/// Formats a number of bytes in the largest unit which keeps it above one.
+struct Size(usize);
+
+impl fmt::Debug for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+        if self.0 < 1024 {
+            return write!(f, "{} B", self.0);
+        }
+        let mut size = self.0 as f64 / 1024.0;
+        let mut unit = 0;
+        while size >= 1024.0 && unit + 1 < UNITS.len() {
+            size /= 1024.0;
+            unit += 1;
+        }
+        write!(f, "{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// A child of a green node, together with its position.
+#[derive(Debug, Clone, Copy)]
+pub struct GreenChild<'a> {
+    index: usize,
+    rel_offset: TextUnit,
+    element: &'a GreenElement,
+}
+
+impl<'a> GreenChild<'a> {
+    /// Index of this child in the parent's children.
+    #[inline]
+    pub fn index(&self) -> usize {
+        self.index
+    }
+    /// Offset of this child, relative to the start of the parent.
+    #[inline]
+    pub fn rel_offset(&self) -> TextUnit {
+        self.rel_offset
+    }
+    /// Range of this child, relative to the start of the parent.
+    #[inline]
+    pub fn rel_range(&self) -> TextRange {
+        TextRange::offset_len(self.rel_offset, self.element.text_len())
+    }
+    /// The child itself.
+    #[inline]
+    pub fn element(&self) -> &'a GreenElement {
+        self.element
+    }
+}
+
+/// Iterator over the children of a green node with their offsets. See
+/// `GreenNode::children_with_offsets`.
+#[derive(Debug, Clone)]
+pub struct GreenChildren<'a> {
+    /// Kind of the parent, for the diagnostics of corrupt trees.
+    kind: SyntaxKind,
+    inner: slice::Iter<'a, GreenElement>,
+    front_index: usize,
+    front_offset: TextUnit,
+    back_offset: TextUnit,
+}
+
+impl<'a> Iterator for GreenChildren<'a> {
+    type Item = GreenChild<'a>;
+
+    #[inline]
+    fn next(&mut self) -> Option<GreenChild<'a>> {
+        let element = self.inner.next()?;
+        let res = GreenChild { index: self.front_index, rel_offset: self.front_offset, element };
+        self.front_index += 1;
+        self.front_offset += element.text_len();
+        Some(res)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a> DoubleEndedIterator for GreenChildren<'a> {
+    #[inline]
+    fn next_back(&mut self) -> Option<GreenChild<'a>> {
+        let element = self.inner.next_back()?;
+        let index = self.front_index + self.inner.len();
+        // With a corrupt length of the parent, or of a child, this would
+        // wrap around in release builds, and give garbage offsets.
+        self.back_offset = match self.back_offset.checked_sub(element.text_len()) {
+            Some(it) => it,
+            None => corrupt_child(self.kind, index, element, self.back_offset),
+        };
+        Some(GreenChild { index, rel_offset: self.back_offset, element })
+    }
+}
+
+#[cold]
+fn corrupt_child(kind: SyntaxKind, index: usize, child: &GreenElement, end: TextUnit) -> ! {
+    panic!(
+        "corrupt green tree: child {} of a {} node, a {} of length {}, \
+         does not fit before its end at {}, see `GreenNode::validate`",
+        index,
+        KindDisplay(kind),
+        KindDisplay(child.kind()),
+        child.text_len(),
+        end,
+    )
+}
+
+impl<'a> ExactSizeIterator for GreenChildren<'a> {}
+
+/// Nodes with more children than this store a table with the start offsets
+/// of the children after the children array, so that finding a child by
+/// offset doesn't sum the lengths of all the children before it. Smaller
+/// nodes don't pay for the table.
+const OFFSET_TABLE_THRESHOLD: usize = 32;
+
+impl GreenNodeHead {
+    fn layout(n_children: usize) -> Layout {
+        GreenNodeHead::layout_with_table(n_children).0
+    }
+
+    /// The layout of a node, and the offset of its table of child offsets,
+    /// if it has one.
+    fn layout_with_table(n_children: usize) -> (Layout, Option<usize>) {
+        let (layout, _) = Layout::new::<GreenNodeHead>()
+            .extend(Layout::array::<GreenElement>(n_children).unwrap())
+            .unwrap();
+        if n_children <= OFFSET_TABLE_THRESHOLD {
+            return (layout.pad_to_align(), None);
+        }
+        let (layout, table_offset) =
+            layout.extend(Layout::array::<TextUnit>(n_children).unwrap()).unwrap();
+        (layout.pad_to_align(), Some(table_offset))
+    }
+}
+
+impl Clone for GreenNode {
+    #[inline]
+    fn clone(&self) -> GreenNode {
+        let old = self.head().rc.fetch_add(1, Ordering::Relaxed);
+        if old > MAX_REFCOUNT {
+            std::process::abort();
+        }
+        GreenNode { ptr: self.ptr }
+    }
+}
+
+impl GreenNode {
+    /// Decrements the refcount, and returns `true` if this was the last
+    /// reference.
+    #[inline]
+    fn release(&self) -> bool {
+        if self.head().rc.fetch_sub(1, Ordering::Release) != 1 {
+            return false;
+        }
+        atomic::fence(Ordering::Acquire);
+        true
+    }
+
+    /// Frees the allocation of a node without references. Children nodes,
+    /// which lose their last reference, are pushed to `orphans` instead of
+    /// being dropped.
+    ///
+    /// Safety: the refcount must be zero, and `self` must be forgotten
+    /// afterwards.
+    unsafe fn free(&mut self, orphans: &mut Vec<GreenNode>) {
+        let n_children = self.children().len();
+        let children = (*self.data_ptr()).children.as_mut_ptr();
+        for i in 0..n_children {
+            match ptr::read(children.add(i)) {
+                GreenElement::Node(node) => {
+                    if node.release() {
+                        orphans.push(node)
+                    } else {
+                        mem::forget(node)
+                    }
+                }
+                GreenElement::Token(token) => drop(token),
+            }
+        }
+        alloc::dealloc(self.ptr.as_ptr() as *mut u8, GreenNodeHead::layout(n_children));
+    }
+}
+
+impl Drop for GreenNode {
+    fn drop(&mut self) {
+        if !self.release() {
+            return;
+        }
+        // Dropping children recursively would overflow the stack for deep
+        // trees, so nodes without references are kept in a worklist. It is
+        // only allocated if a node child has to be freed.
+        let mut orphans = Vec::new();
+        unsafe { self.free(&mut orphans) }
+        while let Some(mut node) = orphans.pop() {
+            unsafe { node.free(&mut orphans) }
+            mem::forget(node);
+        }
+    }
+}
+
+impl PartialEq for GreenNode {
+    fn eq(&self, other: &GreenNode) -> bool {
+        self.structurally_eq(other)
+    }
+}
+
+impl Eq for GreenNode {}
+
+impl Hash for GreenNode {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.content_hash());
+    }
+}
+
+impl fmt::Debug for GreenNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GreenNode")
+            .field("kind", &self.kind())
+            .field("text_len", &self.text_len())
+            .field("children", &self.children())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{self, TreeConfig};
+
+    #[test]
+    fn node_is_a_single_allocation() {
+        let token = GreenToken::new(SyntaxKind(0), "x".into());
+        let token_size = token.memory_size();
+        let node = GreenNode::new(
+            SyntaxKind(1),
+            vec![token.clone().into(), token.clone().into(), token.into()].into_boxed_slice(),
+        );
+        let head_and_children = node.memory_size_of_subtree() - 3 * token_size;
+        assert_eq!(
+            head_and_children,
+            std::mem::size_of::<GreenNodeHead>() + 3 * std::mem::size_of::<GreenElement>()
+        );
+        assert_eq!(node.kind(), SyntaxKind(1));
+        assert_eq!(node.text_len(), 3.into());
+        assert_eq!(node.children().len(), 3);
+
+        let empty = GreenNode::new(SyntaxKind(2), Vec::new().into_boxed_slice());
+        assert_eq!(empty.text_len(), 0.into());
+        assert!(empty.children().is_empty());
+    }
+
+    #[test]
+    fn max_children() {
+        assert_eq!(n_children(SyntaxKind(1), MAX_CHILDREN), u32::MAX);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    #[should_panic(
+        expected = "a SyntaxKind(1) node has 4294967296 children, more than MAX_CHILDREN"
+    )]
+    fn too_many_children() {
+        n_children(SyntaxKind(1), MAX_CHILDREN + 1);
+    }
+
+    fn naive_child_at_offset(node: &GreenNode, offset: TextUnit) -> Option<(usize, TextUnit)> {
+        let mut res = None;
+        let mut start = TextUnit::from(0);
+        for (index, child) in node.children().iter().enumerate() {
+            let end = start + child.text_len();
+            if start <= offset && offset < end && res.is_none() {
+                res = Some((index, start));
+            }
+            if offset == node.text_len() && end == offset {
+                res = Some((index, start));
+            }
+            start = end;
+        }
+        res
+    }
+
+    #[test]
+    fn child_at_offset() {
+        let token = |text: &str| GreenElement::from(GreenToken::new(SyntaxKind(0), text.into()));
+        let texts = ["", "a", "bc", "", "def", ""];
+        // All subsequences of `texts`.
+        for mask in 0..(1 << texts.len()) {
+            let children: Vec<GreenElement> = (0..texts.len())
+                .filter(|i| mask & (1 << i) != 0)
+                .map(|i| token(texts[i]))
+                .collect();
+            let node = GreenNode::new(SyntaxKind(1), children.into_boxed_slice());
+            for offset in 0..=node.text_len().to_usize() + 1 {
+                let offset = TextUnit::from_usize(offset);
+                let actual = node.child_at_offset(offset).map(|(index, start, child)| {
+                    assert_eq!(child, &node.children()[index]);
+                    (index, start)
+                });
+                assert_eq!(actual, naive_child_at_offset(&node, offset), "{:?} {:?}", node, offset);
+            }
+        }
+    }
+
+    #[test]
+    fn child_at_offset_in_a_wide_node() {
+        let texts = ["", "a", "bc", "", "", "def"];
+        let children = (0..100_000).map(|i| token(texts[i * 7 % texts.len()])).collect::<Vec<_>>();
+        let node = GreenNode::from_vec(SyntaxKind(1), children);
+        assert!(node.offset_table().is_some());
+        let len = node.text_len().to_usize();
+        let offsets = (0..len + 2).step_by(97).chain(len - 10..len + 2);
+        for offset in offsets.map(TextUnit::from_usize) {
+            let actual = node.child_at_offset(offset).map(|(index, start, _)| (index, start));
+            assert_eq!(actual, naive_child_at_offset(&node, offset), "{:?}", offset);
+        }
+
+        // The table doesn't change the size of small nodes.
+        let small = GreenNode::from_vec(SyntaxKind(1), vec![token("a"); OFFSET_TABLE_THRESHOLD]);
+        assert!(small.offset_table().is_none());
+        assert_eq!(
+            GreenNodeHead::layout(OFFSET_TABLE_THRESHOLD).size(),
+            mem::size_of::<GreenNodeHead>()
+                + OFFSET_TABLE_THRESHOLD * mem::size_of::<GreenElement>()
+        );
+    }
+
+    #[test]
+    fn children_with_offsets() {
+        let token = |text: &str| GreenElement::from(GreenToken::new(SyntaxKind(0), text.into()));
+        let inner = GreenNode::new(SyntaxKind(1), vec![token("bc"), token("")].into_boxed_slice());
+        let node = GreenNode::new(
+            SyntaxKind(2),
+            vec![token("a"), inner.into(), token(""), token("def")].into_boxed_slice(),
+        );
+        let forward: Vec<_> =
+            node.children_with_offsets().map(|it| (it.index(), it.rel_range())).collect();
+        let mut backward: Vec<_> =
+            node.children_with_offsets().rev().map(|it| (it.index(), it.rel_range())).collect();
+        backward.reverse();
+        let range = |start: u32, end: u32| TextRange::from_to(start.into(), end.into());
+        assert_eq!(
+            forward,
+            vec![(0, range(0, 1)), (1, range(1, 3)), (2, range(3, 3)), (3, range(3, 6))]
+        );
+        assert_eq!(forward, backward);
+
+        // Meeting in the middle.
+        let mut iter = node.children_with_offsets();
+        assert_eq!(iter.next().unwrap().rel_offset(), 0.into());
+        assert_eq!(iter.next_back().unwrap().rel_offset(), 3.into());
+        assert_eq!(iter.len(), 2);
+        assert_eq!(iter.next_back().unwrap().index(), 2);
+        assert_eq!(iter.next().unwrap().rel_range(), range(1, 3));
+        assert!(iter.next().is_none());
+    }
+
+    fn token(text: &str) -> GreenElement {
+        GreenToken::new(SyntaxKind(0), text.into()).into()
+    }
+
+    fn texts(node: &GreenNode) -> Vec<String> {
+        node.children()
+            .iter()
+            .map(|it| match it {
+                GreenElement::Token(it) => it.text().to_string(),
+                GreenElement::Node(_) => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn child_editing() {
+        let node = GreenNode::new(SyntaxKind(1), vec![token("a"), token("b")].into_boxed_slice());
+
+        let replaced = node.replace_child(1, token("cd"));
+        assert_eq!(texts(&replaced), vec!["a", "cd"]);
+        assert_eq!(replaced.text_len(), 3.into());
+        assert_eq!(replaced.kind(), node.kind());
+        assert_eq!(texts(&node.replace_child(0, token(""))), vec!["", "b"]);
+
+        assert_eq!(texts(&node.insert_child(0, token("x"))), vec!["x", "a", "b"]);
+        assert_eq!(texts(&node.insert_child(1, token("x"))), vec!["a", "x", "b"]);
+        assert_eq!(texts(&node.insert_child(2, token("x"))), vec!["a", "b", "x"]);
+
+        assert_eq!(texts(&node.remove_child(0)), vec!["b"]);
+        let removed = node.remove_child(1);
+        assert_eq!(texts(&removed), vec!["a"]);
+        assert_eq!(removed.text_len(), 1.into());
+        // Untouched children are shared.
+        match (&removed.children()[0], &node.children()[0]) {
+            (GreenElement::Token(l), GreenElement::Token(r)) => assert!(l.ptr_eq(r)),
+            _ => unreachable!(),
+        }
+
+        let empty = GreenNode::new(SyntaxKind(1), Vec::new().into_boxed_slice());
+        let one = empty.insert_child(0, token("a"));
+        assert_eq!(texts(&one), vec!["a"]);
+        assert_eq!(one.remove_child(0), empty);
+    }
+
+    #[test]
+    fn child_editing_across_the_offset_table_threshold() {
+        let check = |node: &GreenNode| {
+            for offset in (0..=node.text_len().to_usize() + 1).map(TextUnit::from_usize) {
+                let actual = node.child_at_offset(offset).map(|(index, start, _)| (index, start));
+                assert_eq!(actual, naive_child_at_offset(node, offset));
+            }
+        };
+        let n = OFFSET_TABLE_THRESHOLD;
+        let narrow = GreenNode::from_vec(SyntaxKind(1), vec![token("ab"); n]);
+        assert!(narrow.offset_table().is_none());
+
+        let wide = narrow.insert_child(1, token("x"));
+        assert!(wide.offset_table().is_some());
+        check(&wide);
+        let wide = wide.replace_child(n / 2, token("long text"));
+        assert_eq!(wide.text_len(), TextUnit::from_usize(2 * (n - 1) + 1 + 9));
+        check(&wide);
+        let wide = wide.splice_children(2..4, vec![token(""), token("yz"), token("")]);
+        check(&wide);
+
+        let narrow = wide.remove_child(0).remove_child(0);
+        assert!(narrow.offset_table().is_none());
+        check(&narrow);
+        assert_eq!(narrow.children().len(), n);
+    }
+
+    #[test]
+    #[should_panic(expected = "replace_child: index 2 is out of bounds, the node has 2 children")]
+    fn replace_child_out_of_bounds() {
+        let node = GreenNode::new(SyntaxKind(1), vec![token("a"), token("b")].into_boxed_slice());
+        node.replace_child(2, token("c"));
+    }
+
+    #[test]
+    #[should_panic(expected = "insert_child: index 1 is out of bounds, the node has 0 children")]
+    fn insert_child_out_of_bounds() {
+        let node = GreenNode::new(SyntaxKind(1), Vec::new().into_boxed_slice());
+        node.insert_child(1, token("c"));
+    }
+
+    #[test]
+    #[should_panic(expected = "remove_child: index 0 is out of bounds, the node has 0 children")]
+    fn remove_child_out_of_bounds() {
+        let node = GreenNode::new(SyntaxKind(1), Vec::new().into_boxed_slice());
+        node.remove_child(0);
+    }
+
+    #[test]
+    fn splice_children() {
+        let node = GreenNode::new(
+            SyntaxKind(1),
+            vec![token("a"), token("b"), token("c")].into_boxed_slice(),
+        );
+        let splice = |range: Range<usize>, new: &[&str]| {
+            let spliced = node.splice_children(range, new.iter().map(|&it| token(it)));
+            assert_eq!(spliced.text_len(), TextUnit::of_str(&texts(&spliced).concat()));
+            texts(&spliced)
+        };
+        assert_eq!(splice(0..1, &["x", "y"]), vec!["x", "y", "b", "c"]);
+        assert_eq!(splice(1..2, &["x", "y"]), vec!["a", "x", "y", "c"]);
+        assert_eq!(splice(2..3, &[]), vec!["a", "b"]);
+        assert_eq!(splice(3..3, &["x"]), vec!["a", "b", "c", "x"]);
+        assert_eq!(splice(0..0, &["x"]), vec!["x", "a", "b", "c"]);
+        assert_eq!(splice(0..3, &["x"]), vec!["x"]);
+        assert_eq!(splice(0..3, &[]), Vec::<String>::new());
+        assert_eq!(splice(1..1, &[]), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "splice_children: range 2..4 is out of bounds, the node has 3 children"
+    )]
+    fn splice_children_out_of_bounds() {
+        let node = GreenNode::new(
+            SyntaxKind(1),
+            vec![token("a"), token("b"), token("c")].into_boxed_slice(),
+        );
+        node.splice_children(2..4, Vec::new());
+    }
+
+    fn node(children: Vec<GreenElement>) -> GreenElement {
+        GreenNode::new(SyntaxKind(1), children.into_boxed_slice()).into()
+    }
+
+    fn as_node(element: &GreenElement) -> &GreenNode {
+        match element {
+            GreenElement::Node(it) => it,
+            GreenElement::Token(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn map_children() {
+        let root = as_node(&node(vec![token("a"), node(vec![token("b")]), token("c")])).clone();
+        assert!(root.map_children(|_| None).ptr_eq(&root));
+
+        let mapped = root.map_children(|child| match child {
+            GreenElement::Token(it) if it.text() == "c" => Some(token("cc")),
+            _ => None,
+        });
+        assert!(!mapped.ptr_eq(&root));
+        assert_eq!(mapped.text_len(), 4.into());
+        assert!(as_node(&mapped.children()[1]).ptr_eq(as_node(&root.children()[1])));
+    }
+
+    #[test]
+    fn map_descendant_tokens() {
+        // (a (b (c)) (d))
+        let root = as_node(&node(vec![
+            token("a"),
+            node(vec![token("b"), node(vec![token("c")])]),
+            node(vec![token("d")]),
+        ]))
+        .clone();
+        assert!(root.map_descendant_tokens(|_| None).ptr_eq(&root));
+
+        let mut visited = String::new();
+        let mapped = root.map_descendant_tokens(|token| {
+            visited.push_str(token.text());
+            if token.text() == "c" {
+                Some(GreenToken::new(token.kind(), "C".into()))
+            } else {
+                None
+            }
+        });
+        assert_eq!(visited, "abcd");
+        assert!(!mapped.ptr_eq(&root));
+        let new_b = as_node(&mapped.children()[1]);
+        let old_b = as_node(&root.children()[1]);
+        assert!(!new_b.ptr_eq(old_b));
+        assert_eq!(as_node(&new_b.children()[1]).children()[0], token("C"));
+        // Everything off the changed path is shared.
+        match (&new_b.children()[0], &old_b.children()[0]) {
+            (GreenElement::Token(l), GreenElement::Token(r)) => assert!(l.ptr_eq(r)),
+            _ => unreachable!(),
+        }
+        assert!(as_node(&mapped.children()[2]).ptr_eq(as_node(&root.children()[2])));
+    }
+
+    #[test]
+    fn map_descendant_tokens_of_a_deep_tree() {
+        let root = test_utils::deep_tree(100_000);
+        assert!(root.map_descendant_tokens(|_| None).ptr_eq(&root));
+        let mapped =
+            root.map_descendant_tokens(|token| Some(GreenToken::new(token.kind(), "y".into())));
+        assert_eq!(mapped.descendant_node_count(), 99_999);
+        assert_eq!(mapped.text_len(), 1.into());
+        assert!(!mapped.structurally_eq(&root));
+    }
+
+    #[test]
+    fn descendant_counts() {
+        fn brute_force(node: &GreenNode) -> (u32, u32) {
+            let mut res = (0, 0);
+            for child in node.children() {
+                match child {
+                    GreenElement::Node(it) => {
+                        let (nodes, tokens) = brute_force(it);
+                        res = (res.0 + nodes + 1, res.1 + tokens);
+                    }
+                    GreenElement::Token(_) => res.1 += 1,
+                }
+            }
+            res
+        }
+        for seed in 0..100 {
+            let tree = test_utils::gen_tree(seed, &TreeConfig::default());
+            let (nodes, tokens) = brute_force(&tree);
+            assert_eq!(tree.descendant_node_count(), nodes);
+            assert_eq!(tree.descendant_token_count(), tokens);
+            for child in tree.children() {
+                if let GreenElement::Node(it) = child {
+                    assert_eq!(
+                        (it.descendant_node_count(), it.descendant_token_count()),
+                        brute_force(it)
+                    );
+                }
+            }
+        }
+
+        let empty = node(Vec::new());
+        assert_eq!(brute_force(as_node(&empty)), (0, 0));
+        assert_eq!(
+            (as_node(&empty).descendant_node_count(), as_node(&empty).descendant_token_count()),
+            (0, 0)
+        );
+        let edited = as_node(&node(vec![token("a"), empty.clone()]))
+            .insert_child(0, node(vec![empty, token("b")]));
+        assert_eq!((edited.descendant_node_count(), edited.descendant_token_count()), (3, 2));
+    }
+
+    #[test]
+    fn newline_count() {
+        let tree = node(vec![
+            token("fn f() {\r\n"),
+            node(vec![token("    "), token("/* a\nb */"), token("\n")]),
+            node(vec![]),
+            token("}"),
+        ]);
+        let tree = as_node(&tree);
+        let text = "fn f() {\r\n    /* a\nb */\n}";
+        assert_eq!(tree.newline_count(), 3);
+        for offset in 0..=text.len() {
+            let expected = text[..offset].matches('\n').count() as u32;
+            assert_eq!(tree.newlines_before(TextUnit::from_usize(offset)), expected);
+        }
+    }
+
+    #[test]
+    fn with_kind() {
+        let inner = node(vec![token("a")]);
+        let tree = node(vec![inner.clone(), token("b")]);
+        let tree = as_node(&tree);
+        let retagged = tree.with_kind(SyntaxKind(2));
+        assert_eq!(retagged.kind(), SyntaxKind(2));
+        assert_eq!(retagged.text_len(), tree.text_len());
+        assert_ne!(retagged.content_hash(), tree.content_hash());
+        assert!(as_node(&retagged.children()[0]).ptr_eq(as_node(&inner)));
+        assert!(tree.with_kind(tree.kind()).ptr_eq(tree));
+        assert_eq!(retagged.with_kind(tree.kind()), *tree);
+    }
+
+    #[test]
+    fn small_nodes_store_children_inline() {
+        // Statements like `name = value;`, where most nodes have one to
+        // four children, in a file with many statements.
+        let mut builder = crate::GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(0));
+        for i in 0..10_000 {
+            builder.start_node(SyntaxKind(1));
+            builder.start_node(SyntaxKind(2));
+            builder.token(SyntaxKind(3), format!("name{}", i).into());
+            builder.finish_node();
+            builder.token(SyntaxKind(4), " = ".into());
+            builder.start_node(SyntaxKind(5));
+            builder.token(SyntaxKind(3), i.to_string().into());
+            builder.finish_node();
+            builder.token(SyntaxKind(6), ";\n".into());
+            builder.finish_node();
+        }
+        builder.finish_node();
+        let root = builder.finish();
+        let stats = root.memory_usage();
+        assert_eq!((stats.nodes, stats.tokens), (30_001, 40_000));
+
+        // Only the file has a table of offsets, the statements are just the
+        // header and the children.
+        let head = mem::size_of::<GreenNodeHead>();
+        let element = mem::size_of::<GreenElement>();
+        assert!(root.offset_table().is_some());
+        let root_size = GreenNodeHead::layout(10_000).size();
+        assert_eq!(root_size, head + 10_000 * (element + mem::size_of::<TextUnit>()));
+        let token_size = |element: &GreenElement| match element {
+            GreenElement::Token(it) => it.memory_size(),
+            GreenElement::Node(_) => unreachable!(),
+        };
+        let mut expected = root_size;
+        for statement in root.children() {
+            let statement = as_node(statement);
+            assert!(statement.offset_table().is_none());
+            assert_eq!(GreenNodeHead::layout(4).size(), head + 4 * element);
+            expected += GreenNodeHead::layout(4).size();
+            for child in statement.children() {
+                let token = match child {
+                    GreenElement::Node(it) => {
+                        assert!(it.offset_table().is_none());
+                        expected += GreenNodeHead::layout(1).size();
+                        &it.children()[0]
+                    }
+                    GreenElement::Token(_) => child,
+                };
+                expected += token_size(token);
+            }
+        }
+        assert_eq!(GreenNodeHead::layout(1).size(), head + element);
+        assert_eq!(stats.bytes, expected);
+        assert_eq!(stats.unshared_bytes, expected);
+    }
+
+    #[test]
+    fn memory_usage() {
+        let word = token("word");
+        let list = GreenNode::new(SyntaxKind(1), vec![word.clone(), token(" ")].into_boxed_slice());
+        let shared = GreenNode::from_vec(SyntaxKind(0), vec![list.clone().into(); 100]);
+        let stats = shared.memory_usage();
+        assert_eq!((stats.nodes, stats.tokens, stats.text_bytes), (2, 2, 5));
+        let token_size = |element: &GreenElement| match element {
+            GreenElement::Token(it) => it.memory_size(),
+            GreenElement::Node(_) => unreachable!(),
+        };
+        let token_bytes = token_size(&word) + token_size(&token(" "));
+        let own_size = GreenNodeHead::layout(100).size();
+        let list_size = GreenNodeHead::layout(2).size() + token_bytes;
+        assert_eq!(stats.bytes, own_size + list_size);
+        assert_eq!(stats.unshared_bytes, own_size + 100 * list_size);
+        assert_eq!(stats.unshared_bytes, shared.memory_size_of_subtree());
+
+        // The same tree, but without sharing.
+        let copies = (0..100).map(|_| node(vec![token("word"), token(" ")])).collect::<Vec<_>>();
+        let unshared = GreenNode::from_vec(SyntaxKind(0), copies).memory_usage();
+        assert_eq!((unshared.nodes, unshared.tokens, unshared.text_bytes), (101, 200, 500));
+        assert_eq!(unshared.bytes, unshared.unshared_bytes);
+        assert_eq!(unshared.unshared_bytes, stats.unshared_bytes);
+
+        // Shared tokens, which are not in shared nodes.
+        let root = GreenNode::from_vec(SyntaxKind(0), vec![word.clone(), word.clone(), word]);
+        let stats = root.memory_usage();
+        assert_eq!((stats.nodes, stats.tokens, stats.text_bytes), (1, 1, 4));
+        assert_eq!(
+            format!("{:?}", stats),
+            format!(
+                "MemoryStats {{ nodes: 1, tokens: 1, text: 4 B, total: {} B, unshared: {} B }}",
+                stats.bytes, stats.unshared_bytes
+            )
+        );
+        assert_eq!(format!("{:?}", Size(3 * 1024 * 1024 / 2)), "1.5 MiB");
+    }
+
+    #[test]
+    fn memory_size_of_a_deep_tree() {
+        let root = test_utils::deep_tree(100_000);
+        let stats = root.memory_usage();
+        assert_eq!((stats.nodes, stats.tokens), (100_000, 1));
+        assert_eq!(root.memory_size_of_subtree(), stats.bytes);
+    }
+
+    #[test]
+    fn drop_of_a_deep_tree() {
+        let deep = std::thread::Builder::new()
+            .stack_size(256 * 1024)
+            .spawn(|| {
+                let mut node = GreenNode::new(SyntaxKind(0), vec![token("x")].into_boxed_slice());
+                for _ in 0..300_000 {
+                    node = GreenNode::new(SyntaxKind(0), vec![node.into()].into_boxed_slice());
+                }
+                // Shared subtrees are not freed with the tree.
+                let shared = node.clone();
+                let root =
+                    GreenNode::new(SyntaxKind(1), vec![node.into(), token("y")].into_boxed_slice());
+                drop(root);
+                assert_eq!(shared.head().rc.load(Ordering::Relaxed), 1);
+                drop(shared);
+            })
+            .unwrap();
+        deep.join().unwrap();
+    }
+}
@@ -0,0 +1,218 @@
+//! `Serialize` and `Deserialize` for green trees, behind the `serde1`
+//! feature.
+//!
+//! A node is a `(kind, children)` tuple, a token is a `(kind, text)` tuple,
+//! and a child is an enum with `Node` and `Token` variants. Text lengths are
+//! not stored: deserializing goes through `GreenNodeBuilder`, which computes
+//! them, and which deduplicates small nodes, like it does when parsing.
+use std::fmt;
+
+use serde::{
+    de::{self, DeserializeSeed, EnumAccess, SeqAccess, VariantAccess, Visitor},
+    ser::{SerializeSeq, SerializeTuple},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::{GreenElement, GreenNode, GreenNodeBuilder, GreenToken, SyntaxKind};
+
+const VARIANTS: &[&str] = &["Node", "Token"];
+
+struct Children<'a>(&'a GreenNode);
+
+/// Serializing recurses once per level of the tree, and so does
+/// deserializing, because serde formats nest the children in their parents.
+/// Trees up to 256 levels deep round trip on a 2 MiB stack (the default stack
+/// of spawned threads), even in debug builds; much deeper trees need a larger
+/// stack, and some formats limit the depth on their own. `to_bytes` and
+/// `from_bytes` work for trees of any depth.
+impl Serialize for GreenNode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(2)?;
+        tuple.serialize_element(&self.kind().0)?;
+        tuple.serialize_element(&Children(self))?;
+        tuple.end()
+    }
+}
+
+impl Serialize for Children<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.0.children().len()))?;
+        for child in self.0.children() {
+            seq.serialize_element(child)?;
+        }
+        seq.end()
+    }
+}
+
+impl Serialize for GreenToken {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(2)?;
+        tuple.serialize_element(&self.kind().0)?;
+        tuple.serialize_element(self.text())?;
+        tuple.end()
+    }
+}
+
+impl Serialize for GreenElement {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            GreenElement::Node(it) => {
+                serializer.serialize_newtype_variant("GreenElement", 0, VARIANTS[0], it)
+            }
+            GreenElement::Token(it) => {
+                serializer.serialize_newtype_variant("GreenElement", 1, VARIANTS[1], it)
+            }
+        }
+    }
+}
+
+/// See the `Serialize` impl for the limit on the depth of the trees.
+impl<'de> Deserialize<'de> for GreenNode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<GreenNode, D::Error> {
+        let mut state = State { builder: GreenNodeBuilder::new(), text_len: 0 };
+        NodeSeed(&mut state).deserialize(deserializer)?;
+        Ok(state.builder.finish())
+    }
+}
+
+impl<'de> Deserialize<'de> for GreenToken {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<GreenToken, D::Error> {
+        let (kind, text) = deserializer.deserialize_tuple(2, TokenVisitor)?;
+        Ok(GreenToken::new(kind, text.into()))
+    }
+}
+
+/// The builder of the tree being deserialized, and the length of the text
+/// so far, which must fit into a `TextUnit`.
+struct State {
+    builder: GreenNodeBuilder,
+    text_len: u64,
+}
+
+struct NodeSeed<'a>(&'a mut State);
+struct ChildrenSeed<'a>(&'a mut State);
+struct ElementSeed<'a>(&'a mut State);
+struct TokenSeed<'a>(&'a mut State);
+
+impl<'de> DeserializeSeed<'de> for NodeSeed<'_> {
+    type Value = ();
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_tuple(2, self)
+    }
+}
+
+impl<'de> Visitor<'de> for NodeSeed<'_> {
+    type Value = ();
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a green node")
+    }
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let kind: u16 = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        self.0.builder.start_node(SyntaxKind(kind));
+        if seq.next_element_seed(ChildrenSeed(&mut *self.0))?.is_none() {
+            return Err(de::Error::invalid_length(1, &"a green node"));
+        }
+        self.0.builder.finish_node();
+        Ok(())
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for ChildrenSeed<'_> {
+    type Value = ();
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ChildrenSeed<'_> {
+    type Value = ();
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a sequence of green elements")
+    }
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(()) = seq.next_element_seed(ElementSeed(&mut *self.0))? {}
+        Ok(())
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for ElementSeed<'_> {
+    type Value = ();
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_enum("GreenElement", VARIANTS, self)
+    }
+}
+
+impl<'de> Visitor<'de> for ElementSeed<'_> {
+    type Value = ();
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a green element")
+    }
+    fn visit_enum<A: EnumAccess<'de>>(self, data: A) -> Result<(), A::Error> {
+        let (variant, data) = data.variant::<Variant>()?;
+        match variant {
+            Variant::Node => data.newtype_variant_seed(NodeSeed(self.0)),
+            Variant::Token => data.newtype_variant_seed(TokenSeed(self.0)),
+        }
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for TokenSeed<'_> {
+    type Value = ();
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        let (kind, text) = deserializer.deserialize_tuple(2, TokenVisitor)?;
+        self.0.text_len += text.len() as u64;
+        if self.0.text_len > u64::from(u32::MAX) {
+            return Err(de::Error::custom("the text of the tree is longer than 4 GiB"));
+        }
+        self.0.builder.token(kind, text.into());
+        Ok(())
+    }
+}
+
+struct TokenVisitor;
+
+impl<'de> Visitor<'de> for TokenVisitor {
+    type Value = (SyntaxKind, String);
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a green token")
+    }
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(SyntaxKind, String), A::Error> {
+        let kind: u16 = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let text = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        Ok((SyntaxKind(kind), text))
+    }
+}
+
+enum Variant {
+    Node,
+    Token,
+}
+
+impl<'de> Deserialize<'de> for Variant {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Variant, D::Error> {
+        deserializer.deserialize_identifier(VariantVisitor)
+    }
+}
+
+struct VariantVisitor;
+
+impl<'de> Visitor<'de> for VariantVisitor {
+    type Value = Variant;
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("`Node` or `Token`")
+    }
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Variant, E> {
+        match value {
+            0 => Ok(Variant::Node),
+            1 => Ok(Variant::Token),
+            _ => Err(E::invalid_value(de::Unexpected::Unsigned(value), &self)),
+        }
+    }
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Variant, E> {
+        match value {
+            "Node" => Ok(Variant::Node),
+            "Token" => Ok(Variant::Token),
+            _ => Err(E::unknown_variant(value, VARIANTS)),
+        }
+    }
+}
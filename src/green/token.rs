@@ -0,0 +1,427 @@
+use std::{
+    alloc::{self, Layout},
+    fmt,
+    hash::{Hash, Hasher},
+    ptr, str,
+    sync::{
+        atomic::{self, AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+use crate::{SmolStr, SyntaxKind, TextRange, TextUnit, MAX_TEXT_LEN};
+
+/// Tokens use a 32 bit refcount, to keep the header at 16 bytes. Billions of
+/// references to a single token take tens of gigabytes of children arrays,
+/// so this is not a practical limit, but we still abort rather than overflow.
+const MAX_REFCOUNT: u32 = i32::MAX as u32;
+
+/// Texts up to this many bytes are stored inline, right after the token
+/// header. This matches the inline capacity of `SmolStr`: longer `SmolStr`s
+/// are heap allocated and shared, so we keep (and share) them as is.
+pub(crate) const INLINE_TEXT_CAP: usize = 22;
+
+/// Leaf node in the immutable tree.
+///
+/// Like `GreenNode`, this is a thin pointer, so that `GreenElement` is just
+/// a tagged pointer. Short texts live inline in the token's allocation,
+/// longer ones are either a `SmolStr`, or a range of a shared source text
+/// (see `GreenToken::new_shared`). The representation is not observable:
+/// equality and hashing only look at the kind and the text.
+pub struct GreenToken {
+    ptr: ptr::NonNull<GreenTokenHead>,
+}
+
+#[repr(C)]
+struct GreenTokenHead {
+    rc: AtomicU32,
+    kind: SyntaxKind,
+    repr: TextRepr,
+    text_len: TextUnit,
+    newline_count: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum TextRepr {
+    /// `text_len` bytes of utf-8 follow the header.
+    Inline,
+    /// A `SmolStr` follows the header.
+    Heap,
+    /// A `SharedText` follows the header.
+    Shared,
+}
+
+struct SharedText {
+    source: Arc<str>,
+    range: TextRange,
+}
+
+#[repr(C)]
+struct GreenTokenData<T: ?Sized> {
+    head: GreenTokenHead,
+    text: T,
+}
+
+// `GreenToken` is conceptually an `Arc<(GreenTokenHead, str)>`.
+unsafe impl Send for GreenToken {}
+unsafe impl Sync for GreenToken {}
+
+impl GreenToken {
+    /// Creates new Token.
+    ///
+    /// Panics if the text is longer than `MAX_TEXT_LEN`.
+    #[inline]
+    pub fn new(kind: SyntaxKind, text: SmolStr) -> GreenToken {
+        if text.len() <= INLINE_TEXT_CAP {
+            return GreenToken::new_inline(kind, &text);
+        }
+        let layout = Layout::new::<GreenTokenData<SmolStr>>();
+        unsafe {
+            let res = GreenToken::alloc(layout, kind, TextRepr::Heap, &text);
+            ptr::addr_of_mut!((*res.heap_ptr()).text).write(text);
+            res
+        }
+    }
+
+    /// Creates new Token, whose text is `source[range]`.
+    ///
+    /// Short texts are copied, but long ones (string literals, comments)
+    /// just keep a reference to `source`, so that the text is not
+    /// duplicated. Note that such tokens keep the whole `source` alive.
+    ///
+    /// Panics if `range` is out of bounds or not on `char` boundaries.
+    pub fn new_shared(kind: SyntaxKind, source: Arc<str>, range: TextRange) -> GreenToken {
+        let text = &source[range];
+        if text.len() <= INLINE_TEXT_CAP {
+            return GreenToken::new_inline(kind, text);
+        }
+        let layout = Layout::new::<GreenTokenData<SharedText>>();
+        unsafe {
+            let res = GreenToken::alloc(layout, kind, TextRepr::Shared, text);
+            ptr::addr_of_mut!((*res.shared_ptr()).text).write(SharedText { source, range });
+            res
+        }
+    }
+
+    /// A token with a heap allocated `text`, which claims to be `text_len`
+    /// long, for testing `GreenNode::validate` on corrupt trees, and huge
+    /// texts without allocating them. Inline tokens can't lie about their
+    /// length, as it is that of the text.
+    #[cfg(test)]
+    pub(crate) fn with_corrupt_text_len(
+        kind: SyntaxKind,
+        text: SmolStr,
+        text_len: TextUnit,
+    ) -> GreenToken {
+        let layout = Layout::new::<GreenTokenData<SmolStr>>();
+        unsafe {
+            let res = GreenToken::alloc(layout, kind, TextRepr::Heap, &text);
+            ptr::addr_of_mut!((*res.heap_ptr()).text).write(text);
+            (*res.ptr.as_ptr()).text_len = text_len;
+            res
+        }
+    }
+
+    fn new_inline(kind: SyntaxKind, text: &str) -> GreenToken {
+        debug_assert!(text.len() <= INLINE_TEXT_CAP);
+        let layout = GreenTokenHead::inline_layout(text.len());
+        unsafe {
+            let res = GreenToken::alloc(layout, kind, TextRepr::Inline, text);
+            let dst = (*res.inline_ptr()).text.as_mut_ptr();
+            ptr::copy_nonoverlapping(text.as_ptr(), dst, text.len());
+            res
+        }
+    }
+    /// Kind of this Token.
+    #[inline]
+    pub fn kind(&self) -> SyntaxKind {
+        self.head().kind
+    }
+    /// Text of this Token.
+    #[inline]
+    pub fn text(&self) -> &str {
+        unsafe {
+            match self.head().repr {
+                TextRepr::Inline => str::from_utf8_unchecked(&(*self.inline_ptr()).text),
+                TextRepr::Heap => (*self.heap_ptr()).text.as_str(),
+                TextRepr::Shared => {
+                    let SharedText { source, range } = &(*self.shared_ptr()).text;
+                    source.get_unchecked(range.start().to_usize()..range.end().to_usize())
+                }
+            }
+        }
+    }
+    /// Text of this Token, as a `SmolStr`.
+    #[deprecated(since = "0.5.7", note = "`text` now returns `&str`, use `text().into()`")]
+    pub fn text_smol_str(&self) -> SmolStr {
+        self.text().into()
+    }
+    /// Text of this Token.
+    #[inline]
+    pub fn text_len(&self) -> TextUnit {
+        self.head().text_len
+    }
+
+    /// Number of `\n` characters in the text of this Token.
+    ///
+    /// Only `\n` is counted, so `\r\n` is a single line break, and a lone
+    /// `\r` is not a line break at all.
+    #[inline]
+    pub fn newline_count(&self) -> u32 {
+        self.head().newline_count
+    }
+
+    /// Checks if both tokens point to the same allocation, which implies
+    /// that they are equal.
+    #[inline]
+    pub fn ptr_eq(&self, other: &GreenToken) -> bool {
+        self.ptr == other.ptr
+    }
+
+    /// The address of the allocation, which identifies shared tokens.
+    pub(crate) fn as_ptr(&self) -> *const u8 {
+        self.ptr.as_ptr() as *const u8
+    }
+
+    pub(crate) fn memory_size(&self) -> usize {
+        match self.head().repr {
+            TextRepr::Inline => GreenTokenHead::inline_layout(self.text().len()).size(),
+            TextRepr::Heap => {
+                let text = unsafe { &(*self.heap_ptr()).text };
+                let mut res = Layout::new::<GreenTokenData<SmolStr>>().size();
+                if text.is_heap_allocated() {
+                    res += text.len();
+                }
+                res
+            }
+            // The source text is accounted for by whoever owns it.
+            TextRepr::Shared => Layout::new::<GreenTokenData<SharedText>>().size(),
+        }
+    }
+
+    /// Allocates a token with the header filled in from `text`; the caller
+    /// is responsible for writing the payload of `repr`.
+    unsafe fn alloc(layout: Layout, kind: SyntaxKind, repr: TextRepr, text: &str) -> GreenToken {
+        // `from_usize` only checks this in debug builds. Checked before
+        // allocating, so that the panic doesn't leak the memory.
+        assert!(text.len() <= MAX_TEXT_LEN, "token text is longer than MAX_TEXT_LEN bytes");
+        let ptr = match ptr::NonNull::new(alloc::alloc(layout) as *mut GreenTokenHead) {
+            Some(it) => it,
+            None => alloc::handle_alloc_error(layout),
+        };
+        let text_len = TextUnit::from_usize(text.len());
+        let newline_count = text.bytes().filter(|&it| it == b'\n').count() as u32;
+        ptr.as_ptr().write(GreenTokenHead {
+            rc: AtomicU32::new(1),
+            kind,
+            repr,
+            text_len,
+            newline_count,
+        });
+        GreenToken { ptr }
+    }
+
+    #[inline]
+    fn head(&self) -> &GreenTokenHead {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    #[inline]
+    fn inline_ptr(&self) -> *mut GreenTokenData<[u8]> {
+        debug_assert!(self.head().repr == TextRepr::Inline);
+        let len = self.head().text_len.to_usize();
+        ptr::slice_from_raw_parts_mut(self.ptr.as_ptr() as *mut u8, len)
+            as *mut GreenTokenData<[u8]>
+    }
+
+    #[inline]
+    fn heap_ptr(&self) -> *mut GreenTokenData<SmolStr> {
+        debug_assert!(self.head().repr == TextRepr::Heap);
+        self.ptr.as_ptr() as *mut GreenTokenData<SmolStr>
+    }
+
+    #[inline]
+    fn shared_ptr(&self) -> *mut GreenTokenData<SharedText> {
+        debug_assert!(self.head().repr == TextRepr::Shared);
+        self.ptr.as_ptr() as *mut GreenTokenData<SharedText>
+    }
+}
+
+impl GreenTokenHead {
+    fn inline_layout(text_len: usize) -> Layout {
+        Layout::new::<GreenTokenHead>()
+            .extend(Layout::array::<u8>(text_len).unwrap())
+            .unwrap()
+            .0
+            .pad_to_align()
+    }
+}
+
+impl Clone for GreenToken {
+    #[inline]
+    fn clone(&self) -> GreenToken {
+        let old = self.head().rc.fetch_add(1, Ordering::Relaxed);
+        if old > MAX_REFCOUNT {
+            std::process::abort();
+        }
+        GreenToken { ptr: self.ptr }
+    }
+}
+
+impl Drop for GreenToken {
+    fn drop(&mut self) {
+        if self.head().rc.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+        atomic::fence(Ordering::Acquire);
+        unsafe {
+            let layout = match self.head().repr {
+                TextRepr::Inline => GreenTokenHead::inline_layout(self.text().len()),
+                TextRepr::Heap => {
+                    ptr::drop_in_place(&mut (*self.heap_ptr()).text);
+                    Layout::new::<GreenTokenData<SmolStr>>()
+                }
+                TextRepr::Shared => {
+                    ptr::drop_in_place(&mut (*self.shared_ptr()).text);
+                    Layout::new::<GreenTokenData<SharedText>>()
+                }
+            };
+            alloc::dealloc(self.ptr.as_ptr() as *mut u8, layout);
+        }
+    }
+}
+
+impl PartialEq for GreenToken {
+    fn eq(&self, other: &GreenToken) -> bool {
+        self.ptr_eq(other) || (self.kind() == other.kind() && self.text() == other.text())
+    }
+}
+
+impl Eq for GreenToken {}
+
+impl Hash for GreenToken {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.kind().hash(state);
+        self.text().hash(state);
+    }
+}
+
+impl fmt::Debug for GreenToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GreenToken")
+            .field("kind", &self.kind())
+            .field("text", &self.text())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(text: &str) -> GreenToken {
+        GreenToken::new(SyntaxKind(0), text.into())
+    }
+
+    #[test]
+    fn inline_boundary() {
+        let short = "a".repeat(INLINE_TEXT_CAP);
+        let long = "a".repeat(INLINE_TEXT_CAP + 1);
+        assert!(token(&short).head().repr == TextRepr::Inline);
+        assert!(token(&long).head().repr == TextRepr::Heap);
+        assert_eq!(token(&short).text(), short);
+        assert_eq!(token(&long).text(), long);
+        assert_eq!(token(&long).text_len(), TextUnit::from_usize(INLINE_TEXT_CAP + 1));
+    }
+
+    #[test]
+    fn empty_token() {
+        let empty = token("");
+        assert!(empty.head().repr == TextRepr::Inline);
+        assert_eq!(empty.text(), "");
+        assert_eq!(empty.text_len(), 0.into());
+        assert_eq!(empty, empty.clone());
+    }
+
+    #[test]
+    fn multi_byte_text_at_the_limit() {
+        // 11 two-byte characters fill the inline buffer exactly.
+        let text = "ы".repeat(INLINE_TEXT_CAP / 2);
+        assert_eq!(text.len(), INLINE_TEXT_CAP);
+        let t = token(&text);
+        assert!(t.head().repr == TextRepr::Inline);
+        assert_eq!(t.text(), text);
+
+        let text = format!("{}ы", "a".repeat(INLINE_TEXT_CAP - 1));
+        let t = token(&text);
+        assert!(t.head().repr == TextRepr::Heap);
+        assert_eq!(t.text(), text);
+    }
+
+    #[test]
+    fn equality_ignores_representation() {
+        let text = "a".repeat(INLINE_TEXT_CAP);
+        assert_eq!(token(&text), token(&text));
+        assert_ne!(token(&text), GreenToken::new(SyntaxKind(1), text.as_str().into()));
+        assert_ne!(token("a"), token("b"));
+    }
+
+    #[test]
+    fn memory_size() {
+        // Previously a token was an `Arc` with two counters, a kind and a
+        // 24 byte `SmolStr`: 48 bytes, even for a single space.
+        let old_size = 48;
+        // With a 32 bit refcount the header is only 4-aligned, so short
+        // texts are padded to a multiple of 4 rather than 8.
+        assert_eq!(token(" ").memory_size(), 20);
+        assert!(token(&"a".repeat(INLINE_TEXT_CAP)).memory_size() < old_size);
+        assert_eq!(std::mem::size_of::<GreenTokenHead>(), 16);
+    }
+
+    #[test]
+    fn shared_text() {
+        let comment = format!("/* {} */", "x".repeat(100));
+        let source: Arc<str> = format!("a {} b", comment).into();
+        let range = |start: usize, end: usize| {
+            TextRange::from_to(TextUnit::from_usize(start), TextUnit::from_usize(end))
+        };
+        let short = GreenToken::new_shared(SyntaxKind(0), source.clone(), range(0, 1));
+        assert!(short.head().repr == TextRepr::Inline);
+        let long =
+            GreenToken::new_shared(SyntaxKind(0), source.clone(), range(2, 2 + comment.len()));
+        assert!(long.head().repr == TextRepr::Shared);
+        assert_eq!(long.text(), comment);
+        assert_eq!(long.text_len(), TextUnit::of_str(&comment));
+
+        let owned = GreenToken::new(SyntaxKind(0), comment.as_str().into());
+        assert!(owned.head().repr == TextRepr::Heap);
+        assert_eq!(long, owned);
+        assert_eq!(hash(&long), hash(&owned));
+        assert_eq!(short, token("a"));
+        assert_eq!(hash(&short), hash(&token("a")));
+        assert!(long.memory_size() < owned.memory_size());
+        drop(source);
+        assert_eq!(long.clone().text(), comment);
+    }
+
+    fn hash(token: &GreenToken) -> u64 {
+        let mut h = rustc_hash::FxHasher::default();
+        token.hash(&mut h);
+        h.finish()
+    }
+
+    #[test]
+    fn newline_count() {
+        assert_eq!(token("").newline_count(), 0);
+        assert_eq!(token(" ").newline_count(), 0);
+        assert_eq!(token("\n").newline_count(), 1);
+        assert_eq!(token("\r\n\r\n").newline_count(), 2);
+        assert_eq!(token("\r").newline_count(), 0);
+        let long = format!("/*\n{}\n*/", "x\n".repeat(100));
+        assert_eq!(token(&long).newline_count(), 102);
+        let source: Arc<str> = long.as_str().into();
+        let range = TextRange::offset_len(0.into(), TextUnit::of_str(&long));
+        assert_eq!(GreenToken::new_shared(SyntaxKind(0), source, range).newline_count(), 102);
+    }
+}
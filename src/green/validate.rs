@@ -0,0 +1,182 @@
+//! Consistency checks of the lengths cached in green trees.
+use std::{error::Error, fmt};
+
+use crate::{
+    diagnostics::KindDisplay, green::child_index, GreenElement, GreenNode, GreenToken, SyntaxKind,
+    TextUnit,
+};
+
+/// The error returned by `GreenNode::validate` and
+/// `cursor::SyntaxNode::validate_invariants` for a tree whose cached lengths
+/// or offsets don't add up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    path: Vec<(u32, SyntaxKind)>,
+    message: &'static str,
+    expected: TextUnit,
+    actual: TextUnit,
+}
+
+impl ValidationError {
+    pub(crate) fn new(
+        path: Vec<(u32, SyntaxKind)>,
+        message: &'static str,
+        expected: TextUnit,
+        actual: TextUnit,
+    ) -> ValidationError {
+        ValidationError { path, message, expected, actual }
+    }
+
+    /// Child index and kind of each element on the way to the offending
+    /// one, starting with a child of the validated node. Empty if the
+    /// validated node itself is corrupt.
+    pub fn path(&self) -> &[(u32, SyntaxKind)] {
+        &self.path
+    }
+
+    pub(crate) fn prepend_path(mut self, prefix: &[(u32, SyntaxKind)]) -> ValidationError {
+        self.path.splice(0..0, prefix.iter().copied());
+        self
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid tree at [")?;
+        for (i, (index, kind)) in self.path.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} {}", index, KindDisplay(*kind))?;
+        }
+        write!(f, "]: {}, expected {}, got {}", self.message, self.expected, self.actual)
+    }
+}
+
+impl Error for ValidationError {}
+
+impl GreenNode {
+    /// Checks that the text length of each node is the sum of those of its
+    /// children, with consistent child offsets, and that the text length of
+    /// each token is that of its text.
+    ///
+    /// Trees built with the public constructors are always valid, so this is
+    /// a debugging aid for code which builds or edits trees by hand.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let mut path = Vec::new();
+        validate_node(self, &path)?;
+        let mut stack = vec![self.children().iter().enumerate()];
+        while let Some(children) = stack.last_mut() {
+            let (index, child) = match children.next() {
+                Some(it) => it,
+                None => {
+                    stack.pop();
+                    path.pop();
+                    continue;
+                }
+            };
+            path.push((child_index(index), child.kind()));
+            match child {
+                GreenElement::Node(it) => {
+                    validate_node(it, &path)?;
+                    stack.push(it.children().iter().enumerate());
+                }
+                GreenElement::Token(it) => {
+                    validate_token(it, &path)?;
+                    path.pop();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn validate_node(node: &GreenNode, path: &[(u32, SyntaxKind)]) -> Result<(), ValidationError> {
+    let mut offset = TextUnit::from(0);
+    for (index, child) in node.children().iter().enumerate() {
+        let actual = node.child_offset(index);
+        if actual != offset {
+            let mut path = path.to_vec();
+            path.push((child_index(index), child.kind()));
+            return Err(ValidationError::new(path, "wrong child offset", offset, actual));
+        }
+        offset += child.text_len();
+    }
+    if node.text_len() != offset {
+        let message = "node length is not the sum of the lengths of its children";
+        return Err(ValidationError::new(path.to_vec(), message, offset, node.text_len()));
+    }
+    Ok(())
+}
+
+fn validate_token(token: &GreenToken, path: &[(u32, SyntaxKind)]) -> Result<(), ValidationError> {
+    let expected = TextUnit::of_str(token.text());
+    if token.text_len() != expected {
+        let message = "token length is not the length of its text";
+        return Err(ValidationError::new(path.to_vec(), message, expected, token.text_len()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{LIST, WORD};
+
+    fn list(children: Vec<GreenElement>) -> GreenNode {
+        GreenNode::new(LIST, children.into())
+    }
+
+    fn word(text: &str) -> GreenElement {
+        GreenToken::new(WORD, text.into()).into()
+    }
+
+    #[test]
+    fn valid_trees() {
+        let tree = list(vec![word("a"), list(vec![]).into(), list(vec![word("bc")]).into()]);
+        assert_eq!(tree.validate(), Ok(()));
+        let wide = list((0..100).map(|i| word(&i.to_string())).collect());
+        assert_eq!(wide.validate(), Ok(()));
+    }
+
+    #[test]
+    fn corrupt_nodes() {
+        let bad = list(vec![word("bc")]).with_corrupt_text_len(5.into());
+        assert_eq!(bad.validate().unwrap_err().path(), &[]);
+
+        let tree = list(vec![word("a"), list(vec![word("d"), bad.into()]).into(), word("e")]);
+        let err = tree.validate().unwrap_err();
+        assert_eq!(err.path(), &[(1, LIST), (1, LIST)]);
+        assert_eq!(
+            err.to_string(),
+            "invalid tree at [1 SyntaxKind(2), 1 SyntaxKind(2)]: node length is not the sum \
+             of the lengths of its children, expected 2, got 5"
+        );
+    }
+
+    #[test]
+    fn kind_names() {
+        crate::diagnostics::set_test_kind_names();
+        let (list, word) = (SyntaxKind(1001), SyntaxKind(1000));
+        let bad = GreenToken::with_corrupt_text_len(word, "abc".into(), 1.into());
+        let tree = GreenNode::new(list, vec![bad.into()].into());
+        let tree = GreenNode::new(list, vec![tree.into()].into());
+        assert_eq!(
+            tree.validate().unwrap_err().to_string(),
+            "invalid tree at [0 SyntaxKind(1001) LIST, 0 SyntaxKind(1000) WORD]: token length \
+             is not the length of its text, expected 3, got 1"
+        );
+    }
+
+    #[test]
+    fn corrupt_tokens() {
+        let bad = GreenToken::with_corrupt_text_len(WORD, "abc".into(), 1.into());
+        let tree = list(vec![word("x"), bad.into()]);
+        let tree = list(vec![list(vec![]).into(), tree.into()]);
+        let err = tree.validate().unwrap_err();
+        assert_eq!(err.path(), &[(1, LIST), (1, WORD)]);
+        assert!(err
+            .to_string()
+            .ends_with("token length is not the length of its text, expected 3, got 1"));
+    }
+}
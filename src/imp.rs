@@ -10,7 +10,9 @@ use std::{
     any::Any,
 };
 
-use crate::{swap_cell::SwapCell, GreenElement, GreenNode, GreenIndex, TextUnit};
+use crate::{
+    green::child_index, swap_cell::SwapCell, GreenElement, GreenNode, GreenIndex, TextUnit,
+};
 use colosseum::sync::Arena;
 
 type LazyNode = SwapCell<(TextUnit, GreenIndex), SyntaxNode>;
@@ -91,7 +93,7 @@ where
     N: TransparentNewType<Repr = SyntaxNode>,
 {
     fn clone(&self) -> TreeArc<N> {
-        let n: &N = &*self;
+        let n: &N = self;
         TreeArc::new(n)
     }
 }
@@ -205,6 +207,10 @@ where
 ///
 /// Implementing this trait allows one to cast safely between the wrapper and
 /// the underlying representation.
+///
+/// # Safety
+///
+/// `Self` must be a `#[repr(transparent)]` wrapper around `Self::Repr`.
 pub unsafe trait TransparentNewType: Sized {
     /// Underlying representation of a newtype.
     type Repr;
@@ -214,6 +220,7 @@ pub unsafe trait TransparentNewType: Sized {
         unsafe { &*(repr as *const Self::Repr as *const Self) }
     }
     /// Cast wrapper to the underlying repr.
+    #[allow(clippy::wrong_self_convention)]
     fn into_repr(&self) -> &Self::Repr {
         assert!(mem::size_of::<Self>() == mem::size_of::<Self::Repr>());
         unsafe { &*(self as *const Self as *const Self::Repr) }
@@ -233,7 +240,13 @@ unsafe impl TransparentNewType for SyntaxNode {
 }
 
 impl SyntaxNode {
-    pub(crate) fn new_root(green: GreenNode, data: Option<Box<Any + Send + Sync>>) -> TreeArc<SyntaxNode> {
+    pub(crate) fn new_root(green: GreenNode, data: Option<Box<dyn Any + Send + Sync>>) -> TreeArc<SyntaxNode> {
+        #[cfg(all(debug_assertions, feature = "validate-trees"))]
+        {
+            if let Err(err) = green.validate() {
+                panic!("SyntaxNode::new: {}", err)
+            }
+        }
         let root = SyntaxRoot { arena: Arena::new(), data };
         let red_node: *mut SyntaxNode = {
             let red_node = root.arena.alloc(SwapCell::new((0.into(), GreenIndex(0))));
@@ -284,7 +297,7 @@ impl SyntaxNode {
                 GreenElement::Node(it) => {
                     let off = start_offset;
                     start_offset += it.text_len();
-                    Some(SwapCell::new((off, GreenIndex(index_in_green as u32))))
+                    Some(SwapCell::new((off, GreenIndex(child_index(index_in_green)))))
                 }
             },
         ));
@@ -307,7 +320,7 @@ impl SyntaxNode {
     }
 
     pub(crate) fn children_len(&self) -> SyntaxIndex {
-        SyntaxIndex(self.children_impl().len() as u32)
+        SyntaxIndex(child_index(self.children_impl().len()))
     }
 
     fn children_impl(&self) -> &[LazyNode] {
@@ -0,0 +1,172 @@
+use std::fmt;
+
+use crate::SyntaxKind;
+
+/// Number of kinds a `SyntaxKindSet` can span.
+const WINDOW: u32 = 256;
+
+/// A set of `SyntaxKind`s, which can be built in constants:
+///
+/// ```
+/// use rowan::{SyntaxKind, SyntaxKindSet};
+///
+/// const WHITESPACE: SyntaxKind = SyntaxKind(1);
+/// const COMMENT: SyntaxKind = SyntaxKind(2);
+/// const TRIVIA: SyntaxKindSet = SyntaxKindSet::new(&[WHITESPACE, COMMENT]);
+///
+/// assert!(TRIVIA.contains(COMMENT));
+/// assert!(!TRIVIA.contains(SyntaxKind(3)));
+/// ```
+///
+/// The set is a 256-bit window over the kinds, starting at the smallest
+/// kind in the set, so it takes 40 bytes and is `Copy`. The kinds of a set
+/// must be less than 256 apart, which holds for the kinds of a language
+/// declared in a single enum, if it has up to 256 of them. Building a larger
+/// set panics, or fails to compile in a constant.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SyntaxKindSet {
+    /// The kind of the lowest bit.
+    base: u16,
+    bits: [u64; 4],
+}
+
+impl SyntaxKindSet {
+    pub const EMPTY: SyntaxKindSet = SyntaxKindSet { base: 0, bits: [0; 4] };
+
+    pub const fn new(kinds: &[SyntaxKind]) -> SyntaxKindSet {
+        let mut res = SyntaxKindSet::EMPTY;
+        let mut i = 0;
+        while i < kinds.len() {
+            res = res.with(kinds[i]);
+            i += 1;
+        }
+        res
+    }
+
+    /// This set with `kind` added.
+    pub const fn with(self, kind: SyntaxKind) -> SyntaxKindSet {
+        let mut res = if self.is_empty() {
+            SyntaxKindSet { base: kind.0, bits: [0; 4] }
+        } else if kind.0 < self.base {
+            self.rebased(kind.0)
+        } else {
+            self
+        };
+        let index = (kind.0 - res.base) as u32;
+        assert!(index < WINDOW, "SyntaxKindSet: kinds must be less than 256 apart");
+        res.bits[(index / 64) as usize] |= 1 << (index % 64);
+        res
+    }
+
+    pub const fn union(self, other: SyntaxKindSet) -> SyntaxKindSet {
+        let mut res = self;
+        let mut index = 0;
+        while index < WINDOW {
+            if other.bits[(index / 64) as usize] & (1 << (index % 64)) != 0 {
+                res = res.with(SyntaxKind(other.base + index as u16));
+            }
+            index += 1;
+        }
+        res
+    }
+
+    pub const fn contains(&self, kind: SyntaxKind) -> bool {
+        if kind.0 < self.base {
+            return false;
+        }
+        let index = (kind.0 - self.base) as u32;
+        index < WINDOW && self.bits[(index / 64) as usize] & (1 << (index % 64)) != 0
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.bits[0] == 0 && self.bits[1] == 0 && self.bits[2] == 0 && self.bits[3] == 0
+    }
+
+    /// The kinds of this set, in increasing order.
+    pub fn iter(&self) -> impl Iterator<Item = SyntaxKind> + '_ {
+        (0..WINDOW)
+            .filter(move |index| self.bits[(index / 64) as usize] & (1 << (index % 64)) != 0)
+            .map(move |index| SyntaxKind(self.base + index as u16))
+    }
+
+    /// The same set, with the window starting at `base`, which is at most
+    /// the smallest kind.
+    const fn rebased(self, base: u16) -> SyntaxKindSet {
+        let mut res = SyntaxKindSet { base, bits: [0; 4] };
+        let shift = (self.base - base) as u32;
+        let mut index = 0;
+        while index < WINDOW {
+            if self.bits[(index / 64) as usize] & (1 << (index % 64)) != 0 {
+                let new_index = index + shift;
+                assert!(new_index < WINDOW, "SyntaxKindSet: kinds must be less than 256 apart");
+                res.bits[(new_index / 64) as usize] |= 1 << (new_index % 64);
+            }
+            index += 1;
+        }
+        res
+    }
+}
+
+impl Default for SyntaxKindSet {
+    fn default() -> SyntaxKindSet {
+        SyntaxKindSet::EMPTY
+    }
+}
+
+impl fmt::Debug for SyntaxKindSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOW: SyntaxKindSet = SyntaxKindSet::new(&[SyntaxKind(0), SyntaxKind(63), SyntaxKind(64)]);
+    const HIGH: SyntaxKindSet =
+        SyntaxKindSet::new(&[SyntaxKind(u16::MAX), SyntaxKind(u16::MAX - 255)]);
+    const BOTH: SyntaxKindSet = LOW.union(SyntaxKindSet::new(&[SyntaxKind(255)]));
+
+    #[test]
+    fn membership() {
+        let low = [0, 63, 64];
+        for kind in 0..=u16::MAX {
+            let kind = SyntaxKind(kind);
+            assert_eq!(LOW.contains(kind), low.contains(&kind.0), "{:?}", kind);
+            assert_eq!(HIGH.contains(kind), kind.0 == u16::MAX || kind.0 == u16::MAX - 255);
+            assert_eq!(BOTH.contains(kind), low.contains(&kind.0) || kind.0 == 255);
+        }
+        assert_eq!(format!("{:?}", HIGH), "{SyntaxKind(65280), SyntaxKind(65535)}");
+        assert_eq!(BOTH.iter().map(|it| it.0).collect::<Vec<_>>(), [0, 63, 64, 255]);
+
+        assert!(SyntaxKindSet::EMPTY.is_empty());
+        assert!(!SyntaxKindSet::EMPTY.contains(SyntaxKind(0)));
+        assert_eq!(SyntaxKindSet::new(&[]), SyntaxKindSet::default());
+        assert_eq!(LOW.union(SyntaxKindSet::EMPTY), LOW);
+        assert_eq!(SyntaxKindSet::EMPTY.union(HIGH), HIGH);
+    }
+
+    #[test]
+    fn insertion_order_does_not_matter() {
+        let kinds = [SyntaxKind(1000), SyntaxKind(1200), SyntaxKind(990), SyntaxKind(1245)];
+        let forward = SyntaxKindSet::new(&kinds);
+        let mut reversed = kinds;
+        reversed.reverse();
+        assert_eq!(SyntaxKindSet::new(&reversed), forward);
+        let halves = SyntaxKindSet::new(&kinds[2..]).union(SyntaxKindSet::new(&kinds[..2]));
+        assert_eq!(halves, forward);
+    }
+
+    #[test]
+    #[should_panic(expected = "kinds must be less than 256 apart")]
+    fn too_wide() {
+        LOW.union(HIGH);
+    }
+
+    #[test]
+    #[should_panic(expected = "kinds must be less than 256 apart")]
+    fn too_wide_when_rebased() {
+        SyntaxKindSet::new(&[SyntaxKind(300), SyntaxKind(44)]);
+    }
+}
@@ -1,5 +1,10 @@
 //! A generic library for lossless syntax trees.
 //! See `examples/s_expressions.rs` for a tutorial.
+//!
+//! Offsets and lengths are 32 bit `TextUnit`s, so the text of a tree can be
+//! at most `MAX_TEXT_LEN` bytes long. Building a tree with a longer text
+//! panics, and so does building a node with more than `MAX_CHILDREN`
+//! children.
 #![forbid(
     // missing_debug_implementations,
     unconditional_recursion,
@@ -11,16 +16,32 @@
 
 #[allow(unsafe_code)]
 mod swap_cell;
+#[allow(unsafe_code)]
 mod green;
 #[allow(unsafe_code)]
 pub mod cursor;
-mod api;
+pub mod arena;
+pub mod api;
 #[allow(unsafe_code)]
 mod imp;
 mod syntax_node;
 mod syntax_token;
 mod syntax_element;
-mod algo;
+pub mod algo;
+mod syntax_editor;
+mod syntax_text;
+mod node_map;
+mod visitor;
+mod dump;
+mod kind_set;
+mod trivia;
+pub mod query;
+pub mod reuse;
+pub mod generate;
+mod metrics;
+mod diagnostics;
+#[doc(hidden)]
+pub mod test_utils;
 
 use std::fmt;
 use crate::{green::GreenIndex, imp::SyntaxIndex};
@@ -33,7 +54,15 @@ pub use smol_str::SmolStr;
 pub use text_unit::{TextRange, TextUnit};
 
 pub use crate::{
-    green::{GreenNode, GreenToken, GreenElement, GreenNodeBuilder, Checkpoint},
+    diagnostics::set_kind_names,
+    dump::SexprError,
+    metrics::TreeMetrics,
+    kind_set::SyntaxKindSet,
+    green::{
+        GreenNode, GreenToken, GreenElement, GreenChild, GreenChildren, GreenNodeBuilder,
+        Checkpoint, MemoryStats, DecodeError, LazyGreenTree, LazyNode, LazyElement,
+        ValidationError,
+    },
     imp::SyntaxNode,
     syntax_token::SyntaxToken,
     syntax_element::SyntaxElement,
@@ -44,6 +73,13 @@ pub use crate::{
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct SyntaxKind(pub u16);
 
+/// The maximum length of the text of a tree, in bytes: the largest `TextUnit`.
+pub const MAX_TEXT_LEN: usize = u32::MAX as usize;
+
+/// The maximum number of children of a node, tokens included: child indices
+/// are 32 bit.
+pub const MAX_CHILDREN: usize = u32::MAX as usize;
+
 pub use crate::imp::{TransparentNewType, TreeArc};
 
 // NB: borrow requires that Eq & Hash for `Owned` are consistent with those for
@@ -54,7 +90,7 @@ where
     N: TransparentNewType<Repr = SyntaxNode>,
 {
     fn borrow(&self) -> &N {
-        &*self
+        self
     }
 }
 
@@ -63,7 +99,7 @@ where
     N: TransparentNewType<Repr = SyntaxNode> + fmt::Debug,
 {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        let inner: &N = &*self;
+        let inner: &N = self;
         fmt::Debug::fmt(inner, fmt)
     }
 }
@@ -76,10 +112,85 @@ mod tests {
     fn assert_send_sync() {
         fn f<T: Send + Sync>() {}
         f::<GreenNode>();
+        f::<GreenToken>();
+        f::<GreenElement>();
+        f::<cursor::SyntaxNodeHandle>();
+        f::<cursor::SyntaxNodePtr>();
         f::<SyntaxNode>();
         f::<TreeArc<SyntaxNode>>();
     }
 
+    #[test]
+    fn replace_in_deep_trees() {
+        use crate::test_utils::{deep_tree, WORD};
+
+        let root = SyntaxNode::new(deep_tree(100_000), None);
+        let mut node: &SyntaxNode = &root;
+        while let Some(child) = node.first_child() {
+            node = child;
+        }
+        let token = node.first_token().unwrap();
+        let new = token.replace_with(GreenToken::new(WORD, "y".into()));
+        assert_eq!(cursor::SyntaxNode::new_root(new.clone()).first_token().unwrap().text(), "y");
+        assert_eq!(new.descendant_node_count(), 99_999);
+
+        let new = node.replace_with(GreenNode::new(node.kind(), Box::new([])));
+        assert_eq!(new.text_len(), 0.into());
+    }
+
+    /// The innermost node of `deep_tree` and its only token.
+    fn innermost(root: &cursor::SyntaxNode) -> (cursor::SyntaxNode, cursor::SyntaxToken) {
+        let mut node = root.clone();
+        while let Some(child) = node.first_child() {
+            node = child;
+        }
+        let token = node.first_token().unwrap();
+        (node, token)
+    }
+
+    #[test]
+    fn replace_descendants_in_deep_trees() {
+        use crate::test_utils::{deep_tree, text_of, WORD};
+
+        let root = cursor::SyntaxNode::new_root(deep_tree(100_000));
+        let (_, token) = innermost(&root);
+        let new = root
+            .replace_descendants(vec![(token.into(), GreenToken::new(WORD, "y".into()).into())]);
+        assert_eq!(new.descendant_node_count(), 99_999);
+        assert_eq!(text_of(&cursor::SyntaxNode::new_root(new)), "y");
+    }
+
+    #[test]
+    fn syntax_editor_in_deep_trees() {
+        use crate::test_utils::{deep_tree, text_of, WORD, WS};
+
+        let root = cursor::SyntaxNode::new_root(deep_tree(100_000));
+        let (node, token) = innermost(&root);
+        let mut editor = cursor::SyntaxEditor::new(root.clone());
+        editor.insert_before(node, vec![GreenToken::new(WS, " ".into()).into()]);
+        editor.replace(token, GreenToken::new(WORD, "y".into()));
+        let new = editor.finish().unwrap();
+        assert_eq!(new.descendant_node_count(), 99_999);
+        assert_eq!(text_of(&cursor::SyntaxNode::new_root(new)), " y");
+    }
+
+    #[test]
+    fn try_replace_with() {
+        use crate::test_utils::{wide_tree, LIST, WORD, WS};
+
+        let root = SyntaxNode::new(wide_tree(3), None);
+        let token = root.first_token().unwrap();
+        let err = token.try_replace_with(GreenToken::new(WS, " ".into())).unwrap_err();
+        assert_eq!((err.expected, err.actual), (WORD, WS));
+        let new = token.try_replace_with(GreenToken::new(WORD, "y".into())).unwrap();
+        assert_eq!(new.children().len(), root.green().children().len());
+
+        let err = root.try_replace_with(GreenNode::new(WORD, Box::new([]))).unwrap_err();
+        assert_eq!((err.expected, err.actual), (LIST, WORD));
+        let new = root.try_replace_with(GreenNode::new(LIST, Box::new([]))).unwrap();
+        assert_eq!(new.text_len(), 0.into());
+    }
+
     #[test]
     fn test_size_of() {
         use std::mem::size_of;
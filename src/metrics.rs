@@ -0,0 +1,194 @@
+//! Statistics about the shape of syntax trees.
+use std::{cmp, collections::HashMap, fmt};
+
+use crate::{cursor::SyntaxNode, diagnostics::KindDisplay, GreenElement, SyntaxKind};
+
+/// Statistics about a subtree, see `SyntaxNode::metrics`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TreeMetrics {
+    /// Number of nodes on the longest path down from the root of the
+    /// subtree, not counting the root: 0 for a node without node children.
+    pub max_depth: usize,
+    /// Largest number of children (including tokens) of a node.
+    pub max_children: usize,
+    /// Number of nodes, including the root.
+    pub nodes: usize,
+    pub tokens: usize,
+    /// Bytes of text of the trivia tokens, and of the tokens of trivia nodes.
+    pub trivia_text_len: usize,
+    /// Bytes of text of the other tokens.
+    pub significant_text_len: usize,
+    /// Number of nodes and tokens of each kind.
+    pub kinds: HashMap<SyntaxKind, usize>,
+}
+
+impl SyntaxNode {
+    /// Computes the statistics of the subtree of this node, where all the
+    /// text is significant.
+    pub fn metrics(&self) -> TreeMetrics {
+        self.metrics_with_trivia(|_| false)
+    }
+
+    /// Computes the statistics of the subtree of this node in a single walk
+    /// over the green tree, where `is_trivia` tells apart the trivia text.
+    /// `is_trivia` is called once for each element of the subtree, in
+    /// preorder.
+    pub fn metrics_with_trivia(&self, is_trivia: impl Fn(SyntaxKind) -> bool) -> TreeMetrics {
+        let mut res = TreeMetrics::default();
+        let root = self.green();
+        res.nodes = 1;
+        *res.kinds.entry(root.kind()).or_default() += 1;
+        res.max_children = root.children().len();
+        // The children left to visit of each node on the path from the
+        // root, and whether the node is trivia or in trivia.
+        let mut stack = vec![(root.children().iter(), is_trivia(root.kind()))];
+        while let Some((children, in_trivia)) = stack.last_mut() {
+            let in_trivia = *in_trivia;
+            let child = match children.next() {
+                Some(it) => it,
+                None => {
+                    stack.pop();
+                    continue;
+                }
+            };
+            *res.kinds.entry(child.kind()).or_default() += 1;
+            let trivia = is_trivia(child.kind()) || in_trivia;
+            match child {
+                GreenElement::Node(it) => {
+                    res.nodes += 1;
+                    res.max_children = cmp::max(res.max_children, it.children().len());
+                    res.max_depth = cmp::max(res.max_depth, stack.len());
+                    stack.push((it.children().iter(), trivia));
+                }
+                GreenElement::Token(it) => {
+                    res.tokens += 1;
+                    let len = it.text().len();
+                    if trivia {
+                        res.trivia_text_len += len;
+                    } else {
+                        res.significant_text_len += len;
+                    }
+                }
+            }
+        }
+        res
+    }
+}
+
+impl fmt::Display for TreeMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} nodes, {} tokens, max depth {}, max children {}",
+            self.nodes, self.tokens, self.max_depth, self.max_children
+        )?;
+        writeln!(
+            f,
+            "text: {} bytes significant, {} bytes trivia",
+            self.significant_text_len, self.trivia_text_len
+        )?;
+        let mut kinds = self.kinds.iter().collect::<Vec<_>>();
+        kinds.sort_by_key(|&(kind, count)| (cmp::Reverse(*count), kind.0));
+        for (kind, count) in kinds {
+            writeln!(f, "{:>8} {}", count, KindDisplay(*kind))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::{
+        test_utils::{mixed_tree, Rng, LIST, WORD, WS},
+        GreenNode, GreenToken, WalkEvent,
+    };
+
+    const COMMENT: SyntaxKind = SyntaxKind(3);
+
+    #[test]
+    fn metrics() {
+        let kind = |name: &str| match name {
+            "WS" => Some(WS),
+            "WORD" => Some(WORD),
+            "LIST" => Some(LIST),
+            "COMMENT" => Some(COMMENT),
+            _ => None,
+        };
+        let green = GreenNode::from_sexpr(
+            r#"(LIST
+                (WORD "fn")
+                (WS " ")
+                (LIST (WORD "a") (LIST (WORD "bb") (WS "  ")))
+                (LIST (COMMENT "//x") (WS " "))
+                (WORD "c"))"#,
+            &kind,
+        )
+        .unwrap();
+        let metrics = SyntaxNode::new_root(green).metrics_with_trivia(|kind| kind == WS);
+        let mut kinds = HashMap::new();
+        kinds.insert(LIST, 4);
+        kinds.insert(WORD, 4);
+        kinds.insert(WS, 3);
+        kinds.insert(COMMENT, 1);
+        assert_eq!(
+            metrics,
+            TreeMetrics {
+                max_depth: 2,
+                max_children: 5,
+                nodes: 4,
+                tokens: 8,
+                trivia_text_len: 4,
+                significant_text_len: 9,
+                kinds,
+            }
+        );
+        assert_eq!(
+            metrics.to_string(),
+            "4 nodes, 8 tokens, max depth 2, max children 5
+text: 9 bytes significant, 4 bytes trivia
+       4 SyntaxKind(0)
+       4 SyntaxKind(2)
+       3 SyntaxKind(1)
+       1 SyntaxKind(3)
+"
+        );
+    }
+
+    #[test]
+    fn kind_names() {
+        crate::diagnostics::set_test_kind_names();
+        // Named by `set_test_kind_names`.
+        let (word, list) = (SyntaxKind(1000), SyntaxKind(1001));
+        let green = GreenNode::new(list, vec![GreenToken::new(word, "a".into()).into()].into());
+        assert_eq!(
+            SyntaxNode::new_root(green).metrics().to_string(),
+            "1 nodes, 1 tokens, max depth 0, max children 1
+text: 1 bytes significant, 0 bytes trivia
+       1 SyntaxKind(1000) WORD
+       1 SyntaxKind(1001) LIST
+"
+        );
+    }
+
+    #[test]
+    fn each_element_is_visited_once() {
+        let root = SyntaxNode::new_root(mixed_tree(&mut Rng::new(375), 2_000));
+        let n_elements =
+            root.preorder_with_tokens().filter(|it| matches!(it, WalkEvent::Enter(_))).count();
+        let calls = Cell::new(0);
+        let metrics = root.metrics_with_trivia(|kind| {
+            calls.set(calls.get() + 1);
+            kind == LIST
+        });
+        assert_eq!(calls.get(), n_elements);
+        assert_eq!(metrics.nodes + metrics.tokens, n_elements);
+        assert_eq!(metrics.kinds.values().sum::<usize>(), n_elements);
+        // The root is a list.
+        assert_eq!(metrics.significant_text_len, 0);
+        assert_eq!(metrics.trivia_text_len, root.text_range().len().to_usize());
+        assert_eq!(root.metrics().significant_text_len, metrics.trivia_text_len);
+    }
+}
@@ -0,0 +1,218 @@
+//! Data attached to the nodes of trees, without keeping the trees alive.
+use std::{collections::BTreeMap, fmt};
+
+use crate::cursor::SyntaxNode;
+
+/// A map from nodes to values which doesn't own the nodes, so it doesn't keep
+/// their trees alive: a side table for the results of an analysis, like the
+/// types of expressions.
+///
+/// A node is keyed by its tree and by its index path, so any `SyntaxNode` at
+/// the same position of the same tree finds the value, even if it was created
+/// anew after the one which was inserted was dropped. A node of another tree
+/// never finds it, even one at the same position of a tree with the same
+/// green node: each `new_root`, and so each edit, makes a new tree. A lookup
+/// is proportional to the depth of the node, and to the log of the size of
+/// the map.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SyntaxNodeMap<V> {
+    entries: BTreeMap<(u64, Vec<u32>), V>,
+}
+
+impl<V> Default for SyntaxNodeMap<V> {
+    fn default() -> SyntaxNodeMap<V> {
+        SyntaxNodeMap::new()
+    }
+}
+
+impl<V> SyntaxNodeMap<V> {
+    pub fn new() -> SyntaxNodeMap<V> {
+        SyntaxNodeMap { entries: BTreeMap::new() }
+    }
+
+    fn key(node: &SyntaxNode) -> (u64, Vec<u32>) {
+        (node.tree_id(), node.index_path())
+    }
+
+    /// Sets the value of `node`, returning the previous one.
+    pub fn insert(&mut self, node: &SyntaxNode, value: V) -> Option<V> {
+        self.entries.insert(SyntaxNodeMap::<V>::key(node), value)
+    }
+
+    pub fn get(&self, node: &SyntaxNode) -> Option<&V> {
+        self.entries.get(&SyntaxNodeMap::<V>::key(node))
+    }
+
+    pub fn get_mut(&mut self, node: &SyntaxNode) -> Option<&mut V> {
+        self.entries.get_mut(&SyntaxNodeMap::<V>::key(node))
+    }
+
+    pub fn contains(&self, node: &SyntaxNode) -> bool {
+        self.get(node).is_some()
+    }
+
+    pub fn remove(&mut self, node: &SyntaxNode) -> Option<V> {
+        self.entries.remove(&SyntaxNodeMap::<V>::key(node))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear()
+    }
+
+    /// The index paths of the nodes and their values, in document order
+    /// within each tree: a node comes before its descendants, which come
+    /// before its next siblings. The trees come in the order in which they
+    /// were created. `SyntaxNode::resolve_index_path` finds a node from its
+    /// path.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u32], &V)> {
+        self.entries.iter().map(|((_, path), value)| (path.as_slice(), value))
+    }
+
+    /// The values, in the order of `iter`.
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.entries.values()
+    }
+}
+
+impl<V: fmt::Debug> fmt::Debug for SyntaxNodeMap<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cursor::SyntaxElement,
+        test_utils::{gen_tree, TreeConfig, LIST, WORD},
+        GreenNode, GreenNodeBuilder, GreenToken, WalkEvent,
+    };
+
+    // LIST[LIST[LIST[], LIST[]], "a", LIST[]]: the empty lists are equal,
+    // and the first two are at the same offset.
+    fn tree() -> GreenNode {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(LIST);
+        builder.start_node(LIST);
+        builder.start_node(LIST);
+        builder.finish_node();
+        builder.start_node(LIST);
+        builder.finish_node();
+        builder.finish_node();
+        builder.token(WORD, "a".into());
+        builder.start_node(LIST);
+        builder.finish_node();
+        builder.finish_node();
+        builder.finish()
+    }
+
+    fn nodes(root: &SyntaxNode) -> Vec<SyntaxNode> {
+        root.preorder()
+            .filter_map(|event| match event {
+                WalkEvent::Enter(it) => Some(it),
+                WalkEvent::Leave(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_positions() {
+        let root = SyntaxNode::new_root(tree());
+        let mut map = SyntaxNodeMap::new();
+        for (i, node) in nodes(&root).iter().enumerate() {
+            assert_eq!(map.insert(node, i), None);
+        }
+        assert_eq!(map.len(), 5);
+
+        // The nodes are created anew for each lookup.
+        for (i, node) in nodes(&root).iter().enumerate() {
+            assert_eq!(map.get(node), Some(&i));
+        }
+        let inner = root.first_child().unwrap().first_child().unwrap();
+        assert_eq!(inner.green(), inner.next_sibling().unwrap().green());
+        assert_eq!(map.insert(&inner, 10), Some(2));
+        *map.get_mut(&inner).unwrap() += 1;
+        assert_eq!(map.get(&inner.next_sibling().unwrap()), Some(&3));
+        assert_eq!(map.remove(&inner), Some(11));
+        assert!(!map.contains(&inner));
+        assert_eq!(map.remove(&inner), None);
+
+        let paths = map.iter().map(|(path, _)| path.to_vec()).collect::<Vec<_>>();
+        assert_eq!(paths, [vec![], vec![0], vec![0, 1], vec![2]]);
+        assert_eq!(map.values().copied().collect::<Vec<_>>(), [0, 1, 3, 4]);
+        for (path, value) in map.iter() {
+            let node = SyntaxNode::resolve_index_path(&root, path).unwrap();
+            assert_eq!(map.get(node.as_node().unwrap()), Some(value));
+        }
+        assert_eq!(format!("{:?}", map), "{[]: 0, [0]: 1, [0, 1]: 3, [2]: 4}");
+
+        drop(root);
+        assert_eq!(map.len(), 4);
+        map.clear();
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn other_trees() {
+        let green = tree();
+        let root = SyntaxNode::new_root(green.clone());
+        let mut map = SyntaxNodeMap::new();
+        for node in nodes(&root) {
+            map.insert(&node, ());
+        }
+
+        // Another tree of the same green node.
+        let twin = SyntaxNode::new_root(green);
+        assert!(nodes(&twin).iter().all(|it| !map.contains(it)));
+        // A subtree on its own is another tree.
+        let list = root.first_child().unwrap();
+        assert!(!map.contains(&list.clone_subtree()));
+
+        // An edited tree.
+        let token = root.first_token().unwrap();
+        let edited = SyntaxNode::new_root(token.replace_with(GreenToken::new(WORD, "b".into())));
+        assert!(nodes(&edited).iter().all(|it| !map.contains(it)));
+
+        // A tree created after the first one was dropped, which could reuse
+        // its memory.
+        drop((root, list, token));
+        let again = SyntaxNode::new_root(tree());
+        assert!(nodes(&again).iter().all(|it| !map.contains(it)));
+        assert_eq!(map.len(), 5);
+
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(LIST);
+        builder.finish_node();
+        map.insert(&SyntaxNode::new_root(builder.finish()), ());
+        assert_eq!(map.len(), 6);
+    }
+
+    #[test]
+    fn generated_trees() {
+        let config = TreeConfig::default();
+        for seed in 0..20 {
+            let root = SyntaxNode::new_root(gen_tree(seed, &config));
+            let mut map = SyntaxNodeMap::new();
+            let mut expected = Vec::new();
+            for (i, event) in root.preorder_with_tokens().enumerate() {
+                if let WalkEvent::Enter(SyntaxElement::Node(node)) = event {
+                    map.insert(&node, i);
+                    expected.push(i);
+                }
+            }
+            assert_eq!(map.values().copied().collect::<Vec<_>>(), expected, "seed {}", seed);
+            for (node, value) in nodes(&root).iter().zip(&expected) {
+                assert_eq!(map.get(node), Some(value));
+            }
+        }
+    }
+}
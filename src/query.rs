@@ -0,0 +1,445 @@
+//! Structural queries over the kinds (and token texts) of a tree.
+//!
+//! ```
+//! use rowan::{cursor::SyntaxNode, query::Pattern, GreenNodeBuilder, SyntaxKind};
+//!
+//! const IDENT: SyntaxKind = SyntaxKind(0);
+//! const NAME: SyntaxKind = SyntaxKind(1);
+//! const STRUCT: SyntaxKind = SyntaxKind(2);
+//! const FILE: SyntaxKind = SyntaxKind(3);
+//!
+//! let mut builder = GreenNodeBuilder::new();
+//! builder.start_node(FILE);
+//! builder.start_node(STRUCT);
+//! builder.start_node(NAME);
+//! builder.token(IDENT, "Foo".into());
+//! builder.finish_node();
+//! builder.finish_node();
+//! builder.start_node(STRUCT);
+//! builder.finish_node();
+//! builder.finish_node();
+//! let root = SyntaxNode::new_root(builder.finish());
+//!
+//! // `STRUCT [NAME]`: the structs with a name.
+//! let named = Pattern::kind(STRUCT).child(Pattern::kind(NAME));
+//! assert_eq!(named.find_all(&root).count(), 1);
+//!
+//! // The files with a `Foo` somewhere inside.
+//! let foo = Pattern::kind(FILE).descendant(Pattern::token(IDENT).with_text("Foo"));
+//! assert!(foo.matches(&root));
+//! ```
+use std::cmp;
+
+use crate::{cursor::SyntaxNode, GreenElement, GreenToken, SmolStr, SyntaxKind};
+
+/// A pattern over a node, or a token, and its subtree.
+///
+/// A pattern matches an element of its kind (of any kind for `any`), which
+/// also satisfies each of its constraints: having a child matching a given
+/// pattern (`child`), or a descendant (`descendant`). Constraints are
+/// independent: two `child` constraints can be satisfied by the same child.
+///
+/// Only node patterns have constraints, and only token patterns have a text.
+/// The matched element is the root of the pattern, so that
+/// `FN > PARAM_LIST > PARAM` matches the functions which have such a
+/// parameter:
+///
+/// ```
+/// # use rowan::{query::Pattern, SyntaxKind};
+/// # const FN: SyntaxKind = SyntaxKind(0);
+/// # const PARAM_LIST: SyntaxKind = SyntaxKind(1);
+/// # const PARAM: SyntaxKind = SyntaxKind(2);
+/// Pattern::kind(FN).child(Pattern::kind(PARAM_LIST).child(Pattern::kind(PARAM)));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    /// `None` matches any kind.
+    kind: Option<SyntaxKind>,
+    is_token: bool,
+    text: Option<SmolStr>,
+    children: Vec<Pattern>,
+    descendants: Vec<Pattern>,
+}
+
+impl Pattern {
+    /// Matches the nodes of kind `kind`.
+    pub fn kind(kind: SyntaxKind) -> Pattern {
+        Pattern::new(Some(kind), false)
+    }
+
+    /// Matches any node.
+    pub fn any() -> Pattern {
+        Pattern::new(None, false)
+    }
+
+    /// Matches the tokens of kind `kind`. As `matches` and `find_all` look
+    /// for nodes, token patterns are only useful in constraints.
+    pub fn token(kind: SyntaxKind) -> Pattern {
+        Pattern::new(Some(kind), true)
+    }
+
+    /// Matches any token.
+    pub fn any_token() -> Pattern {
+        Pattern::new(None, true)
+    }
+
+    fn new(kind: Option<SyntaxKind>, is_token: bool) -> Pattern {
+        Pattern { kind, is_token, text: None, children: Vec::new(), descendants: Vec::new() }
+    }
+
+    /// This token pattern, which only matches tokens with text `text`.
+    ///
+    /// # Panics
+    ///
+    /// If this is a node pattern.
+    pub fn with_text(mut self, text: impl Into<SmolStr>) -> Pattern {
+        assert!(self.is_token, "only token patterns have a text");
+        self.text = Some(text.into());
+        self
+    }
+
+    /// This node pattern, which only matches nodes with a child matching
+    /// `child`.
+    ///
+    /// # Panics
+    ///
+    /// If this is a token pattern.
+    pub fn child(mut self, child: Pattern) -> Pattern {
+        assert!(!self.is_token, "tokens have no children");
+        self.children.push(child);
+        self
+    }
+
+    /// This node pattern, which only matches nodes with a descendant (other
+    /// than the node itself) matching `descendant`.
+    ///
+    /// # Panics
+    ///
+    /// If this is a token pattern.
+    pub fn descendant(mut self, descendant: Pattern) -> Pattern {
+        assert!(!self.is_token, "tokens have no descendants");
+        self.descendants.push(descendant);
+        self
+    }
+
+    /// Whether `node` matches this pattern.
+    ///
+    /// Without `descendant` constraints, only the levels of the subtree
+    /// which the pattern reaches are looked at.
+    pub fn matches(&self, node: &SyntaxNode) -> bool {
+        if !self.matches_kind(node.kind(), false) {
+            return false;
+        }
+        let mut res = false;
+        Matcher::new(self).walk(node, self.height(), &mut |index, _| res |= index == 0);
+        res
+    }
+
+    /// The nodes of the subtree of `root` (including `root`) which match
+    /// this pattern, in preorder.
+    ///
+    /// The subtree is walked once, whatever the nesting of the pattern and
+    /// of the matches: the matches of all of the sub-patterns are computed
+    /// together, bottom-up, and a sub-pattern is only checked against
+    /// elements of its kind.
+    pub fn find_all(&self, root: &SyntaxNode) -> impl Iterator<Item = SyntaxNode> {
+        let mut res = Vec::new();
+        Matcher::new(self).walk(root, None, &mut |index, node| res.push((index, node.clone())));
+        // Matches are found when leaving the nodes, in postorder.
+        res.sort_by_key(|&(index, _)| index);
+        res.into_iter().map(|(_, node)| node)
+    }
+
+    fn matches_kind(&self, kind: SyntaxKind, is_token: bool) -> bool {
+        self.is_token == is_token && self.kind.is_none_or(|it| it == kind)
+    }
+
+    fn matches_token(&self, token: &GreenToken) -> bool {
+        self.matches_kind(token.kind(), true)
+            && self.text.as_ref().is_none_or(|it| it == token.text())
+    }
+
+    /// The depth of the constraints of this pattern, `None` if they are
+    /// unbounded.
+    fn height(&self) -> Option<usize> {
+        if !self.descendants.is_empty() {
+            return None;
+        }
+        self.children.iter().try_fold(0, |acc, it| Some(cmp::max(acc, it.height()? + 1)))
+    }
+}
+
+/// A pattern and its sub-patterns, numbered so that the root is the last.
+struct Matcher<'a> {
+    patterns: Vec<Compiled<'a>>,
+}
+
+struct Compiled<'a> {
+    pattern: &'a Pattern,
+    children: Vec<usize>,
+    descendants: Vec<usize>,
+}
+
+impl<'a> Matcher<'a> {
+    fn new(pattern: &'a Pattern) -> Matcher<'a> {
+        fn compile<'a>(pattern: &'a Pattern, acc: &mut Vec<Compiled<'a>>) -> usize {
+            let children = pattern.children.iter().map(|it| compile(it, acc)).collect();
+            let descendants = pattern.descendants.iter().map(|it| compile(it, acc)).collect();
+            acc.push(Compiled { pattern, children, descendants });
+            acc.len() - 1
+        }
+        let mut patterns = Vec::new();
+        compile(pattern, &mut patterns);
+        Matcher { patterns }
+    }
+
+    /// Calls `f` with the nodes of the subtree of `root` which match the
+    /// root pattern, and their index in preorder, in postorder. Nodes deeper
+    /// than `max_depth` are not visited, which is only right for `root` if
+    /// the constraints don't reach further.
+    fn walk(
+        &self,
+        root: &SyntaxNode,
+        max_depth: Option<usize>,
+        f: &mut dyn FnMut(usize, &SyntaxNode),
+    ) {
+        let n = self.patterns.len();
+        // For each node on the stack, the patterns matched by one of its
+        // children, followed by those matched below its children.
+        let mut hits = Vec::new();
+        let mut matched = vec![false; n];
+        let mut stack = Vec::new();
+        self.enter(root, &mut hits);
+        stack.push((root.clone(), root.children(), 0));
+        let mut n_nodes = 1;
+        while !stack.is_empty() {
+            let may_descend = max_depth.is_none_or(|it| stack.len() <= it);
+            let (_, children, _) = stack.last_mut().unwrap();
+            if let Some(child) = children.next().filter(|_| may_descend) {
+                self.enter(&child, &mut hits);
+                stack.push((child.clone(), child.children(), n_nodes));
+                n_nodes += 1;
+                continue;
+            }
+            let (node, _, index) = stack.pop().unwrap();
+            let frame = hits.len() - 2 * n;
+            for (i, compiled) in self.patterns.iter().enumerate() {
+                matched[i] = compiled.pattern.matches_kind(node.kind(), false)
+                    && compiled.children.iter().all(|&it| hits[frame + it])
+                    && compiled.descendants.iter().all(|&it| hits[frame + n + it]);
+            }
+            if matched[n - 1] {
+                f(index, &node);
+            }
+            if let Some(parent) = frame.checked_sub(2 * n) {
+                for i in 0..n {
+                    hits[parent + i] |= matched[i];
+                    hits[parent + n + i] |= matched[i] || hits[frame + n + i];
+                }
+            }
+            hits.truncate(frame);
+        }
+    }
+
+    /// Pushes the frame of `node`, with the patterns matched by its tokens.
+    fn enter(&self, node: &SyntaxNode, hits: &mut Vec<bool>) {
+        let n = self.patterns.len();
+        let frame = hits.len();
+        hits.resize(frame + 2 * n, false);
+        let tokens = node.green().children().iter().filter_map(|it| match it {
+            GreenElement::Token(it) => Some(it),
+            GreenElement::Node(_) => None,
+        });
+        for token in tokens {
+            for (i, compiled) in self.patterns.iter().enumerate() {
+                if compiled.pattern.matches_token(token) {
+                    hits[frame + i] = true;
+                    hits[frame + n + i] = true;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cursor::SyntaxElement,
+        test_utils::{mixed_tree, Rng, LIST, WORD, WS},
+        GreenNodeBuilder, WalkEvent,
+    };
+
+    const FN: SyntaxKind = SyntaxKind(3);
+    const PARAM_LIST: SyntaxKind = SyntaxKind(4);
+    const PARAM: SyntaxKind = SyntaxKind(5);
+
+    // LIST
+    //   FN
+    //     WORD "fn"
+    //     PARAM_LIST
+    //       PARAM
+    //         WORD "x"
+    //   FN
+    //     WORD "fn"
+    //     PARAM_LIST
+    //     LIST
+    //       PARAM
+    //         WORD "y"
+    fn sample() -> SyntaxNode {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(LIST);
+        builder.start_node(FN);
+        builder.token(WORD, "fn".into());
+        builder.start_node(PARAM_LIST);
+        builder.start_node(PARAM);
+        builder.token(WORD, "x".into());
+        builder.finish_node();
+        builder.finish_node();
+        builder.finish_node();
+        builder.start_node(FN);
+        builder.token(WORD, "fn".into());
+        builder.start_node(PARAM_LIST);
+        builder.finish_node();
+        builder.start_node(LIST);
+        builder.start_node(PARAM);
+        builder.token(WORD, "y".into());
+        builder.finish_node();
+        builder.finish_node();
+        builder.finish_node();
+        builder.finish_node();
+        SyntaxNode::new_root(builder.finish())
+    }
+
+    /// The text of the first token of each node of `pattern` in `root`.
+    fn find_all(pattern: &Pattern, root: &SyntaxNode) -> Vec<String> {
+        pattern
+            .find_all(root)
+            .map(|it| format!("{:?}", it.kind()) + " " + it.first_token().unwrap().text())
+            .collect()
+    }
+
+    #[test]
+    fn nested_patterns() {
+        let root = sample();
+        let params = Pattern::kind(FN).child(Pattern::kind(PARAM_LIST).child(Pattern::kind(PARAM)));
+        assert_eq!(find_all(&params, &root), ["SyntaxKind(3) fn"]);
+        let fns = root.children().collect::<Vec<_>>();
+        assert!(params.matches(&fns[0]));
+        assert!(!params.matches(&fns[1]));
+        assert!(!params.matches(&root));
+
+        let with_list =
+            Pattern::kind(FN).child(Pattern::kind(PARAM_LIST)).child(Pattern::kind(LIST));
+        assert_eq!(find_all(&with_list, &root), ["SyntaxKind(3) fn"]);
+        assert!(with_list.matches(&fns[1]));
+
+        let any = Pattern::any().child(Pattern::any().child(Pattern::kind(PARAM)));
+        assert_eq!(find_all(&any, &root), ["SyntaxKind(3) fn", "SyntaxKind(3) fn"]);
+        assert_eq!(Pattern::any().find_all(&root).count(), 8);
+    }
+
+    #[test]
+    fn descendants() {
+        let root = sample();
+        let params = Pattern::kind(FN).descendant(Pattern::kind(PARAM));
+        assert_eq!(find_all(&params, &root), ["SyntaxKind(3) fn", "SyntaxKind(3) fn"]);
+        assert!(params.matches(&root.last_child().unwrap()));
+
+        // A node is not its own descendant.
+        let lists = Pattern::kind(LIST).descendant(Pattern::kind(LIST));
+        assert_eq!(find_all(&lists, &root), ["SyntaxKind(2) fn"]);
+        let nested =
+            Pattern::kind(LIST).descendant(Pattern::kind(FN).descendant(Pattern::kind(LIST)));
+        assert!(nested.matches(&root));
+        assert!(!Pattern::kind(PARAM).descendant(Pattern::any()).matches(&root));
+    }
+
+    #[test]
+    fn token_texts() {
+        let root = sample();
+        let x = Pattern::kind(PARAM).child(Pattern::token(WORD).with_text("x"));
+        assert_eq!(find_all(&x, &root), ["SyntaxKind(5) x"]);
+        let y = Pattern::kind(FN).descendant(Pattern::token(WORD).with_text("y"));
+        assert_eq!(find_all(&y, &root), ["SyntaxKind(3) fn"]);
+        assert!(y.matches(&root.last_child().unwrap()));
+        let words = Pattern::any().child(Pattern::any_token().with_text("fn"));
+        assert_eq!(find_all(&words, &root).len(), 2);
+        assert!(find_all(&Pattern::any().child(Pattern::token(WS)), &root).is_empty());
+        // Token patterns never match nodes, even when their kinds are equal.
+        assert!(!Pattern::token(LIST).matches(&root));
+    }
+
+    #[test]
+    #[should_panic(expected = "only token patterns have a text")]
+    fn text_of_a_node() {
+        Pattern::kind(LIST).with_text("x");
+    }
+
+    /// Direct recursive evaluation, without the two-level bookkeeping.
+    fn naive_matches(pattern: &Pattern, element: &SyntaxElement) -> bool {
+        let (node, token) = match element {
+            SyntaxElement::Node(it) => (Some(it), None),
+            SyntaxElement::Token(it) => (None, Some(it)),
+        };
+        if pattern.is_token != token.is_some()
+            || pattern.kind.is_some_and(|it| it != element.kind())
+            || pattern
+                .text
+                .as_ref()
+                .is_some_and(|it| Some(it.as_str()) != token.map(|it| it.text()))
+        {
+            return false;
+        }
+        let node = match node {
+            Some(it) => it,
+            None => return true,
+        };
+        let children = node.children_with_tokens().collect::<Vec<_>>();
+        pattern.children.iter().all(|p| children.iter().any(|it| naive_matches(p, it)))
+            && pattern.descendants.iter().all(|p| {
+                node.preorder_with_tokens().skip(1).any(|event| match event {
+                    WalkEvent::Enter(it) => naive_matches(p, &it),
+                    WalkEvent::Leave(_) => false,
+                })
+            })
+    }
+
+    fn random_pattern(rng: &mut Rng, depth: usize) -> Pattern {
+        const TEXTS: [&str; 3] = ["x", "let", "self"];
+        if depth == 0 || rng.below(4) == 0 {
+            return match rng.below(3) {
+                0 => Pattern::token(WORD).with_text(TEXTS[rng.below(3) as usize]),
+                1 => Pattern::any(),
+                _ => Pattern::kind(LIST),
+            };
+        }
+        let mut res = if rng.below(2) == 0 { Pattern::any() } else { Pattern::kind(LIST) };
+        for _ in 0..rng.below(3) {
+            let sub = random_pattern(rng, depth - 1);
+            res = if rng.below(2) == 0 { res.child(sub) } else { res.descendant(sub) };
+        }
+        res
+    }
+
+    #[test]
+    fn agrees_with_naive_matching() {
+        let mut rng = Rng::new(363);
+        let root = SyntaxNode::new_root(mixed_tree(&mut rng, 300));
+        for _ in 0..50 {
+            let pattern = random_pattern(&mut rng, 3);
+            let expected = root
+                .preorder()
+                .filter_map(|event| match event {
+                    WalkEvent::Enter(it) => Some(it),
+                    WalkEvent::Leave(_) => None,
+                })
+                .filter(|it| naive_matches(&pattern, &it.clone().into()))
+                .collect::<Vec<_>>();
+            assert_eq!(pattern.find_all(&root).collect::<Vec<_>>(), expected, "{:?}", pattern);
+            for node in root.children() {
+                assert_eq!(pattern.matches(&node), expected.contains(&node), "{:?}", pattern);
+            }
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! Finding the nodes of an old tree which an incremental reparse can reuse.
+use crate::{algo::TextEdit, cursor::SyntaxNode, GreenElement, GreenNode, SyntaxKind, TextUnit};
+
+/// An index of the nodes of an old tree which weren't damaged by an edit,
+/// by their offset in the new text.
+///
+/// A node is undamaged if it lies entirely before or after the deleted
+/// range, without touching it: an insertion right at the end of a node can
+/// extend it, so such nodes are not offered either.
+#[derive(Debug, Clone)]
+pub struct ReuseOracle {
+    /// New start offsets and nodes, sorted by offset, with the outer nodes
+    /// before the inner ones at the same offset.
+    nodes: Vec<(TextUnit, GreenNode)>,
+}
+
+impl ReuseOracle {
+    /// Indexes the nodes of `old_root` which `edit` leaves intact. The
+    /// offsets of `edit` are those of the old tree.
+    pub fn new(old_root: &SyntaxNode, edit: &TextEdit) -> ReuseOracle {
+        let damage = edit.delete;
+        let inserted = TextUnit::of_str(&edit.insert);
+        let mut nodes = Vec::new();
+        // A preorder walk yields the nodes by their start offset, outer ones
+        // first, and the translation preserves the order of the undamaged
+        // nodes, so `nodes` ends up sorted.
+        let mut stack = vec![(old_root.green(), old_root.text_range().start())];
+        while let Some((node, start)) = stack.pop() {
+            let end = start + node.text_len();
+            if end < damage.start() {
+                nodes.push((start, node.clone()));
+            } else if start > damage.end() {
+                nodes.push((start - damage.len() + inserted, node.clone()));
+            }
+            for (i, child) in node.children().iter().enumerate().rev() {
+                if let GreenElement::Node(child) = child {
+                    stack.push((child, start + node.child_offset(i)));
+                }
+            }
+        }
+        ReuseOracle { nodes }
+    }
+
+    /// The outermost old node of `kind` which starts at `new_offset` in the
+    /// new text, if it is undamaged. O(log n) in the number of old nodes,
+    /// plus the number of nodes starting at `new_offset`.
+    pub fn find_reusable(&self, kind: SyntaxKind, new_offset: TextUnit) -> Option<GreenNode> {
+        let from = self.nodes.partition_point(|(start, _)| *start < new_offset);
+        self.nodes[from..]
+            .iter()
+            .take_while(|(start, _)| *start == new_offset)
+            .find(|(_, node)| node.kind() == kind)
+            .map(|(_, node)| node.clone())
+    }
+
+    /// The number of undamaged old nodes.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cursor,
+        test_utils::{mixed_tree, text_of, Rng, LIST, WORD, WS},
+        TextRange, WalkEvent,
+    };
+
+    fn edit(delete: TextRange, insert: &str) -> TextEdit {
+        TextEdit { delete, insert: insert.into() }
+    }
+
+    #[test]
+    fn reuse() {
+        let kind = |name: &str| match name {
+            "WS" => Some(WS),
+            "WORD" => Some(WORD),
+            "LIST" => Some(LIST),
+            _ => None,
+        };
+        // "(ab) (cd) (ef)", with the "c" replaced by "xyz".
+        let green = GreenNode::from_sexpr(
+            r#"(LIST
+                (LIST (WORD "ab")) (WS " ")
+                (LIST (WORD "cd")) (WS " ")
+                (LIST (WORD "ef")))"#,
+            &kind,
+        )
+        .unwrap();
+        let root = SyntaxNode::new_root(green);
+        let oracle = ReuseOracle::new(&root, &edit(TextRange::from_to(3.into(), 4.into()), "xyz"));
+        assert_eq!(oracle.len(), 2);
+        let first = oracle.find_reusable(LIST, 0.into()).unwrap();
+        assert_eq!(text_of(&SyntaxNode::new_root(first)), "ab");
+        assert_eq!(oracle.find_reusable(WORD, 0.into()), None);
+        // The root and the middle list contain the damage.
+        assert_eq!(oracle.find_reusable(LIST, 3.into()), None);
+        // The last list moved from 6 to 8.
+        assert_eq!(oracle.find_reusable(LIST, 6.into()), None);
+        assert_eq!(
+            text_of(&SyntaxNode::new_root(oracle.find_reusable(LIST, 8.into()).unwrap())),
+            "ef"
+        );
+
+        // An insertion right after "ab" touches the first list.
+        let oracle = ReuseOracle::new(&root, &edit(TextRange::offset_len(2.into(), 0.into()), "!"));
+        assert_eq!(oracle.find_reusable(LIST, 0.into()), None);
+        assert_eq!(
+            text_of(&SyntaxNode::new_root(oracle.find_reusable(LIST, 4.into()).unwrap())),
+            "cd"
+        );
+    }
+
+    #[test]
+    fn random_edits() {
+        let mut rng = Rng::new(376);
+        for _ in 0..50 {
+            let root = SyntaxNode::new_root(mixed_tree(&mut rng, 200));
+            let old_text = text_of(&root);
+            let damage = rng.range(root.text_range().len());
+            let insert = ["", "x", "xyz "][rng.below(3) as usize];
+            let oracle = ReuseOracle::new(&root, &edit(damage, insert));
+            let (start, end) = (damage.start().to_usize(), damage.end().to_usize());
+            let new_text = format!("{}{}{}", &old_text[..start], insert, &old_text[end..]);
+
+            let mut n_reusable = 0;
+            for event in root.preorder() {
+                let node = match event {
+                    WalkEvent::Enter(it) => it,
+                    WalkEvent::Leave(_) => continue,
+                };
+                let range = node.text_range();
+                let touches = range.end() >= damage.start() && range.start() <= damage.end();
+                if touches {
+                    continue;
+                }
+                n_reusable += 1;
+                let new_start = if range.start() < damage.start() {
+                    range.start()
+                } else {
+                    range.start() - damage.len() + TextUnit::of_str(insert)
+                };
+                let found = oracle.find_reusable(node.kind(), new_start).unwrap();
+                let found_range = TextRange::offset_len(new_start, found.text_len());
+                assert_eq!(text_of(&SyntaxNode::new_root(found)), &new_text[found_range]);
+            }
+            assert_eq!(oracle.len(), n_reusable);
+
+            // Nothing is offered over the new text of the damage.
+            let new_damage = TextRange::offset_len(damage.start(), TextUnit::of_str(insert));
+            for offset in new_damage.start().to_usize()..=new_damage.end().to_usize() {
+                for &kind in &[LIST, WORD, WS] {
+                    assert_eq!(oracle.find_reusable(kind, (offset as u32).into()), None);
+                }
+            }
+        }
+    }
+}
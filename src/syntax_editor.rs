@@ -0,0 +1,254 @@
+use std::fmt;
+
+use crate::{
+    cursor::{rebuild, GreenEdit, SyntaxElement, SyntaxNode},
+    diagnostics::ElementDisplay,
+    GreenElement, GreenNode,
+};
+
+/// Collects edits against a single syntax tree, and applies all of them at
+/// once.
+///
+/// Unlike `replace_with` and friends, recording an edit doesn't produce a new
+/// tree, so all elements of the original tree stay valid edit targets until
+/// `finish` is called. Edits are keyed by the position of the element in the
+/// original tree.
+#[derive(Debug)]
+pub struct SyntaxEditor {
+    root: SyntaxNode,
+    edits: Vec<(SyntaxElement, Edit)>,
+}
+
+#[derive(Debug)]
+enum Edit {
+    Replace(GreenElement),
+    Delete,
+    InsertBefore(Vec<GreenElement>),
+    InsertAfter(Vec<GreenElement>),
+}
+
+impl Edit {
+    fn is_insertion(&self) -> bool {
+        match self {
+            Edit::InsertBefore(_) | Edit::InsertAfter(_) => true,
+            Edit::Replace(_) | Edit::Delete => false,
+        }
+    }
+}
+
+/// The reason why `SyntaxEditor::finish` failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EditConflict {
+    /// The element is not a descendant of the node the editor was created
+    /// for. The node itself can't be edited either, as it has no parent.
+    NotADescendant(SyntaxElement),
+    /// The element was replaced or deleted more than once.
+    EditedTwice(SyntaxElement),
+    /// The element is inside of a subtree, which was replaced or deleted.
+    InsideOfEdited { element: SyntaxElement, edited: SyntaxElement },
+}
+
+impl fmt::Display for EditConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let at = |element: &SyntaxElement| ElementDisplay(element.kind(), element.text_range());
+        match self {
+            EditConflict::NotADescendant(element) => {
+                write!(f, "{} is not a descendant of the edited node", at(element))
+            }
+            EditConflict::EditedTwice(element) => {
+                write!(f, "{} is replaced or deleted more than once", at(element))
+            }
+            EditConflict::InsideOfEdited { element, edited } => write!(
+                f,
+                "{} is edited, but it is inside of replaced or deleted {}",
+                at(element),
+                at(edited)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EditConflict {}
+
+impl SyntaxEditor {
+    /// Creates an editor for the subtree of `root`.
+    pub fn new(root: SyntaxNode) -> SyntaxEditor {
+        SyntaxEditor { root, edits: Vec::new() }
+    }
+
+    /// Replaces `element` with `replacement`.
+    pub fn replace(
+        &mut self,
+        element: impl Into<SyntaxElement>,
+        replacement: impl Into<GreenElement>,
+    ) {
+        self.edits.push((element.into(), Edit::Replace(replacement.into())))
+    }
+
+    /// Removes `element` from the tree.
+    pub fn delete(&mut self, element: impl Into<SyntaxElement>) {
+        self.edits.push((element.into(), Edit::Delete))
+    }
+
+    /// Inserts `new` right before `element`. Several insertions before the
+    /// same element are applied in order.
+    pub fn insert_before(&mut self, element: impl Into<SyntaxElement>, new: Vec<GreenElement>) {
+        self.edits.push((element.into(), Edit::InsertBefore(new)))
+    }
+
+    /// Inserts `new` right after `element`. Several insertions after the
+    /// same element are applied in order.
+    pub fn insert_after(&mut self, element: impl Into<SyntaxElement>, new: Vec<GreenElement>) {
+        self.edits.push((element.into(), Edit::InsertAfter(new)))
+    }
+
+    /// Applies all the recorded edits in a single pass, and returns the new
+    /// green tree of the whole file (like `SyntaxNode::replace_with` does).
+    ///
+    /// Inserting next to a deleted or replaced element is fine, but editing
+    /// inside of it, or replacing it twice is a conflict, because the result
+    /// would depend on the order of the edits.
+    pub fn finish(self) -> Result<GreenNode, EditConflict> {
+        if self.edits.is_empty() {
+            return Ok(self.root.green_root());
+        }
+        let mut edits = Vec::with_capacity(self.edits.len());
+        for (element, edit) in self.edits {
+            match self.root.try_path_to(&element) {
+                Some(path) if !path.is_empty() => edits.push((path, element, edit)),
+                _ => return Err(EditConflict::NotADescendant(element)),
+            }
+        }
+        // The sort is stable, so insertions at the same place keep their
+        // order, and ancestors come right before their descendants.
+        edits.sort_by(|(left, _, _), (right, _, _)| left.cmp(right));
+        for (i, (path, element, edit)) in edits.iter().enumerate() {
+            if edit.is_insertion() {
+                continue;
+            }
+            let conflict =
+                edits[i + 1..].iter().take_while(|(other, _, _)| other.starts_with(path)).find(
+                    |(other, _, other_edit)| other.len() > path.len() || !other_edit.is_insertion(),
+                );
+            if let Some((other, other_element, _)) = conflict {
+                return Err(if other.len() == path.len() {
+                    EditConflict::EditedTwice(element.clone())
+                } else {
+                    EditConflict::InsideOfEdited {
+                        element: other_element.clone(),
+                        edited: element.clone(),
+                    }
+                });
+            }
+        }
+
+        let edits = edits
+            .into_iter()
+            .map(|(path, _, edit)| {
+                let edit = match edit {
+                    Edit::Replace(it) => GreenEdit::Replace(Some(it)),
+                    Edit::Delete => GreenEdit::Replace(None),
+                    Edit::InsertBefore(it) => GreenEdit::InsertBefore(it),
+                    Edit::InsertAfter(it) => GreenEdit::InsertAfter(it),
+                };
+                (path, edit)
+            })
+            .collect::<Vec<_>>();
+        let new_root = rebuild(self.root.green(), &edits);
+        Ok(self.root.replace_green(new_root))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cursor::SyntaxToken,
+        test_utils::{sample, text_of, LIST, WORD, WS},
+        GreenToken, WalkEvent,
+    };
+
+    fn word(text: &str) -> GreenElement {
+        GreenToken::new(WORD, text.into()).into()
+    }
+
+    fn tokens(root: &SyntaxNode) -> Vec<SyntaxToken> {
+        root.preorder_with_tokens()
+            .filter_map(|event| match event {
+                WalkEvent::Enter(SyntaxElement::Token(token)) => Some(token),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn all_edit_kinds() {
+        let root = SyntaxNode::new_root(sample());
+        let list = root.first_child().unwrap();
+        let bar_list = list.last_child().unwrap();
+        let tokens = tokens(&root);
+        let (foo, ws, baz) = (&tokens[0], &tokens[1], &tokens[4]);
+
+        let mut editor = SyntaxEditor::new(root.clone());
+        editor.replace(foo.clone(), word("oof"));
+        editor.delete(ws.clone());
+        editor.insert_after(ws.clone(), vec![word("-")]);
+        editor.insert_before(bar_list.clone(), vec![word("<")]);
+        editor.insert_after(bar_list.clone(), vec![word(">")]);
+        editor.insert_after(bar_list.clone(), vec![word("!")]);
+        editor.replace(baz.clone(), word("zab"));
+        editor.insert_before(baz.clone(), vec![word("_")]);
+        assert_eq!(text_of(&SyntaxNode::new_root(editor.finish().unwrap())), "oof-<bar>! _zab");
+
+        // Edits relative to a nested node produce a whole new tree.
+        let mut editor = SyntaxEditor::new(bar_list.clone());
+        editor.insert_before(bar_list.first_token().unwrap(), vec![word("x")]);
+        assert_eq!(text_of(&SyntaxNode::new_root(editor.finish().unwrap())), "foo xbar baz");
+
+        let unchanged = SyntaxEditor::new(root.clone()).finish().unwrap();
+        assert!(unchanged.ptr_eq(root.green()));
+    }
+
+    #[test]
+    fn conflicts() {
+        let root = SyntaxNode::new_root(sample());
+        let list = root.first_child().unwrap();
+        let foo = root.first_token().unwrap();
+
+        let mut editor = SyntaxEditor::new(root.clone());
+        editor.delete(list.clone());
+        editor.replace(foo.clone(), word("x"));
+        let err = editor.finish().unwrap_err();
+        assert_eq!(
+            err,
+            EditConflict::InsideOfEdited {
+                element: foo.clone().into(),
+                edited: list.clone().into()
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "SyntaxKind(0)@[0; 3) is edited, but it is inside of replaced or deleted \
+             SyntaxKind(2)@[0; 7)"
+        );
+
+        let mut editor = SyntaxEditor::new(root.clone());
+        editor.insert_after(foo.clone(), vec![word("x")]);
+        editor.replace(list.clone(), GreenNode::new(LIST, Vec::new().into_boxed_slice()));
+        assert!(editor.finish().is_err());
+
+        let mut editor = SyntaxEditor::new(root.clone());
+        editor.replace(foo.clone(), word("x"));
+        editor.delete(foo.clone());
+        let err = editor.finish().unwrap_err();
+        assert_eq!(err, EditConflict::EditedTwice(foo.clone().into()));
+        assert_eq!(err.to_string(), "SyntaxKind(0)@[0; 3) is replaced or deleted more than once");
+
+        let mut editor = SyntaxEditor::new(list.clone());
+        editor.delete(root.last_token().unwrap());
+        assert!(matches!(editor.finish(), Err(EditConflict::NotADescendant(_))));
+        let mut editor = SyntaxEditor::new(root.clone());
+        editor.delete(root.clone());
+        assert_eq!(editor.finish(), Err(EditConflict::NotADescendant(root.clone().into())));
+    }
+}
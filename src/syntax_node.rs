@@ -8,8 +8,9 @@ use std::{
 use crate::{
     TreeArc, WalkEvent, SyntaxKind,
     SyntaxNode, SyntaxToken, SyntaxElement, SyntaxIndex,
-    GreenNode, GreenElement, GreenIndex,
-    TextRange,
+    GreenNode, GreenElement, GreenIndex, green::child_index,
+    TextRange, TextUnit,
+    cursor::KindMismatch, diagnostics::ElementDisplay,
 };
 
 // SyntaxNodes have identity equality semantics
@@ -46,14 +47,14 @@ impl fmt::Display for SyntaxNode {
 impl SyntaxNode {
     /// Creates a new `SyntaxNode`, which becomes the root of the tree.
     #[inline]
-    pub fn new(green: GreenNode, data: Option<Box<Any + Send + Sync>>) -> TreeArc<SyntaxNode> {
+    pub fn new(green: GreenNode, data: Option<Box<dyn Any + Send + Sync>>) -> TreeArc<SyntaxNode> {
         Self::new_root(green, data)
     }
 
     /// Get root data.
     #[inline]
     pub fn root_data(&self) -> Option<&(dyn Any + Send + Sync)> {
-        self.root().data.as_ref().map(|it| &**it)
+        self.root().data.as_deref()
     }
 
     /// Get the green node for this node
@@ -88,7 +89,7 @@ impl SyntaxNode {
     }
 
     /// Get the first, including tokens.
-    pub fn first_child_or_token(&self) -> Option<SyntaxElement> {
+    pub fn first_child_or_token(&self) -> Option<SyntaxElement<'_>> {
         let res = match self.green().children().first()? {
             GreenElement::Node(_) => self.first_child()?.into(),
             GreenElement::Token(_) => SyntaxToken {
@@ -109,14 +110,20 @@ impl SyntaxNode {
         self.get_child(idx)
     }
 
+    /// The (absolute) start offset of the green child at `index`, derived
+    /// from the green node rather than from the offsets of its siblings.
+    pub(crate) fn child_start(&self, index: usize) -> TextUnit {
+        self.range().start() + self.green().child_offset(index)
+    }
+
     /// Get last child, including tokens.
-    pub fn last_child_or_token(&self) -> Option<SyntaxElement> {
+    pub fn last_child_or_token(&self) -> Option<SyntaxElement<'_>> {
         let res = match self.green().children().last()? {
             GreenElement::Node(_) => self.last_child()?.into(),
             GreenElement::Token(t) => SyntaxToken {
                 parent: self,
-                start_offset: self.range().end() - t.text_len(),
-                index_in_green: GreenIndex(self.green().children().len() as u32 - 1),
+                start_offset: self.child_start(self.green().children().len() - 1),
+                index_in_green: GreenIndex(child_index(self.green().children().len() - 1)),
                 index_in_parent: self.children_len(),
             }
             .into(),
@@ -133,7 +140,7 @@ impl SyntaxNode {
     }
 
     /// Get next sibling, including tokens.
-    pub fn next_sibling_or_token(&self) -> Option<SyntaxElement> {
+    pub fn next_sibling_or_token(&self) -> Option<SyntaxElement<'_>> {
         let parent = self.parent()?;
         let parent_data = self.parent_data()?;
         let index_in_green = parent_data.index_in_green.next();
@@ -160,16 +167,16 @@ impl SyntaxNode {
     }
 
     /// Get previous sibling, including tokens.
-    pub fn prev_sibling_or_token(&self) -> Option<SyntaxElement> {
+    pub fn prev_sibling_or_token(&self) -> Option<SyntaxElement<'_>> {
         let parent = self.parent()?;
         let parent_data = self.parent_data()?;
         let index_in_green = parent_data.index_in_green.prev();
         match parent.green().get_child(index_in_green)? {
             GreenElement::Node(_) => self.prev_sibling().map(SyntaxElement::from),
-            GreenElement::Token(it) => {
+            GreenElement::Token(_) => {
                 let token = SyntaxToken {
                     parent,
-                    start_offset: self.range().start() - it.text_len(),
+                    start_offset: parent.child_start(index_in_green.0 as usize),
                     index_in_green,
                     index_in_parent: parent_data.index_in_parent,
                 };
@@ -180,43 +187,45 @@ impl SyntaxNode {
 
     /// Return the leftmost token in the subtree of this node
     #[inline]
-    pub fn first_token(&self) -> Option<SyntaxToken> {
+    pub fn first_token(&self) -> Option<SyntaxToken<'_>> {
         self.first_child_or_token()?.first_token()
     }
 
     /// Return the rightmost token in the subtree of this node
     #[inline]
-    pub fn last_token(&self) -> Option<SyntaxToken> {
+    pub fn last_token(&self) -> Option<SyntaxToken<'_>> {
         self.last_child_or_token()?.last_token()
     }
 
     /// Returns a green tree, equal to the green tree this node
     /// belongs two, except with this node substitute. The complexity
     /// of operation is proportional to the depth of the tree
+    ///
+    /// Panics if the kind of `replacement` differs from the kind of this
+    /// node, see `try_replace_with`.
     pub fn replace_with(&self, replacement: GreenNode) -> GreenNode {
-        assert_eq!(self.kind(), replacement.kind());
-        match self.parent() {
-            None => replacement,
-            Some(parent) => {
-                let me = self.parent_data().unwrap().index_in_green;
-                let mut replacement = Some(replacement);
-                let children: Box<[_]> = parent
-                    .green()
-                    .children()
-                    .iter()
-                    .enumerate()
-                    .map(|(i, child)| {
-                        if i as u32 == me.0 {
-                            replacement.take().unwrap().into()
-                        } else {
-                            child.clone()
-                        }
-                    })
-                    .collect();
-                assert!(replacement.is_none());
-                let new_parent = GreenNode::new(parent.kind(), children);
-                parent.replace_with(new_parent)
-            }
+        self.try_replace_with(replacement).unwrap_or_else(|err| {
+            panic!("replace_with: {}, for {}", err, ElementDisplay(self.kind(), self.range()))
+        })
+    }
+
+    /// Like `replace_with`, but returns an error instead of panicking if the
+    /// kind of `replacement` differs from the kind of this node.
+    pub fn try_replace_with(&self, replacement: GreenNode) -> Result<GreenNode, KindMismatch> {
+        KindMismatch::check(self.kind(), replacement.kind())?;
+        Ok(self.replace_green(replacement))
+    }
+
+    pub(crate) fn replace_green(&self, replacement: GreenNode) -> GreenNode {
+        let mut node = self;
+        let mut replacement = replacement;
+        // Rebuild the ancestors bottom-up, so that deep trees don't blow
+        // the stack.
+        while let Some(parent) = node.parent() {
+            let me = node.parent_data().unwrap().index_in_green;
+            replacement = parent.green().replace_child(me.0 as usize, replacement.into());
+            node = parent;
         }
+        replacement
     }
 }
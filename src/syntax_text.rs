@@ -0,0 +1,163 @@
+//! The text of a node or a token, see `cursor::SyntaxElement::text`.
+use std::{fmt, slice};
+
+use crate::{
+    cursor::{SyntaxElement, SyntaxNode, SyntaxToken},
+    GreenElement, TextUnit,
+};
+
+/// A view of the text of a node, or of a token, which doesn't copy it.
+///
+/// The text of a node is split into the texts of its tokens, the chunks,
+/// which are found by walking the green tree each time they are needed, so
+/// no red nodes are created for them. Use `to_string` to get a `String`.
+#[derive(Clone)]
+pub struct SyntaxText {
+    element: SyntaxElement,
+}
+
+impl SyntaxText {
+    pub(crate) fn new(element: SyntaxElement) -> SyntaxText {
+        SyntaxText { element }
+    }
+
+    pub fn len(&self) -> TextUnit {
+        self.element.text_range().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0.into()
+    }
+
+    /// The texts of the tokens, in order. Tokens with an empty text yield
+    /// empty chunks. A token's text is a single chunk.
+    pub fn chunks(&self) -> impl Iterator<Item = &str> {
+        match &self.element {
+            SyntaxElement::Node(node) => {
+                Chunks { single: None, stack: vec![node.green().children().iter()] }
+            }
+            SyntaxElement::Token(token) => Chunks { single: Some(token.text()), stack: Vec::new() },
+        }
+    }
+}
+
+struct Chunks<'a> {
+    single: Option<&'a str>,
+    stack: Vec<slice::Iter<'a, GreenElement>>,
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        if let Some(text) = self.single.take() {
+            return Some(text);
+        }
+        while let Some(children) = self.stack.last_mut() {
+            match children.next() {
+                Some(GreenElement::Token(it)) => return Some(it.text()),
+                Some(GreenElement::Node(it)) => self.stack.push(it.children().iter()),
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+        None
+    }
+}
+
+impl fmt::Display for SyntaxText {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.chunks().try_for_each(|it| f.write_str(it))
+    }
+}
+
+impl fmt::Debug for SyntaxText {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.to_string(), f)
+    }
+}
+
+impl SyntaxNode {
+    /// The text of the subtree of this node.
+    pub fn text(&self) -> SyntaxText {
+        SyntaxText::new(self.clone().into())
+    }
+}
+
+impl SyntaxElement {
+    /// The text of the subtree of this node, or of this token.
+    pub fn text(&self) -> SyntaxText {
+        SyntaxText::new(self.clone())
+    }
+
+    pub fn text_len(&self) -> TextUnit {
+        self.text_range().len()
+    }
+}
+
+impl fmt::Display for SyntaxElement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.text(), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_utils::{gen_tree, text_of, TreeConfig},
+        GreenNodeBuilder, SyntaxKind, WalkEvent,
+    };
+
+    #[test]
+    fn text_of_elements() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(SyntaxKind(2));
+        builder.token(SyntaxKind(0), "foo".into());
+        builder.start_node(SyntaxKind(2));
+        builder.finish_node();
+        builder.start_node(SyntaxKind(2));
+        builder.token(SyntaxKind(1), " ".into());
+        builder.token(SyntaxKind(0), "".into());
+        builder.token(SyntaxKind(0), "bar".into());
+        builder.finish_node();
+        builder.finish_node();
+        let root = SyntaxNode::new_root(builder.finish());
+
+        let text = root.text();
+        assert_eq!(text.chunks().collect::<Vec<_>>(), ["foo", " ", "", "bar"]);
+        assert_eq!(text.to_string(), "foo bar");
+        assert_eq!(format!("{:?}", text), "\"foo bar\"");
+        assert_eq!(text.len(), 7.into());
+
+        let children = root.children_with_tokens().collect::<Vec<_>>();
+        assert_eq!(children[0].text().chunks().collect::<Vec<_>>(), ["foo"]);
+        assert!(children[1].text().is_empty());
+        assert_eq!(children[1].text().chunks().count(), 0);
+        assert_eq!(children[2].to_string(), " bar");
+        assert_eq!(children[2].text_len(), 4.into());
+    }
+
+    #[test]
+    fn text_of_generated_trees() {
+        let config = TreeConfig::default();
+        for seed in 0..50 {
+            let root = SyntaxNode::new_root(gen_tree(seed, &config));
+            for event in root.preorder_with_tokens() {
+                let element = match event {
+                    WalkEvent::Enter(it) => it,
+                    WalkEvent::Leave(_) => continue,
+                };
+                let expected = match &element {
+                    SyntaxElement::Node(it) => text_of(it),
+                    SyntaxElement::Token(it) => it.text().to_string(),
+                };
+                assert_eq!(element.text().to_string(), expected, "seed {}", seed);
+                assert_eq!(element.to_string(), expected);
+                assert_eq!(element.text_len(), TextUnit::of_str(&expected));
+                assert_eq!(element.text().len(), element.text_range().len());
+            }
+        }
+    }
+}
@@ -4,6 +4,7 @@ use crate::{
     TextUnit, SmolStr, TextRange, SyntaxKind,
     SyntaxNode, SyntaxElement, SyntaxIndex,
     GreenToken, GreenNode, GreenElement, GreenIndex,
+    cursor::KindMismatch, diagnostics::ElementDisplay,
 };
 
 /// A token (leaf node) in a syntax tree.
@@ -39,9 +40,14 @@ impl<'a> SyntaxToken<'a> {
     }
     /// Text of this token.
     #[inline]
-    pub fn text(&self) -> &'a SmolStr {
+    pub fn text(&self) -> &'a str {
         self.green().text()
     }
+    /// Text of this token, as a `SmolStr`.
+    #[deprecated(since = "0.5.7", note = "`text` now returns `&str`, use `text().into()`")]
+    pub fn text_smol_str(&self) -> SmolStr {
+        self.text().into()
+    }
     /// Text range, covered by this token.
     #[inline]
     pub fn range(&self) -> TextRange {
@@ -75,10 +81,10 @@ impl<'a> SyntaxToken<'a> {
         let index_in_green = self.index_in_green.prev();
         let green = self.parent().green.get_child(index_in_green)?;
         let element = match green {
-            GreenElement::Token(it) => {
+            GreenElement::Token(_) => {
                 let token = SyntaxToken {
                     parent: self.parent(),
-                    start_offset: self.start_offset - it.text_len(),
+                    start_offset: self.parent().child_start(index_in_green.0 as usize),
                     index_in_green,
                     index_in_parent: self.index_in_parent,
                 };
@@ -116,29 +122,23 @@ impl<'a> SyntaxToken<'a> {
     /// Returns a green tree, equal to the green tree this token
     /// belongs two, except with this token substitute. The complexity
     /// of operation is proportional to the depth of the tree
+    ///
+    /// Panics if the kind of `replacement` differs from the kind of this
+    /// token, see `try_replace_with`.
     pub fn replace_with(&self, replacement: GreenToken) -> GreenNode {
-        assert_eq!(self.kind(), replacement.kind());
-        let mut replacement = Some(replacement);
+        self.try_replace_with(replacement).unwrap_or_else(|err| {
+            panic!("replace_with: {}, for {}", err, ElementDisplay(self.kind(), self.range()))
+        })
+    }
+
+    /// Like `replace_with`, but returns an error instead of panicking if the
+    /// kind of `replacement` differs from the kind of this token.
+    pub fn try_replace_with(&self, replacement: GreenToken) -> Result<GreenNode, KindMismatch> {
+        KindMismatch::check(self.kind(), replacement.kind())?;
         let parent = self.parent();
         let me = self.index_in_green;
-
-        let children: Box<[_]> =
-            parent
-                .green()
-                .children()
-                .iter()
-                .enumerate()
-                .map(|(i, child)| {
-                    if i as u32 == me.0 {
-                        replacement.take().unwrap().into()
-                    } else {
-                        child.clone()
-                    }
-                })
-                .collect();
-        assert!(replacement.is_none());
-        let new_parent = GreenNode::new(parent.kind(), children);
-        parent.replace_with(new_parent)
+        let new_parent = parent.green().replace_child(me.0 as usize, replacement.into());
+        Ok(parent.replace_green(new_parent))
     }
 
     fn green(&self) -> &'a GreenToken {
@@ -0,0 +1,145 @@
+//! Synthetic trees of different shapes, shared by the tests and the
+//! benchmarks. Not a part of the public API: random trees for property
+//! tests are in `generate`, which this module reexports.
+use crate::{
+    cursor::{SyntaxElement, SyntaxNode},
+    GreenNode, GreenNodeBuilder, SyntaxKind, WalkEvent,
+};
+
+pub use crate::generate::{gen_tree, Rng, TreeConfig};
+
+pub const WORD: SyntaxKind = SyntaxKind(0);
+pub const WS: SyntaxKind = SyntaxKind(1);
+pub const LIST: SyntaxKind = SyntaxKind(2);
+pub const ROOT: SyntaxKind = SyntaxKind(3);
+
+/// The small tree of the unit tests:
+///
+/// ```text
+/// ROOT@[0; 11)
+///   LIST@[0; 7)
+///     WORD "foo"
+///     WS " "
+///     LIST@[4; 7)
+///       WORD "bar"
+///   WS " "
+///   WORD "baz"
+/// ```
+pub fn sample() -> GreenNode {
+    let mut builder = GreenNodeBuilder::new();
+    builder.start_node(ROOT);
+    builder.start_node(LIST);
+    builder.token(WORD, "foo".into());
+    builder.token(WS, " ".into());
+    builder.start_node(LIST);
+    builder.token(WORD, "bar".into());
+    builder.finish_node();
+    builder.finish_node();
+    builder.token(WS, " ".into());
+    builder.token(WORD, "baz".into());
+    builder.finish_node();
+    builder.finish()
+}
+
+/// The texts of the tokens of `node`, concatenated token by token, so that
+/// it doesn't depend on `SyntaxText`.
+pub fn text_of(node: &SyntaxNode) -> String {
+    node.preorder_with_tokens()
+        .filter_map(|event| match event {
+            WalkEvent::Enter(SyntaxElement::Token(token)) => Some(token.text().to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// A single list with `n_children` children, every other of which is a list
+/// with a single word.
+pub fn wide_tree(n_children: usize) -> GreenNode {
+    let mut builder = GreenNodeBuilder::new();
+    builder.start_node(LIST);
+    for i in 0..n_children {
+        if i % 2 == 0 {
+            builder.start_node(LIST);
+            builder.token(WORD, "word".into());
+            builder.finish_node();
+        } else {
+            builder.token(WS, " ".into());
+        }
+    }
+    builder.finish_node();
+    builder.finish()
+}
+
+/// `depth` lists, nested in each other, with a single "x" word in the
+/// innermost one.
+pub fn deep_tree(depth: usize) -> GreenNode {
+    let mut builder = GreenNodeBuilder::new();
+    for _ in 0..depth {
+        builder.start_node(LIST);
+    }
+    builder.token(WORD, "x".into());
+    for _ in 0..depth {
+        builder.finish_node();
+    }
+    builder.finish()
+}
+
+/// A tree with `n_tokens` tokens, grouped into lists of ten, which are in
+/// turn grouped into lists of ten, and so on.
+pub fn nested_tree(n_tokens: usize) -> GreenNode {
+    fn go(builder: &mut GreenNodeBuilder, n_tokens: usize) {
+        builder.start_node(LIST);
+        if n_tokens <= 10 {
+            for i in 0..n_tokens {
+                let (kind, text) = if i % 2 == 0 { (WORD, "word") } else { (WS, " ") };
+                builder.token(kind, text.into());
+            }
+        } else {
+            let chunk = n_tokens.div_ceil(10);
+            let mut left = n_tokens;
+            while left > 0 {
+                let n = chunk.min(left);
+                go(builder, n);
+                left -= n;
+            }
+        }
+        builder.finish_node();
+    }
+    let mut builder = GreenNodeBuilder::new();
+    go(&mut builder, n_tokens);
+    builder.finish()
+}
+
+/// A tree with `n_tokens` tokens, which looks more like real code: lists of
+/// random length and depth (up to 16), words of random length, and runs of
+/// whitespace with newlines.
+pub fn mixed_tree(rng: &mut Rng, n_tokens: usize) -> GreenNode {
+    const WORDS: [&str; 6] = ["x", "fn", "let", "self", "return", "ident_123"];
+    const SPACES: [&str; 4] = [" ", "\n", "\n    ", "  "];
+    let mut builder = GreenNodeBuilder::new();
+    builder.start_node(LIST);
+    let mut depth = 0;
+    for i in 0..n_tokens {
+        match rng.below(8) {
+            0 if depth < 16 => {
+                builder.start_node(LIST);
+                depth += 1;
+            }
+            1 if depth > 0 => {
+                builder.finish_node();
+                depth -= 1;
+            }
+            _ => (),
+        }
+        if i % 2 == 0 {
+            builder.token(WORD, WORDS[rng.below(WORDS.len() as u64) as usize].into());
+        } else {
+            builder.token(WS, SPACES[rng.below(SPACES.len() as u64) as usize].into());
+        }
+    }
+    for _ in 0..depth {
+        builder.finish_node();
+    }
+    builder.finish_node();
+    builder.finish()
+}
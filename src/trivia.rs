@@ -0,0 +1,347 @@
+use crate::{
+    cursor::{SyntaxElement, SyntaxNode, SyntaxToken},
+    SyntaxKind, SyntaxKindSet,
+};
+
+/// The kinds which navigation through `with_trivia` skips.
+///
+/// Both tokens and nodes can be trivia. A trivia node is skipped with its
+/// whole subtree, while a node of another kind is kept even if there's only
+/// trivia inside of it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct TriviaConfig {
+    kinds: SyntaxKindSet,
+}
+
+impl TriviaConfig {
+    pub const fn new(kinds: SyntaxKindSet) -> TriviaConfig {
+        TriviaConfig { kinds }
+    }
+
+    pub const fn is_trivia(&self, kind: SyntaxKind) -> bool {
+        self.kinds.contains(kind)
+    }
+}
+
+/// A view of a node, a token or an element, whose navigation methods skip
+/// trivia. See `SyntaxNode::with_trivia`.
+///
+/// The tree itself is not changed: the methods return ordinary elements,
+/// with their usual ranges. To keep skipping trivia from there, call
+/// `with_trivia` on them again.
+#[derive(Debug)]
+pub struct WithTrivia<'a, T> {
+    element: &'a T,
+    config: &'a TriviaConfig,
+}
+
+impl<T> Clone for WithTrivia<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for WithTrivia<'_, T> {}
+
+impl SyntaxNode {
+    /// A view of this node, which skips the trivia of `config`.
+    pub fn with_trivia<'a>(&'a self, config: &'a TriviaConfig) -> WithTrivia<'a, SyntaxNode> {
+        WithTrivia { element: self, config }
+    }
+}
+
+impl SyntaxToken {
+    /// A view of this token, which skips the trivia of `config`.
+    pub fn with_trivia<'a>(&'a self, config: &'a TriviaConfig) -> WithTrivia<'a, SyntaxToken> {
+        WithTrivia { element: self, config }
+    }
+}
+
+impl SyntaxElement {
+    /// A view of this element, which skips the trivia of `config`.
+    pub fn with_trivia<'a>(&'a self, config: &'a TriviaConfig) -> WithTrivia<'a, SyntaxElement> {
+        WithTrivia { element: self, config }
+    }
+}
+
+impl<'a> WithTrivia<'a, SyntaxNode> {
+    pub fn children(&self) -> impl Iterator<Item = SyntaxNode> + 'a {
+        let config = self.config;
+        self.element.children().filter(move |it| !config.is_trivia(it.kind()))
+    }
+
+    pub fn children_with_tokens(&self) -> impl Iterator<Item = SyntaxElement> + 'a {
+        let config = self.config;
+        self.element.children_with_tokens().filter(move |it| !config.is_trivia(it.kind()))
+    }
+
+    pub fn first_child_or_token(&self) -> Option<SyntaxElement> {
+        self.skip_forward(self.element.first_child_or_token())
+    }
+
+    pub fn last_child_or_token(&self) -> Option<SyntaxElement> {
+        self.skip_backward(self.element.last_child_or_token())
+    }
+
+    pub fn next_sibling_or_token(&self) -> Option<SyntaxElement> {
+        self.skip_forward(self.element.next_sibling_or_token())
+    }
+
+    pub fn prev_sibling_or_token(&self) -> Option<SyntaxElement> {
+        self.skip_backward(self.element.prev_sibling_or_token())
+    }
+
+    /// The first non-trivia token in the subtree of this node, outside of
+    /// trivia nodes.
+    pub fn first_token(&self) -> Option<SyntaxToken> {
+        first_token(self.element.first_child_or_token(), self.config)
+    }
+
+    /// The last non-trivia token in the subtree of this node, outside of
+    /// trivia nodes.
+    pub fn last_token(&self) -> Option<SyntaxToken> {
+        last_token(self.element.last_child_or_token(), self.config)
+    }
+}
+
+impl<'a> WithTrivia<'a, SyntaxToken> {
+    pub fn next_sibling_or_token(&self) -> Option<SyntaxElement> {
+        self.skip_forward(self.element.next_sibling_or_token())
+    }
+
+    pub fn prev_sibling_or_token(&self) -> Option<SyntaxElement> {
+        self.skip_backward(self.element.prev_sibling_or_token())
+    }
+
+    /// The next non-trivia token in the file, outside of trivia nodes.
+    pub fn next_token(&self) -> Option<SyntaxToken> {
+        std::iter::successors(self.element.next_token(), SyntaxToken::next_token)
+            .find(|it| !self.is_in_trivia(it))
+    }
+
+    /// The previous non-trivia token in the file, outside of trivia nodes.
+    pub fn prev_token(&self) -> Option<SyntaxToken> {
+        std::iter::successors(self.element.prev_token(), SyntaxToken::prev_token)
+            .find(|it| !self.is_in_trivia(it))
+    }
+
+    fn is_in_trivia(&self, token: &SyntaxToken) -> bool {
+        self.config.is_trivia(token.kind())
+            || token.parent().ancestors().any(|it| self.config.is_trivia(it.kind()))
+    }
+}
+
+impl<'a> WithTrivia<'a, SyntaxElement> {
+    pub fn next_sibling_or_token(&self) -> Option<SyntaxElement> {
+        self.skip_forward(self.element.next_sibling_or_token())
+    }
+
+    pub fn prev_sibling_or_token(&self) -> Option<SyntaxElement> {
+        self.skip_backward(self.element.prev_sibling_or_token())
+    }
+
+    /// The first non-trivia token of this element, see
+    /// `WithTrivia::<SyntaxNode>::first_token`.
+    pub fn first_token(&self) -> Option<SyntaxToken> {
+        match self.element {
+            SyntaxElement::Node(it) => it.with_trivia(self.config).first_token(),
+            SyntaxElement::Token(it) if self.config.is_trivia(it.kind()) => None,
+            SyntaxElement::Token(it) => Some(it.clone()),
+        }
+    }
+
+    pub fn last_token(&self) -> Option<SyntaxToken> {
+        match self.element {
+            SyntaxElement::Node(it) => it.with_trivia(self.config).last_token(),
+            SyntaxElement::Token(it) if self.config.is_trivia(it.kind()) => None,
+            SyntaxElement::Token(it) => Some(it.clone()),
+        }
+    }
+}
+
+impl<T> WithTrivia<'_, T> {
+    /// `element` or its first following sibling, which is not trivia.
+    fn skip_forward(&self, element: Option<SyntaxElement>) -> Option<SyntaxElement> {
+        std::iter::successors(element, SyntaxElement::next_sibling_or_token)
+            .find(|it| !self.config.is_trivia(it.kind()))
+    }
+
+    fn skip_backward(&self, element: Option<SyntaxElement>) -> Option<SyntaxElement> {
+        std::iter::successors(element, SyntaxElement::prev_sibling_or_token)
+            .find(|it| !self.config.is_trivia(it.kind()))
+    }
+}
+
+/// The first non-trivia token in `first` and its following siblings, and in
+/// their subtrees. Each level keeps the next sibling to look at, so the walk
+/// never goes above the level of `first`.
+fn first_token(first: Option<SyntaxElement>, config: &TriviaConfig) -> Option<SyntaxToken> {
+    let mut stack = vec![first];
+    while let Some(next) = stack.last_mut() {
+        let element = match next.take() {
+            Some(it) => it,
+            None => {
+                stack.pop();
+                continue;
+            }
+        };
+        *next = element.next_sibling_or_token();
+        if config.is_trivia(element.kind()) {
+            continue;
+        }
+        match element {
+            SyntaxElement::Token(it) => return Some(it),
+            SyntaxElement::Node(it) => stack.push(it.first_child_or_token()),
+        }
+    }
+    None
+}
+
+fn last_token(last: Option<SyntaxElement>, config: &TriviaConfig) -> Option<SyntaxToken> {
+    let mut stack = vec![last];
+    while let Some(prev) = stack.last_mut() {
+        let element = match prev.take() {
+            Some(it) => it,
+            None => {
+                stack.pop();
+                continue;
+            }
+        };
+        *prev = element.prev_sibling_or_token();
+        if config.is_trivia(element.kind()) {
+            continue;
+        }
+        match element {
+            SyntaxElement::Token(it) => return Some(it),
+            SyntaxElement::Node(it) => stack.push(it.last_child_or_token()),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_utils::{mixed_tree, Rng, LIST, ROOT, WORD, WS},
+        GreenNode, GreenNodeBuilder, TextRange, WalkEvent,
+    };
+
+    const COMMENT: SyntaxKind = SyntaxKind(4);
+
+    /// The elements of the subtree of `node` in preorder, except for
+    /// trivia and their subtrees.
+    fn filtered_preorder(node: &SyntaxNode, config: &TriviaConfig) -> Vec<SyntaxElement> {
+        let mut res = Vec::new();
+        let mut trivia_depth = 0;
+        for event in node.preorder_with_tokens() {
+            match event {
+                WalkEvent::Enter(it) => {
+                    if trivia_depth > 0 || config.is_trivia(it.kind()) {
+                        trivia_depth += 1;
+                    } else {
+                        res.push(it);
+                    }
+                }
+                WalkEvent::Leave(_) if trivia_depth > 0 => trivia_depth -= 1,
+                WalkEvent::Leave(_) => (),
+            }
+        }
+        res
+    }
+
+    /// The same, through the view.
+    fn view_preorder(node: &SyntaxNode, config: &TriviaConfig) -> Vec<SyntaxElement> {
+        let mut res = vec![SyntaxElement::Node(node.clone())];
+        let mut stack = vec![node.with_trivia(config).first_child_or_token()];
+        while let Some(next) = stack.last_mut() {
+            let element = match next.take() {
+                Some(it) => it,
+                None => {
+                    stack.pop();
+                    continue;
+                }
+            };
+            *next = element.with_trivia(config).next_sibling_or_token();
+            res.push(element.clone());
+            if let SyntaxElement::Node(it) = element {
+                stack.push(it.with_trivia(config).first_child_or_token());
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn view_skips_trivia() {
+        // The root is not a list, so that lists can be trivia.
+        let list = mixed_tree(&mut Rng::new(92), 2_000);
+        let root = SyntaxNode::new_root(GreenNode::new(ROOT, vec![list.into()].into()));
+        let configs = [
+            TriviaConfig::new(SyntaxKindSet::new(&[WS])),
+            TriviaConfig::new(SyntaxKindSet::new(&[WS, LIST])),
+            TriviaConfig::default(),
+        ];
+        for config in configs.iter() {
+            let expected = filtered_preorder(&root, config);
+            assert_eq!(view_preorder(&root, config), expected);
+
+            let tokens = expected
+                .iter()
+                .filter_map(|it| match it {
+                    SyntaxElement::Token(it) => Some(it.clone()),
+                    SyntaxElement::Node(_) => None,
+                })
+                .collect::<Vec<_>>();
+            let view = root.with_trivia(config);
+            assert_eq!(view.first_token().as_ref(), tokens.first());
+            assert_eq!(view.last_token().as_ref(), tokens.last());
+            for pair in tokens.windows(2) {
+                assert_eq!(pair[0].with_trivia(config).next_token().as_ref(), Some(&pair[1]));
+                assert_eq!(pair[1].with_trivia(config).prev_token().as_ref(), Some(&pair[0]));
+            }
+        }
+    }
+
+    #[test]
+    fn nodes_of_trivia() {
+        // LIST@[0; 15)
+        //   WS " "
+        //   LIST@[1; 9)
+        //     COMMENT "// x"
+        //     WS "    "
+        //   WORD "foo"
+        //   LIST@[12; 15)
+        //     WS "   "
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(LIST);
+        builder.token(WS, " ".into());
+        builder.start_node(LIST);
+        builder.token(COMMENT, "// x".into());
+        builder.token(WS, "    ".into());
+        builder.finish_node();
+        builder.token(WORD, "foo".into());
+        builder.start_node(LIST);
+        builder.token(WS, "   ".into());
+        builder.finish_node();
+        builder.finish_node();
+        let root = SyntaxNode::new_root(builder.finish());
+        let config = TriviaConfig::new(SyntaxKindSet::new(&[WS, COMMENT]));
+        let view = root.with_trivia(&config);
+
+        // Lists of trivia are kept, but have no tokens in the view.
+        let comments = view.first_child_or_token().unwrap();
+        assert_eq!(comments.kind(), LIST);
+        assert_eq!(comments.with_trivia(&config).first_token(), None);
+        assert_eq!(view.children().count(), 2);
+        assert_eq!(view.children_with_tokens().count(), 3);
+        let foo = view.first_token().unwrap();
+        assert_eq!(foo.text(), "foo");
+        assert_eq!(view.last_token(), Some(foo.clone()));
+        assert_eq!(foo.with_trivia(&config).next_token(), None);
+        assert_eq!(foo.with_trivia(&config).prev_token(), None);
+        assert_eq!(foo.with_trivia(&config).prev_sibling_or_token(), Some(comments.clone()));
+        let spaces = view.last_child_or_token().unwrap();
+        assert_eq!(spaces.text_range(), TextRange::from_to(12.into(), 15.into()));
+        assert_eq!(spaces.with_trivia(&config).prev_sibling_or_token(), Some(foo.into()));
+    }
+}
@@ -0,0 +1,483 @@
+//! Walking a tree with callbacks, see `cursor::SyntaxNode::accept` and
+//! `cursor::SyntaxNode::rewrite`.
+use crate::{
+    cursor::{SyntaxElement, SyntaxElementChildren, SyntaxNode, SyntaxToken},
+    GreenElement, GreenNode, GreenToken, WalkEvent,
+};
+
+/// What the walk of `SyntaxNode::accept` does after a callback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VisitControl {
+    /// Goes on with the children of the node, or with the next element.
+    Continue,
+    /// Doesn't go into the children of the node, but still calls
+    /// `leave_node` for it. The same as `Continue` after a token.
+    SkipSubtree,
+    /// Ends the walk, without any further callbacks, not even `leave_node`
+    /// for the nodes entered so far.
+    Stop,
+}
+
+/// The callbacks of a preorder walk of a tree, see `SyntaxNode::accept`.
+/// Each has a default which does nothing and goes on with the walk, so a
+/// visitor only implements those it needs.
+pub trait Visitor {
+    /// Called for each node before its children.
+    fn enter_node(&mut self, node: &SyntaxNode) -> VisitControl {
+        let _ = node;
+        VisitControl::Continue
+    }
+
+    /// Called for each entered node after its children, or right after
+    /// `enter_node` if it returned `SkipSubtree`.
+    fn leave_node(&mut self, node: &SyntaxNode) {
+        let _ = node;
+    }
+
+    fn visit_token(&mut self, token: &SyntaxToken) -> VisitControl {
+        let _ = token;
+        VisitControl::Continue
+    }
+}
+
+impl SyntaxNode {
+    /// Walks the subtree of this node, this node included, in preorder,
+    /// calling `visitor` for each node and token. The walk is iterative, so
+    /// deep trees don't overflow the stack.
+    pub fn accept(&self, visitor: &mut impl Visitor) {
+        // The depth tells when the walk leaves this node, without comparing
+        // nodes.
+        let mut depth = 0usize;
+        let mut next = Some(WalkEvent::Enter(SyntaxElement::Node(self.clone())));
+        while let Some(event) = next.take() {
+            next = match event {
+                WalkEvent::Enter(SyntaxElement::Node(node)) => match visitor.enter_node(&node) {
+                    VisitControl::Stop => return,
+                    VisitControl::SkipSubtree => Some(WalkEvent::Leave(node.into())),
+                    VisitControl::Continue => Some(match node.first_child_or_token() {
+                        Some(child) => {
+                            depth += 1;
+                            WalkEvent::Enter(child)
+                        }
+                        None => WalkEvent::Leave(node.into()),
+                    }),
+                },
+                WalkEvent::Enter(SyntaxElement::Token(token)) => {
+                    match visitor.visit_token(&token) {
+                        VisitControl::Stop => return,
+                        VisitControl::SkipSubtree | VisitControl::Continue => {
+                            Some(WalkEvent::Leave(token.into()))
+                        }
+                    }
+                }
+                WalkEvent::Leave(element) => {
+                    if let SyntaxElement::Node(node) = &element {
+                        visitor.leave_node(node);
+                    }
+                    if depth == 0 {
+                        None
+                    } else {
+                        Some(match element.next_sibling_or_token() {
+                            Some(sibling) => WalkEvent::Enter(sibling),
+                            None => {
+                                depth -= 1;
+                                WalkEvent::Leave(element.parent().unwrap().into())
+                            }
+                        })
+                    }
+                }
+            };
+        }
+    }
+}
+
+/// What `SyntaxNode::rewrite` does with a node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rewrite {
+    /// Keeps the subtree of the node as is, without visiting it.
+    Keep,
+    /// Puts this node in place of the subtree of the node, which is not
+    /// visited.
+    Replace(GreenNode),
+    /// Visits the children of the node, to rewrite them.
+    Recurse,
+}
+
+/// The callbacks of `SyntaxNode::rewrite`. By default, all nodes are visited
+/// and nothing is rewritten.
+pub trait RewriteVisitor {
+    /// Called for each node before its children, if it is visited.
+    fn rewrite_node(&mut self, node: &SyntaxNode) -> Rewrite {
+        let _ = node;
+        Rewrite::Recurse
+    }
+
+    /// Called for each token of the visited nodes. `None` keeps the token.
+    fn rewrite_token(&mut self, token: &SyntaxToken) -> Option<GreenToken> {
+        let _ = token;
+        None
+    }
+}
+
+/// A node whose children are being rewritten.
+struct RewriteFrame {
+    node: SyntaxNode,
+    children: SyntaxElementChildren,
+    new_children: Vec<GreenElement>,
+    changed: bool,
+}
+
+impl RewriteFrame {
+    fn new(node: SyntaxNode) -> RewriteFrame {
+        let children = node.children_with_tokens();
+        RewriteFrame { node, children, new_children: Vec::new(), changed: false }
+    }
+
+    fn push(&mut self, child: GreenElement, changed: bool) {
+        self.new_children.push(child);
+        self.changed |= changed;
+    }
+}
+
+impl SyntaxNode {
+    /// Rewrites the subtree of this node with `visitor`, in a single
+    /// preorder walk, and returns the new green node. Use `replace_with` to
+    /// put it into the whole tree.
+    ///
+    /// The green nodes are rebuilt bottom-up, and only those with a rewritten
+    /// descendant: the others, and the subtrees which weren't visited, are
+    /// shared with the old tree, so that a rewrite which changes nothing
+    /// returns the green node of this node itself. A replacement can be of
+    /// any kind.
+    pub fn rewrite(&self, visitor: &mut impl RewriteVisitor) -> GreenNode {
+        match visitor.rewrite_node(self) {
+            Rewrite::Keep => return self.to_green(),
+            Rewrite::Replace(green) => return green,
+            Rewrite::Recurse => (),
+        }
+        // The walk is iterative, so deep trees don't overflow the stack.
+        let mut stack = vec![RewriteFrame::new(self.clone())];
+        loop {
+            let frame = stack.last_mut().unwrap();
+            match frame.children.next() {
+                Some(SyntaxElement::Token(token)) => match visitor.rewrite_token(&token) {
+                    Some(new) => {
+                        let changed = !new.ptr_eq(token.green());
+                        frame.push(new.into(), changed)
+                    }
+                    None => frame.push(token.to_green().into(), false),
+                },
+                Some(SyntaxElement::Node(node)) => match visitor.rewrite_node(&node) {
+                    Rewrite::Keep => frame.push(node.to_green().into(), false),
+                    Rewrite::Replace(new) => {
+                        let changed = !new.ptr_eq(node.green());
+                        frame.push(new.into(), changed)
+                    }
+                    Rewrite::Recurse => stack.push(RewriteFrame::new(node)),
+                },
+                None => {
+                    let frame = stack.pop().unwrap();
+                    let green = if frame.changed {
+                        GreenNode::new(frame.node.kind(), frame.new_children.into())
+                    } else {
+                        frame.node.to_green()
+                    };
+                    match stack.last_mut() {
+                        Some(parent) => parent.push(green.into(), frame.changed),
+                        None => return green,
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_utils::{deep_tree, mixed_tree, sample, text_of, Rng, LIST, ROOT, WORD, WS},
+        GreenNodeBuilder, SyntaxKind,
+    };
+
+    /// Records the callbacks, and skips the subtrees of the nodes of `skip`.
+    #[derive(Default)]
+    struct Log {
+        skip: Option<SyntaxKind>,
+        stop_at: Option<&'static str>,
+        events: Vec<String>,
+    }
+
+    impl Visitor for Log {
+        fn enter_node(&mut self, node: &SyntaxNode) -> VisitControl {
+            self.events.push(format!("enter {}", node.kind().0));
+            if Some(node.kind()) == self.skip {
+                VisitControl::SkipSubtree
+            } else {
+                VisitControl::Continue
+            }
+        }
+
+        fn leave_node(&mut self, node: &SyntaxNode) {
+            self.events.push(format!("leave {}", node.kind().0));
+        }
+
+        fn visit_token(&mut self, token: &SyntaxToken) -> VisitControl {
+            self.events.push(format!("{:?}", token.text()));
+            if Some(token.text()) == self.stop_at {
+                VisitControl::Stop
+            } else {
+                VisitControl::Continue
+            }
+        }
+    }
+
+    #[test]
+    fn walk() {
+        let root = SyntaxNode::new_root(sample());
+        let mut log = Log::default();
+        root.accept(&mut log);
+        assert_eq!(
+            log.events,
+            [
+                "enter 3", "enter 2", "\"foo\"", "\" \"", "enter 2", "\"bar\"", "leave 2",
+                "leave 2", "\" \"", "\"baz\"", "leave 3",
+            ]
+        );
+
+        // A subtree on its own.
+        let mut log = Log::default();
+        root.first_child().unwrap().last_child().unwrap().accept(&mut log);
+        assert_eq!(log.events, ["enter 2", "\"bar\"", "leave 2"]);
+    }
+
+    #[test]
+    fn skip_subtrees() {
+        let mut log = Log { skip: Some(LIST), ..Log::default() };
+        SyntaxNode::new_root(sample()).accept(&mut log);
+        assert_eq!(log.events, ["enter 3", "enter 2", "leave 2", "\" \"", "\"baz\"", "leave 3"]);
+
+        struct Count {
+            skip: fn(&SyntaxNode) -> bool,
+            nodes: usize,
+            tokens: usize,
+        }
+
+        impl Visitor for Count {
+            fn enter_node(&mut self, node: &SyntaxNode) -> VisitControl {
+                self.nodes += 1;
+                if (self.skip)(node) {
+                    VisitControl::SkipSubtree
+                } else {
+                    VisitControl::Continue
+                }
+            }
+
+            fn visit_token(&mut self, _: &SyntaxToken) -> VisitControl {
+                self.tokens += 1;
+                VisitControl::Continue
+            }
+        }
+
+        let root = SyntaxNode::new_root(mixed_tree(&mut Rng::new(405), 2_000));
+        let mut count = Count { skip: |_| false, nodes: 0, tokens: 0 };
+        root.accept(&mut count);
+        assert_eq!(count.nodes, root.preorder().filter_map(WalkEvent::enter).count());
+        assert_eq!(count.tokens, 2_000);
+
+        // The lists starting at an odd offset, which the root doesn't.
+        let skip = |it: &SyntaxNode| it.text_range().start().to_usize() % 2 == 1;
+        let mut count = Count { skip, nodes: 0, tokens: 0 };
+        root.accept(&mut count);
+        let elements = root.descendants_with_tokens_filtered(|it| !skip(it)).collect::<Vec<_>>();
+        assert_eq!(count.nodes, elements.iter().filter(|it| it.is_node()).count());
+        assert_eq!(count.tokens, elements.iter().filter(|it| it.is_token()).count());
+        assert!(count.tokens < 2_000);
+    }
+
+    #[test]
+    fn stop() {
+        let root = SyntaxNode::new_root(sample());
+        let mut log = Log { stop_at: Some("bar"), ..Log::default() };
+        root.accept(&mut log);
+        // No `leave_node` for the entered nodes.
+        assert_eq!(log.events, ["enter 3", "enter 2", "\"foo\"", "\" \"", "enter 2", "\"bar\""]);
+
+        struct FirstSpace(Option<SyntaxToken>, usize);
+
+        impl Visitor for FirstSpace {
+            fn enter_node(&mut self, _: &SyntaxNode) -> VisitControl {
+                assert!(self.0.is_none());
+                VisitControl::Continue
+            }
+
+            fn leave_node(&mut self, _: &SyntaxNode) {
+                assert!(self.0.is_none());
+            }
+
+            fn visit_token(&mut self, token: &SyntaxToken) -> VisitControl {
+                assert!(self.0.is_none());
+                self.1 += 1;
+                if token.kind() == WS {
+                    self.0 = Some(token.clone());
+                    return VisitControl::Stop;
+                }
+                VisitControl::Continue
+            }
+        }
+
+        let mut first = FirstSpace(None, 0);
+        root.accept(&mut first);
+        assert_eq!(first.0, root.first_token().unwrap().next_token());
+        assert_eq!(first.1, 2);
+    }
+
+    #[test]
+    fn deep_trees() {
+        struct Depth(usize, usize);
+
+        impl Visitor for Depth {
+            fn enter_node(&mut self, _: &SyntaxNode) -> VisitControl {
+                self.0 += 1;
+                self.1 = self.1.max(self.0);
+                VisitControl::Continue
+            }
+
+            fn leave_node(&mut self, _: &SyntaxNode) {
+                self.0 -= 1;
+            }
+        }
+
+        let mut depth = Depth(0, 0);
+        SyntaxNode::new_root(deep_tree(100_000)).accept(&mut depth);
+        assert_eq!((depth.0, depth.1), (0, 100_000));
+    }
+
+    /// Renames the tokens with the text `from` to `to`, replaces the nodes
+    /// with the text of `replace`, keeps those of kind `keep`, and records
+    /// what it visits.
+    #[derive(Default)]
+    struct Rewriter {
+        from: &'static str,
+        to: &'static str,
+        replace: Option<(&'static str, GreenNode)>,
+        keep: Option<SyntaxKind>,
+        visited: Vec<String>,
+    }
+
+    impl RewriteVisitor for Rewriter {
+        fn rewrite_node(&mut self, node: &SyntaxNode) -> Rewrite {
+            self.visited.push(node.kind().0.to_string());
+            match &self.replace {
+                Some((text, green)) if node.text().to_string() == *text => {
+                    Rewrite::Replace(green.clone())
+                }
+                _ if Some(node.kind()) == self.keep => Rewrite::Keep,
+                _ => Rewrite::Recurse,
+            }
+        }
+
+        fn rewrite_token(&mut self, token: &SyntaxToken) -> Option<GreenToken> {
+            self.visited.push(token.text().to_string());
+            if token.text() == self.from {
+                Some(GreenToken::new(token.kind(), self.to.into()))
+            } else {
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn identity_rewrites() {
+        struct Identity;
+
+        impl RewriteVisitor for Identity {}
+
+        let root = SyntaxNode::new_root(sample());
+        assert!(root.rewrite(&mut Identity).ptr_eq(root.green()));
+        let root = SyntaxNode::new_root(mixed_tree(&mut Rng::new(406), 2_000));
+        assert!(root.rewrite(&mut Identity).ptr_eq(root.green()));
+        let list = root.first_child().unwrap();
+        assert!(list.rewrite(&mut Identity).ptr_eq(list.green()));
+
+        // Replacing tokens and nodes with themselves changes nothing either.
+        struct Same;
+
+        impl RewriteVisitor for Same {
+            fn rewrite_node(&mut self, node: &SyntaxNode) -> Rewrite {
+                if node.parent().is_some() {
+                    Rewrite::Replace(node.to_green())
+                } else {
+                    Rewrite::Recurse
+                }
+            }
+
+            fn rewrite_token(&mut self, token: &SyntaxToken) -> Option<GreenToken> {
+                Some(token.to_green())
+            }
+        }
+
+        assert!(root.rewrite(&mut Same).ptr_eq(root.green()));
+    }
+
+    #[test]
+    fn rename_tokens() {
+        let root = SyntaxNode::new_root(sample());
+        let mut rewriter = Rewriter { from: "baz", to: "quux", ..Rewriter::default() };
+        let new = root.rewrite(&mut rewriter);
+        assert_eq!(rewriter.visited, ["3", "2", "foo", " ", "2", "bar", " ", "baz"]);
+        assert_eq!(text_of(&SyntaxNode::new_root(new.clone())), "foo bar quux");
+        // The list, without a renamed token, is shared.
+        match &new.children()[0] {
+            GreenElement::Node(it) => assert!(it.ptr_eq(root.first_child().unwrap().green())),
+            GreenElement::Token(_) => panic!(),
+        }
+
+        let mut rewriter = Rewriter { from: "x", to: "y", ..Rewriter::default() };
+        let new = SyntaxNode::new_root(deep_tree(100_000)).rewrite(&mut rewriter);
+        assert_eq!(rewriter.visited.len(), 100_001);
+        assert_eq!(text_of(&SyntaxNode::new_root(new)), "y");
+    }
+
+    #[test]
+    fn replace_nodes() {
+        let mut builder = GreenNodeBuilder::new();
+        builder.start_node(ROOT);
+        builder.token(WORD, "x".into());
+        builder.token(WORD, "y".into());
+        builder.finish_node();
+        let replacement = builder.finish();
+
+        // The inner list, in the outer one, which is recursed into. The
+        // children of the replaced node are not visited.
+        let root = SyntaxNode::new_root(sample());
+        let replace = Some(("bar", replacement.clone()));
+        let mut rewriter = Rewriter { from: "foo", to: "f", replace, ..Rewriter::default() };
+        let new = root.rewrite(&mut rewriter);
+        assert_eq!(rewriter.visited, ["3", "2", "foo", " ", "2", " ", "baz"]);
+        assert_eq!(text_of(&SyntaxNode::new_root(new.clone())), "f xy baz");
+        let list = match &new.children()[0] {
+            GreenElement::Node(it) => it.clone(),
+            GreenElement::Token(_) => panic!(),
+        };
+        assert_eq!(list.kind(), LIST);
+        match &list.children()[2] {
+            GreenElement::Node(it) => assert!(it.ptr_eq(&replacement)),
+            GreenElement::Token(_) => panic!(),
+        }
+
+        // The node itself.
+        let inner = root.first_child().unwrap().last_child().unwrap();
+        let replace = Some(("bar", replacement.clone()));
+        assert!(inner
+            .rewrite(&mut Rewriter { replace, ..Rewriter::default() })
+            .ptr_eq(&replacement));
+
+        // Kept nodes are not visited either.
+        let mut rewriter =
+            Rewriter { from: "foo", to: "f", keep: Some(LIST), ..Rewriter::default() };
+        let new = root.rewrite(&mut rewriter);
+        assert_eq!(rewriter.visited, ["3", "2", " ", "baz"]);
+        assert!(new.ptr_eq(root.green()));
+    }
+}
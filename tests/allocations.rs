@@ -0,0 +1,145 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    collections::HashSet,
+};
+
+use rowan::{
+    cursor::{set_free_list_capacity, SyntaxElement, SyntaxNode},
+    test_utils::{nested_tree, wide_tree, Rng},
+    GreenNodeBuilder, SyntaxKind,
+};
+
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|it| it.set(it.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn allocations_during(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(|it| it.get());
+    f();
+    ALLOCATIONS.with(|it| it.get()) - before
+}
+
+// Visits each child, only one of them is alive at a time.
+fn walk(root: &SyntaxNode) {
+    let mut child = root.first_child();
+    while let Some(it) = child {
+        child = it.next_sibling();
+    }
+}
+
+#[cfg(not(feature = "no-free-list"))]
+#[test]
+fn free_list_reuses_nodes() {
+    // Every other child is a node, so there are 10k of them.
+    let root = SyntaxNode::new_root(wide_tree(20_000));
+
+    set_free_list_capacity(0);
+    let without_reuse = allocations_during(|| walk(&root));
+    assert!(without_reuse >= 10_000, "{}", without_reuse);
+
+    set_free_list_capacity(16);
+    walk(&root);
+    let with_reuse = allocations_during(|| walk(&root));
+    assert_eq!(with_reuse, 0);
+
+    set_free_list_capacity(1 << 20);
+    let children = allocations_during(|| drop(root.children().collect::<Vec<_>>()));
+    assert!(children >= 10_000, "{}", children);
+    // All of the 10k children now sit in the free list.
+    let again = allocations_during(|| drop(root.children().collect::<Vec<_>>()));
+    assert!(again < 100, "{}", again);
+}
+
+#[cfg(feature = "no-free-list")]
+#[test]
+fn nodes_are_never_reused() {
+    // Every other child is a node, so there are 10k of them.
+    let root = SyntaxNode::new_root(wide_tree(20_000));
+    set_free_list_capacity(16);
+    walk(&root);
+    let allocations = allocations_during(|| walk(&root));
+    assert_eq!(allocations, 10_000);
+    assert_eq!(rowan::cursor::free_list_capacity(), 0);
+}
+
+#[test]
+fn preorder_creates_one_node_per_descendant() {
+    let mut builder = GreenNodeBuilder::new();
+    builder.start_node(SyntaxKind(0));
+    for _ in 0..100 {
+        builder.start_node(SyntaxKind(1));
+        for _ in 0..10 {
+            builder.start_node(SyntaxKind(1));
+            builder.finish_node();
+        }
+        builder.finish_node();
+    }
+    builder.finish_node();
+    let root = SyntaxNode::new_root(builder.finish());
+
+    // Without the free list, each new node is an allocation.
+    set_free_list_capacity(0);
+    let mut events = 0;
+    let allocations = allocations_during(|| events = root.preorder().count());
+    assert_eq!(events, 2 * 1101);
+    assert_eq!(allocations, 1100);
+
+    if cfg!(not(feature = "no-free-list")) {
+        set_free_list_capacity(16);
+        root.preorder().count();
+        let allocations = allocations_during(|| {
+            root.preorder().count();
+        });
+        assert_eq!(allocations, 0);
+    }
+}
+
+#[test]
+fn offset_queries_create_only_the_result_path() {
+    let root = SyntaxNode::new_root(nested_tree(10_000));
+    let len = root.text_range().end();
+    set_free_list_capacity(0);
+
+    let mut rng = Rng::new(7);
+    for _ in 0..100 {
+        let range = rng.range(len);
+        let mut covering = None;
+        let allocations = allocations_during(|| covering = Some(root.covering_node(range)));
+        let covering = covering.unwrap();
+        // Tokens are not allocated, every node on the path but the root is.
+        let node = match covering {
+            SyntaxElement::Node(it) => it,
+            SyntaxElement::Token(it) => it.parent(),
+        };
+        assert_eq!(allocations, node.ancestors().count() - 1, "{:?}", range);
+
+        let offset = rng.offset(len);
+        let mut tokens = None;
+        let allocations = allocations_during(|| tokens = Some(root.token_at_offset(offset)));
+        // Both tokens of a `Between` share the path down to the node where
+        // they split.
+        let nodes = tokens
+            .unwrap()
+            .flat_map(|token| token.parent().ancestors())
+            .filter(|node| *node != root)
+            .collect::<HashSet<_>>();
+        assert_eq!(allocations, nodes.len(), "{:?}", offset);
+    }
+}
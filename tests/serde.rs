@@ -0,0 +1,400 @@
+#![cfg(feature = "serde1")]
+//! Round trips through a minimal self-describing data format, which
+//! supports just enough of serde for green trees.
+use std::sync::Arc;
+
+use rowan::{
+    cursor::{SyntaxNode, SyntaxNodePtr},
+    test_utils::{deep_tree, mixed_tree, text_of, Rng, LIST, WORD},
+    GreenElement, GreenNode, GreenNodeBuilder, GreenToken, SyntaxKind, TextRange,
+};
+use serde::{
+    de::{self, value::Error, IntoDeserializer, Visitor},
+    forward_to_deserialize_any,
+    ser::{self, Impossible},
+    Deserialize, Serialize,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    U64(u64),
+    Str(String),
+    Seq(Vec<Value>),
+    Variant(u32, &'static str, Box<Value>),
+}
+
+fn to_value<T: Serialize>(value: &T) -> Value {
+    value.serialize(ValueSerializer).unwrap()
+}
+
+fn from_value<'de, T: Deserialize<'de>>(value: Value) -> Result<T, Error> {
+    T::deserialize(value)
+}
+
+struct ValueSerializer;
+
+struct SeqSerializer(Vec<Value>);
+
+macro_rules! unsupported {
+    ($($method:ident($($arg:ty),*) -> $ret:ty;)*) => {$(
+        fn $method(self, $(_: $arg),*) -> Result<$ret, Error> {
+            Err(ser::Error::custom(concat!(stringify!($method), " is not supported")))
+        }
+    )*};
+}
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Value;
+    type Error = Error;
+    type SerializeSeq = SeqSerializer;
+    type SerializeTuple = SeqSerializer;
+    type SerializeTupleStruct = Impossible<Value, Error>;
+    type SerializeTupleVariant = Impossible<Value, Error>;
+    type SerializeMap = Impossible<Value, Error>;
+    type SerializeStruct = Impossible<Value, Error>;
+    type SerializeStructVariant = Impossible<Value, Error>;
+
+    fn serialize_u16(self, v: u16) -> Result<Value, Error> {
+        Ok(Value::U64(v.into()))
+    }
+    fn serialize_u32(self, v: u32) -> Result<Value, Error> {
+        Ok(Value::U64(v.into()))
+    }
+    fn serialize_str(self, v: &str) -> Result<Value, Error> {
+        Ok(Value::Str(v.to_string()))
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        Ok(Value::Variant(index, variant, Box::new(value.serialize(ValueSerializer)?)))
+    }
+    fn serialize_seq(self, len: Option<usize>) -> Result<SeqSerializer, Error> {
+        Ok(SeqSerializer(Vec::with_capacity(len.unwrap_or(0))))
+    }
+    fn serialize_tuple(self, len: usize) -> Result<SeqSerializer, Error> {
+        Ok(SeqSerializer(Vec::with_capacity(len)))
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, _: &T) -> Result<Value, Error> {
+        Err(ser::Error::custom("serialize_some is not supported"))
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        _: &T,
+    ) -> Result<Value, Error> {
+        Err(ser::Error::custom("serialize_newtype_struct is not supported"))
+    }
+
+    unsupported! {
+        serialize_bool(bool) -> Value;
+        serialize_i8(i8) -> Value;
+        serialize_i16(i16) -> Value;
+        serialize_i32(i32) -> Value;
+        serialize_i64(i64) -> Value;
+        serialize_u8(u8) -> Value;
+        serialize_u64(u64) -> Value;
+        serialize_f32(f32) -> Value;
+        serialize_f64(f64) -> Value;
+        serialize_char(char) -> Value;
+        serialize_bytes(&[u8]) -> Value;
+        serialize_none() -> Value;
+        serialize_unit() -> Value;
+        serialize_unit_struct(&'static str) -> Value;
+        serialize_unit_variant(&'static str, u32, &'static str) -> Value;
+        serialize_tuple_struct(&'static str, usize) -> Impossible<Value, Error>;
+        serialize_tuple_variant(&'static str, u32, &'static str, usize) -> Impossible<Value, Error>;
+        serialize_map(Option<usize>) -> Impossible<Value, Error>;
+        serialize_struct(&'static str, usize) -> Impossible<Value, Error>;
+        serialize_struct_variant(&'static str, u32, &'static str, usize) -> Impossible<Value, Error>;
+    }
+}
+
+impl ser::SerializeSeq for SeqSerializer {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.0.push(value.serialize(ValueSerializer)?);
+        Ok(())
+    }
+    fn end(self) -> Result<Value, Error> {
+        Ok(Value::Seq(self.0))
+    }
+}
+
+impl ser::SerializeTuple for SeqSerializer {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+    fn end(self) -> Result<Value, Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Value {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Value::U64(it) => visitor.visit_u64(it),
+            Value::Str(it) => visitor.visit_string(it),
+            Value::Seq(it) => {
+                let mut seq = de::value::SeqDeserializer::new(it.into_iter());
+                let res = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+                Ok(res)
+            }
+            Value::Variant(index, _, value) => visitor.visit_enum(Variant(index, *value)),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, Error> for Value {
+    type Deserializer = Value;
+    fn into_deserializer(self) -> Value {
+        self
+    }
+}
+
+struct Variant(u32, Value);
+
+impl<'de> de::EnumAccess<'de> for Variant {
+    type Error = Error;
+    type Variant = Value;
+    fn variant_seed<V: de::DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Value), Error> {
+        let index: de::value::U32Deserializer<Error> = self.0.into_deserializer();
+        Ok((seed.deserialize(index)?, self.1))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Value {
+    type Error = Error;
+    fn unit_variant(self) -> Result<(), Error> {
+        Err(de::Error::custom("unit variants are not supported"))
+    }
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self)
+    }
+    fn tuple_variant<V: Visitor<'de>>(self, _: usize, _: V) -> Result<V::Value, Error> {
+        Err(de::Error::custom("tuple variants are not supported"))
+    }
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _: &'static [&'static str],
+        _: V,
+    ) -> Result<V::Value, Error> {
+        Err(de::Error::custom("struct variants are not supported"))
+    }
+}
+
+/// What `serde_json::to_string` would produce.
+fn to_json(value: &Value) -> String {
+    fn go(value: &Value, buf: &mut String) {
+        match value {
+            Value::U64(it) => buf.push_str(&it.to_string()),
+            Value::Str(it) => {
+                buf.push('"');
+                for c in it.chars() {
+                    match c {
+                        '"' => buf.push_str("\\\""),
+                        '\\' => buf.push_str("\\\\"),
+                        '\n' => buf.push_str("\\n"),
+                        c if (c as u32) < 0x20 => buf.push_str(&format!("\\u{:04x}", c as u32)),
+                        c => buf.push(c),
+                    }
+                }
+                buf.push('"');
+            }
+            Value::Seq(it) => {
+                buf.push('[');
+                for (i, value) in it.iter().enumerate() {
+                    if i > 0 {
+                        buf.push(',');
+                    }
+                    go(value, buf);
+                }
+                buf.push(']');
+            }
+            Value::Variant(_, name, value) => {
+                buf.push_str(&format!("{{\"{}\":", name));
+                go(value, buf);
+                buf.push('}');
+            }
+        }
+    }
+    let mut buf = String::new();
+    go(value, &mut buf);
+    buf
+}
+
+fn node(kind: u64, children: Vec<Value>) -> Value {
+    Value::Variant(0, "Node", Box::new(Value::Seq(vec![Value::U64(kind), Value::Seq(children)])))
+}
+
+fn token(kind: u64, text: &str) -> Value {
+    Value::Variant(
+        1,
+        "Token",
+        Box::new(Value::Seq(vec![Value::U64(kind), Value::Str(text.to_string())])),
+    )
+}
+
+#[test]
+fn round_trip() {
+    let green = mixed_tree(&mut Rng::new(92), 10_000);
+    let back: GreenNode = from_value(to_value(&green)).unwrap();
+    assert!(back.structurally_eq(&green));
+    assert_eq!(back.text_len(), green.text_len());
+    assert_eq!(text_of(&SyntaxNode::new_root(back)), text_of(&SyntaxNode::new_root(green)));
+
+    // Tokens which share the source text come back owning it.
+    let source: Arc<str> = format!("{}\n", "x".repeat(1_000)).into();
+    let mut builder = GreenNodeBuilder::with_source(source.clone());
+    builder.start_node(LIST);
+    builder.source_token(WORD, TextRange::from_to(0.into(), 1_000.into()));
+    builder.token(WORD, "\n".into());
+    builder.finish_node();
+    let green = builder.finish();
+    let back: GreenNode = from_value(to_value(&green)).unwrap();
+    assert!(back.structurally_eq(&green));
+    assert_eq!(text_of(&SyntaxNode::new_root(back)), &*source);
+
+    let token = GreenToken::new(WORD, "word".into());
+    assert_eq!(to_value(&token), Value::Seq(vec![Value::U64(0), Value::Str("word".to_string())]));
+    assert_eq!(from_value::<GreenToken>(to_value(&token)).unwrap(), token);
+}
+
+#[test]
+fn round_trip_of_deep_trees() {
+    // The depth which the docs of the impls promise to support.
+    let round_trip = || {
+        let green = deep_tree(256);
+        let back: GreenNode = from_value(to_value(&green)).unwrap();
+        assert!(back.structurally_eq(&green));
+    };
+    std::thread::Builder::new()
+        .stack_size(2 * 1024 * 1024)
+        .spawn(round_trip)
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+#[test]
+fn deserialized_nodes_are_deduplicated() {
+    let children = (0..100)
+        .map(|_| {
+            let word = GreenToken::new(WORD, "word".into());
+            GreenElement::from(GreenNode::new(LIST, vec![word.into()].into_boxed_slice()))
+        })
+        .collect::<Vec<_>>();
+    let green = GreenNode::new(LIST, children.into_boxed_slice());
+    assert_eq!(green.memory_usage().nodes, 101);
+
+    let back: GreenNode = from_value(to_value(&green)).unwrap();
+    assert!(back.structurally_eq(&green));
+    assert_eq!(back.memory_usage().nodes, 2);
+}
+
+#[test]
+fn malformed_input_is_an_error() {
+    let valid = node(2, vec![token(0, "word"), node(2, vec![token(1, " ")])]);
+    let valid = match valid {
+        Value::Variant(0, _, it) => *it,
+        _ => unreachable!(),
+    };
+    let back: GreenNode = from_value(valid.clone()).unwrap();
+    assert_eq!(text_of(&SyntaxNode::new_root(back)), "word ");
+
+    let malformed = vec![
+        // A kind which is not a `u16`.
+        Value::Seq(vec![Value::U64(70_000), Value::Seq(vec![])]),
+        // A node which is too short, or too long.
+        Value::Seq(vec![Value::U64(2)]),
+        Value::Seq(vec![Value::U64(2), Value::Seq(vec![]), Value::Seq(vec![])]),
+        // Text instead of children.
+        Value::Seq(vec![Value::U64(2), Value::Str("word".to_string())]),
+        // An unknown kind of element.
+        Value::Seq(vec![
+            Value::U64(2),
+            Value::Seq(vec![Value::Variant(2, "Trivia", Box::new(valid))]),
+        ]),
+        // A token without text, deep in the tree.
+        Value::Seq(vec![
+            Value::U64(2),
+            Value::Seq(vec![node(
+                2,
+                vec![
+                    token(0, "word"),
+                    node(
+                        2,
+                        vec![Value::Variant(1, "Token", Box::new(Value::Seq(vec![Value::U64(0)])))],
+                    ),
+                ],
+            )]),
+        ]),
+    ];
+    for value in malformed {
+        assert!(from_value::<GreenNode>(value.clone()).is_err(), "{:?}", value);
+    }
+    assert!(from_value::<GreenToken>(Value::Seq(vec![Value::U64(0), Value::U64(0)])).is_err());
+}
+
+#[test]
+fn kinds_are_preserved() {
+    let mut builder = GreenNodeBuilder::new();
+    builder.start_node(SyntaxKind(u16::MAX));
+    builder.token(SyntaxKind(1234), "x".into());
+    builder.finish_node();
+    let green = builder.finish();
+    let back: GreenNode = from_value(to_value(&green)).unwrap();
+    assert_eq!(back.kind(), SyntaxKind(u16::MAX));
+    assert_eq!(back.children()[0].kind(), SyntaxKind(1234));
+}
+
+#[test]
+fn binary_encoding_is_smaller_than_json() {
+    let green = mixed_tree(&mut Rng::new(92), 10_000);
+    let json = to_json(&to_value(&green));
+    assert!(json.starts_with("[2,[{\"Token\":[0,\""), "{}", &json[..20]);
+    let bytes = green.to_bytes();
+    assert!(
+        bytes.len() * 3 < json.len(),
+        "json: {} bytes, binary: {} bytes",
+        json.len(),
+        bytes.len()
+    );
+}
+
+#[test]
+fn node_ptrs() {
+    let root = SyntaxNode::new_root(mixed_tree(&mut Rng::new(92), 100));
+    let node = root.last_child().unwrap();
+    let ptr = SyntaxNodePtr::new(&node);
+    let value = to_value(&ptr);
+    let (start, end) = (node.text_range().start().to_usize(), node.text_range().end().to_usize());
+    assert_eq!(to_json(&value), format!("[{},{},{}]", node.kind().0, start, end));
+    let back: SyntaxNodePtr = from_value(value).unwrap();
+    assert_eq!(back, ptr);
+    assert_eq!(back.to_node(&root), Some(node));
+
+    let backwards = Value::Seq(vec![Value::U64(2), Value::U64(7), Value::U64(4)]);
+    let error = from_value::<SyntaxNodePtr>(backwards).unwrap_err();
+    assert_eq!(error.to_string(), "the start of the range is after the end");
+    let short = Value::Seq(vec![Value::U64(2), Value::U64(4)]);
+    assert!(from_value::<SyntaxNodePtr>(short).is_err());
+}